@@ -43,7 +43,7 @@ pub(crate) fn read_blob(blob_ptr: Lsn, config: &Config) -> Result<Vec<u8>> {
         })
     } else {
         let buf = if config.use_compression {
-            maybe_decompress(buf)?
+            maybe_decompress(buf, config)?
         } else {
             buf
         };
@@ -107,6 +107,30 @@ pub(crate) fn gc_blobs(config: &Config, stable_lsn: Lsn) -> Result<()> {
     Ok(())
 }
 
+// Returns the lsn of every blob in the blob directory.
+pub(crate) fn blob_ids(config: &Config) -> Result<Vec<Lsn>> {
+    let blob_dir = config.blob_path(0);
+    let blob_dir = blob_dir.parent().unwrap();
+
+    let mut ids = vec![];
+    for blob in std::fs::read_dir(blob_dir)? {
+        let path = blob?.path();
+        let lsn_str = path.file_name().unwrap().to_str().unwrap();
+        match lsn_str.parse() {
+            Ok(lsn) => ids.push(lsn),
+            Err(e) => {
+                return Err(Error::Unsupported(format!(
+                    "blobs directory contains \
+                     unparsable path ({:?}): {}",
+                    path, e
+                )));
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
 pub(crate) fn remove_blob(id: Lsn, config: &Config) -> Result<()> {
     let path = config.blob_path(id);
 
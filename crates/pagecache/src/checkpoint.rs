@@ -0,0 +1,109 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use super::*;
+
+const COPY_BUF_SIZE: usize = 1 << 20;
+
+/// Copies the files that make up a running system into
+/// `dest`. The caller must prevent log segments from being
+/// reused, blobs from being removed, and snapshots from
+/// being written while this runs.
+///
+/// The data file is copied before the blobs, so that any
+/// blob referenced by the copied log was created before the
+/// blob directory was read. Blobs and log contents written
+/// after the copy began are either ignored or discarded
+/// during recovery, just as they would be after a crash.
+pub(crate) fn write_checkpoint(config: &Config, dest: &Path) -> Result<()> {
+    if dest.exists() && dest.read_dir()?.next().is_some() {
+        return Err(Error::Unsupported(format!(
+            "checkpoint destination {:?} must be empty",
+            dest
+        )));
+    }
+
+    let blob_dest = dest.join("blobs");
+    fs::create_dir_all(&blob_dest)?;
+
+    copy_file(&config.config_path(), &dest.join("conf"))?;
+
+    let retention_path = config.retention_path();
+    if retention_path.exists() {
+        copy_file(&retention_path, &dest.join("retention"))?;
+    }
+
+    for snapshot in config.get_snapshot_files()? {
+        let name = snapshot.file_name().unwrap().to_owned();
+        if name.to_string_lossy().ends_with(".generating") {
+            continue;
+        }
+        copy_file(&snapshot, &dest.join(name))?;
+    }
+
+    copy_data_file(config, &dest.join("db"))?;
+
+    let blob_dir: PathBuf = config.get_path().join("blobs");
+    for blob in fs::read_dir(&blob_dir)? {
+        let path = blob?.path();
+        let name = path.file_name().unwrap().to_owned();
+        match copy_file(&path, &blob_dest.join(name)) {
+            // blobs that belong to aborted reservations
+            // may be removed while we are copying, but
+            // they are never referenced by the log.
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                trace!("skipping removed blob {:?} in checkpoint", path);
+            }
+            other => other?,
+        }
+    }
+
+    sync_dir(&blob_dest)?;
+    sync_dir(dest)?;
+
+    Ok(())
+}
+
+// Copies the data file using the already-opened handle,
+// because it is exclusively locked by this process.
+fn copy_data_file(config: &Config, to: &Path) -> Result<()> {
+    let len = config.file.metadata()?.len();
+
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+
+    let mut buf = vec![0; COPY_BUF_SIZE];
+    let mut offset = 0;
+    while offset < len {
+        let chunk = std::cmp::min(len - offset, COPY_BUF_SIZE as u64);
+        let buf = &mut buf[..assert_usize(chunk)];
+        config.file.pread_exact(buf, offset)?;
+        f.write_all(buf)?;
+        offset += chunk;
+    }
+
+    f.sync_all()?;
+
+    Ok(())
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    fs::copy(from, to)?;
+    fs::File::open(to)?.sync_all()?;
+    Ok(())
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+
+    #[cfg(not(unix))]
+    let _ = dir;
+
+    Ok(())
+}
@@ -87,6 +87,9 @@ pub struct ConfigBuilder {
     #[doc(hidden)]
     pub merge_operator: Option<usize>,
     #[doc(hidden)]
+    #[serde(skip)]
+    pub merge_operator_fn: Option<MergeOperatorFn>,
+    #[doc(hidden)]
     pub print_profile_on_drop: bool,
     #[doc(hidden)]
     pub idgen_persist_interval: u64,
@@ -94,6 +97,8 @@ pub struct ConfigBuilder {
     pub async_io: bool,
     #[doc(hidden)]
     pub async_io_threads: usize,
+    #[doc(hidden)]
+    pub format_version: u32,
 }
 
 unsafe impl Send for ConfigBuilder {}
@@ -119,10 +124,12 @@ impl Default for ConfigBuilder {
             temporary: false,
             segment_mode: SegmentMode::Gc,
             merge_operator: None,
+            merge_operator_fn: None,
             print_profile_on_drop: false,
             idgen_persist_interval: 1_000_000,
             async_io: true,
             async_io_threads: 3,
+            format_version: FORMAT_VERSION,
         }
     }
 }
@@ -158,10 +165,32 @@ impl ConfigBuilder {
         self
     }
 
+    /// Set the options that cannot change across restarts,
+    /// `io_buf_size` and `use_compression`, to the values stored
+    /// with the database at the configured path, if there is one
+    /// (builder). This lets tools open databases that they did
+    /// not create.
+    pub fn stored_options(mut self) -> Result<ConfigBuilder> {
+        if let Some(stored) = self.read_config()? {
+            self.io_buf_size = stored.io_buf_size;
+            self.use_compression = stored.use_compression;
+        }
+        Ok(self)
+    }
+
     /// Set the merge operator that can be relied on during merges in
     /// the `PageCache`.
+    ///
+    /// It is still used to replay merges that were logged before
+    /// merge operators were set per tree, so a system that was
+    /// opened with one must keep being opened with it.
+    #[deprecated(
+        note = "set merge operators per tree with `Tree::set_merge_operator`"
+    )]
+    #[allow(deprecated)]
     pub fn merge_operator(mut self, mo: MergeOperator) -> ConfigBuilder {
         self.merge_operator = Some(mo as usize);
+        self.merge_operator_fn = Some(MergeOperatorFn(mo));
         self
     }
 
@@ -241,6 +270,7 @@ impl ConfigBuilder {
             file: Arc::new(file),
             refs: Arc::new(AtomicUsize::new(0)),
             global_error: Arc::new(AtomicPtr::default()),
+            metrics: Arc::new(Metrics::default()),
             #[cfg(feature = "event_log")]
             event_log: Arc::new(crate::event_log::EventLog::default()),
             thread_pool,
@@ -390,16 +420,15 @@ impl ConfigBuilder {
 
     fn verify_config_changes_ok(&self) -> Result<()> {
         match self.read_config() {
+            // NB a system written with another format version is
+            // refused by `Config::verify_format_version` when it's
+            // started, so its settings are neither compared nor
+            // overwritten here.
+            Ok(Some(ref old)) if old.format_version != self.format_version => {
+                Ok(())
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::InvalidData => Ok(()),
             Ok(Some(old)) => {
-                if old.merge_operator.is_some() {
-                    supported!(
-                        self.merge_operator.is_some(),
-                        "this system was previously opened with a \
-                         merge operator. must supply one FOREVER after \
-                         choosing to do so once, BWAHAHAHAHAHAHA!!!!"
-                    );
-                }
-
                 supported!(
                     self.use_compression == old.use_compression,
                     format!(
@@ -486,7 +515,15 @@ impl ConfigBuilder {
             );
         }
 
-        Ok(deserialize::<ConfigBuilder>(&*buf).ok())
+        // configurations written before the format version was
+        // recorded can't be deserialized, and must not be mistaken
+        // for missing ones, which would get overwritten.
+        deserialize::<ConfigBuilder>(&*buf).map(Some).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("failed to deserialize the configuration: {}", e),
+            )
+        })
     }
 
     // Get the path of the database
@@ -502,17 +539,23 @@ impl ConfigBuilder {
         path
     }
 
-    fn db_path(&self) -> PathBuf {
+    pub(crate) fn db_path(&self) -> PathBuf {
         let mut path = self.get_path();
         path.push("db");
         path
     }
 
-    fn config_path(&self) -> PathBuf {
+    pub(crate) fn config_path(&self) -> PathBuf {
         let mut path = self.get_path();
         path.push("conf");
         path
     }
+
+    pub(crate) fn retention_path(&self) -> PathBuf {
+        let mut path = self.get_path();
+        path.push("retention");
+        path
+    }
 }
 
 /// A finalized `ConfigBuilder` that can be use multiple times
@@ -525,6 +568,9 @@ pub struct Config {
     threads: Arc<AtomicUsize>,
     refs: Arc<AtomicUsize>,
     pub(crate) global_error: Arc<AtomicPtr<Error>>,
+    #[doc(hidden)]
+    /// the metrics of the pagecache that uses this `Config`
+    pub metrics: Arc<Metrics>,
     #[cfg(feature = "event_log")]
     /// an event log for concurrent debugging
     pub event_log: Arc<event_log::EventLog>,
@@ -545,6 +591,7 @@ impl Clone for Config {
             threads: self.threads.clone(),
             thread_pool: self.thread_pool.clone(),
             global_error: self.global_error.clone(),
+            metrics: self.metrics.clone(),
         }
     }
 }
@@ -564,7 +611,7 @@ impl Drop for Config {
             debug!("threadpool drained");
 
             if self.print_profile_on_drop {
-                M.print_profile();
+                self.metrics.print_profile();
             }
 
             if !self.temporary {
@@ -667,6 +714,53 @@ impl Config {
         Ok(snap_dir.read_dir()?.filter_map(filter).collect())
     }
 
+    /// Returns an `Error::Unsupported` if the system was written
+    /// with a different version of the on-disk format than the
+    /// one this crate uses.
+    #[doc(hidden)]
+    pub fn verify_format_version(&self) -> Result<()> {
+        let old_version = match self.read_config() {
+            Ok(Some(old)) => old.format_version,
+            Ok(None) => return Ok(()),
+            // configurations from before the format version was
+            // recorded don't deserialize into the current one.
+            Err(ref e) if e.kind() == std::io::ErrorKind::InvalidData => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        supported!(
+            old_version == self.format_version,
+            format!(
+                "this system was written with version {} of the \
+                 on-disk format, but this version of sled uses \
+                 version {}. please export it using the version \
+                 of sled that wrote it, and import it into a new \
+                 system with this one.",
+                old_version, self.format_version,
+            )
+        );
+
+        Ok(())
+    }
+
+    /// Returns an `Error::Unsupported` if the system was opened
+    /// with a merge operator before, which is needed to replay
+    /// the merges that were logged then, but none was set now.
+    #[doc(hidden)]
+    pub fn verify_merge_operator(&self) -> Result<()> {
+        if let Some(old) = self.read_config()? {
+            supported!(
+                old.merge_operator.is_none()
+                    || self.merge_operator_fn.is_some(),
+                "this system was previously opened with a \
+                 merge operator. must supply one FOREVER after \
+                 choosing to do so once, BWAHAHAHAHAHAHA!!!!"
+            );
+        }
+
+        Ok(())
+    }
+
     #[doc(hidden)]
     pub fn verify_snapshot<PM, P>(&self) -> Result<()>
     where
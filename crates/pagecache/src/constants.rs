@@ -27,6 +27,11 @@ pub const BATCH_MANIFEST: u8 = 4;
 /// detect torn writes.
 pub const EVIL_BYTE: u8 = 6;
 
+/// The version of the on-disk format that this crate writes.
+/// Systems that were written with another version can't be
+/// opened, and must be exported and imported instead.
+pub const FORMAT_VERSION: u32 = 1;
+
 /// Log messages have a header of this length.
 pub const MSG_HEADER_LEN: usize = 17;
 
@@ -0,0 +1,356 @@
+//! Inspection of the segments, messages and snapshot stored
+//! on disk, for debugging recovery. Nothing is ever written,
+//! and nothing is skipped: stale segments and the messages
+//! that recovery would ignore are reported too.
+use super::*;
+
+/// The contents of the log and the snapshot of a database,
+/// read by `inspect`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inspection<P>
+where
+    P: DeserializeOwned + Serialize,
+{
+    /// Every segment with a valid header, in the order of
+    /// their `Lsn`s.
+    pub segments: Vec<SegmentInfo<P>>,
+    /// The last snapshot that was written, if any.
+    pub snapshot: Option<SnapshotInfo>,
+}
+
+impl<P> Inspection<P>
+where
+    P: DeserializeOwned + Serialize,
+{
+    /// Iterates over the messages of every segment, in
+    /// the order of their `Lsn`s.
+    pub fn messages(&self) -> impl Iterator<Item = &MessageInfo<P>> {
+        self.segments
+            .iter()
+            .flat_map(|segment| segment.messages.iter())
+    }
+
+    /// Returns the messages that the current state of the
+    /// page `pid` is made of, starting from the last one
+    /// that replaced it as a whole.
+    pub fn page(&self, pid: PageId) -> Vec<&MessageInfo<P>> {
+        let mut chain = vec![];
+        for message in self.messages() {
+            match message.update {
+                Some((msg_pid, ref update)) if msg_pid == pid => {
+                    if let Update::Append(_) = update {
+                    } else {
+                        chain.clear();
+                    }
+                    chain.push(message);
+                }
+                _ => {}
+            }
+        }
+        chain
+    }
+
+    /// Returns the last `Meta` that was written, along with
+    /// the `Lsn` of its message.
+    pub fn meta(&self) -> Option<(Lsn, &Meta)> {
+        self.messages()
+            .filter_map(|message| match message.update {
+                Some((_pid, Update::Meta(ref meta))) => {
+                    Some((message.lsn, meta))
+                }
+                _ => None,
+            })
+            .last()
+    }
+}
+
+/// A segment of the log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentInfo<P>
+where
+    P: DeserializeOwned + Serialize,
+{
+    /// The offset of the segment in the file.
+    pub lid: LogId,
+    /// The `Lsn` in its header.
+    pub lsn: Lsn,
+    /// The `Lsn` in its trailer, if the trailer passed
+    /// its checksum.
+    pub trailer_lsn: Option<Lsn>,
+    /// The highest stable `Lsn` known when the trailer
+    /// was written, if it passed its checksum.
+    pub highest_known_stable_lsn: Option<Lsn>,
+    /// Whether the trailer belongs to this segment, which
+    /// means that the whole segment was written.
+    pub sealed: bool,
+    /// The percentage of the pages with fragments in this
+    /// segment that still need one of them, computed from
+    /// the log like the `SegmentAccountant` does.
+    pub live_pct: u8,
+    /// The messages of the segment, up to its pad or the
+    /// first one that is corrupted.
+    pub messages: Vec<MessageInfo<P>>,
+}
+
+/// A message in a segment of the log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageInfo<P>
+where
+    P: DeserializeOwned + Serialize,
+{
+    /// The offset of the message in the file.
+    pub lid: LogId,
+    /// The `Lsn` of the message.
+    pub lsn: Lsn,
+    /// The kind in its header.
+    pub kind: MessageKind,
+    /// The length of its contents, excluding the header.
+    pub len: usize,
+    /// The blob that it points to, if it is a blob message.
+    pub blob: Option<BlobPointer>,
+    /// The page that it updates, and the update, if it
+    /// could be read.
+    pub update: Option<(PageId, Update<P>)>,
+    /// Why its contents could not be read, if they could not.
+    pub error: Option<String>,
+}
+
+/// The contents of a snapshot file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotInfo {
+    /// The last `Lsn` included in the snapshot.
+    pub max_lsn: Lsn,
+    /// The highest `Lsn` persisted to a segment trailer.
+    pub max_trailer_stable_lsn: Lsn,
+    /// The last `LogId` included in the snapshot.
+    pub last_lid: LogId,
+    /// The highest `LogId` observed while generating it.
+    pub max_lid: LogId,
+    /// The highest allocated page.
+    pub max_pid: PageId,
+    /// The number of pages in its page table.
+    pub pages: usize,
+    /// The free pages, sorted.
+    pub free: Vec<PageId>,
+}
+
+/// Reads every segment of the log of `config` with a valid
+/// header, every message in it, and the last snapshot, without
+/// writing to any of them. The page fragments in the messages
+/// are passed through `describe`.
+///
+/// The database must not be in use.
+pub fn inspect<P, Q, F>(
+    config: &Config,
+    mut describe: F,
+) -> Result<Inspection<Q>>
+where
+    P: DeserializeOwned + Serialize,
+    Q: DeserializeOwned + Serialize,
+    F: FnMut(P) -> Q,
+{
+    let f = &config.file;
+    let segment_len = config.io_buf_size as LogId;
+
+    let mut segments = vec![];
+    let mut lid = 0;
+    while let Ok(header) = f.read_segment_header(lid) {
+        if header.ok && (header.lsn != 0 || lid == 0) {
+            segments.push(inspect_segment(
+                config,
+                lid,
+                header.lsn,
+                &mut describe,
+            )?);
+        }
+        lid += segment_len;
+    }
+
+    segments.sort_by_key(|segment| segment.lsn);
+
+    // the Lsn of the message that the current state of
+    // each page starts from
+    let mut chain_starts: FastMap8<PageId, Lsn> = FastMap8::default();
+    for segment in &segments {
+        for message in &segment.messages {
+            if let Some((pid, ref update)) = message.update {
+                if let Update::Append(_) = update {
+                } else {
+                    chain_starts.insert(pid, message.lsn);
+                }
+            }
+        }
+    }
+
+    for segment in &mut segments {
+        let mut pids = FastSet8::default();
+        let mut present = FastSet8::default();
+        for message in &segment.messages {
+            if let Some((pid, _)) = message.update {
+                pids.insert(pid);
+                let in_chain = match chain_starts.get(&pid) {
+                    Some(&lsn) => lsn <= message.lsn,
+                    None => false,
+                };
+                if in_chain {
+                    present.insert(pid);
+                }
+            }
+        }
+        segment.live_pct = if pids.is_empty() {
+            100
+        } else {
+            (present.len() * 100 / pids.len()) as u8
+        };
+    }
+
+    let snapshot = snapshot::read_snapshot(config)?.map(|snapshot| {
+        let mut free: Vec<PageId> = snapshot.free.into_iter().collect();
+        free.sort();
+        SnapshotInfo {
+            max_lsn: snapshot.max_lsn,
+            max_trailer_stable_lsn: snapshot.max_trailer_stable_lsn,
+            last_lid: snapshot.last_lid,
+            max_lid: snapshot.max_lid,
+            max_pid: snapshot.max_pid,
+            pages: snapshot.pt.len(),
+            free,
+        }
+    });
+
+    Ok(Inspection { segments, snapshot })
+}
+
+fn inspect_segment<P, Q, F>(
+    config: &Config,
+    segment_lid: LogId,
+    segment_lsn: Lsn,
+    describe: &mut F,
+) -> Result<SegmentInfo<Q>>
+where
+    P: DeserializeOwned + Serialize,
+    Q: DeserializeOwned + Serialize,
+    F: FnMut(P) -> Q,
+{
+    let f = &config.file;
+    let segment_len = config.io_buf_size as LogId;
+    let trailer_offset = segment_len - SEG_TRAILER_LEN as LogId;
+
+    let trailer = match f.read_segment_trailer(segment_lid + trailer_offset) {
+        Ok(trailer) if trailer.ok => Some(trailer),
+        _ => None,
+    };
+
+    let mut segment = SegmentInfo {
+        lid: segment_lid,
+        lsn: segment_lsn,
+        trailer_lsn: trailer.map(|trailer| trailer.lsn),
+        highest_known_stable_lsn: trailer
+            .map(|trailer| trailer.highest_known_stable_lsn),
+        sealed: trailer.map(|trailer| trailer.lsn)
+            == Some(segment_lsn + trailer_offset as Lsn),
+        live_pct: 100,
+        messages: vec![],
+    };
+
+    let ceiling = trailer_offset - MSG_HEADER_LEN as LogId;
+
+    let mut offset = SEG_HEADER_LEN as LogId;
+    while offset <= ceiling {
+        let mut message = MessageInfo {
+            lid: segment_lid + offset,
+            lsn: segment_lsn + offset as Lsn,
+            kind: MessageKind::Inline,
+            len: 0,
+            blob: None,
+            update: None,
+            error: None,
+        };
+
+        let mut buf = None;
+        let mut last = false;
+
+        match f.read_message(message.lid, message.lsn, config) {
+            Ok(LogRead::Inline(_lsn, inline_buf, len)) => {
+                message.len = len;
+                buf = Some(inline_buf);
+            }
+            Ok(LogRead::Blob(_lsn, blob_buf, blob_ptr)) => {
+                message.kind = MessageKind::Blob;
+                message.len = BLOB_INLINE_LEN;
+                message.blob = Some(blob_ptr);
+                buf = Some(blob_buf);
+            }
+            Ok(LogRead::DanglingBlob(_lsn, blob_ptr)) => {
+                message.kind = MessageKind::Blob;
+                message.len = BLOB_INLINE_LEN;
+                message.blob = Some(blob_ptr);
+                message.error = Some("the blob has been removed".to_owned());
+            }
+            Ok(LogRead::BatchManifest(_lsn)) => {
+                message.kind = MessageKind::BatchManifest;
+                message.len = BATCH_MANIFEST_INLINE_LEN;
+            }
+            Ok(LogRead::Failed(_lsn, len)) => {
+                message.kind = MessageKind::Failed;
+                message.len = len;
+            }
+            Ok(LogRead::Pad(_lsn)) => {
+                message.kind = MessageKind::Pad;
+                message.len = assert_usize(ceiling - offset);
+                last = true;
+            }
+            Ok(LogRead::Corrupted(len)) => {
+                message.kind = MessageKind::Corrupted;
+                message.len = len;
+                last = true;
+            }
+            Err(e) => {
+                // the header was valid, but the message could
+                // not be read, which only leaves the rest of
+                // the segment readable if it points to a blob.
+                let mut header_buf = [0u8; MSG_HEADER_LEN];
+                f.pread_exact(&mut header_buf, message.lid)?;
+                let header: MessageHeader = header_buf.into();
+                message.kind = header.kind;
+                message.error = Some(e.to_string());
+                if header.kind == MessageKind::Blob {
+                    message.len = BLOB_INLINE_LEN;
+                } else {
+                    message.len = header.len;
+                    last = true;
+                }
+            }
+        }
+
+        if let Some(buf) = buf {
+            match deserialize::<LoggedUpdate<P>>(&buf) {
+                Ok(LoggedUpdate { pid, update }) => {
+                    let update = match update {
+                        Update::Append(frag) => Update::Append(describe(frag)),
+                        Update::Compact(frag) => {
+                            Update::Compact(describe(frag))
+                        }
+                        Update::Free => Update::Free,
+                        Update::Counter(counter) => Update::Counter(counter),
+                        Update::Meta(meta) => Update::Meta(meta),
+                    };
+                    message.update = Some((pid, update));
+                }
+                Err(e) => {
+                    message.error =
+                        Some(format!("failed to deserialize: {}", e));
+                }
+            }
+        }
+
+        offset += (MSG_HEADER_LEN + message.len) as LogId;
+        segment.messages.push(message);
+
+        if last {
+            break;
+        }
+    }
+
+    Ok(segment)
+}
@@ -0,0 +1,406 @@
+//! Verification of the log and the pages stored in it, which
+//! reports every problem that it finds instead of stopping at
+//! the first one, like recovery does.
+use std::collections::BTreeMap;
+
+use super::*;
+
+/// The result of checking the integrity of a database,
+/// created by `PageCache::verify_integrity`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IntegrityReport {
+    /// The number of log segments that were checked.
+    pub segments: usize,
+    /// The number of log messages that were read.
+    pub messages: usize,
+    /// The number of blob files that were read.
+    pub blobs: usize,
+    /// The number of pages whose fragments were read.
+    pub pages: usize,
+    /// The number of nodes of stored structures that were
+    /// checked, if any.
+    pub nodes: usize,
+    /// Every problem that was found.
+    pub problems: Vec<IntegrityProblem>,
+}
+
+impl IntegrityReport {
+    /// Returns `true` if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for IntegrityReport {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        writeln!(
+            f,
+            "checked {} segments, {} messages, {} blobs, {} pages \
+             and {} nodes: {} problems found",
+            self.segments,
+            self.messages,
+            self.blobs,
+            self.pages,
+            self.nodes,
+            self.problems.len()
+        )?;
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// A problem found while checking the integrity of a database.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntegrityProblem {
+    /// The header of the segment at `lid` holds an `Lsn`
+    /// that is not the start of a segment.
+    MisalignedSegment {
+        /// The offset of the segment in the file.
+        lid: LogId,
+        /// The `Lsn` in its header.
+        lsn: Lsn,
+    },
+    /// The segment at `lid` has the same `Lsn` in its header
+    /// as the segment at `other`.
+    DuplicateSegment {
+        /// The offset of the segment in the file.
+        lid: LogId,
+        /// The `Lsn` in its header.
+        lsn: Lsn,
+        /// The offset of the other segment.
+        other: LogId,
+    },
+    /// The segment at `lid` has no valid trailer, although
+    /// it is older than the segments that may still be
+    /// written to.
+    UnsealedSegment {
+        /// The offset of the segment in the file.
+        lid: LogId,
+        /// The `Lsn` in its header.
+        lsn: Lsn,
+    },
+    /// The message at `lid` in a sealed segment could not be
+    /// read, or failed its checksum, as did the blob that it
+    /// points to if it is a blob message.
+    CorruptedMessage {
+        /// The offset of the message in the file.
+        lid: LogId,
+        /// The `Lsn` that the message was expected to have.
+        lsn: Lsn,
+        /// Why the message could not be read.
+        reason: String,
+    },
+    /// A fragment of a page could not be read from the
+    /// location that the page table points to.
+    UnreadablePage {
+        /// The page.
+        pid: PageId,
+        /// The `Lsn` of the fragment.
+        lsn: Lsn,
+        /// The location of the fragment.
+        ptr: DiskPtr,
+        /// Why the fragment could not be read.
+        reason: String,
+    },
+    /// A node of the structure recorded in the `Meta` under
+    /// the name `tree` violates its invariants.
+    InvalidNode {
+        /// The name of the structure in the `Meta`.
+        tree: Vec<u8>,
+        /// The page of the node.
+        pid: PageId,
+        /// The invariant that was violated.
+        reason: String,
+    },
+}
+
+impl fmt::Display for IntegrityProblem {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        use self::IntegrityProblem::*;
+
+        match self {
+            MisalignedSegment { lid, lsn } => write!(
+                f,
+                "segment at lid {} has a misaligned header lsn {}",
+                lid, lsn
+            ),
+            DuplicateSegment { lid, lsn, other } => write!(
+                f,
+                "segment at lid {} has the same header lsn {} \
+                 as the segment at lid {}",
+                lid, lsn, other
+            ),
+            UnsealedSegment { lid, lsn } => write!(
+                f,
+                "segment at lid {} with lsn {} has no valid trailer",
+                lid, lsn
+            ),
+            CorruptedMessage { lid, lsn, reason } => write!(
+                f,
+                "message at lid {} with expected lsn {} \
+                 is corrupted: {}",
+                lid, lsn, reason
+            ),
+            UnreadablePage {
+                pid,
+                lsn,
+                ptr,
+                reason,
+            } => write!(
+                f,
+                "page {} has an unreadable fragment at lsn {} ptr {}: {}",
+                pid, lsn, ptr, reason
+            ),
+            InvalidNode { tree, pid, reason } => write!(
+                f,
+                "node {} of tree {:?} is invalid: {}",
+                pid,
+                String::from_utf8_lossy(tree),
+                reason
+            ),
+        }
+    }
+}
+
+/// Reads every segment of the log, and every message in it,
+/// adding the problems found to `report`, and passing the
+/// `Lsn`, offset and contents of every message that holds a
+/// page update to `visit`. The blobs that messages point to
+/// are only read, and their messages only visited, if
+/// `read_blobs` is set. Unlike the
+/// iterator returned by `raw_segment_iter_from`, which ends
+/// at the first segment or message that cannot be read, this
+/// continues with the next one, and never modifies the file.
+///
+/// The newest `io_bufs` segments may still be written to, so
+/// they are not expected to have a trailer, and their
+/// messages are read until the first one that is invalid.
+pub(crate) fn verify_segments<F>(
+    config: &Config,
+    report: &mut IntegrityReport,
+    read_blobs: bool,
+    mut visit: F,
+) -> Result<()>
+where
+    F: FnMut(Lsn, LogId, Vec<u8>),
+{
+    let f = &config.file;
+    let segment_len = config.io_buf_size as LogId;
+
+    // the offset of every segment in use, by its lsn
+    let mut ordering: BTreeMap<Lsn, LogId> = BTreeMap::new();
+
+    let mut lid = 0;
+    while let Ok(header) = f.read_segment_header(lid) {
+        if header.ok && (header.lsn != 0 || lid == 0) {
+            if header.lsn % segment_len as Lsn != 0 {
+                report.problems.push(IntegrityProblem::MisalignedSegment {
+                    lid,
+                    lsn: header.lsn,
+                });
+            } else if let Some(&other) = ordering.get(&header.lsn) {
+                report.problems.push(IntegrityProblem::DuplicateSegment {
+                    lid,
+                    lsn: header.lsn,
+                    other,
+                });
+            } else {
+                ordering.insert(header.lsn, lid);
+            }
+        }
+        lid += segment_len;
+    }
+
+    let tail_start = ordering.len().saturating_sub(config.io_bufs);
+
+    for (idx, (&lsn, &lid)) in ordering.iter().enumerate() {
+        report.segments += 1;
+
+        let trailer_offset = segment_len - SEG_TRAILER_LEN as LogId;
+        let sealed = match f.read_segment_trailer(lid + trailer_offset) {
+            Ok(trailer) => {
+                trailer.ok && trailer.lsn == lsn + trailer_offset as Lsn
+            }
+            Err(_) => false,
+        };
+
+        if !sealed && idx < tail_start {
+            report
+                .problems
+                .push(IntegrityProblem::UnsealedSegment { lid, lsn });
+        }
+
+        verify_messages(
+            config, lsn, lid, sealed, read_blobs, report, &mut visit,
+        )?;
+    }
+
+    Ok(())
+}
+
+// Reads the messages of the segment at `lid`, until its pad.
+fn verify_messages<F>(
+    config: &Config,
+    segment_lsn: Lsn,
+    segment_lid: LogId,
+    sealed: bool,
+    read_blobs: bool,
+    report: &mut IntegrityReport,
+    visit: &mut F,
+) -> Result<()>
+where
+    F: FnMut(Lsn, LogId, Vec<u8>),
+{
+    let f = &config.file;
+    let ceiling = segment_lid + config.io_buf_size as LogId
+        - SEG_TRAILER_LEN as LogId
+        - MSG_HEADER_LEN as LogId;
+
+    let mut offset = SEG_HEADER_LEN as LogId;
+    while segment_lid + offset <= ceiling {
+        let lid = segment_lid + offset;
+        let lsn = segment_lsn + offset as Lsn;
+
+        let corrupted = |reason: String| IntegrityProblem::CorruptedMessage {
+            lid,
+            lsn,
+            reason,
+        };
+
+        let read = if read_blobs {
+            f.read_message(lid, lsn, config)
+        } else {
+            f.read_message_without_blob(lid, lsn, config)
+        };
+
+        let len = match read {
+            Ok(LogRead::Inline(_lsn, buf, len)) => {
+                report.messages += 1;
+                visit(lsn, lid, buf);
+                len
+            }
+            Ok(LogRead::Blob(_lsn, buf, _blob_ptr)) => {
+                report.messages += 1;
+                if read_blobs {
+                    report.blobs += 1;
+                    visit(lsn, lid, buf);
+                }
+                BLOB_INLINE_LEN
+            }
+            Ok(LogRead::DanglingBlob(..)) => {
+                // the blobs of replaced fragments are removed
+                // without rewriting the messages that hold them.
+                report.messages += 1;
+                BLOB_INLINE_LEN
+            }
+            Ok(LogRead::BatchManifest(_)) => {
+                report.messages += 1;
+                BATCH_MANIFEST_INLINE_LEN
+            }
+            Ok(LogRead::Failed(_lsn, len)) => len,
+            Ok(LogRead::Pad(_lsn)) => return Ok(()),
+            Ok(LogRead::Corrupted(_len)) => {
+                if sealed {
+                    report.problems.push(corrupted(
+                        "failed its checksum or has an invalid header"
+                            .to_owned(),
+                    ));
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                // the header was valid, but the message could
+                // not be read, which only leaves the rest of
+                // the segment readable if it points to a blob.
+                report.problems.push(corrupted(e.to_string()));
+
+                let mut header_buf = [0u8; MSG_HEADER_LEN];
+                f.pread_exact(&mut header_buf, lid)?;
+                let header: MessageHeader = header_buf.into();
+                if header.kind != MessageKind::Blob {
+                    return Ok(());
+                }
+                report.messages += 1;
+                BLOB_INLINE_LEN
+            }
+        };
+
+        offset += (MSG_HEADER_LEN + len) as LogId;
+    }
+
+    Ok(())
+}
+
+/// Reads every fragment of the page `pid` from the locations
+/// in `frags`, adding the problems found to `report`.
+pub(crate) fn verify_page(
+    config: &Config,
+    pid: PageId,
+    frags: &[(Lsn, DiskPtr)],
+    report: &mut IntegrityReport,
+) {
+    report.pages += 1;
+
+    for &(lsn, ptr) in frags {
+        let unreadable = |reason: String| IntegrityProblem::UnreadablePage {
+            pid,
+            lsn,
+            ptr,
+            reason,
+        };
+
+        // NB a blob pointer that was relocated keeps the `Lsn` of
+        // its blob, so the message is expected to have the `Lsn`
+        // of its location in its segment instead.
+        let expected_lsn = match ptr {
+            DiskPtr::Inline(_lid) => lsn,
+            DiskPtr::Blob(lid, _blob_ptr) => {
+                let segment_len = config.io_buf_size as LogId;
+                let segment_lid = lid / segment_len * segment_len;
+                match config.file.read_segment_header(segment_lid) {
+                    Ok(header) => header.lsn + (lid - segment_lid) as Lsn,
+                    Err(e) => {
+                        report.problems.push(unreadable(e.to_string()));
+                        continue;
+                    }
+                }
+            }
+        };
+
+        if ptr.is_blob() {
+            report.blobs += 1;
+        }
+
+        match config.file.read_message(ptr.lid(), expected_lsn, config) {
+            Ok(LogRead::Inline(..)) if !ptr.is_blob() => {}
+            Ok(LogRead::Blob(_lsn, _buf, blob_ptr))
+                if ptr.is_blob() && blob_ptr == ptr.blob().1 => {}
+            Ok(LogRead::DanglingBlob(_lsn, blob_ptr)) => {
+                report.problems.push(unreadable(format!(
+                    "blob {} does not exist",
+                    blob_ptr
+                )));
+            }
+            Ok(LogRead::Inline(..)) | Ok(LogRead::Blob(..)) => {
+                report.problems.push(unreadable(
+                    "points to the message of another fragment".to_owned(),
+                ));
+            }
+            Ok(other) => {
+                report.problems.push(unreadable(format!(
+                    "expected a page fragment, but read {:?}",
+                    other
+                )));
+            }
+            Err(e) => report.problems.push(unreadable(e.to_string())),
+        }
+    }
+}
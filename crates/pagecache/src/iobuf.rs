@@ -5,6 +5,11 @@ use std::{
     sync::{Arc, Condvar, Mutex},
 };
 
+use futures::{
+    sync::oneshot::{channel as future_channel, Sender as FutureSender},
+    Async, Future, Poll,
+};
+
 use self::reader::LogReader;
 
 use super::*;
@@ -59,6 +64,12 @@ pub(super) struct IoBufs {
     pub(crate) intervals: Mutex<Vec<(Lsn, Lsn)>>,
     pub(super) interval_updated: Condvar,
 
+    // Futures created by `make_stable_async` that are waiting for
+    // the stable lsn to reach a particular value. They are completed
+    // by whichever thread advances `stable_lsn` past that value,
+    // rather than having a thread block on `interval_updated`.
+    pub(crate) stable_waiters: Mutex<Vec<(Lsn, FutureSender<Result<Lsn>>)>>,
+
     // The highest CONTIGUOUS log sequence number that has been written to
     // stable storage. This may be lower than the length of the underlying
     // file, and there may be buffers that have been written out-of-order
@@ -179,6 +190,7 @@ impl IoBufs {
 
             intervals: Mutex::new(vec![]),
             interval_updated: Condvar::new(),
+            stable_waiters: Mutex::new(vec![]),
 
             stable_lsn: AtomicLsn::new(stable),
             max_reserved_lsn: AtomicLsn::new(stable),
@@ -201,13 +213,19 @@ impl IoBufs {
 
         let locked_at = clock();
 
-        M.accountant_lock.measure(locked_at - start);
+        self.config
+            .metrics
+            .accountant_lock
+            .measure(locked_at - start);
 
         let ret = f(&mut sa);
 
         drop(sa);
 
-        M.accountant_hold.measure(clock() - locked_at);
+        self.config
+            .metrics
+            .accountant_hold
+            .measure(clock() - locked_at);
 
         ret
     }
@@ -233,6 +251,7 @@ impl IoBufs {
             segment_base: None,
             segment_iter,
             trailer: None,
+            batch_end: 0,
         }
     }
 
@@ -248,6 +267,34 @@ impl IoBufs {
         self.stable_lsn.load(SeqCst) as Lsn
     }
 
+    // Completes the futures returned by `make_stable_async`
+    // that are waiting for an lsn that is now stable, or all
+    // of them if the system has hit a global error.
+    pub(crate) fn notify_stable_waiters(&self) {
+        let mut waiters = self.stable_waiters.lock().unwrap();
+        if waiters.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.config.global_error() {
+            for (_lsn, tx) in waiters.drain(..) {
+                let _ = tx.send(Err(e.clone()));
+            }
+            return;
+        }
+
+        let stable = self.stable();
+        let mut i = 0;
+        while i < waiters.len() {
+            if waiters[i].0 <= stable {
+                let (_lsn, tx) = waiters.swap_remove(i);
+                let _ = tx.send(Ok(stable));
+            } else {
+                i += 1;
+            }
+        }
+    }
+
     // Adds a header to the front of the buffer
     pub(crate) fn encapsulate(
         &self,
@@ -326,7 +373,7 @@ impl IoBufs {
     // Write an IO buffer's data to stable storage and set up the
     // next IO buffer for writing.
     pub(crate) fn write_to_log(&self, idx: usize) -> Result<()> {
-        let _measure = Measure::new(&M.write_to_log);
+        let _measure = Measure::new(&self.config.metrics.write_to_log);
         let iobuf = &self.bufs[idx];
         let header = iobuf.get_header();
         let lid = iobuf.get_lid();
@@ -436,7 +483,10 @@ impl IoBufs {
             f.sync_all()?;
             io_fail!(self, "trailer write post");
 
-            M.written_bytes.measure(SEG_TRAILER_LEN as f64);
+            self.config
+                .metrics
+                .written_bytes
+                .measure(SEG_TRAILER_LEN as f64);
 
             iobuf.set_maxed(false);
 
@@ -471,7 +521,7 @@ impl IoBufs {
             self.mark_interval(base_lsn, complete_len);
         }
 
-        M.written_bytes.measure(total_len as f64);
+        self.config.metrics.written_bytes.measure(total_len as f64);
 
         // signal that this IO buffer is now uninitialized
         let max = std::usize::MAX as LogId;
@@ -482,7 +532,7 @@ impl IoBufs {
         // are going to wait on the condition variable will observe
         // the change.
         debug_delay();
-        let _ = self.buf_mu.lock().unwrap();
+        drop(self.buf_mu.lock().unwrap());
 
         // communicate to other threads that we have written an IO buffer.
         debug_delay();
@@ -564,6 +614,7 @@ impl IoBufs {
 
         if updated {
             self.interval_updated.notify_all();
+            self.notify_stable_waiters();
         }
 
         // NB we continue to hold the intervals mutex for
@@ -595,7 +646,7 @@ impl IoBufs {
 /// been made stable on disk. Returns the number of
 /// bytes written.
 pub(crate) fn make_stable(iobufs: &Arc<IoBufs>, lsn: Lsn) -> Result<usize> {
-    let _measure = Measure::new(&M.make_stable);
+    let _measure = Measure::new(&iobufs.config.metrics.make_stable);
 
     // NB before we write the 0th byte of the file, stable  is -1
     let first_stable = iobufs.stable();
@@ -641,6 +692,50 @@ pub(super) fn flush(iobufs: &Arc<IoBufs>) -> Result<usize> {
     make_stable(iobufs, max_reserved_lsn)
 }
 
+/// Returns a future that resolves once the specified log
+/// sequence number has been made stable on disk, without
+/// blocking the calling thread while the write happens.
+/// Without a thread pool (`async_io` disabled), the sealed
+/// buffer is written synchronously by the calling thread.
+pub(crate) fn make_stable_async(iobufs: &Arc<IoBufs>, lsn: Lsn) -> FlushFuture {
+    let first_stable = iobufs.stable();
+    let (tx, rx) = future_channel();
+    let future = FlushFuture { first_stable, rx };
+
+    if first_stable >= lsn {
+        let _ = tx.send(Ok(first_stable));
+        return future;
+    }
+
+    // seal the current buffer so that it gets written out,
+    // possibly in the background if we have a thread pool.
+    loop {
+        let idx = iobufs.idx();
+        let header = iobufs.bufs[idx].get_header();
+        if offset(header) == 0 || is_sealed(header) {
+            break;
+        }
+        if let Err(e) = maybe_seal_and_write_iobuf(iobufs, idx, header, false) {
+            let _ = tx.send(Err(e));
+            return future;
+        }
+    }
+
+    iobufs.stable_waiters.lock().unwrap().push((lsn, tx));
+
+    // the stable lsn may have advanced before we registered
+    iobufs.notify_stable_waiters();
+
+    future
+}
+
+/// Called by users who wish to force the current buffer
+/// to flush some pending writes without blocking.
+pub(super) fn flush_async(iobufs: &Arc<IoBufs>) -> FlushFuture {
+    let max_reserved_lsn = iobufs.max_reserved_lsn.load(SeqCst) as Lsn;
+    make_stable_async(iobufs, max_reserved_lsn)
+}
+
 /// Attempt to seal the current IO buffer, possibly
 /// writing it to disk if there are no other writers
 /// operating on it.
@@ -716,7 +811,8 @@ pub(crate) fn maybe_seal_and_write_iobuf(
     // open new slot
     let mut next_lsn = lsn;
 
-    let measure_assign_offset = Measure::new(&M.assign_offset);
+    let measure_assign_offset =
+        Measure::new(&iobufs.config.metrics.assign_offset);
 
     let next_offset = if from_reserve || maxed {
         // roll lsn to the next offset
@@ -755,7 +851,8 @@ pub(crate) fn maybe_seal_and_write_iobuf(
     // NB we spin on this CAS because the next iobuf may not actually
     // be written to disk yet! (we've lapped the writer in the iobuf
     // ring buffer)
-    let measure_assign_spinloop = Measure::new(&M.assign_spinloop);
+    let measure_assign_spinloop =
+        Measure::new(&iobufs.config.metrics.assign_spinloop);
     let backoff = Backoff::new();
     while next_iobuf.cas_lid(max, next_offset).is_err() {
         backoff.snooze();
@@ -791,7 +888,7 @@ pub(crate) fn maybe_seal_and_write_iobuf(
     // are going to wait on the condition variable will observe
     // the change.
     debug_delay();
-    let _ = iobufs.buf_mu.lock().unwrap();
+    drop(iobufs.buf_mu.lock().unwrap());
 
     // communicate to other threads that we have advanced an IO buffer.
     debug_delay();
@@ -824,6 +921,7 @@ pub(crate) fn maybe_seal_and_write_iobuf(
                 if let Err(e) = iobufs.write_to_log(idx) {
                     error!("hit error while writing segment {}: {:?}", idx, e);
                     iobufs.config.set_global_error(e);
+                    iobufs.notify_stable_waiters();
                 }
             });
             Ok(())
@@ -839,6 +937,46 @@ pub(crate) fn maybe_seal_and_write_iobuf(
     }
 }
 
+/// A future that resolves to the number of bytes that
+/// were made stable between its creation and its completion.
+/// Created by `Log::flush_async` and `Log::make_stable_async`.
+#[derive(Debug)]
+pub struct FlushFuture {
+    first_stable: Lsn,
+    rx: futures::sync::oneshot::Receiver<Result<Lsn>>,
+}
+
+impl FlushFuture {
+    /// Returns a future that fails with `error` right away.
+    pub(crate) fn failed(error: Error) -> FlushFuture {
+        let (tx, rx) = future_channel();
+        let _ = tx.send(Err(error));
+        FlushFuture {
+            first_stable: 0,
+            rx,
+        }
+    }
+}
+
+impl Future for FlushFuture {
+    type Item = usize;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<usize, Error> {
+        match self.rx.poll() {
+            Ok(Async::Ready(Ok(stable))) => {
+                Ok(Async::Ready(assert_usize(stable - self.first_stable)))
+            }
+            Ok(Async::Ready(Err(e))) => Err(e),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_canceled) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the log was shut down before the flush completed",
+            ))),
+        }
+    }
+}
+
 impl Debug for IoBufs {
     fn fmt(
         &self,
@@ -10,6 +10,9 @@ pub struct LogIter {
     pub max_lsn: Lsn,
     pub cur_lsn: Lsn,
     pub trailer: Option<Lsn>,
+    // the highest last lsn of any sealed batch whose
+    // manifest has been read so far.
+    pub batch_end: Lsn,
 }
 
 impl Iterator for LogIter {
@@ -80,9 +83,15 @@ impl Iterator for LogIter {
                     return Some((lsn, DiskPtr::Inline(lid), buf));
                 }
                 Ok(LogRead::BatchManifest(last_lsn_in_batch)) => {
-                    if last_lsn_in_batch > self.max_lsn {
+                    // NB the manifest of an open batch holds the highest
+                    // `Lsn`, which recovery's `max_lsn` is not below.
+                    if last_lsn_in_batch == std::i64::MAX
+                        || last_lsn_in_batch > self.max_lsn
+                    {
                         return None;
                     } else {
+                        self.batch_end =
+                            std::cmp::max(self.batch_end, last_lsn_in_batch);
                         self.cur_lsn +=
                             (MSG_HEADER_LEN + BATCH_MANIFEST_INLINE_LEN) as Lsn;
                         continue;
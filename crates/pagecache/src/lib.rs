@@ -29,10 +29,13 @@ macro_rules! rep_no_copy {
 }
 
 mod blob_io;
+mod checkpoint;
 mod config;
 mod constants;
 mod diskptr;
 mod ds;
+mod inspect;
+mod integrity;
 mod iobuf;
 mod iterator;
 mod map;
@@ -44,8 +47,11 @@ mod parallel_io;
 mod reader;
 mod reservation;
 mod result;
+mod retention;
+mod salvage;
 mod segment;
 mod snapshot;
+mod stats;
 mod tx;
 mod util;
 
@@ -80,19 +86,20 @@ use log::{debug, error, trace, warn};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 #[doc(hidden)]
-use self::logger::{MessageHeader, MessageKind, SegmentHeader, SegmentTrailer};
+use self::logger::{MessageHeader, SegmentHeader, SegmentTrailer};
 
 #[cfg(not(unix))]
 use self::metrics::uptime;
 
 use self::{
-    blob_io::{gc_blobs, read_blob, remove_blob, write_blob},
+    blob_io::{blob_ids, gc_blobs, read_blob, remove_blob, write_blob},
     iobuf::IoBufs,
     iterator::LogIter,
     metrics::{clock, measure},
-    pagecache::{LoggedUpdate, Update},
+    pagecache::LoggedUpdate,
     parallel_io::Pio,
     reader::LogReader,
+    retention::LogRetention,
     segment::{raw_segment_iter_from, SegmentAccountant},
     snapshot::{advance_snapshot, PageState},
     util::{arr_to_u32, arr_to_u64, maybe_decompress, u32_to_arr, u64_to_arr},
@@ -102,15 +109,23 @@ pub use self::{
     config::{Config, ConfigBuilder},
     diskptr::DiskPtr,
     ds::{node_from_frag_vec, Lru, Node, PageTable, Stack, StackIter, VecSet},
-    logger::{Log, LogRead},
+    inspect::{inspect, Inspection, MessageInfo, SegmentInfo, SnapshotInfo},
+    integrity::{IntegrityProblem, IntegrityReport},
+    iobuf::FlushFuture,
+    logger::{Log, LogRead, MessageKind},
     map::{FastMap1, FastMap4, FastMap8, FastSet1, FastSet4, FastSet8},
     materializer::{Materializer, NullMaterializer},
     meta::Meta,
-    metrics::M,
-    pagecache::{CacheEntry, PageCache, PageGet, PagePtr},
+    metrics::{HistogramSnapshot, MetricUnit, MetricsSnapshot},
+    pagecache::{
+        CacheEntry, LogReplay, LogShipper, PageCache, PageGet, PagePtr,
+        PageView, RecoveryGuard, Replayed, SharedBatch, Update,
+    },
     reservation::Reservation,
     result::{CasResult, Error, Result},
+    salvage::{salvage, Salvage},
     segment::SegmentMode,
+    stats::StorageStats,
     tx::Tx,
 };
 
@@ -118,10 +133,11 @@ pub use self::{
 pub use self::{
     constants::{
         BATCH_MANIFEST, BATCH_MANIFEST_INLINE_LEN, BLOB_FLUSH, BLOB_INLINE_LEN,
-        EVIL_BYTE, FAILED_FLUSH, INLINE_FLUSH, MINIMUM_ITEMS_PER_SEGMENT,
-        MSG_HEADER_LEN, SEGMENT_PAD, SEG_HEADER_LEN, SEG_TRAILER_LEN,
+        EVIL_BYTE, FAILED_FLUSH, FORMAT_VERSION, INLINE_FLUSH,
+        MINIMUM_ITEMS_PER_SEGMENT, MSG_HEADER_LEN, SEGMENT_PAD, SEG_HEADER_LEN,
+        SEG_TRAILER_LEN,
     },
-    metrics::Measure,
+    metrics::{Measure, Metrics},
     snapshot::{read_snapshot_or_default, Snapshot},
 };
 
@@ -141,13 +157,37 @@ pub type Lsn = i64;
 pub type PageId = u64;
 
 /// Allows arbitrary logic to be injected into mere operations of the `PageCache`.
+#[deprecated(
+    note = "set merge operators per tree with `Tree::set_merge_operator`"
+)]
 pub type MergeOperator = fn(
     key: &[u8],
     last_value: Option<&[u8]>,
     new_merge: &[u8],
 ) -> Option<Vec<u8>>;
 
-pub(crate) fn crc32(buf: &[u8]) -> u32 {
+/// A `MergeOperator` that was set with the deprecated
+/// `ConfigBuilder::merge_operator`.
+#[doc(hidden)]
+#[allow(deprecated)]
+#[derive(Clone, Copy)]
+pub struct MergeOperatorFn(pub MergeOperator);
+
+impl Debug for MergeOperatorFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MergeOperatorFn({:#x})", self.0 as usize)
+    }
+}
+
+impl PartialEq for MergeOperatorFn {
+    fn eq(&self, other: &MergeOperatorFn) -> bool {
+        self.0 as usize == other.0 as usize
+    }
+}
+
+/// Computes the crc32 checksum that is used
+/// throughout the log, snapshots and blobs.
+pub fn crc32(buf: &[u8]) -> u32 {
     let mut hasher = crc32fast::Hasher::new();
     hasher.update(&buf);
     hasher.finalize()
@@ -60,6 +60,7 @@ impl Log {
             log_iter,
             Snapshot::default(),
             &config,
+            true,
         )?;
 
         Log::start(config, snapshot)
@@ -71,6 +72,16 @@ impl Log {
         iobuf::flush(&self.iobufs)
     }
 
+    /// Returns a future that resolves once every pending
+    /// IO buffer has been written to disk. The writes are
+    /// performed by the configured IO thread pool when
+    /// `async_io` is enabled, so no thread blocks while
+    /// waiting for them. Otherwise they are performed by
+    /// the calling thread before this returns.
+    pub fn flush_async(&self) -> FlushFuture {
+        iobuf::flush_async(&self.iobufs)
+    }
+
     /// Write a buffer into the log. Returns the log sequence
     /// number and the file offset of the write.
     pub fn write<B>(&self, buf: B) -> Result<(Lsn, DiskPtr)>
@@ -119,6 +130,13 @@ impl Log {
         iobuf::make_stable(&self.iobufs, lsn)
     }
 
+    /// Returns a future that resolves once the specified
+    /// log sequence number has been made stable on disk,
+    /// to the number of bytes written in the meantime.
+    pub fn make_stable_async(&self, lsn: Lsn) -> FlushFuture {
+        iobuf::make_stable_async(&self.iobufs, lsn)
+    }
+
     // SegmentAccountant access for coordination with the `PageCache`
     pub(crate) fn with_sa<B, F>(&self, f: F) -> B
     where
@@ -155,7 +173,7 @@ impl Log {
             if self.config.use_compression {
                 use zstd::block::compress;
 
-                let _measure = Measure::new(&M.compress);
+                let _measure = Measure::new(&self.config.metrics.compress);
 
                 let compressed_buf =
                     compress(buf, self.config.compression_factor).unwrap();
@@ -194,18 +212,69 @@ impl Log {
         Ok(pointers)
     }
 
+    /// Reserves space for an uncompressed batch manifest.
+    pub(crate) fn reserve_batch_manifest(&self) -> Result<Reservation<'_>> {
+        self.reserve_inner(&[0; std::mem::size_of::<Lsn>()], false)
+    }
+
+    /// Overwrites the batch manifest that was previously
+    /// written at `lsn` and `lid`, which must already be
+    /// stable, so that it marks `last` as the final `Lsn` of
+    /// the batch. If `last` is `None`, the manifest is marked
+    /// as failed instead, and recovery will skip over it.
+    /// The new manifest becomes durable with the next flush.
+    pub(crate) fn rewrite_batch_manifest(
+        &self,
+        lsn: Lsn,
+        lid: LogId,
+        last: Option<Lsn>,
+    ) -> Result<()> {
+        let body = u64_to_arr(last.unwrap_or(0) as u64);
+
+        let mut header = MessageHeader {
+            kind: if last.is_some() {
+                MessageKind::BatchManifest
+            } else {
+                MessageKind::Failed
+            },
+            lsn,
+            len: body.len(),
+            crc32: 0,
+        };
+
+        // the order of hashing must be the
+        // same here as during calls to
+        // LogReader::read_message
+        let unhashed: [u8; MSG_HEADER_LEN] = header.into();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&body);
+        hasher.update(&unhashed);
+        header.crc32 = hasher.finalize();
+
+        let header_bytes: [u8; MSG_HEADER_LEN] = header.into();
+
+        let mut buf = Vec::with_capacity(MSG_HEADER_LEN + body.len());
+        buf.extend_from_slice(&header_bytes);
+        buf.extend_from_slice(&body);
+
+        maybe_fail!("rewrite_batch_manifest");
+        self.config.file.pwrite_all(&buf, lid)?;
+
+        Ok(())
+    }
+
     fn reserve_inner<'a>(
         &'a self,
         buf: &[u8],
         is_blob_rewrite: bool,
     ) -> Result<Reservation<'a>> {
-        let _measure = Measure::new(&M.reserve_lat);
+        let _measure = Measure::new(&self.config.metrics.reserve_lat);
 
         let n_io_bufs = self.config.io_bufs;
 
         let total_buf_len = MSG_HEADER_LEN + buf.len();
 
-        M.reserve_sz.measure(total_buf_len as f64);
+        self.config.metrics.reserve_sz.measure(total_buf_len as f64);
 
         let max_overhead = std::cmp::max(SEG_HEADER_LEN, SEG_TRAILER_LEN);
 
@@ -238,7 +307,7 @@ impl Log {
         let backoff = Backoff::new();
 
         loop {
-            M.log_reservation_attempted();
+            self.config.metrics.log_reservation_attempted();
 
             // don't continue if the system
             // has encountered an issue.
@@ -269,8 +338,9 @@ impl Log {
                 if backoff.is_completed() {
                     // use a condition variable to wait until
                     // we've updated the written_bufs counter.
-                    let _measure =
-                        Measure::new(&M.reserve_written_condvar_wait);
+                    let _measure = Measure::new(
+                        &self.config.metrics.reserve_written_condvar_wait,
+                    );
 
                     let mut buf_mu = self.iobufs.buf_mu.lock().unwrap();
                     while written_bufs == self.iobufs.written_bufs.load(SeqCst)
@@ -297,8 +367,9 @@ impl Log {
                 if backoff.is_completed() {
                     // use a condition variable to wait until
                     // we've updated the current_buf counter.
-                    let _measure =
-                        Measure::new(&M.reserve_current_condvar_wait);
+                    let _measure = Measure::new(
+                        &self.config.metrics.reserve_current_condvar_wait,
+                    );
                     let mut buf_mu = self.iobufs.buf_mu.lock().unwrap();
                     while current_buf == self.iobufs.current_buf.load(SeqCst) {
                         buf_mu = self.iobufs.buf_updated.wait(buf_mu).unwrap();
@@ -400,7 +471,7 @@ impl Log {
                 is_blob_rewrite,
             )?;
 
-            M.log_reservation_success();
+            self.config.metrics.log_reservation_success();
 
             let ptr = if over_blob_threshold {
                 DiskPtr::new_blob(reservation_offset, reservation_lsn)
@@ -483,7 +554,7 @@ impl Drop for Log {
             // that it's safe to destroy our inner
             // members, since it doesn't pick up on
             // the implicit barriers used elsewhere.
-            let _ = self.iobufs.buf_mu.lock().unwrap();
+            drop(self.iobufs.buf_mu.lock().unwrap());
         }
 
         // don't do any more IO if we're crashing
@@ -503,12 +574,19 @@ impl Drop for Log {
 
 /// Represents the kind of message written to the log
 #[derive(Debug, Copy, Clone, PartialEq)]
-pub(crate) enum MessageKind {
+pub enum MessageKind {
+    /// A message that holds its contents inline.
     Inline,
+    /// A message that points to a blob file.
     Blob,
+    /// A message whose write was aborted.
     Failed,
+    /// The padding at the end of a segment.
     Pad,
+    /// A message whose header is invalid, or that
+    /// failed its checksum.
     Corrupted,
+    /// A message that marks the last `Lsn` of a batch.
     BatchManifest,
 }
 
@@ -7,6 +7,7 @@ use super::*;
 #[derive(Clone, Debug, Eq, PartialEq, Default, Serialize, Deserialize)]
 pub struct Meta {
     inner: BTreeMap<Vec<u8>, PageId>,
+    properties: BTreeMap<Vec<u8>, BTreeMap<String, Vec<u8>>>,
 }
 
 impl Meta {
@@ -20,11 +21,53 @@ impl Meta {
         self.inner.insert(name, pid);
     }
 
-    /// Remove the page mapping for a given identifier
+    /// Remove the page mapping for a given identifier,
+    /// along with any properties recorded for it
     pub fn del_root(&mut self, name: &[u8]) -> Option<PageId> {
+        self.properties.remove(name);
         self.inner.remove(name)
     }
 
+    /// Retrieve a named property recorded for an identifier
+    pub fn get_property(&self, name: &[u8], property: &str) -> Option<&[u8]> {
+        self.properties
+            .get(name)
+            .and_then(|properties| properties.get(property))
+            .map(|value| &**value)
+    }
+
+    /// Return the properties recorded for an identifier
+    pub fn properties(&self, name: &[u8]) -> BTreeMap<String, Vec<u8>> {
+        self.properties.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Record a named property for an identifier
+    pub fn set_property(
+        &mut self,
+        name: Vec<u8>,
+        property: String,
+        value: Vec<u8>,
+    ) {
+        self.properties
+            .entry(name)
+            .or_default()
+            .insert(property, value);
+    }
+
+    /// Remove a named property recorded for an identifier
+    pub fn del_property(
+        &mut self,
+        name: &[u8],
+        property: &str,
+    ) -> Option<Vec<u8>> {
+        let properties = self.properties.get_mut(name)?;
+        let ret = properties.remove(property);
+        if properties.is_empty() {
+            self.properties.remove(name);
+        }
+        ret
+    }
+
     /// Return the current rooted tenants in Meta
     pub fn tenants(&self) -> BTreeMap<Vec<u8>, PageId> {
         self.inner.clone()
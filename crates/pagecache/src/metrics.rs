@@ -1,5 +1,6 @@
 use std::{
-    sync::atomic::AtomicUsize,
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering::Acquire},
     time::{Duration, Instant},
 };
 
@@ -7,18 +8,12 @@ use std::{
 use std::marker::PhantomData;
 
 #[cfg(not(feature = "no_metrics"))]
-use std::sync::atomic::Ordering::{Acquire, Relaxed};
+use std::sync::atomic::Ordering::Relaxed;
 
 use super::*;
 
 use historian::Histo;
 
-lazy_static! {
-    /// A metric collector for all pagecache users running in this
-    /// process.
-    pub static ref M: Metrics = Metrics::default();
-}
-
 pub(crate) fn clock() -> f64 {
     if cfg!(feature = "no_metrics") {
         0.
@@ -80,9 +75,13 @@ pub(crate) fn measure<F: FnOnce() -> R, R>(histo: &Histo, f: F) -> R {
     f()
 }
 
+/// A metric collector for one pagecache, shared by the
+/// clones of its `Config`.
+#[doc(hidden)]
 #[derive(Default, Debug)]
 pub struct Metrics {
     pub advance_snapshot: Histo,
+    pub checkpoint: Histo,
     pub tree_set: Histo,
     pub tree_get: Histo,
     pub tree_del: Histo,
@@ -90,6 +89,11 @@ pub struct Metrics {
     pub tree_scan: Histo,
     pub tree_reverse_scan: Histo,
     pub tree_merge: Histo,
+    pub tree_transaction: Histo,
+    pub tree_apply_batch: Histo,
+    pub tree_snapshot: Histo,
+    pub tree_export: Histo,
+    pub tree_import: Histo,
     pub tree_start: Histo,
     pub tree_traverse: Histo,
     pub tree_child_split_attempt: CachePadded<AtomicUsize>,
@@ -103,6 +107,8 @@ pub struct Metrics {
     pub replace_page: Histo,
     pub link_page: Histo,
     pub merge_page: Histo,
+    pub page_view: Histo,
+    pub replay: Histo,
     pub page_out: Histo,
     pub pull: Histo,
     pub serialize: Histo,
@@ -249,6 +255,11 @@ impl Metrics {
             lat("merge", &self.tree_merge),
             lat("del", &self.tree_del),
             lat("cas", &self.tree_cas),
+            lat("transaction", &self.tree_transaction),
+            lat("apply_batch", &self.tree_apply_batch),
+            lat("snapshot", &self.tree_snapshot),
+            lat("export", &self.tree_export),
+            lat("import", &self.tree_import),
             lat("scan", &self.tree_scan),
             lat("rev scan", &self.tree_reverse_scan),
         ]);
@@ -267,11 +278,14 @@ impl Metrics {
         println!("pagecache:");
         p(vec![
             lat("snapshot", &self.advance_snapshot),
+            lat("checkpoint", &self.checkpoint),
             lat("page_in", &self.page_in),
             lat("rewrite", &self.rewrite_page),
             lat("replace", &self.replace_page),
             lat("link", &self.link_page),
             lat("merge", &self.merge_page),
+            lat("view", &self.page_view),
+            lat("replay", &self.replay),
             lat("pull", &self.pull),
             lat("page_out", &self.page_out),
         ]);
@@ -358,3 +372,307 @@ impl Metrics {
 
     pub fn print_profile(&self) {}
 }
+
+impl Metrics {
+    /// Copies the current counters and histogram percentiles
+    /// of this collector into a `MetricsSnapshot`.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        use MetricUnit::{Bytes, Seconds};
+
+        let counters = vec![
+            ("tree_loops", &self.tree_loops),
+            ("tree_child_split_attempt", &self.tree_child_split_attempt),
+            ("tree_child_split_success", &self.tree_child_split_success),
+            ("tree_parent_split_attempt", &self.tree_parent_split_attempt),
+            ("tree_parent_split_success", &self.tree_parent_split_success),
+            ("tree_root_split_attempt", &self.tree_root_split_attempt),
+            ("tree_root_split_success", &self.tree_root_split_success),
+            ("log_reservations", &self.log_reservations),
+            ("log_reservation_attempts", &self.log_reservation_attempts),
+        ];
+
+        let histograms = vec![
+            ("tree_start", Seconds, &self.tree_start),
+            ("tree_traverse", Seconds, &self.tree_traverse),
+            ("tree_get", Seconds, &self.tree_get),
+            ("tree_set", Seconds, &self.tree_set),
+            ("tree_merge", Seconds, &self.tree_merge),
+            ("tree_del", Seconds, &self.tree_del),
+            ("tree_cas", Seconds, &self.tree_cas),
+            ("tree_transaction", Seconds, &self.tree_transaction),
+            ("tree_apply_batch", Seconds, &self.tree_apply_batch),
+            ("tree_snapshot", Seconds, &self.tree_snapshot),
+            ("tree_export", Seconds, &self.tree_export),
+            ("tree_import", Seconds, &self.tree_import),
+            ("tree_scan", Seconds, &self.tree_scan),
+            ("tree_reverse_scan", Seconds, &self.tree_reverse_scan),
+            ("advance_snapshot", Seconds, &self.advance_snapshot),
+            ("checkpoint", Seconds, &self.checkpoint),
+            ("page_in", Seconds, &self.page_in),
+            ("rewrite_page", Seconds, &self.rewrite_page),
+            ("replace_page", Seconds, &self.replace_page),
+            ("link_page", Seconds, &self.link_page),
+            ("merge_page", Seconds, &self.merge_page),
+            ("page_view", Seconds, &self.page_view),
+            ("replay", Seconds, &self.replay),
+            ("pull", Seconds, &self.pull),
+            ("page_out", Seconds, &self.page_out),
+            ("serialize", Seconds, &self.serialize),
+            ("deserialize", Seconds, &self.deserialize),
+            ("compress", Seconds, &self.compress),
+            ("decompress", Seconds, &self.decompress),
+            ("make_stable", Seconds, &self.make_stable),
+            ("read", Seconds, &self.read),
+            ("write_to_log", Seconds, &self.write_to_log),
+            ("written", Bytes, &self.written_bytes),
+            ("assign_offset", Seconds, &self.assign_offset),
+            ("assign_spinloop", Seconds, &self.assign_spinloop),
+            ("reserve_lat", Seconds, &self.reserve_lat),
+            ("reserve_size", Bytes, &self.reserve_sz),
+            (
+                "reserve_current_condvar_wait",
+                Seconds,
+                &self.reserve_current_condvar_wait,
+            ),
+            (
+                "reserve_written_condvar_wait",
+                Seconds,
+                &self.reserve_written_condvar_wait,
+            ),
+            ("accountant_lock", Seconds, &self.accountant_lock),
+            ("accountant_hold", Seconds, &self.accountant_hold),
+            ("accountant_next", Seconds, &self.accountant_next),
+            ("accountant_mark_link", Seconds, &self.accountant_mark_link),
+            (
+                "accountant_mark_replace",
+                Seconds,
+                &self.accountant_mark_replace,
+            ),
+            ("accountant_bump_tip", Seconds, &self.accountant_bump_tip),
+        ];
+
+        MetricsSnapshot {
+            counters: counters
+                .into_iter()
+                .map(|(name, counter)| (name, counter.load(Acquire) as u64))
+                .collect(),
+            histograms: histograms
+                .into_iter()
+                .map(|(name, unit, histo)| {
+                    HistogramSnapshot::new(name, unit, histo)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A copy of the metrics of a pagecache, taken by
+/// `Metrics::snapshot`, that can be rendered for a
+/// monitoring system.
+///
+/// Nothing is recorded when the `no_metrics` feature is
+/// enabled, so every counter and histogram is then empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    /// The counters, by name, which only ever increase.
+    pub counters: Vec<(&'static str, u64)>,
+    /// The histograms of latencies and sizes.
+    pub histograms: Vec<HistogramSnapshot>,
+}
+
+/// What the values of a histogram measure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricUnit {
+    /// Latencies, in seconds.
+    Seconds,
+    /// Sizes, in bytes.
+    Bytes,
+}
+
+impl MetricUnit {
+    fn name(self) -> &'static str {
+        match self {
+            MetricUnit::Seconds => "seconds",
+            MetricUnit::Bytes => "bytes",
+        }
+    }
+}
+
+/// The distribution of the values recorded in one histogram.
+///
+/// Percentiles are approximate, since the histograms store
+/// values in logarithmic buckets, and are `NaN` when nothing
+/// has been recorded yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    /// The name of the histogram, like `"tree_get"`.
+    pub name: &'static str,
+    /// The unit of `sum` and of the percentiles.
+    pub unit: MetricUnit,
+    /// The number of recorded values.
+    pub count: u64,
+    /// The sum of the recorded values.
+    pub sum: f64,
+    /// The smallest recorded value.
+    pub min: f64,
+    /// The median.
+    pub p50: f64,
+    /// The 90th percentile.
+    pub p90: f64,
+    /// The 99th percentile.
+    pub p99: f64,
+    /// The 99.9th percentile.
+    pub p999: f64,
+    /// The largest recorded value.
+    pub max: f64,
+}
+
+impl HistogramSnapshot {
+    fn new(name: &'static str, unit: MetricUnit, histo: &Histo) -> Self {
+        // latencies are recorded in nanoseconds
+        let scale = match unit {
+            MetricUnit::Seconds => 1e9,
+            MetricUnit::Bytes => 1.,
+        };
+
+        HistogramSnapshot {
+            name,
+            unit,
+            count: histo.count() as u64,
+            sum: histo.sum() as f64 / scale,
+            min: histo.percentile(0.) / scale,
+            p50: histo.percentile(50.) / scale,
+            p90: histo.percentile(90.) / scale,
+            p99: histo.percentile(99.) / scale,
+            p999: histo.percentile(99.9) / scale,
+            max: histo.percentile(100.) / scale,
+        }
+    }
+
+    fn quantiles(&self) -> [(&'static str, f64); 6] {
+        [
+            ("0", self.min),
+            ("0.5", self.p50),
+            ("0.9", self.p90),
+            ("0.99", self.p99),
+            ("0.999", self.p999),
+            ("1", self.max),
+        ]
+    }
+}
+
+impl MetricsSnapshot {
+    /// Returns the counter called `name`, if there is one.
+    pub fn counter(&self, name: &str) -> Option<u64> {
+        self.counters
+            .iter()
+            .find(|(counter, _)| *counter == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// Returns the histogram called `name`, if there is one.
+    pub fn histogram(&self, name: &str) -> Option<&HistogramSnapshot> {
+        self.histograms.iter().find(|histo| histo.name == name)
+    }
+
+    /// Renders the snapshot in the Prometheus text exposition
+    /// format, with every metric name prefixed by `namespace`
+    /// and an underscore. Counters get a `_total` suffix, and
+    /// histograms are exposed as summaries whose names end
+    /// with their unit.
+    pub fn to_prometheus(&self, namespace: &str) -> String {
+        let mut out = String::new();
+        self.write_prometheus(&mut out, namespace)
+            .expect("writing to a String cannot fail");
+        out
+    }
+
+    fn write_prometheus(
+        &self,
+        out: &mut String,
+        namespace: &str,
+    ) -> fmt::Result {
+        for (name, value) in &self.counters {
+            let metric = format!("{}_{}_total", namespace, name);
+            writeln!(out, "# TYPE {} counter", metric)?;
+            writeln!(out, "{} {}", metric, value)?;
+        }
+
+        for histo in &self.histograms {
+            let metric =
+                format!("{}_{}_{}", namespace, histo.name, histo.unit.name());
+            writeln!(out, "# TYPE {} summary", metric)?;
+            for (quantile, value) in &histo.quantiles() {
+                writeln!(
+                    out,
+                    "{}{{quantile=\"{}\"}} {}",
+                    metric, quantile, value
+                )?;
+            }
+            writeln!(out, "{}_sum {}", metric, histo.sum)?;
+            writeln!(out, "{}_count {}", metric, histo.count)?;
+        }
+
+        Ok(())
+    }
+
+    /// Renders the snapshot as a JSON object, with a
+    /// `"counters"` object of counter values by name and a
+    /// `"histograms"` object of histograms by name. The
+    /// percentiles of empty histograms are `null`.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out)
+            .expect("writing to a String cannot fail");
+        out
+    }
+
+    fn write_json(&self, out: &mut String) -> fmt::Result {
+        // names are all plain identifiers, so they never
+        // need to be escaped.
+        let number = |value: f64| {
+            if value.is_finite() {
+                value.to_string()
+            } else {
+                "null".to_owned()
+            }
+        };
+
+        out.push_str("{\"counters\":{");
+        for (i, (name, value)) in self.counters.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(out, "\"{}\":{}", name, value)?;
+        }
+
+        out.push_str("},\"histograms\":{");
+        for (i, histo) in self.histograms.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(
+                out,
+                "\"{}\":{{\"unit\":\"{}\",\"count\":{},\"sum\":{}",
+                histo.name,
+                histo.unit.name(),
+                histo.count,
+                number(histo.sum),
+            )?;
+            for (field, value) in &[
+                ("min", histo.min),
+                ("p50", histo.p50),
+                ("p90", histo.p90),
+                ("p99", histo.p99),
+                ("p999", histo.p999),
+                ("max", histo.max),
+            ] {
+                write!(out, ",\"{}\":{}", field, number(*value))?;
+            }
+            out.push('}');
+        }
+        out.push_str("}}");
+
+        Ok(())
+    }
+}
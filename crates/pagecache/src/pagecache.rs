@@ -3,7 +3,7 @@ use std::{
     collections::BinaryHeap,
     marker::PhantomData,
     ops::Deref,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard},
 };
 
 use rayon::prelude::*;
@@ -143,8 +143,8 @@ impl<M: Send> CacheEntry<M> {
 
 /// `LoggedUpdate` is for writing blocks of `Update`'s to disk
 /// sequentially, to reduce IO during page reads.
-#[serde(bound(deserialize = ""))]
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(bound(deserialize = ""))]
 pub(super) struct LoggedUpdate<PageFrag>
 where
     PageFrag: Serialize + DeserializeOwned,
@@ -153,16 +153,22 @@ where
     pub(super) update: Update<PageFrag>,
 }
 
-#[serde(bound(deserialize = ""))]
+/// An update to a page, as it is written to the log.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub(super) enum Update<PageFrag>
+#[serde(bound(deserialize = ""))]
+pub enum Update<PageFrag>
 where
     PageFrag: DeserializeOwned + Serialize,
 {
+    /// A fragment that is merged into the page.
     Append(PageFrag),
+    /// A fragment that replaces the whole page.
     Compact(PageFrag),
+    /// Frees the page.
     Free,
+    /// Sets the value of the id generator.
     Counter(u64),
+    /// Replaces the `Meta` page.
     Meta(Meta),
 }
 
@@ -282,36 +288,252 @@ where
 }
 
 /// Ensures that any operations that are written to disk between the
-/// creation of this guard and its destruction will be recovered
-/// atomically. When this guard is dropped, it marks in an earlier
-/// reservation where the stable tip must be in order to perform
-/// recovery. If this is beyond where the system successfully
-/// wrote before crashing, then the recovery will stop immediately
-/// before any of the atomic batch can be partially recovered.
+/// creation of this guard and the call to `seal_batch` will be
+/// recovered atomically. The guard writes an open batch manifest
+/// to the log, which stops recovery before any of the batch can be
+/// partially recovered. `seal_batch` later overwrites the manifest
+/// in place with the last LSN of the batch. If that LSN is beyond
+/// where the system successfully wrote before crashing, recovery
+/// also stops at the manifest.
+///
+/// Because the manifest is written immediately, a batch may be
+/// larger than the IO buffers. While the guard is alive, the log
+/// segments that hold the manifest and the batch are not reused.
 ///
 /// Must call `seal_batch` to complete the atomic batch operation.
+/// If the guard is dropped instead, the manifest is marked as
+/// failed, and whatever part of the batch was written is
+/// recovered like any other write. A batch that fails partway
+/// through must therefore either write updates that undo the
+/// part that was applied before it is sealed, or be `abort`ed.
+/// If sealing fails, the system is failed in the same way as
+/// by `abort`.
 pub struct RecoveryGuard<'a> {
-    batch_res: Reservation<'a>,
+    log: &'a Log,
+    lsn: Lsn,
+    lid: LogId,
+    sealed: bool,
+    _pin: PageView,
 }
 
 impl<'a> RecoveryGuard<'a> {
-    /// Writes the last LSN for a batch into an earlier
-    /// reservation, releasing it.
-    pub fn seal_batch(mut self) -> Result<()> {
+    /// Writes the last LSN for a batch into the manifest
+    /// at the beginning of the batch, and returns it. The
+    /// batch is durable once `PageCache::make_batch_stable`
+    /// returns for that LSN.
+    pub fn seal_batch(mut self) -> Result<Lsn> {
         let max_reserved = self
-            .batch_res
             .log
             .iobufs
             .max_reserved_lsn
             .load(std::sync::atomic::Ordering::Acquire);
-        self.batch_res.mark_writebatch(max_reserved);
-        self.batch_res.complete().map(|_| ())
+
+        self.sealed = true;
+        if let Err(e) = self.rewrite(Some(max_reserved)) {
+            // NB the batch may already be visible in memory,
+            // and can neither be completed nor discarded now.
+            self.log.config.set_global_error(e.clone());
+            return Err(e);
+        }
+
+        Ok(max_reserved)
+    }
+
+    /// Leaves the manifest open, so that recovery stops at it
+    /// and no part of the batch is recovered, and sets `error`
+    /// as the global error of the system. Every later write,
+    /// flush and transaction then fails with it, since nothing
+    /// written after the manifest would be recovered either.
+    /// This is only meant for batches that failed partway
+    /// through and could not be undone.
+    pub fn abort(mut self, error: Error) {
+        // NB this keeps the drop from marking it as failed
+        self.sealed = true;
+        self.log.config.set_global_error(error);
     }
 
     /// Returns the LSN representing the beginning of this
     /// batch.
     pub fn lsn(&self) -> Lsn {
-        self.batch_res.lsn()
+        self.lsn
+    }
+
+    fn rewrite(&self, last: Option<Lsn>) -> Result<()> {
+        // NB the IO buffer that holds the open manifest must be
+        // written before we overwrite it, or it would clobber
+        // our update when it is written later.
+        self.log.make_stable(self.lsn)?;
+        self.log.rewrite_batch_manifest(self.lsn, self.lid, last)
+    }
+}
+
+impl<'a> Drop for RecoveryGuard<'a> {
+    fn drop(&mut self) {
+        if !self.sealed {
+            if let Err(e) = self.rewrite(None) {
+                error!("failed to mark unsealed batch as failed: {:?}", e);
+            }
+        }
+    }
+}
+
+/// A point-in-time view of the fragment chains that make
+/// up every page, created by `PageCache::view`. While a
+/// `PageView` is alive, the log segments and blobs that
+/// it refers to will be neither reused nor removed, so
+/// the pages can be materialized exactly as they were
+/// when the view was created, using `PageCache::get_from_view`.
+pub struct PageView {
+    lsn: Lsn,
+    pages: FastMap8<PageId, Vec<(Lsn, DiskPtr)>>,
+    iobufs: Arc<IoBufs>,
+}
+
+impl PageView {
+    /// The highest LSN that any fragment in this
+    /// view may have been written at.
+    pub fn lsn(&self) -> Lsn {
+        self.lsn
+    }
+}
+
+impl Debug for PageView {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        write!(
+            f,
+            "PageView {{ lsn: {} pages: {} }}",
+            self.lsn,
+            self.pages.len()
+        )
+    }
+}
+
+impl Drop for PageView {
+    fn drop(&mut self) {
+        if let Err(e) = self.iobufs.with_sa(|sa| sa.unpin_read_view()) {
+            error!("failed to release PageView: {:?}", e);
+        }
+    }
+}
+
+/// A page update read back from the log by `LogReplay`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Replayed<P> {
+    /// A fragment was appended to the page.
+    Append(P),
+    /// The page was freed.
+    Free,
+}
+
+/// An iterator over the page fragments appended to the log
+/// at or after a given `Lsn`, up to the stable tip of the log
+/// at the time it was created. While it is alive, the log
+/// segments and blobs that it reads from will be neither
+/// reused nor removed. Created by `PageCache::replay`.
+pub struct LogReplay<P> {
+    from: Lsn,
+    iter: LogIter,
+    iobufs: Arc<IoBufs>,
+    _pd: PhantomData<P>,
+}
+
+impl<P> Iterator for LogReplay<P>
+where
+    P: Serialize + DeserializeOwned,
+{
+    type Item = Result<(Lsn, PageId, Replayed<P>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (lsn, _ptr, bytes) = self.iter.next()?;
+
+            // NB the underlying iterator starts from the
+            // beginning of the segment that contains `from`.
+            if lsn < self.from {
+                continue;
+            }
+
+            let logged_update =
+                match measure(&self.iobufs.config.metrics.deserialize, || {
+                    deserialize(&bytes)
+                }) {
+                    Ok(logged_update) => logged_update,
+                    Err(e) => {
+                        error!("failed to deserialize replayed update: {}", e);
+                        return Some(Err(Error::Corruption {
+                            at: DiskPtr::Inline(lsn as LogId),
+                        }));
+                    }
+                };
+
+            let LoggedUpdate { pid, update } = logged_update;
+
+            match update {
+                Update::Append(frag) => {
+                    return Some(Ok((lsn, pid, Replayed::Append(frag))));
+                }
+                Update::Free => return Some(Ok((lsn, pid, Replayed::Free))),
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl<P> Drop for LogReplay<P> {
+    fn drop(&mut self) {
+        if let Err(e) = self.iobufs.with_sa(|sa| sa.unpin_read_view()) {
+            error!("failed to release LogReplay: {:?}", e);
+        }
+    }
+}
+
+/// An iterator over the serialized page updates in the log at
+/// or after a given `Lsn`, up to the stable tip of the log at
+/// the time it was created. Unlike `LogReplay`, every kind of
+/// update is returned, in the form that `PageCache::apply_shipped`
+/// expects, so that another `PageCache` can be kept identical
+/// to this one. Iteration stops before any write batch that
+/// has not been sealed yet. Created by `PageCache::ship`.
+pub struct LogShipper {
+    from: Lsn,
+    iter: LogIter,
+    iobufs: Arc<IoBufs>,
+}
+
+impl Iterator for LogShipper {
+    type Item = (Lsn, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (lsn, _ptr, bytes) = self.iter.next()?;
+
+            // NB the underlying iterator starts from the
+            // beginning of the segment that contains `from`.
+            if lsn >= self.from {
+                return Some((lsn, bytes));
+            }
+        }
+    }
+}
+
+impl LogShipper {
+    /// Returns `true` if a write batch that was shipped may
+    /// continue after the update at `lsn`, in which case the
+    /// updates up to `lsn` must not be applied without the
+    /// ones that follow it.
+    pub fn in_batch(&self, lsn: Lsn) -> bool {
+        lsn < self.iter.batch_end
+    }
+}
+
+impl Drop for LogShipper {
+    fn drop(&mut self) {
+        if let Err(e) = self.iobufs.with_sa(|sa| sa.unpin_read_view()) {
+            error!("failed to release LogShipper: {:?}", e);
+        }
     }
 }
 
@@ -401,7 +623,43 @@ where
     idgen: Arc<AtomicU64>,
     idgen_persists: Arc<AtomicU64>,
     idgen_persist_mu: Arc<Mutex<()>>,
+    retention: Mutex<LogRetention>,
+    shared_batch: RwLock<Option<OpenBatch>>,
     was_recovered: bool,
+    recovered_lsn: Lsn,
+}
+
+// The open manifest of the batch that `SharedBatch`es join.
+struct OpenBatch {
+    lsn: Lsn,
+    lid: LogId,
+    _pin: PageView,
+}
+
+/// A write that is part of the batch that is shared by the
+/// writes since the last flush, created by
+/// `PageCache::join_shared_batch`. The batch is recovered
+/// atomically like the one of a `RecoveryGuard`, but it is
+/// only sealed by the next `flush`, `flush_async` or
+/// `make_batch_stable`, which can not happen while a write
+/// that joined it is in progress. Until then, log readers
+/// and snapshots stop at its manifest.
+///
+/// A write that fails partway through must either write
+/// updates that undo the part that was applied before this
+/// is dropped, or be `abort`ed.
+pub struct SharedBatch<'a> {
+    config: &'a Config,
+    _open: RwLockReadGuard<'a, Option<OpenBatch>>,
+}
+
+impl<'a> SharedBatch<'a> {
+    /// Leaves the shared batch open for good, so that recovery
+    /// stops at its manifest, and sets `error` as the global
+    /// error of the system, like `RecoveryGuard::abort`.
+    pub fn abort(self, error: Error) {
+        self.config.set_global_error(error);
+    }
 }
 
 struct PageTableEntry<P>
@@ -481,6 +739,7 @@ where
     /// Instantiate a new `PageCache`.
     pub fn start(config: Config) -> Result<PageCache<PM, P>> {
         config.reset_global_error();
+        config.verify_format_version()?;
 
         let cache_capacity = config.cache_capacity;
         let cache_shard_bits = config.cache_bits;
@@ -491,6 +750,8 @@ where
         // snapshot before loading it.
         let snapshot = read_snapshot_or_default::<PM, P>(&config)?;
 
+        let retention = LogRetention::read(&config)?;
+
         let mut pc = PageCache {
             _materializer: PhantomData,
             config: config.clone(),
@@ -504,12 +765,21 @@ where
             idgen_persist_mu: Arc::new(Mutex::new(())),
             idgen: Arc::new(AtomicU64::new(0)),
             idgen_persists: Arc::new(AtomicU64::new(0)),
+            retention: Mutex::new(retention),
+            shared_batch: RwLock::new(None),
             was_recovered: false,
+            recovered_lsn: 0,
         };
 
         // now we read it back in
         pc.load_snapshot();
 
+        pc.recovered_lsn = pc.stable_lsn();
+
+        if !pc.config.read_only {
+            pc.release_orphaned_blobs()?;
+        }
+
         let tx = pc.begin()?;
         let mut was_recovered = true;
 
@@ -567,11 +837,25 @@ where
     /// Flushes any pending IO buffers to disk to ensure durability.
     /// Returns the number of bytes written during this call.
     pub fn flush(&self) -> Result<usize> {
+        self.seal_shared_batch()?;
         self.log.flush()
     }
 
-    /// Begins a transaction.
+    /// Returns a future that resolves once any pending IO
+    /// buffers have been written to disk, to the number of
+    /// bytes written in the meantime.
+    pub fn flush_async(&self) -> FlushFuture {
+        if let Err(e) = self.seal_shared_batch() {
+            return FlushFuture::failed(e);
+        }
+        self.log.flush_async()
+    }
+
+    /// Begins a transaction. Fails with the global error of
+    /// the system if it has hit one, because the pages may
+    /// hold writes that will not be recovered.
     pub fn begin(&self) -> Result<Tx> {
+        self.config.global_error()?;
         Ok(Tx::new(self.generate_id()?))
     }
 
@@ -605,19 +889,577 @@ where
 
     /// Initiate an atomic sequence of writes to the
     /// underlying log. Returns a `RecoveryGuard` which,
-    /// when dropped, will record the current max reserved
-    /// LSN into an earlier log reservation. During recovery,
+    /// when sealed, will record the current max reserved
+    /// LSN into an earlier log message. During recovery,
     /// when we hit this early atomic LSN marker, if the
-    /// specified LSN is beyond the contiguous tip of the log,
-    /// we immediately halt recovery, preventing the recovery
-    /// of partial transactions or write batches. This is
-    /// a relatively low-level primitive that can be used
-    /// to facilitate transactions and write batches when
-    /// combined with a concurrency control system in another
-    /// component.
+    /// batch was never sealed, or the specified LSN is
+    /// beyond the contiguous tip of the log, we immediately
+    /// halt recovery, preventing the recovery of partial
+    /// transactions or write batches. Any other write that
+    /// is logged while the guard is open is only recovered
+    /// if the batch is. This is a relatively low-level
+    /// primitive that can be used to facilitate transactions
+    /// and write batches when combined with a concurrency
+    /// control system in another component.
     pub fn pin_log<'a>(&'a self) -> Result<RecoveryGuard<'a>> {
-        let batch_res = self.log.reserve(&[0; std::mem::size_of::<Lsn>()])?;
-        Ok(RecoveryGuard { batch_res })
+        // NB must pin before writing the manifest, so that
+        // the segment it is written to can't be reused
+        // before the batch is sealed.
+        let pin = self.pin_view();
+
+        let mut batch_res = self.log.reserve_batch_manifest()?;
+
+        // an open batch ends beyond any possible stable tip
+        batch_res.mark_writebatch(std::i64::MAX);
+        let (lsn, ptr) = batch_res.complete()?;
+
+        Ok(RecoveryGuard {
+            log: &self.log,
+            lsn,
+            lid: ptr.lid(),
+            sealed: false,
+            _pin: pin,
+        })
+    }
+
+    /// Joins the batch that is shared by the writes since the
+    /// last flush, opening it if there is none. Unlike with
+    /// `pin_log`, nothing is written to disk to seal it, which
+    /// the next flush does for all of them at once.
+    pub fn join_shared_batch(&self) -> Result<SharedBatch<'_>> {
+        loop {
+            let open = self.shared_batch.read().unwrap();
+            if open.is_some() {
+                return Ok(SharedBatch {
+                    config: &self.config,
+                    _open: open,
+                });
+            }
+            drop(open);
+
+            let mut open = self.shared_batch.write().unwrap();
+            if open.is_none() {
+                // NB must pin before writing the manifest, so that
+                // the segment it is written to can't be reused
+                // before the batch is sealed.
+                let pin = self.pin_view();
+
+                let mut batch_res = self.log.reserve_batch_manifest()?;
+                batch_res.mark_writebatch(std::i64::MAX);
+                let (lsn, ptr) = batch_res.complete()?;
+
+                *open = Some(OpenBatch {
+                    lsn,
+                    lid: ptr.lid(),
+                    _pin: pin,
+                });
+            }
+        }
+    }
+
+    // Seals the shared batch, if there is one, with the last
+    // LSN that was reserved so far, and syncs its manifest.
+    fn seal_shared_batch(&self) -> Result<()> {
+        let mut open = self.shared_batch.write().unwrap();
+
+        // NB a batch that was aborted must stay open
+        self.config.global_error()?;
+
+        let batch = match open.take() {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+
+        let max_reserved = self
+            .log
+            .iobufs
+            .max_reserved_lsn
+            .load(std::sync::atomic::Ordering::Acquire);
+
+        // NB the IO buffer that holds the open manifest must be
+        // written before we overwrite it, or it would clobber
+        // our update when it is written later.
+        let res = self
+            .log
+            .make_stable(batch.lsn)
+            .and_then(|_| {
+                self.log.rewrite_batch_manifest(
+                    batch.lsn,
+                    batch.lid,
+                    Some(max_reserved),
+                )
+            })
+            .and_then(|()| self.config.file.sync_all().map_err(Error::from));
+
+        if let Err(e) = res {
+            // NB the batch may already be visible in memory,
+            // and can neither be completed nor discarded now.
+            self.config.set_global_error(e.clone());
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Write a copy of the log, blobs and latest snapshot
+    /// into the directory at `path`, which must either not
+    /// exist or be empty. The copy can be opened directly by
+    /// configuring its `path`. Writes may continue while the
+    /// checkpoint is being written, and the copy will reflect
+    /// at least every write that was stable when this was
+    /// called.
+    pub fn checkpoint<Q: AsRef<std::path::Path>>(&self, path: Q) -> Result<()> {
+        let _measure = Measure::new(&self.config.metrics.checkpoint);
+
+        // NB holding the snapshot mutex prevents a new snapshot
+        // from being written, and older ones from being removed,
+        // while we are copying them.
+        let _snapshot = self.last_snapshot.lock().unwrap();
+
+        // NB the registered log consumers must match the
+        // segments that were retained for them.
+        let _retention = self.retention.lock().unwrap();
+
+        // pinning a view prevents segments from being rewritten
+        // or truncated, and blobs from being removed, until it
+        // is dropped.
+        let _pin = self.pin_view();
+
+        self.flush()?;
+
+        checkpoint::write_checkpoint(&self.config, path.as_ref())
+    }
+
+    /// Create a `PageView` that captures the current state of
+    /// every allocated page. The caller is responsible for
+    /// preventing concurrent logical modifications while this
+    /// runs if it requires the view to be consistent across
+    /// several pages. Does not flush the log, so the caller
+    /// must pass the view's `lsn` to `make_stable` before
+    /// reading pages from it, which may be done after
+    /// concurrent modifications are allowed again.
+    pub fn view(&self) -> Result<PageView> {
+        let _measure = Measure::new(&self.config.metrics.page_view);
+
+        // NB must pin before reading any page, so that nothing
+        // we capture can be reused or removed before we return.
+        let mut view = self.pin_view();
+
+        let tx = Tx::new(0);
+
+        for pid in 0..self.max_pid.load(SeqCst) {
+            if pid == META_PID || pid == COUNTER_PID {
+                continue;
+            }
+
+            let pte_ptr = match self.inner.get(pid, &tx) {
+                None => continue,
+                Some(p) => p,
+            };
+
+            let head = unsafe { pte_ptr.deref().stack.head(&tx) };
+
+            let entries: Vec<&CacheEntry<P>> =
+                StackIter::from_ptr(head, &tx).collect();
+
+            if entries.is_empty() || entries[0].is_free() {
+                continue;
+            }
+
+            view.pages.insert(
+                pid,
+                entries.iter().map(|ce| (ce.lsn(), ce.ptr())).collect(),
+            );
+        }
+
+        drop(tx);
+
+        view.lsn = self.log.iobufs.max_reserved_lsn.load(SeqCst);
+
+        Ok(view)
+    }
+
+    /// Checks the integrity of the log and of every allocated
+    /// page, returning a report of the problems found instead
+    /// of failing on the first one. Every segment of the log is
+    /// read, along with every message in it, and then every
+    /// fragment of every page is read from the location that
+    /// the page table points to, along with the blob that it
+    /// points to if it is stored as one. Blobs that no page
+    /// points to anymore are not read, as they may be removed
+    /// at any time. Writes may continue while this runs, and
+    /// segments are neither rewritten nor removed until it
+    /// returns.
+    pub fn verify_integrity(&self) -> Result<IntegrityReport> {
+        let _pin = self.pin_view();
+
+        let tx = Tx::new(0);
+
+        let mut pages = vec![];
+        for pid in 0..self.max_pid.load(SeqCst) {
+            let pte_ptr = match self.inner.get(pid, &tx) {
+                None => continue,
+                Some(p) => p,
+            };
+
+            let head = unsafe { pte_ptr.deref().stack.head(&tx) };
+
+            let entries: Vec<&CacheEntry<P>> =
+                StackIter::from_ptr(head, &tx).collect();
+
+            if entries.is_empty() || entries[0].is_free() {
+                continue;
+            }
+
+            let frags: Vec<(Lsn, DiskPtr)> =
+                entries.iter().map(|ce| (ce.lsn(), ce.ptr())).collect();
+            pages.push((pid, frags));
+        }
+
+        drop(tx);
+
+        // the fragments that we found may not have been
+        // written yet.
+        self.make_stable(self.log.iobufs.max_reserved_lsn.load(SeqCst))?;
+
+        let mut report = IntegrityReport::default();
+
+        integrity::verify_segments(
+            &self.config,
+            &mut report,
+            false,
+            |_, _, _| {},
+        )?;
+
+        for (pid, frags) in pages {
+            integrity::verify_page(&self.config, pid, &frags, &mut report);
+        }
+
+        Ok(report)
+    }
+
+    /// Returns statistics about the space that this `PageCache`
+    /// uses on disk: the sizes of its log, blob and snapshot
+    /// files, the state and utilization of every segment of the
+    /// log, and the number of allocated and free page ids.
+    pub fn storage_stats(&self) -> Result<StorageStats> {
+        let mut stats = StorageStats::default();
+
+        self.log.with_sa(|sa| sa.storage_stats(&mut stats));
+
+        let free_pids = self.free.lock().unwrap().len();
+        stats.free_pids = free_pids;
+        stats.pages = self.max_pid.load(SeqCst) - free_pids as PageId;
+
+        stats::measure_files(&self.config, &mut stats)?;
+
+        Ok(stats)
+    }
+
+    // Returns an empty `PageView`, which releases
+    // its pin on the log when dropped.
+    fn pin_view(&self) -> PageView {
+        self.log.with_sa(|sa| sa.pin_read_view());
+
+        PageView {
+            lsn: 0,
+            pages: FastMap8::default(),
+            iobufs: self.log.iobufs.clone(),
+        }
+    }
+
+    /// Registers a durable log consumer with the given name,
+    /// returning the `Lsn` from which it should replay the log.
+    /// For a new consumer this is the current tip of the log,
+    /// and `state` is stored alongside it. `state` is opaque to
+    /// the `PageCache`, and can hold whatever the consumer
+    /// needs in order to interpret the log from its position
+    /// onward. If the consumer is already registered, its
+    /// existing position and state are kept. Until the
+    /// consumer is advanced or removed, the log segments
+    /// containing messages at or after its `Lsn` are
+    /// retained, including across restarts.
+    pub fn register_log_consumer(
+        &self,
+        name: &[u8],
+        state: &[u8],
+    ) -> Result<Lsn> {
+        let mut retention = self.retention.lock().unwrap();
+
+        if let Some(lsn) = retention.get(name) {
+            return Ok(lsn);
+        }
+
+        self.flush()?;
+        let lsn = self.stable_lsn();
+
+        let mut new_retention = retention.clone();
+        new_retention.insert(name, lsn, state);
+        self.set_retention(&mut retention, new_retention)?;
+
+        Ok(lsn)
+    }
+
+    /// Returns the `Lsn` from which a registered log
+    /// consumer should resume, along with the state that
+    /// was stored with it, or `None` if no consumer with
+    /// this name is registered.
+    pub fn log_consumer(&self, name: &[u8]) -> Option<(Lsn, Vec<u8>)> {
+        self.retention.lock().unwrap().get_with_state(name)
+    }
+
+    /// Returns the names of every registered log consumer.
+    pub fn log_consumers(&self) -> Vec<Vec<u8>> {
+        self.retention.lock().unwrap().names()
+    }
+
+    /// Records that a registered log consumer no longer needs
+    /// any log message below `lsn`, allowing the segments that
+    /// only contain such messages to be reused, and replaces
+    /// its state. Returns an error if the consumer is not
+    /// registered, or if `lsn` is lower than its current
+    /// position.
+    pub fn advance_log_consumer(
+        &self,
+        name: &[u8],
+        lsn: Lsn,
+        state: &[u8],
+    ) -> Result<()> {
+        let mut retention = self.retention.lock().unwrap();
+
+        match retention.get(name) {
+            None => {
+                return Err(Error::Unsupported(format!(
+                    "no log consumer named {:?} is registered",
+                    name
+                )));
+            }
+            Some(current) if current > lsn => {
+                return Err(Error::Unsupported(format!(
+                    "log consumer {:?} is at lsn {}, and \
+                     cannot be moved back to lsn {}",
+                    name, current, lsn
+                )));
+            }
+            Some(_) => {}
+        }
+
+        let mut new_retention = retention.clone();
+        new_retention.insert(name, lsn, state);
+        self.set_retention(&mut retention, new_retention)
+    }
+
+    /// Removes a registered log consumer, releasing any log
+    /// segments that were only retained for it. Returns
+    /// `false` if no consumer with this name was registered.
+    pub fn remove_log_consumer(&self, name: &[u8]) -> Result<bool> {
+        let mut retention = self.retention.lock().unwrap();
+
+        if retention.get(name).is_none() {
+            return Ok(false);
+        }
+
+        let mut new_retention = retention.clone();
+        new_retention.remove(name);
+        self.set_retention(&mut retention, new_retention)?;
+
+        Ok(true)
+    }
+
+    // Durably replaces the registered log consumers, and
+    // informs the `SegmentAccountant` of the new floor.
+    fn set_retention(
+        &self,
+        current: &mut LogRetention,
+        new: LogRetention,
+    ) -> Result<()> {
+        // NB when lowering the floor, the segment accountant
+        // must learn about it before it is made durable, so
+        // that no segment it refers to can be reused in between.
+        let lowering = match (current.floor(), new.floor()) {
+            (Some(old), Some(new)) => new < old,
+            (None, Some(_)) => true,
+            _ => false,
+        };
+
+        if lowering {
+            self.log.with_sa(|sa| sa.set_retention(new.floor()))?;
+        }
+
+        if let Err(e) = new.write(&self.config) {
+            if lowering {
+                self.log.with_sa(|sa| sa.set_retention(current.floor()))?;
+            }
+            return Err(e);
+        }
+
+        if !lowering {
+            self.log.with_sa(|sa| sa.set_retention(new.floor()))?;
+        }
+
+        *current = new;
+
+        Ok(())
+    }
+
+    /// Returns an iterator over the page fragments that were
+    /// appended, and the pages that were freed, at or after
+    /// `from`. Only the log segments retained by a registered
+    /// log consumer, or otherwise not yet reused, can be replayed.
+    pub fn replay(&self, from: Lsn) -> Result<LogReplay<P>> {
+        let _measure = Measure::new(&self.config.metrics.replay);
+
+        self.flush()?;
+
+        self.log.with_sa(|sa| sa.pin_read_view());
+
+        Ok(LogReplay {
+            from,
+            iter: self.log.iobufs.iter_from(from),
+            iobufs: self.log.iobufs.clone(),
+            _pd: PhantomData,
+        })
+    }
+
+    /// Returns an iterator over every update that was written
+    /// to the log at or after `from`, for applying to a replica
+    /// of this `PageCache` with `apply_shipped`. Only the log
+    /// segments retained by a registered log consumer, or
+    /// otherwise not yet reused, can be shipped.
+    pub fn ship(&self, from: Lsn) -> Result<LogShipper> {
+        let _measure = Measure::new(&self.config.metrics.replay);
+
+        self.flush()?;
+
+        self.log.with_sa(|sa| sa.pin_read_view());
+
+        Ok(LogShipper {
+            from,
+            iter: self.log.iobufs.iter_from(from),
+            iobufs: self.log.iobufs.clone(),
+        })
+    }
+
+    /// Applies an update that was read from the log of another
+    /// `PageCache` by `ship` to the same page of this one. If
+    /// every update shipped from the other `PageCache` is applied
+    /// in order, starting from an identical copy of it, such as
+    /// one written by `checkpoint`, both will contain the same
+    /// pages. Nothing else may modify this `PageCache` while it
+    /// is being kept up to date in this way.
+    pub fn apply_shipped(&self, message: &[u8]) -> Result<()> {
+        let logged_update: LoggedUpdate<P> =
+            measure(&self.config.metrics.deserialize, || deserialize(message))
+                .map_err(|e| {
+                    error!("failed to deserialize shipped update: {}", e);
+                    Error::Unsupported(
+                        "received a malformed update from a primary".into(),
+                    )
+                })?;
+
+        let LoggedUpdate { pid, update } = logged_update;
+
+        trace!("applying shipped update to pid {}: {:?}", pid, update);
+
+        let tx = Tx::new(0);
+
+        if self.inner.get(pid, &tx).is_none() {
+            self.install_shipped_pid(pid, &tx);
+        }
+
+        loop {
+            let old = match self.get(pid, &tx)? {
+                PageGet::Materialized(_, ptr)
+                | PageGet::Free(ptr)
+                | PageGet::Counter(_, ptr)
+                | PageGet::Meta(_, ptr) => ptr,
+                PageGet::Unallocated => PagePtr {
+                    cached_ptr: Shared::null(),
+                    wts: 0,
+                },
+            };
+
+            let succeeded = match update.clone() {
+                Update::Append(frag) => self.link(pid, old, frag, &tx)?.is_ok(),
+                Update::Compact(frag) => {
+                    let result = self.replace(pid, old, frag, &tx)?;
+                    if result.is_ok() {
+                        // the page may have been reallocated
+                        // after being freed.
+                        let mut free = self.free.lock().unwrap();
+                        *free = free.drain().filter(|&p| p != pid).collect();
+                    }
+                    result.is_ok()
+                }
+                Update::Free => {
+                    let result =
+                        self.cas_page(pid, old, Update::Free, false, &tx)?;
+                    if result.is_ok() {
+                        self.free.lock().unwrap().push(pid);
+                    }
+                    result.is_ok()
+                }
+                other => self.cas_page(pid, old, other, false, &tx)?.is_ok(),
+            };
+
+            if succeeded {
+                return Ok(());
+            }
+        }
+    }
+
+    // Creates the page table entry for a page that was
+    // allocated for the first time by a primary.
+    fn install_shipped_pid(&self, pid: PageId, tx: &Tx) {
+        let new_pte = PageTableEntry {
+            stack: Stack::default(),
+            rts: AtomicLsn::new(0),
+            pending: AtomicLsn::new(0),
+        };
+
+        let pte_ptr = Owned::new(new_pte).into_shared(tx);
+
+        self.inner.cas(pid, Shared::null(), pte_ptr, tx).expect(
+            "installing a page that was allocated by a \
+             primary should never conflict on existing data",
+        );
+
+        let mut max_pid = self.max_pid.load(SeqCst);
+        while max_pid <= pid {
+            match self.max_pid.compare_exchange(
+                max_pid,
+                pid + 1,
+                SeqCst,
+                SeqCst,
+            ) {
+                Ok(_) => break,
+                Err(actual) => max_pid = actual,
+            }
+        }
+    }
+
+    /// Materialize a page as it was when the provided
+    /// `PageView` was created, reading its fragments from
+    /// stable storage. Returns `None` if the page was not
+    /// allocated at that time.
+    pub fn get_from_view(
+        &self,
+        view: &PageView,
+        pid: PageId,
+    ) -> Result<Option<P>> {
+        let entries = match view.pages.get(&pid) {
+            None => return Ok(None),
+            Some(entries) => entries,
+        };
+
+        let frags: Vec<P> = entries
+            .iter()
+            .map(|&(lsn, ptr)| self.pull(lsn, ptr).map(Update::into_frag))
+            .collect::<Result<_>>()?;
+
+        let _measure = Measure::new(&self.config.metrics.merge_page);
+
+        Ok(Some(PM::merge(frags.iter().rev(), &self.config)))
     }
 
     #[doc(hidden)]
@@ -733,7 +1575,7 @@ where
         new: P,
         tx: &'g Tx,
     ) -> Result<CasResult<'g, P, P>> {
-        let _measure = Measure::new(&M.link_page);
+        let _measure = Measure::new(&self.config.metrics.link_page);
 
         trace!("linking pid {} with {:?}", pid, new);
 
@@ -747,7 +1589,9 @@ where
             update: Update::Append(new),
         };
 
-        let bytes = measure(&M.serialize, || serialize(&prepend).unwrap());
+        let bytes = measure(&self.config.metrics.serialize, || {
+            serialize(&prepend).unwrap()
+        });
 
         let mut new = if let Update::Append(new) = prepend.update {
             let cache_entry =
@@ -904,7 +1748,7 @@ where
         new: P,
         tx: &'g Tx,
     ) -> Result<CasResult<'g, P, P>> {
-        let _measure = Measure::new(&M.replace_page);
+        let _measure = Measure::new(&self.config.metrics.replace_page);
 
         trace!("replacing pid {} with {:?}", pid, new);
 
@@ -939,7 +1783,7 @@ where
     // segment has had enough resident page fragments moved
     // away to trigger the `segment_cleanup_threshold`.
     fn rewrite_page<'g>(&self, pid: PageId, tx: &'g Tx) -> Result<()> {
-        let _measure = Measure::new(&M.rewrite_page);
+        let _measure = Measure::new(&self.config.metrics.rewrite_page);
 
         trace!("rewriting pid {}", pid);
 
@@ -1045,7 +1889,9 @@ where
         };
 
         let replace: LoggedUpdate<P> = LoggedUpdate { pid, update: new };
-        let bytes = measure(&M.serialize, || serialize(&replace).unwrap());
+        let bytes = measure(&self.config.metrics.serialize, || {
+            serialize(&replace).unwrap()
+        });
         let mut new = Some(replace.update);
 
         loop {
@@ -1262,7 +2108,7 @@ where
         if successes.len() > self.config.page_consolidation_threshold {
             trace!("consolidating pid {} with len {}!", pid, successes.len());
             let update = {
-                let _measure = Measure::new(&M.merge_page);
+                let _measure = Measure::new(&self.config.metrics.merge_page);
 
                 let combined_iter = successes.iter().map(|(c, ..)| &**c).rev();
 
@@ -1386,6 +2232,21 @@ where
         self.log.make_stable(lsn)
     }
 
+    /// Blocks until a batch that `RecoveryGuard::seal_batch`
+    /// sealed with the last LSN `last_lsn` is durable.
+    pub fn make_batch_stable(&self, last_lsn: Lsn) -> Result<()> {
+        // NB recovery would stop at the shared batch before it
+        // reaches this one if it was left open.
+        self.seal_shared_batch()?;
+        self.log.make_stable(last_lsn)?;
+
+        // NB the manifest is overwritten in place, which the
+        // sync of a later IO buffer only covers if there is one.
+        self.config.file.sync_all()?;
+
+        Ok(())
+    }
+
     /// Increase a page's associated transactional read
     /// timestamp (RTS) to as high as the specified timestamp.
     pub fn bump_page_rts(&self, pid: PageId, ts: Lsn, tx: &Tx) {
@@ -1439,6 +2300,13 @@ where
         self.was_recovered
     }
 
+    /// Returns the stable tip of the log as it was right
+    /// after recovery, before anything was written to it
+    /// by this process.
+    pub fn recovered_lsn(&self) -> Lsn {
+        self.recovered_lsn
+    }
+
     /// Generate a monotonic ID. Not guaranteed to be
     /// contiguous. Written to disk every `idgen_persist_interval`
     /// operations, followed by a blocking flush. During recovery, we
@@ -1547,6 +2415,63 @@ where
         new: Option<PageId>,
         tx: &'g Tx,
     ) -> Result<std::result::Result<(), Option<PageId>>> {
+        self.cas_meta(tx, |meta| {
+            let actual = meta.get_root(&name);
+            if actual != old {
+                return Err(actual);
+            }
+
+            let mut new_meta = meta.clone();
+            if let Some(new) = new {
+                new_meta.set_root(name.clone(), new);
+            } else {
+                new_meta.del_root(&name);
+            }
+            Ok(new_meta)
+        })
+    }
+
+    /// Compare-and-swap a named property that is recorded
+    /// for a given identifier in the `Meta` mapping.
+    pub fn cas_property_in_meta(
+        &self,
+        name: &[u8],
+        property: &str,
+        old: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+        tx: &Tx,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>> {
+        self.cas_meta(tx, |meta| {
+            let actual = meta.get_property(name, property);
+            if actual != old {
+                return Err(actual.map(<[u8]>::to_vec));
+            }
+
+            let mut new_meta = meta.clone();
+            if let Some(ref new) = new {
+                new_meta.set_property(
+                    name.to_vec(),
+                    property.to_owned(),
+                    new.clone(),
+                );
+            } else {
+                new_meta.del_property(name, property);
+            }
+            Ok(new_meta)
+        })
+    }
+
+    // Replaces the `Meta` page with the result of `f`,
+    // retrying if it is concurrently replaced, unless
+    // `f` rejects the current `Meta`.
+    fn cas_meta<F, E>(
+        &self,
+        tx: &Tx,
+        f: F,
+    ) -> Result<std::result::Result<(), E>>
+    where
+        F: Fn(&Meta) -> std::result::Result<Meta, E>,
+    {
         loop {
             let meta_page_get = self.get(META_PID, tx)?;
 
@@ -1557,17 +2482,10 @@ where
                 }
             };
 
-            let actual = meta.get_root(&name);
-            if actual != old {
-                return Ok(Err(actual));
-            }
-
-            let mut new_meta = (*meta).clone();
-            if let Some(new) = new {
-                new_meta.set_root(name.clone(), new);
-            } else {
-                new_meta.del_root(&name);
-            }
+            let new_meta = match f(meta) {
+                Ok(new_meta) => new_meta,
+                Err(e) => return Ok(Err(e)),
+            };
 
             let new_meta_frag = Update::Meta(new_meta);
 
@@ -1599,7 +2517,7 @@ where
         pte_ptr: Shared<'g, PageTableEntry<P>>,
         tx: &'g Tx,
     ) -> Result<Option<PageGet<'g, P>>> {
-        let _measure = Measure::new(&M.page_in);
+        let _measure = Measure::new(&self.config.metrics.page_in);
 
         debug_delay();
         let mut head = unsafe { pte_ptr.deref().stack.head(tx) };
@@ -1751,7 +2669,7 @@ where
             // `Frag`.
             fetched.pop().unwrap()
         } else {
-            let _measure = Measure::new(&M.merge_page);
+            let _measure = Measure::new(&self.config.metrics.merge_page);
 
             let combined_iter = to_merge
                 .into_iter()
@@ -1885,7 +2803,7 @@ where
     }
 
     fn page_out<'g>(&self, to_evict: Vec<PageId>, tx: &'g Tx) -> Result<()> {
-        let _measure = Measure::new(&M.page_out);
+        let _measure = Measure::new(&self.config.metrics.page_out);
         for pid in to_evict {
             let pte_ptr = match self.inner.get(pid, tx) {
                 None => continue,
@@ -1959,7 +2877,7 @@ where
 
     fn pull(&self, lsn: Lsn, ptr: DiskPtr) -> Result<Update<P>> {
         trace!("pulling lsn {} ptr {} from disk", lsn, ptr);
-        let _measure = Measure::new(&M.pull);
+        let _measure = Measure::new(&self.config.metrics.pull);
         let bytes = match self.log.read(lsn, ptr).map_err(|_| ()) {
             Ok(LogRead::Inline(read_lsn, buf, _len)) => {
                 assert_eq!(
@@ -1986,7 +2904,7 @@ where
             }
         }?;
 
-        let logged_update = measure(&M.deserialize, || {
+        let logged_update = measure(&self.config.metrics.deserialize, || {
             deserialize::<LoggedUpdate<P>>(&*bytes)
                 .map_err(|_| ())
                 .expect("failed to deserialize data")
@@ -2046,7 +2964,8 @@ where
                 iobufs.stable(),
             );
 
-            let res = advance_snapshot::<PM, P>(iter, last_snapshot, &config);
+            let res =
+                advance_snapshot::<PM, P>(iter, last_snapshot, &config, false);
 
             // NB it's important to resume writing before replacing the snapshot
             // into the mutex, otherwise we create a race condition where the SA is
@@ -2098,6 +3017,43 @@ where
         Ok(())
     }
 
+    // Removes the blobs that were still held for a read view
+    // or a log consumer when the system shut down, and that
+    // no recovered page refers to anymore.
+    fn release_orphaned_blobs(&self) -> Result<()> {
+        let mut referenced = FastSet8::default();
+        {
+            let snapshot = self.last_snapshot.lock().unwrap();
+            for state in snapshot.as_ref().unwrap().pt.values() {
+                match *state {
+                    PageState::Present(ref ptrs) => {
+                        for &(_lsn, ptr) in ptrs {
+                            if ptr.is_blob() {
+                                referenced.insert(ptr.blob().1);
+                            }
+                        }
+                    }
+                    PageState::Free(_lsn, ptr) => {
+                        if ptr.is_blob() {
+                            referenced.insert(ptr.blob().1);
+                        }
+                    }
+                }
+            }
+        }
+
+        let orphans: Vec<BlobPointer> = blob_ids(&self.config)?
+            .into_iter()
+            .filter(|id| !referenced.contains(id))
+            .collect();
+
+        if !orphans.is_empty() {
+            debug!("removing {} orphaned blobs", orphans.len());
+        }
+
+        self.log.with_sa(|sa| sa.release_orphaned_blobs(orphans))
+    }
+
     fn load_snapshot(&mut self) {
         // panic if not set
         let snapshot = self.last_snapshot.try_lock().unwrap().clone().unwrap();
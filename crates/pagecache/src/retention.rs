@@ -0,0 +1,113 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Read, Write},
+};
+
+use super::*;
+
+/// The durable set of log consumers, each of which prevents
+/// the segments holding log messages at or after its
+/// registered `Lsn` from being reused, so that those
+/// messages can be replayed after a restart. Each consumer
+/// also stores an opaque state that is only meaningful to it.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LogRetention {
+    consumers: BTreeMap<Vec<u8>, (Lsn, Vec<u8>)>,
+}
+
+impl LogRetention {
+    /// Reads the registered consumers for this system,
+    /// or returns an empty set if none have been written.
+    pub(crate) fn read(config: &Config) -> Result<LogRetention> {
+        let path = config.retention_path();
+
+        let mut f = match fs::File::open(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(LogRetention::default());
+            }
+            other => other?,
+        };
+
+        let mut buf = vec![];
+        f.read_to_end(&mut buf)?;
+
+        if buf.len() < 4 {
+            return Err(Error::Corruption {
+                at: DiskPtr::Inline(0),
+            });
+        }
+
+        let crc_at = buf.len() - 4;
+        let crc_expected = arr_to_u32(&buf[crc_at..]);
+        buf.truncate(crc_at);
+
+        if crc32(&buf) != crc_expected {
+            error!("crc for log retention file {:?} failed", path);
+            return Err(Error::Corruption {
+                at: DiskPtr::Inline(crc_at as LogId),
+            });
+        }
+
+        deserialize(&buf).map_err(|_| Error::Corruption {
+            at: DiskPtr::Inline(0),
+        })
+    }
+
+    /// Atomically replaces the registered consumers on disk.
+    pub(crate) fn write(&self, config: &Config) -> Result<()> {
+        let bytes = serialize(self).unwrap();
+        let crc = u32_to_arr(crc32(&bytes));
+
+        let path = config.retention_path();
+        let tmp_path = path.with_extension("generating");
+
+        let mut f = fs::File::create(&tmp_path)?;
+        maybe_fail!("write_retention bytes");
+        f.write_all(&bytes)?;
+        f.write_all(&crc)?;
+        f.sync_all()?;
+        drop(f);
+
+        fs::rename(&tmp_path, &path)?;
+
+        #[cfg(unix)]
+        fs::File::open(config.get_path())?.sync_all()?;
+
+        maybe_fail!("write_retention post");
+
+        Ok(())
+    }
+
+    /// The `Lsn` of the consumer with the given name.
+    pub(crate) fn get(&self, consumer: &[u8]) -> Option<Lsn> {
+        self.consumers.get(consumer).map(|(lsn, _)| *lsn)
+    }
+
+    /// The `Lsn` and state of the consumer with the given name.
+    pub(crate) fn get_with_state(
+        &self,
+        consumer: &[u8],
+    ) -> Option<(Lsn, Vec<u8>)> {
+        self.consumers.get(consumer).cloned()
+    }
+
+    pub(crate) fn insert(&mut self, consumer: &[u8], lsn: Lsn, state: &[u8]) {
+        self.consumers
+            .insert(consumer.to_vec(), (lsn, state.to_vec()));
+    }
+
+    pub(crate) fn remove(&mut self, consumer: &[u8]) -> Option<Lsn> {
+        self.consumers.remove(consumer).map(|(lsn, _)| lsn)
+    }
+
+    /// The names of every registered consumer.
+    pub(crate) fn names(&self) -> Vec<Vec<u8>> {
+        self.consumers.keys().cloned().collect()
+    }
+
+    /// The lowest `Lsn` that any consumer still needs.
+    pub(crate) fn floor(&self) -> Option<Lsn> {
+        self.consumers.values().map(|(lsn, _)| *lsn).min()
+    }
+}
@@ -0,0 +1,163 @@
+//! Recovery of the pages that can still be read from a
+//! damaged log, which skips the segments, messages and blobs
+//! that cannot be read instead of stopping at the first one,
+//! like recovery does.
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use super::*;
+
+/// The pages that could be read from a damaged log by
+/// `salvage`, along with the parts of it that could not.
+#[derive(Debug)]
+pub struct Salvage<P> {
+    /// The last state of every page that could be read in
+    /// full, materialized from its fragments.
+    pub pages: FastMap8<PageId, P>,
+    /// The last state of the `Meta` page that could be read.
+    pub meta: Option<Meta>,
+    /// The pages that had updates in the log, but whose last
+    /// state could not be materialized, and why.
+    pub lost: FastMap8<PageId, String>,
+    /// The pages in `pages` whose base fragment was written
+    /// before a part of the log that could not be read, so
+    /// they may be missing some of their updates.
+    pub damaged: FastSet8<PageId>,
+    /// The problems found while reading the log, as reported
+    /// by `PageCache::verify_integrity`.
+    pub report: IntegrityReport,
+}
+
+/// Reads every page update that can still be read from the
+/// log of `config`, skipping the segments, messages and blobs
+/// that cannot be read, and materializes the last state of
+/// every page that has a readable base fragment after which
+/// none of its updates are missing. The log is never written
+/// to, so this can be used on a database that fails to start.
+///
+/// A message that cannot be read does not reveal which page
+/// it belongs to, so every page whose base fragment was
+/// written before it is materialized anyway, but also listed
+/// in `damaged`.
+pub fn salvage<PM, P>(config: &Config) -> Result<Salvage<P>>
+where
+    PM: Materializer<PageFrag = P>,
+    P: 'static + Debug + Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    let mut report = IntegrityReport::default();
+    let mut updates: FastMap8<PageId, Vec<(Lsn, Update<P>)>> =
+        FastMap8::default();
+    let mut undecodable = vec![];
+
+    integrity::verify_segments(config, &mut report, true, |lsn, lid, buf| {
+        match deserialize::<LoggedUpdate<P>>(&buf) {
+            Ok(LoggedUpdate { pid, update }) => updates
+                .entry(pid)
+                .or_insert_with(Vec::new)
+                .push((lsn, update)),
+            Err(e) => undecodable.push(IntegrityProblem::CorruptedMessage {
+                lid,
+                lsn,
+                reason: format!("failed to deserialize: {}", e),
+            }),
+        }
+    })?;
+
+    report.problems.extend(undecodable);
+
+    // NB the rest of a segment is skipped after a message that
+    // cannot be read, so everything up to its end may be lost.
+    let segment_len = config.io_buf_size as Lsn;
+    let lost_spans: Vec<(Lsn, Lsn)> = report
+        .problems
+        .iter()
+        .filter_map(|problem| match problem {
+            IntegrityProblem::CorruptedMessage { lsn, .. } => {
+                Some((*lsn, (lsn / segment_len + 1) * segment_len))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut salvage = Salvage {
+        pages: FastMap8::default(),
+        meta: None,
+        lost: FastMap8::default(),
+        damaged: FastSet8::default(),
+        report,
+    };
+    let mut meta_lsn = None;
+
+    for (pid, mut page_updates) in updates {
+        page_updates.sort_by_key(|(lsn, _update)| *lsn);
+
+        let mut base_lsn = None;
+        let mut frags: Vec<P> = vec![];
+        let mut missing_base = false;
+
+        for (lsn, update) in page_updates {
+            match update {
+                Update::Compact(frag) => {
+                    base_lsn = Some(lsn);
+                    frags = vec![frag];
+                    missing_base = false;
+                }
+                Update::Append(frag) => {
+                    if base_lsn.is_some() {
+                        frags.push(frag);
+                    } else {
+                        missing_base = true;
+                    }
+                }
+                Update::Free => {
+                    base_lsn = None;
+                    frags.clear();
+                    missing_base = false;
+                }
+                Update::Meta(meta) => {
+                    if meta_lsn < Some(lsn) {
+                        meta_lsn = Some(lsn);
+                        salvage.meta = Some(meta);
+                    }
+                }
+                Update::Counter(_) => {}
+            }
+        }
+
+        if missing_base {
+            salvage.lost.insert(
+                pid,
+                "the base fragment of the page could not be read".to_owned(),
+            );
+            continue;
+        }
+
+        let base_lsn = match base_lsn {
+            Some(base_lsn) => base_lsn,
+            // the page was freed, or is not a materialized page
+            None => continue,
+        };
+
+        // NB fragments that were written on top of a lost one may
+        // not apply to what remains, which the materializer is
+        // free to treat as a bug.
+        let merged =
+            catch_unwind(AssertUnwindSafe(|| PM::merge(frags.iter(), config)));
+
+        match merged {
+            Ok(page) => {
+                if lost_spans.iter().any(|&(_lo, hi)| hi > base_lsn) {
+                    salvage.damaged.insert(pid);
+                }
+                salvage.pages.insert(pid, page);
+            }
+            Err(_) => {
+                salvage.lost.insert(
+                    pid,
+                    "the fragments of the page could not be merged".to_owned(),
+                );
+            }
+        }
+    }
+
+    Ok(salvage)
+}
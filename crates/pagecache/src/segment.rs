@@ -88,6 +88,16 @@ pub(super) struct SegmentAccountant {
     tip: LogId,
     to_clean: VecSet<LogId>,
     pause_rewriting: bool,
+    // the number of outstanding `PageView`s, which
+    // require that old segments and blobs are neither
+    // reused nor removed until they are dropped.
+    read_views: usize,
+    held_blob_removals: Vec<BlobPointer>,
+    // the lowest `Lsn` that a registered log consumer
+    // has not yet acknowledged. Segments containing
+    // messages at or above it are neither reused nor
+    // truncated, so that they may be replayed.
+    retain_from: Option<Lsn>,
     safety_buffer: Vec<LogId>,
     ordering: BTreeMap<Lsn, LogId>,
     async_truncations: Vec<Oneshot<Result<()>>>,
@@ -230,6 +240,7 @@ impl Segment {
         lsn: Lsn,
         from_recovery: bool,
         config: &Config,
+        held_blobs: Option<&mut Vec<BlobPointer>>,
     ) -> Result<FastSet8<(PageId, usize)>> {
         trace!("setting Segment with lsn {:?} to Inactive", self.lsn());
         assert_eq!(
@@ -249,14 +260,18 @@ impl Segment {
         // now we can push any deferred blob removals to the removed set
         let deferred_rm_blob =
             mem::replace(&mut self.deferred_rm_blob, FastSet8::default());
-        for ptr in deferred_rm_blob {
-            trace!(
-                "removing blob {} while transitioning \
-                 segment lsn {:?} to Inactive",
-                ptr,
-                self.lsn,
-            );
-            remove_blob(ptr, config)?;
+        if let Some(held_blobs) = held_blobs {
+            held_blobs.extend(deferred_rm_blob);
+        } else {
+            for ptr in deferred_rm_blob {
+                trace!(
+                    "removing blob {} while transitioning \
+                     segment lsn {:?} to Inactive",
+                    ptr,
+                    self.lsn,
+                );
+                remove_blob(ptr, config)?;
+            }
         }
 
         let deferred_replacements =
@@ -366,6 +381,7 @@ impl Segment {
         &mut self,
         blob_ptr: BlobPointer,
         config: &Config,
+        held_blobs: Option<&mut Vec<BlobPointer>>,
     ) -> Result<()> {
         match self.state {
             Active => {
@@ -374,6 +390,9 @@ impl Segment {
                 // we defer this pid's removal until the transfer.
                 self.deferred_rm_blob.insert(blob_ptr);
             }
+            Inactive | Draining if held_blobs.is_some() => {
+                held_blobs.unwrap().push(blob_ptr);
+            }
             Inactive | Draining => {
                 trace!(
                     "directly removing blob {} that was referred-to \
@@ -424,11 +443,16 @@ impl SegmentAccountant {
             tip: 0,
             to_clean: Default::default(),
             pause_rewriting: false,
+            read_views: 0,
+            held_blob_removals: vec![],
+            retain_from: None,
             safety_buffer: vec![],
             ordering: BTreeMap::new(),
             async_truncations: Vec::new(),
         };
 
+        ret.retain_from = LogRetention::read(&ret.config)?.floor();
+
         if let SegmentMode::Linear = ret.config.segment_mode {
             // this is a hack to prevent segments from being overwritten
             // when operating without a `PageCache`
@@ -578,7 +602,12 @@ impl SegmentAccountant {
 
             if let Some(lsn) = segment.lsn {
                 if lsn != highest_lsn && segment.state == Active {
-                    segment.active_to_inactive(lsn, true, &self.config)?;
+                    segment.active_to_inactive(
+                        lsn,
+                        true,
+                        &self.config,
+                        None,
+                    )?;
                 }
 
                 self.ordering.insert(lsn, segment_start);
@@ -739,7 +768,7 @@ impl SegmentAccountant {
         // segments during recovery.
         self.ensure_safe_free_distance(lid);
 
-        if in_recovery {
+        if in_recovery && !self.segment_retained(lid) {
             // We only want to immediately remove the segment
             // mapping if we're in recovery because otherwise
             // we may be acting on updates relating to things
@@ -774,6 +803,100 @@ impl SegmentAccountant {
         }
     }
 
+    /// Prevents segments from being reused or truncated, and
+    /// blobs from being removed, until the matching call to
+    /// `unpin_read_view`. This keeps every `DiskPtr` that is
+    /// currently reachable valid for the lifetime of a `PageView`.
+    pub(super) fn pin_read_view(&mut self) {
+        self.read_views += 1;
+    }
+
+    /// Releases a read view pinned by `pin_read_view`, removing
+    /// any blobs that were held while it was outstanding.
+    pub(super) fn unpin_read_view(&mut self) -> Result<()> {
+        assert!(self.read_views > 0, "unbalanced unpin_read_view call");
+        self.read_views -= 1;
+
+        self.release_held_blobs()
+    }
+
+    /// Sets the lowest `Lsn` that must remain readable through
+    /// `iter_from` for registered log consumers, or `None` if
+    /// there are no consumers left.
+    pub(super) fn set_retention(&mut self, floor: Option<Lsn>) -> Result<()> {
+        self.retain_from = floor;
+        self.release_held_blobs()
+    }
+
+    /// Removes the blobs that no recovered page refers to,
+    /// which were held for a read view or a log consumer
+    /// when the system last shut down. The ones that a log
+    /// consumer may still replay are held again until it
+    /// advances past them.
+    pub(super) fn release_orphaned_blobs(
+        &mut self,
+        orphans: Vec<BlobPointer>,
+    ) -> Result<()> {
+        self.held_blob_removals.extend(orphans);
+        self.release_held_blobs()
+    }
+
+    // Removes held blobs that are no longer needed by
+    // either an outstanding read view or a log consumer.
+    // NB blobs held at shutdown are removed during the
+    // next recovery by `release_orphaned_blobs`.
+    fn release_held_blobs(&mut self) -> Result<()> {
+        if self.read_views > 0 {
+            return Ok(());
+        }
+
+        let retain_from = self.retain_from;
+        let (release, held): (Vec<_>, Vec<_>) =
+            mem::replace(&mut self.held_blob_removals, vec![])
+                .into_iter()
+                .partition(|&ptr| match retain_from {
+                    Some(floor) => ptr < floor,
+                    None => true,
+                });
+
+        self.held_blob_removals = held;
+
+        for ptr in release {
+            trace!("removing held blob {}", ptr);
+            remove_blob(ptr, &self.config)?;
+        }
+
+        Ok(())
+    }
+
+    fn holding_blobs(&self) -> bool {
+        self.read_views > 0 || self.retain_from.is_some()
+    }
+
+    // Returns true if the segment at this offset may
+    // contain messages that a log consumer still needs.
+    fn segment_retained(&self, lid: LogId) -> bool {
+        let floor = if let Some(floor) = self.retain_from {
+            floor
+        } else {
+            return false;
+        };
+
+        let io_buf_size = self.config.io_buf_size as Lsn;
+
+        // NB only segments that end after the floor are retained,
+        // so the older entries of the ordering can be skipped.
+        let first_retained = floor.saturating_sub(io_buf_size) + 1;
+
+        self.ordering
+            .range(first_retained..)
+            .any(|(_lsn, &l)| l == lid)
+    }
+
+    fn rewriting_paused(&self) -> bool {
+        self.pause_rewriting || self.read_views > 0
+    }
+
     /// Called by the `PageCache` when a page has been rewritten completely.
     /// We mark all of the old segments that contained the previous state
     /// from the page, and if the old segments are empty or clear enough to
@@ -785,7 +908,8 @@ impl SegmentAccountant {
         old_ptrs: Vec<DiskPtr>,
         new_ptr: DiskPtr,
     ) -> Result<()> {
-        let _measure = Measure::new(&M.accountant_mark_replace);
+        let metrics = self.config.metrics.clone();
+        let _measure = Measure::new(&metrics.accountant_mark_replace);
 
         trace!(
             "mark_replace pid {} from ptrs {:?} to ptr {} with lsn {}",
@@ -820,8 +944,16 @@ impl SegmentAccountant {
                     "queueing blob removal for {} in our own segment",
                     old_ptr
                 );
-                self.segments[new_idx]
-                    .remove_blob(old_ptr.blob().1, &self.config)?;
+                let held_blobs = if self.holding_blobs() {
+                    Some(&mut self.held_blob_removals)
+                } else {
+                    None
+                };
+                self.segments[new_idx].remove_blob(
+                    old_ptr.blob().1,
+                    &self.config,
+                    held_blobs,
+                )?;
             }
 
             let old_idx = self.lid_to_idx(old_lid);
@@ -947,7 +1079,8 @@ impl SegmentAccountant {
     /// to a logical page at a particular offset. We ensure the
     /// page is present in the segment's page set.
     pub(super) fn mark_link(&mut self, pid: PageId, lsn: Lsn, ptr: DiskPtr) {
-        let _measure = Measure::new(&M.accountant_mark_link);
+        let metrics = self.config.metrics.clone();
+        let _measure = Measure::new(&metrics.accountant_mark_link);
 
         trace!("mark_link pid {} at ptr {}", pid, ptr);
         let idx = self.lid_to_idx(ptr.lid());
@@ -985,8 +1118,17 @@ impl SegmentAccountant {
         let lid = self.ordering[&lsn];
         let idx = self.lid_to_idx(lid);
 
-        let replacements =
-            self.segments[idx].active_to_inactive(lsn, false, &self.config)?;
+        let held_blobs = if self.holding_blobs() {
+            Some(&mut self.held_blob_removals)
+        } else {
+            None
+        };
+        let replacements = self.segments[idx].active_to_inactive(
+            lsn,
+            false,
+            &self.config,
+            held_blobs,
+        )?;
 
         let mut old_segments = FastSet8::default();
 
@@ -1126,7 +1268,8 @@ impl SegmentAccountant {
 
     /// Returns the next offset to write a new segment in.
     pub(super) fn next(&mut self, lsn: Lsn) -> Result<LogId> {
-        let _measure = Measure::new(&M.accountant_next);
+        let metrics = self.config.metrics.clone();
+        let _measure = Measure::new(&metrics.accountant_next);
 
         assert_eq!(
             lsn % self.config.io_buf_size as Lsn,
@@ -1136,12 +1279,13 @@ impl SegmentAccountant {
 
         // truncate if possible
         loop {
-            if self.tip == 0 {
+            if self.tip == 0 || self.read_views > 0 {
                 break;
             }
             let last_segment = self.tip - self.config.io_buf_size as LogId;
             if self.free.get(&last_segment) == Some(&false)
                 && !self.safety_buffer.contains(&last_segment)
+                && !self.segment_retained(last_segment)
             {
                 self.free.remove(&last_segment);
                 self.truncate(last_segment)?;
@@ -1154,12 +1298,14 @@ impl SegmentAccountant {
         let safe = self
             .free
             .keys()
-            .filter(|l| !self.safety_buffer.contains(l))
+            .filter(|l| {
+                !self.safety_buffer.contains(l) && !self.segment_retained(**l)
+            })
             .take(1)
             .cloned()
             .nth(0);
 
-        let lid = if self.pause_rewriting || safe.is_none() {
+        let lid = if self.rewriting_paused() || safe.is_none() {
             self.bump_tip()
         } else {
             let next = safe.unwrap();
@@ -1230,7 +1376,7 @@ impl SegmentAccountant {
         }
 
         assert!(
-            self.pause_rewriting,
+            self.rewriting_paused(),
             "must pause rewriting before \
              iterating over segments"
         );
@@ -1322,6 +1468,39 @@ impl SegmentAccountant {
     fn segment_in_free(&self, lid: LogId) -> bool {
         self.free.contains_key(&lid)
     }
+
+    /// Adds the state and estimated utilization of every
+    /// segment to `stats`.
+    pub(super) fn storage_stats(&self, stats: &mut StorageStats) {
+        let segment_bytes = self.config.io_buf_size as u64;
+        stats.segment_bytes = segment_bytes;
+
+        for segment in &self.segments {
+            let live = segment.live_pct() as u64;
+            let live_bytes = segment_bytes * live / 100;
+
+            match segment.state {
+                Free => {
+                    stats.free_segments += 1;
+                    stats.reclaimable_bytes += segment_bytes;
+                    continue;
+                }
+                Active => {
+                    stats.active_segments += 1;
+                    stats.live_bytes += live_bytes;
+                    continue;
+                }
+                Inactive => stats.inactive_segments += 1,
+                Draining => stats.draining_segments += 1,
+            }
+
+            stats.live_bytes += live_bytes;
+            stats.reclaimable_bytes += segment_bytes - live_bytes;
+
+            let bucket = std::cmp::min(live as usize / 10, 9);
+            stats.utilization[bucket] += 1;
+        }
+    }
 }
 
 // Scan the log file if we don't know of any Lsn offsets yet,
@@ -1534,6 +1713,7 @@ pub(super) fn raw_segment_iter_from(
         segment_base: None,
         segment_iter,
         trailer: None,
+        batch_end: 0,
     })
 }
 
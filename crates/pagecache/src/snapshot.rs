@@ -71,6 +71,7 @@ impl Snapshot {
         lsn: Lsn,
         disk_ptr: DiskPtr,
         bytes: &[u8],
+        remove_blobs_below: Option<Lsn>,
         config: &Config,
     ) -> Result<()>
     where
@@ -144,6 +145,7 @@ impl Snapshot {
                     pid,
                     replaced_at_segment_lsn,
                     replaced_at_idx,
+                    remove_blobs_below,
                     config,
                 );
                 self.pt
@@ -160,6 +162,7 @@ impl Snapshot {
                     pid,
                     replaced_at_segment_lsn,
                     replaced_at_idx,
+                    remove_blobs_below,
                     config,
                 );
                 self.pt.insert(pid, PageState::Free(lsn, disk_ptr));
@@ -175,6 +178,7 @@ impl Snapshot {
         pid: PageId,
         replaced_at_segment_lsn: Lsn,
         replaced_at_idx: usize,
+        remove_blobs_below: Option<Lsn>,
         config: &Config,
     ) {
         let replacements = self
@@ -198,25 +202,29 @@ impl Snapshot {
                 // they were not completed. blobs are
                 // not rewritten if they are the only
                 // frag for a page during rewrite.
-                if coords.len() > 1 {
-                    let blob_ptrs = coords
-                        .iter()
-                        .filter(|(_, ptr)| ptr.is_blob())
-                        .map(|(_, ptr)| ptr.blob().1);
-
-                    for blob_ptr in blob_ptrs {
-                        trace!(
-                            "removing blob while advancing \
-                             snapshot: {}",
-                            blob_ptr,
-                        );
+                let floor = match remove_blobs_below {
+                    Some(floor) if coords.len() > 1 => floor,
+                    _ => return,
+                };
+
+                let blob_ptrs = coords
+                    .iter()
+                    .filter(|(_, ptr)| ptr.is_blob())
+                    .map(|(_, ptr)| ptr.blob().1)
+                    .filter(|&blob_ptr| blob_ptr < floor);
+
+                for blob_ptr in blob_ptrs {
+                    trace!(
+                        "removing blob while advancing \
+                         snapshot: {}",
+                        blob_ptr,
+                    );
 
-                        // we don't care if this actually works
-                        // because it's possible that a previous
-                        // snapshot has run over this log and
-                        // removed the blob already.
-                        let _ = remove_blob(blob_ptr, config);
-                    }
+                    // we don't care if this actually works
+                    // because it's possible that a previous
+                    // snapshot has run over this log and
+                    // removed the blob already.
+                    let _ = remove_blob(blob_ptr, config);
                 }
             }
             Some(PageState::Free(_lsn, ptr)) => {
@@ -240,12 +248,13 @@ pub(super) fn advance_snapshot<PM, P>(
     iter: LogIter,
     mut snapshot: Snapshot,
     config: &Config,
+    recovering: bool,
 ) -> Result<Snapshot>
 where
     PM: Materializer<PageFrag = P>,
     P: 'static + Debug + Clone + Serialize + DeserializeOwned + Send + Sync,
 {
-    let _measure = Measure::new(&M.advance_snapshot);
+    let _measure = Measure::new(&config.metrics.advance_snapshot);
 
     trace!("building on top of old snapshot: {:?}", snapshot);
 
@@ -253,6 +262,18 @@ where
 
     let mut last_seg_lsn = snapshot.max_lsn / io_buf_size as Lsn;
 
+    // NB while the system is running, the `SegmentAccountant`
+    // removes the blobs of replaced pages itself, and holds
+    // the ones that an outstanding read view may still read.
+    // During recovery, blobs that a log consumer may still
+    // replay are held again by `release_orphaned_blobs`.
+    let remove_blobs_below = if recovering {
+        let floor = LogRetention::read(config)?.floor();
+        Some(floor.unwrap_or(std::i64::MAX))
+    } else {
+        None
+    };
+
     for (lsn, ptr, bytes) in iter {
         trace!(
             "in advance_snapshot looking at item with lsn {} ptr {}",
@@ -288,7 +309,13 @@ where
         }
 
         if !PM::is_null() {
-            if let Err(e) = snapshot.apply::<P>(lsn, ptr, &*bytes, config) {
+            if let Err(e) = snapshot.apply::<P>(
+                lsn,
+                ptr,
+                &bytes,
+                remove_blobs_below,
+                config,
+            ) {
                 error!("encountered error while reading log message: {}", e);
                 break;
             }
@@ -342,11 +369,11 @@ where
 
     let log_iter = raw_segment_iter_from(last_snap.max_lsn, config)?;
 
-    advance_snapshot::<PM, P>(log_iter, last_snap, config)
+    advance_snapshot::<PM, P>(log_iter, last_snap, config, true)
 }
 
 /// Read a `Snapshot` from disk.
-fn read_snapshot(config: &Config) -> std::io::Result<Option<Snapshot>> {
+pub(super) fn read_snapshot(config: &Config) -> std::io::Result<Option<Snapshot>> {
     let mut candidates = config.get_snapshot_files()?;
     if candidates.is_empty() {
         debug!("no previous snapshot found");
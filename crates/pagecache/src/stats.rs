@@ -0,0 +1,145 @@
+//! Statistics about the space that a database uses on disk,
+//! and how much of it could be reclaimed by cleaning its
+//! segments.
+use super::*;
+
+/// The space used by a database on disk, created by
+/// `PageCache::storage_stats`.
+///
+/// The `SegmentAccountant` tracks which pages each segment
+/// still holds fragments of, rather than their sizes, so the
+/// live and reclaimable bytes of segments are estimated from
+/// the fraction of their pages that have not been relocated.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StorageStats {
+    /// The size of the log file, in bytes.
+    pub file_bytes: u64,
+    /// The size of each segment of the log file, in bytes.
+    pub segment_bytes: u64,
+    /// The number of segments that are free for reuse.
+    pub free_segments: usize,
+    /// The number of segments that are being written to.
+    pub active_segments: usize,
+    /// The number of segments that are no longer written to.
+    pub inactive_segments: usize,
+    /// The number of segments whose pages are being
+    /// relocated before they become free.
+    pub draining_segments: usize,
+    /// The number of segments that are neither free nor
+    /// active, by the percentage of their pages that are
+    /// still live: the first bucket counts segments that
+    /// are 0-9% live, and the last one those that are
+    /// 90-100% live.
+    pub utilization: [usize; 10],
+    /// The estimated number of bytes in segments that
+    /// belong to pages that have not been relocated.
+    pub live_bytes: u64,
+    /// The number of blob files.
+    pub blobs: usize,
+    /// The size of all blob files, in bytes.
+    pub blob_bytes: u64,
+    /// The number of allocated pages.
+    pub pages: u64,
+    /// The number of page ids that are free for reuse.
+    pub free_pids: usize,
+    /// The size of all snapshot files, in bytes.
+    pub snapshot_bytes: u64,
+    /// The estimated number of bytes of the log file that
+    /// hold no live data, in free segments or in the parts
+    /// of other segments whose pages were relocated.
+    pub reclaimable_bytes: u64,
+}
+
+impl StorageStats {
+    /// The total size of the log, blob and snapshot files,
+    /// in bytes.
+    pub fn total_bytes(&self) -> u64 {
+        self.file_bytes + self.blob_bytes + self.snapshot_bytes
+    }
+
+    /// The total number of segments in the log file.
+    pub fn segments(&self) -> usize {
+        self.free_segments
+            + self.active_segments
+            + self.inactive_segments
+            + self.draining_segments
+    }
+}
+
+impl fmt::Display for StorageStats {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> std::result::Result<(), fmt::Error> {
+        writeln!(
+            f,
+            "{} bytes in total: {} in the log file, {} in {} blobs \
+             and {} in snapshots",
+            self.total_bytes(),
+            self.file_bytes,
+            self.blob_bytes,
+            self.blobs,
+            self.snapshot_bytes,
+        )?;
+        writeln!(
+            f,
+            "{} segments of {} bytes: {} free, {} active, {} inactive \
+             and {} draining",
+            self.segments(),
+            self.segment_bytes,
+            self.free_segments,
+            self.active_segments,
+            self.inactive_segments,
+            self.draining_segments,
+        )?;
+        writeln!(
+            f,
+            "~{} live bytes and ~{} reclaimable bytes",
+            self.live_bytes, self.reclaimable_bytes,
+        )?;
+        writeln!(
+            f,
+            "{} pages and {} free pids",
+            self.pages, self.free_pids
+        )?;
+        for (i, count) in self.utilization.iter().enumerate() {
+            let high = if i == 9 { 100 } else { i * 10 + 9 };
+            writeln!(
+                f,
+                "{:>3}-{:>3}% live: {} segments",
+                i * 10,
+                high,
+                count,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Adds the sizes of the log file and of the blob and
+// snapshot files to `stats`. Blobs and snapshots may be
+// removed concurrently, so files that disappear while
+// being measured are skipped.
+pub(crate) fn measure_files(
+    config: &Config,
+    stats: &mut StorageStats,
+) -> Result<()> {
+    stats.file_bytes = config.file.metadata()?.len();
+
+    let blob_dir = config.blob_path(0);
+    let blob_dir = blob_dir.parent().unwrap();
+    for blob in std::fs::read_dir(blob_dir)? {
+        if let Ok(metadata) = blob?.metadata() {
+            stats.blobs += 1;
+            stats.blob_bytes += metadata.len();
+        }
+    }
+
+    for snapshot in config.get_snapshot_files()? {
+        if let Ok(metadata) = std::fs::metadata(snapshot) {
+            stats.snapshot_bytes += metadata.len();
+        }
+    }
+
+    Ok(())
+}
@@ -1,5 +1,7 @@
 use std::convert::TryInto;
 
+use crate::Config;
+
 #[cfg(feature = "compression")]
 use zstd::block::decompress;
 
@@ -23,7 +25,10 @@ pub(crate) fn u32_to_arr(number: u32) -> [u8; 4] {
     number.to_le_bytes()
 }
 
-pub(crate) fn maybe_decompress(buf: Vec<u8>) -> std::io::Result<Vec<u8>> {
+pub(crate) fn maybe_decompress(
+    buf: Vec<u8>,
+    config: &Config,
+) -> std::io::Result<Vec<u8>> {
     #[cfg(feature = "compression")]
     {
         use std::sync::atomic::AtomicUsize;
@@ -33,7 +38,7 @@ pub(crate) fn maybe_decompress(buf: Vec<u8>) -> std::io::Result<Vec<u8>> {
         static MAX_COMPRESSION_RATIO: AtomicUsize = AtomicUsize::new(1);
         use std::sync::atomic::Ordering::{Acquire, Release};
 
-        let _measure = Measure::new(&M.decompress);
+        let _measure = Measure::new(&config.metrics.decompress);
         loop {
             let ratio = MAX_COMPRESSION_RATIO.load(Acquire);
             match decompress(&*buf, buf.len() * ratio) {
@@ -57,5 +62,8 @@ pub(crate) fn maybe_decompress(buf: Vec<u8>) -> std::io::Result<Vec<u8>> {
     }
 
     #[cfg(not(feature = "compression"))]
-    Ok(buf)
+    {
+        let _ = config;
+        Ok(buf)
+    }
 }
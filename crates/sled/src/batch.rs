@@ -0,0 +1,191 @@
+//! Atomic batches of writes.
+use super::*;
+
+/// A batch of updates that will be applied
+/// atomically to a `Tree` using `Tree::apply_batch`.
+///
+/// Updates are applied in the order in which
+/// they were added to the batch.
+///
+/// # Examples
+///
+/// ```
+/// use sled::{Batch, ConfigBuilder, Db, IVec};
+///
+/// let config = ConfigBuilder::new().temporary(true).build();
+/// let db = Db::start(config).unwrap();
+/// db.set(b"k3", vec![3]).unwrap();
+///
+/// let mut batch = Batch::default();
+/// batch.set(b"k1", vec![1]);
+/// batch.set(b"k2", vec![2]);
+/// batch.del(b"k3");
+///
+/// db.apply_batch(batch).unwrap();
+///
+/// assert_eq!(db.get(b"k1"), Ok(Some(IVec::from(vec![1]))));
+/// assert_eq!(db.get(b"k2"), Ok(Some(IVec::from(vec![2]))));
+/// assert_eq!(db.get(b"k3"), Ok(None));
+/// ```
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Batch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum BatchOp {
+    Set(Key, IVec),
+    Del(Key),
+    Merge(Key, IVec),
+}
+
+impl BatchOp {
+    fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Set(key, _)
+            | BatchOp::Del(key)
+            | BatchOp::Merge(key, _) => key,
+        }
+    }
+}
+
+impl Batch {
+    /// Set a key to a new value.
+    pub fn set<K, V>(&mut self, key: K, value: V)
+    where
+        K: AsRef<[u8]>,
+        IVec: From<V>,
+    {
+        self.ops
+            .push(BatchOp::Set(key.as_ref().to_vec(), IVec::from(value)));
+    }
+
+    /// Remove a key.
+    pub fn del<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.ops.push(BatchOp::Del(key.as_ref().to_vec()));
+    }
+
+    /// Merge a value into a key using the merge
+    /// operator of the `Tree` that the batch is applied to.
+    pub fn merge<K, V>(&mut self, key: K, value: V)
+    where
+        K: AsRef<[u8]>,
+        IVec: From<V>,
+    {
+        self.ops
+            .push(BatchOp::Merge(key.as_ref().to_vec(), IVec::from(value)));
+    }
+
+    /// Returns the number of updates in this batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if this batch contains no updates.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl Tree {
+    /// Apply a `Batch` atomically. Either all of its
+    /// updates are recovered after a crash, or none
+    /// of them are. Other writers of the batch's keys
+    /// wait until it is applied. The batch is durable
+    /// when this returns, and subscribers are only
+    /// notified of its updates once it is. If an update fails,
+    /// the ones before it are undone before the error is
+    /// returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{Batch, ConfigBuilder, Db, Event};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    /// let mut events = db.watch_prefix(vec![]);
+    ///
+    /// let mut batch = Batch::default();
+    /// batch.set(b"a", vec![1]);
+    /// batch.del(b"b");
+    /// db.apply_batch(batch).unwrap();
+    ///
+    /// assert_eq!(
+    ///     events.next(),
+    ///     Some(Event::Set(b"a".to_vec(), vec![1].into()))
+    /// );
+    /// assert_eq!(events.next(), Some(Event::Del(b"b".to_vec())));
+    /// ```
+    pub fn apply_batch(&self, batch: Batch) -> Result<()> {
+        let _measure = Measure::new(&self.context.metrics.tree_apply_batch);
+
+        if self.context.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+
+        let has_merges = batch.ops.iter().any(|op| {
+            if let BatchOp::Merge(..) = op {
+                true
+            } else {
+                false
+            }
+        });
+
+        if has_merges {
+            self.merge_operator()?;
+        }
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut commit = self
+            .context
+            .concurrency_control
+            .commit(&self.context.pagecache)?;
+
+        let keys: Vec<(&Tree, &[u8])> =
+            batch.ops.iter().map(|op| (self, op.key())).collect();
+        commit.mark_keys(&keys)?;
+
+        // NB this fails before anything is written if an
+        // index of this tree has not been registered yet.
+        self.indexes()?;
+
+        let recovery_guard = self.context.pagecache.pin_log()?;
+        let mut deferred = DeferredEvents::default();
+
+        let mut res = Ok(());
+        for op in batch.ops {
+            res = match op {
+                BatchOp::Set(key, value) => {
+                    self.set_inner(&key, value, Some(&mut deferred)).map(|_| ())
+                }
+                BatchOp::Del(key) => {
+                    self.del_inner(&key, Some(&mut deferred)).map(|_| ())
+                }
+                BatchOp::Merge(key, value) => {
+                    self.merge_inner(&key, value, Some(&mut deferred))
+                }
+            };
+            if res.is_err() {
+                break;
+            }
+        }
+
+        // NB an error partway through undoes what was applied
+        let ((), last_lsn) = deferred.seal_or_undo(recovery_guard, res)?;
+
+        drop(commit);
+
+        // NB subscribers only hear of the batch once it is durable
+        self.context.pagecache.make_batch_stable(last_lsn)?;
+
+        deferred.complete();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+//! Checks the integrity of a sled database directory, which
+//! must not be in use by another process, and prints a report
+//! of the problems found.
+//!
+//! Usage: `sled-check <path>`
+//!
+//! Exits with 0 if no problems were found, 1 if some were,
+//! and 2 if the database could not be checked at all.
+use std::{panic, path::PathBuf, process};
+
+use sled::{ConfigBuilder, Db};
+
+fn main() {
+    let path = match std::env::args_os().nth(1) {
+        Some(path) => PathBuf::from(path),
+        None => {
+            eprintln!("usage: sled-check <path>");
+            process::exit(2);
+        }
+    };
+
+    // NB starting a `Db` creates the files of a new database
+    // where there are none, which would then pass the check.
+    if !path.join("conf").is_file() || !path.join("db").is_file() {
+        eprintln!("no database at {:?}", path);
+        process::exit(2);
+    }
+
+    let builder = match ConfigBuilder::new().path(&path).stored_options() {
+        Ok(builder) => builder.read_only(true).flush_every_ms(None),
+        Err(e) => {
+            eprintln!("failed to read the configuration at {:?}: {}", path, e);
+            process::exit(2);
+        }
+    };
+
+    // `ConfigBuilder::build` panics when the files of the
+    // database can not be opened.
+    let config = match panic::catch_unwind(|| builder.build()) {
+        Ok(config) => config,
+        Err(_) => {
+            eprintln!("failed to open the database files at {:?}", path);
+            process::exit(2);
+        }
+    };
+
+    let db = match Db::start(config) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("failed to open the database at {:?}: {}", path, e);
+            process::exit(2);
+        }
+    };
+
+    match db.verify_integrity() {
+        Ok(report) => {
+            print!("{}", report);
+            if !report.is_ok() {
+                process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("failed to check the database at {:?}: {}", path, e);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! Prints the on-disk structures of a sled database directory,
+//! which must not be in use by another process, for debugging
+//! recovery. Nothing is written to the database.
+//!
+//! Usage: `sled-inspect [--json] <path> <command>`, where
+//! `<command>` is one of:
+//!
+//! * `segments`: the segments of the log, with the `Lsn`s in
+//!   their headers and trailers, and their live percentage.
+//! * `messages [<lid>]`: the messages of every segment, or of
+//!   the segment at `<lid>`, by kind.
+//! * `page <pid>`: the fragments that the current state of the
+//!   page is made of.
+//! * `meta`: the trees in the `Meta`, and their roots.
+//! * `snapshot`: the contents of the last snapshot.
+//!
+//! Output is text, or JSON with `--json`. Exits with 0 if the
+//! command succeeded, and 2 if it did not.
+use std::{
+    fmt::{self, Write as _},
+    io::{self, Write as _},
+    path::PathBuf,
+    process,
+};
+
+use sled::{
+    ConfigBuilder, Db, FragInfo, Inspection, MessageInfo, SegmentInfo,
+    SnapshotInfo, Update,
+};
+
+const USAGE: &str = "usage: sled-inspect [--json] <path> \
+                     (segments | messages [<lid>] | page <pid> | meta | snapshot)";
+
+enum Command {
+    Segments,
+    Messages(Option<u64>),
+    Page(u64),
+    Meta,
+    Snapshot,
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let json = args.first().map(String::as_str) == Some("--json");
+    if json {
+        args.remove(0);
+    }
+
+    let (path, command) = match parse(&args) {
+        Some(parsed) => parsed,
+        None => exit(USAGE),
+    };
+
+    if !path.is_dir() {
+        exit(&format!("no database directory at {:?}", path));
+    }
+
+    let config = match ConfigBuilder::new().path(&path).stored_options() {
+        Ok(config) => config.build(),
+        Err(e) => exit(&format!(
+            "failed to read the configuration at {:?}: {}",
+            path, e
+        )),
+    };
+    let inspection = match Db::inspect(config) {
+        Ok(inspection) => inspection,
+        Err(e) => exit(&format!(
+            "failed to inspect the database at {:?}: {}",
+            path, e
+        )),
+    };
+
+    let output = match command {
+        Command::Segments => segments(&inspection, json),
+        Command::Messages(lid) => messages(&inspection, lid, json),
+        Command::Page(pid) => page(&inspection, pid, json),
+        Command::Meta => meta(&inspection, json),
+        Command::Snapshot => snapshot(&inspection, json),
+    };
+
+    match output {
+        // NB writing fails when the output is piped to a
+        // command that stops reading early, like `head`.
+        Ok(output) => {
+            let _ = io::stdout().write_all(output.as_bytes());
+        }
+        Err(e) => exit(&e),
+    }
+}
+
+fn parse(args: &[String]) -> Option<(PathBuf, Command)> {
+    let path = PathBuf::from(args.first()?);
+    let number = |idx: usize| args.get(idx).and_then(|arg| arg.parse().ok());
+
+    let command = match (args.get(1)?.as_str(), args.len()) {
+        ("segments", 2) => Command::Segments,
+        ("messages", 2) => Command::Messages(None),
+        ("messages", 3) => Command::Messages(Some(number(2)?)),
+        ("page", 3) => Command::Page(number(2)?),
+        ("meta", 2) => Command::Meta,
+        ("snapshot", 2) => Command::Snapshot,
+        _ => return None,
+    };
+
+    Some((path, command))
+}
+
+fn exit(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2);
+}
+
+fn segments(
+    inspection: &Inspection<FragInfo>,
+    json: bool,
+) -> Result<String, String> {
+    if json {
+        let segments = inspection.segments.iter().map(segment_json).collect();
+        return Ok(format!("{}\n", Json::Array(segments)));
+    }
+
+    let mut out = String::new();
+    for segment in &inspection.segments {
+        let trailer = match segment.trailer_lsn {
+            _ if segment.sealed => "sealed".to_owned(),
+            Some(lsn) => format!("stale trailer lsn {}", lsn),
+            None => "no trailer".to_owned(),
+        };
+        writeln!(
+            out,
+            "lid {} lsn {}: {}, {}% live, {} messages",
+            segment.lid,
+            segment.lsn,
+            trailer,
+            segment.live_pct,
+            segment.messages.len()
+        )
+        .unwrap();
+    }
+    Ok(out)
+}
+
+fn messages(
+    inspection: &Inspection<FragInfo>,
+    lid: Option<u64>,
+    json: bool,
+) -> Result<String, String> {
+    let segments: Vec<&SegmentInfo<FragInfo>> = inspection
+        .segments
+        .iter()
+        .filter(|segment| lid.is_none() || lid == Some(segment.lid))
+        .collect();
+
+    if let (Some(lid), true) = (lid, segments.is_empty()) {
+        return Err(format!("no segment at lid {}", lid));
+    }
+
+    if json {
+        let segments = segments
+            .into_iter()
+            .map(|segment| {
+                let mut object = segment_json(segment);
+                if let Json::Object(ref mut fields) = object {
+                    let messages =
+                        segment.messages.iter().map(message_json).collect();
+                    fields.push(("messages", Json::Array(messages)));
+                }
+                object
+            })
+            .collect();
+        return Ok(format!("{}\n", Json::Array(segments)));
+    }
+
+    let mut out = String::new();
+    for segment in segments {
+        writeln!(out, "segment at lid {} lsn {}", segment.lid, segment.lsn)
+            .unwrap();
+        for message in &segment.messages {
+            write!(
+                out,
+                "  lid {} lsn {}: {:?}, {} bytes",
+                message.lid, message.lsn, message.kind, message.len
+            )
+            .unwrap();
+            if let Some(blob) = message.blob {
+                write!(out, ", blob {}", blob).unwrap();
+            }
+            if let Some((pid, ref update)) = message.update {
+                write!(out, ", page {} {}", pid, describe_update(update))
+                    .unwrap();
+            }
+            if let Some(ref error) = message.error {
+                write!(out, ", error: {}", error).unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+    }
+    Ok(out)
+}
+
+fn page(
+    inspection: &Inspection<FragInfo>,
+    pid: u64,
+    json: bool,
+) -> Result<String, String> {
+    let chain = inspection.page(pid);
+    if chain.is_empty() {
+        return Err(format!("no messages hold page {}", pid));
+    }
+
+    if json {
+        let chain = chain.into_iter().map(message_json).collect();
+        return Ok(format!("{}\n", Json::Array(chain)));
+    }
+
+    let mut out = String::new();
+    for message in chain {
+        if let Some((_pid, ref update)) = message.update {
+            writeln!(
+                out,
+                "lid {} lsn {}: {}",
+                message.lid,
+                message.lsn,
+                describe_update(update)
+            )
+            .unwrap();
+        }
+    }
+    Ok(out)
+}
+
+fn meta(
+    inspection: &Inspection<FragInfo>,
+    json: bool,
+) -> Result<String, String> {
+    let (lsn, meta) = match inspection.meta() {
+        Some(meta) => meta,
+        None => return Err("no readable message holds the meta page".into()),
+    };
+
+    if json {
+        let tenants = tenants_json(&meta.tenants());
+        let object =
+            Json::Object(vec![("lsn", lsn.into()), ("tenants", tenants)]);
+        return Ok(format!("{}\n", object));
+    }
+
+    let mut out = String::new();
+    writeln!(out, "meta at lsn {}", lsn).unwrap();
+    for (name, root) in meta.tenants() {
+        writeln!(out, "  {:?}: root {}", String::from_utf8_lossy(&name), root)
+            .unwrap();
+    }
+    Ok(out)
+}
+
+fn snapshot(
+    inspection: &Inspection<FragInfo>,
+    json: bool,
+) -> Result<String, String> {
+    let snapshot = match inspection.snapshot {
+        Some(ref snapshot) => snapshot,
+        None => return Err("no readable snapshot was found".into()),
+    };
+
+    if json {
+        return Ok(format!("{}\n", snapshot_json(snapshot)));
+    }
+
+    Ok(format!(
+        "max_lsn: {}\nmax_trailer_stable_lsn: {}\nlast_lid: {}\n\
+         max_lid: {}\nmax_pid: {}\npages: {}\nfree: {:?}\n",
+        snapshot.max_lsn,
+        snapshot.max_trailer_stable_lsn,
+        snapshot.last_lid,
+        snapshot.max_lid,
+        snapshot.max_pid,
+        snapshot.pages,
+        snapshot.free
+    ))
+}
+
+fn describe_update(update: &Update<FragInfo>) -> String {
+    match update {
+        Update::Append(frag) => format!("Append {} {}", frag.kind, frag.detail),
+        Update::Compact(frag) => {
+            format!("Compact {} {}", frag.kind, frag.detail)
+        }
+        Update::Free => "Free".to_owned(),
+        Update::Counter(counter) => format!("Counter {}", counter),
+        Update::Meta(meta) => {
+            format!("Meta with {} trees", meta.tenants().len())
+        }
+    }
+}
+
+fn segment_json(segment: &SegmentInfo<FragInfo>) -> Json {
+    Json::Object(vec![
+        ("lid", segment.lid.into()),
+        ("lsn", segment.lsn.into()),
+        ("trailer_lsn", segment.trailer_lsn.into()),
+        (
+            "highest_known_stable_lsn",
+            segment.highest_known_stable_lsn.into(),
+        ),
+        ("sealed", segment.sealed.into()),
+        ("live_pct", u64::from(segment.live_pct).into()),
+        ("message_count", (segment.messages.len() as u64).into()),
+    ])
+}
+
+fn message_json(message: &MessageInfo<FragInfo>) -> Json {
+    let mut fields = vec![
+        ("lid", message.lid.into()),
+        ("lsn", message.lsn.into()),
+        ("kind", format!("{:?}", message.kind).into()),
+        ("len", (message.len as u64).into()),
+        ("blob", message.blob.into()),
+    ];
+
+    if let Some((pid, ref update)) = message.update {
+        fields.push(("pid", pid.into()));
+        let (kind, details) = match update {
+            Update::Append(frag) => ("Append", frag_json(frag)),
+            Update::Compact(frag) => ("Compact", frag_json(frag)),
+            Update::Free => ("Free", Json::Null),
+            Update::Counter(counter) => ("Counter", (*counter).into()),
+            Update::Meta(meta) => ("Meta", tenants_json(&meta.tenants())),
+        };
+        fields.push(("update", kind.to_owned().into()));
+        fields.push(("details", details));
+    }
+
+    fields.push(("error", message.error.clone().into()));
+
+    Json::Object(fields)
+}
+
+fn frag_json(frag: &FragInfo) -> Json {
+    Json::Object(vec![
+        ("kind", frag.kind.clone().into()),
+        ("detail", frag.detail.clone().into()),
+    ])
+}
+
+fn tenants_json(tenants: &std::collections::BTreeMap<Vec<u8>, u64>) -> Json {
+    Json::Array(
+        tenants
+            .iter()
+            .map(|(name, root)| {
+                Json::Object(vec![
+                    ("name", String::from_utf8_lossy(name).into_owned().into()),
+                    ("root", (*root).into()),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn snapshot_json(snapshot: &SnapshotInfo) -> Json {
+    Json::Object(vec![
+        ("max_lsn", snapshot.max_lsn.into()),
+        (
+            "max_trailer_stable_lsn",
+            snapshot.max_trailer_stable_lsn.into(),
+        ),
+        ("last_lid", snapshot.last_lid.into()),
+        ("max_lid", snapshot.max_lid.into()),
+        ("max_pid", snapshot.max_pid.into()),
+        ("pages", (snapshot.pages as u64).into()),
+        (
+            "free",
+            Json::Array(snapshot.free.iter().map(|&pid| pid.into()).collect()),
+        ),
+    ])
+}
+
+// Just enough JSON to print the values above.
+enum Json {
+    Null,
+    Bool(bool),
+    Number(i128),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Json {
+        Json::Number(i128::from(n))
+    }
+}
+
+impl From<u64> for Json {
+    fn from(n: u64) -> Json {
+        Json::Number(i128::from(n))
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(opt: Option<T>) -> Json {
+        opt.map_or(Json::Null, Into::into)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => {
+                f.write_char('"')?;
+                for c in s.chars() {
+                    match c {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        c if (c as u32) < 0x20 => {
+                            write!(f, "\\u{:04x}", c as u32)?
+                        }
+                        c => f.write_char(c)?,
+                    }
+                }
+                f.write_char('"')
+            }
+            Json::Array(items) => {
+                f.write_char('[')?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(fields) => {
+                f.write_char('{')?;
+                for (idx, (key, value)) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}:{}", Json::String((*key).to_owned()), value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
@@ -0,0 +1,452 @@
+//! Bulk loading of an empty `Tree` from keys in ascending order.
+//!
+//! Rather than inserting each key through `path_for_key` and
+//! splitting nodes as they fill up, the loader packs keys into
+//! leaves up to `blink_node_split_size`, and packs the low keys
+//! of each completed node into the index level above it, so
+//! every node is written to the log only once it is complete.
+//! Completed nodes are logged in batches of
+//! `BATCH_NODES`, and the tree only becomes visible once its
+//! root is installed in the `Meta`.
+use std::{mem::size_of, sync::atomic::Ordering::SeqCst};
+
+use super::*;
+
+// The number of completed nodes that are logged together.
+const BATCH_NODES: usize = 256;
+
+impl Tree {
+    /// Loads the keys and values of `iter`, which must be in
+    /// strictly ascending order of keys, into this empty `Tree`.
+    /// This is much faster than calling `set` for each key, as
+    /// packed leaf and index nodes are built and written to the
+    /// log directly, without any splits.
+    ///
+    /// The loaded keys become visible all at once, after every
+    /// node has been written, and the log is flushed before
+    /// returning. A crash before then leaves this `Tree` empty.
+    /// Subscribers are not notified of the loaded keys.
+    ///
+    /// Returns an `Error::Unsupported` if the keys are not in
+    /// strictly ascending order, or if this `Tree` is not empty,
+    /// has indexes, or has change feeds, including when it is
+    /// written to while the keys are loaded.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db, IVec};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    ///
+    /// let t = db.open_tree(b"numbers").unwrap();
+    /// t.bulk_load((0..1000_u32).map(|i| (i.to_be_bytes(), vec![1])))
+    ///     .unwrap();
+    ///
+    /// assert_eq!(t.len(), 1000);
+    /// assert_eq!(t.get(7_u32.to_be_bytes()), Ok(Some(IVec::from(vec![1]))));
+    ///
+    /// // only empty trees can be bulk loaded
+    /// assert!(t.bulk_load(vec![(b"k", vec![1])]).is_err());
+    /// ```
+    pub fn bulk_load<I, K, V>(&self, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        IVec: From<V>,
+    {
+        self.try_bulk_load(iter.into_iter().map(|(k, v)| Ok((k, v, None))))
+    }
+
+    // Like `bulk_load`, but stops and leaves this `Tree` empty
+    // at the first error yielded by `iter`, and loads values
+    // that expire at the time given with them, if any.
+    pub(crate) fn try_bulk_load<I, K, V>(&self, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Result<(K, V, Option<u64>)>>,
+        K: AsRef<[u8]>,
+        IVec: From<V>,
+    {
+        if self.context.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+
+        self.verify_bulk_loadable()?;
+
+        let mut loader = BulkLoader {
+            tree: self,
+            levels: vec![],
+            completed: vec![],
+            allocated: vec![],
+            last_key: None,
+            next_expiry: std::u64::MAX,
+        };
+
+        match loader.load(iter).and_then(|root| self.install_root(root)) {
+            Ok(leftmost_chain) => {
+                expiry::lower_next_expiry(
+                    &self.context.next_expiry,
+                    loader.next_expiry,
+                );
+                self.gc_pages(leftmost_chain)?;
+                self.context.pagecache.flush()?;
+                Ok(())
+            }
+            Err(e) => {
+                loader.free_pages()?;
+                Err(e)
+            }
+        }
+    }
+
+    fn verify_bulk_loadable(&self) -> Result<()> {
+        if self.iter().next().is_some() {
+            return Err(Error::Unsupported(
+                "only empty trees can be bulk loaded".to_owned(),
+            ));
+        }
+
+        if self.is_indexed()? {
+            return Err(Error::Unsupported(
+                "trees with indexes can not be bulk loaded, \
+                 but indexes registered afterwards are built \
+                 from the loaded keys"
+                    .to_owned(),
+            ));
+        }
+
+        // NB change feeds are named after their tree in the
+        // same way in `ChangeFeed::register`.
+        let mut feed_prefix =
+            (self.tree_id.len() as u64).to_be_bytes().to_vec();
+        feed_prefix.extend_from_slice(&self.tree_id);
+        let has_feeds = self
+            .context
+            .pagecache
+            .log_consumers()
+            .iter()
+            .any(|consumer| consumer.starts_with(&feed_prefix));
+
+        if has_feeds {
+            return Err(Error::Unsupported(
+                "trees with change feeds can not be bulk loaded, \
+                 as the feeds would not observe the loaded leaves"
+                    .to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Replaces the root of this empty tree with `root`, and
+    // returns the leftmost path of the old tree, to be freed
+    // with `gc_pages`.
+    fn install_root(&self, root: PageId) -> Result<Vec<PageId>> {
+        // NB writers are paused, so the tree can not be
+        // written to between checking that it is still
+        // empty and replacing its root.
+        let _pause = self.context.concurrency_control.pause();
+
+        self.verify_bulk_loadable()?;
+
+        let tx = self.context.pagecache.begin()?;
+
+        let old_root = self
+            .context
+            .pagecache
+            .meta_pid_for_name(&self.tree_id, &tx)?;
+
+        let leftmost_chain: Vec<PageId> = self
+            .path_for_key(b"", &tx)?
+            .into_iter()
+            .map(|(id, _frag, _tp)| id)
+            .collect();
+
+        let res = self.context.pagecache.cas_root_in_meta(
+            self.tree_id.clone(),
+            Some(old_root),
+            Some(root),
+            &tx,
+        )?;
+
+        if res.is_err() {
+            return Err(Error::Unsupported(
+                "the tree was concurrently removed or \
+                 restructured while it was bulk loaded"
+                    .to_owned(),
+            ));
+        }
+
+        self.root.store(root, SeqCst);
+
+        tx.flush();
+
+        Ok(leftmost_chain)
+    }
+}
+
+// An entry of a node being filled by the `BulkLoader`.
+enum Entry {
+    // a value, and the time at which it expires, if any
+    Value(IVec, Option<u64>),
+    Child(PageId),
+}
+
+// The node that is being filled at one level of the tree.
+struct Level {
+    pid: PageId,
+    node: Node,
+    size: u64,
+}
+
+struct BulkLoader<'a> {
+    tree: &'a Tree,
+    // from the leaves up to the root
+    levels: Vec<Level>,
+    // nodes that are complete, but not logged yet
+    completed: Vec<(PageId, Node)>,
+    // every page allocated so far, to free if the load fails
+    allocated: Vec<PageId>,
+    last_key: Option<IVec>,
+    // the earliest time at which a loaded value expires
+    next_expiry: u64,
+}
+
+impl<'a> BulkLoader<'a> {
+    // Writes every key and value of `iter` to leaves, and
+    // returns the pid of the root of the new tree.
+    fn load<I, K, V>(&mut self, iter: I) -> Result<PageId>
+    where
+        I: IntoIterator<Item = Result<(K, V, Option<u64>)>>,
+        K: AsRef<[u8]>,
+        IVec: From<V>,
+    {
+        for item in iter {
+            let (key, value, expires_at) = item?;
+            let key: IVec = key.as_ref().into();
+
+            if let Some(ref last_key) = self.last_key {
+                if *last_key >= key {
+                    return Err(Error::Unsupported(format!(
+                        "bulk_load requires keys in strictly ascending \
+                         order, but {:?} followed {:?}",
+                        key, last_key
+                    )));
+                }
+            }
+            self.last_key = Some(key.clone());
+
+            if let Some(at) = expires_at {
+                self.next_expiry = std::cmp::min(self.next_expiry, at);
+            }
+
+            self.push(0, key, Entry::Value(IVec::from(value), expires_at))?;
+        }
+
+        if self.levels.is_empty() {
+            let pid = self.reserve()?;
+            self.levels.push(Level::new(pid, Data::Leaf(vec![])));
+        }
+
+        // complete the rightmost node of each level, and add
+        // it to the level above. The root is always an index.
+        let mut height = 0;
+        loop {
+            let Level { pid, node, .. } = self.levels[height].take();
+            let lo = node.lo.clone();
+            self.write(pid, node)?;
+
+            if height > 0 && height + 1 == self.levels.len() {
+                self.write_completed()?;
+                return Ok(pid);
+            }
+
+            self.push(height + 1, lo, Entry::Child(pid))?;
+            height += 1;
+        }
+    }
+
+    // Adds an entry to the node being filled at `height`,
+    // completing that node first if the entry does not fit.
+    fn push(&mut self, height: usize, key: IVec, entry: Entry) -> Result<()> {
+        if self.levels.len() == height {
+            let data = match entry {
+                Entry::Value(..) => Data::Leaf(vec![]),
+                Entry::Child(_) => Data::Index(vec![]),
+            };
+            let pid = self.reserve()?;
+            self.levels.push(Level::new(pid, data));
+        }
+
+        // like `Data::size_in_bytes`, plus the key
+        // as the hi key of a completed node
+        let entry_size = match entry {
+            Entry::Value(ref value, _) => value.len() + size_of::<IVec>(),
+            Entry::Child(_) => size_of::<PageId>(),
+        } as u64
+            + 2 * key.len() as u64
+            + size_of::<IVec>() as u64;
+
+        // like `Node::size_in_bytes`, for an expiration time
+        let entry_size = match entry {
+            Entry::Value(_, Some(_)) => {
+                entry_size + key.size_in_bytes() + size_of::<u64>() as u64
+            }
+            _ => entry_size,
+        };
+
+        // nodes toward the root are larger, as in `recursive_split`
+        let max_size = (self.tree.context.blink_node_split_size as u64)
+            << std::cmp::min(height, 8);
+
+        let level = &self.levels[height];
+        if level.node.data.len() >= 2 && level.size + entry_size > max_size {
+            let next_pid = self.reserve()?;
+            let data = match entry {
+                Entry::Value(..) => Data::Leaf(vec![]),
+                Entry::Child(_) => Data::Index(vec![]),
+            };
+
+            let level = &mut self.levels[height];
+            let Level { pid, mut node, .. } = std::mem::replace(
+                level,
+                Level::new(next_pid, data).with_lo(key.clone()),
+            );
+            node.hi = key.clone();
+            node.next = Some(next_pid);
+
+            let lo = node.lo.clone();
+            self.write(pid, node)?;
+            self.push(height + 1, lo, Entry::Child(pid))?;
+        }
+
+        let level = &mut self.levels[height];
+        let encoded_key = prefix_encode(&level.node.lo, &key);
+        level.size += entry_size;
+        match (&mut level.node.data, entry) {
+            (Data::Leaf(ref mut records), Entry::Value(value, expires_at)) => {
+                records.push((encoded_key, value));
+
+                // NB keys arrive in ascending order, which keeps
+                // the expiries sorted by their decoded keys.
+                if let Some(at) = expires_at {
+                    level.node.expiries.push((key, at));
+                }
+            }
+            (Data::Index(ref mut ptrs), Entry::Child(pid)) => {
+                ptrs.push((encoded_key, pid));
+            }
+            _ => unreachable!("every level holds one kind of entry"),
+        }
+
+        Ok(())
+    }
+
+    // Allocates a placeholder page for a node, so that its
+    // pid is known to its left sibling before it is complete.
+    fn reserve(&mut self) -> Result<PageId> {
+        let tx = self.tree.context.pagecache.begin()?;
+        let placeholder = Level::new(0, Data::Leaf(vec![])).node;
+        let (pid, _ptr) = self
+            .tree
+            .context
+            .pagecache
+            .allocate(Frag::Base(placeholder), &tx)?;
+        self.allocated.push(pid);
+        Ok(pid)
+    }
+
+    fn write(&mut self, pid: PageId, node: Node) -> Result<()> {
+        self.completed.push((pid, node));
+
+        if self.completed.len() >= BATCH_NODES {
+            self.write_completed()?;
+        }
+
+        Ok(())
+    }
+
+    // Replaces the placeholders of the completed nodes with
+    // the nodes themselves, in one batch of log writes.
+    fn write_completed(&mut self) -> Result<()> {
+        let pagecache = &self.tree.context.pagecache;
+        let recovery_guard = pagecache.pin_log()?;
+        let tx = pagecache.begin()?;
+
+        for (pid, node) in self.completed.drain(..) {
+            let ptr = match pagecache.get(pid, &tx)? {
+                PageGet::Materialized(_frag, ptr) => ptr,
+                broken => {
+                    return Err(Error::ReportableBug(format!(
+                        "got non-base node while bulk loading: {:?}",
+                        broken
+                    )));
+                }
+            };
+
+            if pagecache.replace(pid, ptr, Frag::Base(node), &tx)?.is_err() {
+                return Err(Error::ReportableBug(format!(
+                    "the page {} of a bulk loaded tree was concurrently \
+                     modified before its root was installed",
+                    pid
+                )));
+            }
+        }
+
+        tx.flush();
+
+        recovery_guard.seal_batch()?;
+
+        Ok(())
+    }
+
+    fn free_pages(&mut self) -> Result<()> {
+        let pagecache = &self.tree.context.pagecache;
+        let tx = pagecache.begin()?;
+
+        for pid in self.allocated.drain(..) {
+            if let PageGet::Materialized(_frag, ptr) =
+                pagecache.get(pid, &tx)?
+            {
+                // NB nothing else can reach these pages, so
+                // freeing them can not conflict.
+                let _ = pagecache.free(pid, ptr, &tx)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Level {
+    fn new(pid: PageId, data: Data) -> Level {
+        let node = Node {
+            data,
+            next: None,
+            lo: vec![].into(),
+            hi: vec![].into(),
+            expiries: vec![],
+            merging_child: None,
+            merging: false,
+        };
+        let size = node.size_in_bytes();
+        Level { pid, node, size }
+    }
+
+    fn with_lo(mut self, lo: IVec) -> Level {
+        self.node.lo = lo;
+        self.size = self.node.size_in_bytes();
+        self
+    }
+
+    fn take(&mut self) -> Level {
+        let data = match self.node.data {
+            Data::Leaf(_) => Data::Leaf(vec![]),
+            Data::Index(_) => Data::Index(vec![]),
+        };
+        std::mem::replace(self, Level::new(self.pid, data))
+    }
+}
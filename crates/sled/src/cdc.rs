@@ -0,0 +1,384 @@
+//! Durable, resumable change feeds over a `Tree`.
+use std::sync::atomic::Ordering::SeqCst;
+
+use pagecache::{FastMap8, LogReplay, Replayed};
+
+use super::*;
+
+/// A change that was made to a `Tree`, as read from a
+/// `ChangeFeed`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// A new complete (key, value) pair
+    Set(Vec<u8>, IVec),
+    /// A merge operand that was logged by an older version
+    Merge(Vec<u8>, IVec),
+    /// A deleted key
+    Del(Vec<u8>),
+    /// Every key from the first one, up to but excluding the
+    /// second one if there is one, was deleted by a single
+    /// `Tree::delete_range`.
+    DelRange(Vec<u8>, Option<Vec<u8>>),
+}
+
+/// A `Change` read from a `ChangeFeed`, tagged with the
+/// `Lsn` of the log message that recorded it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    /// The position of this event in the log. Pass it to
+    /// `ChangeFeed::ack` once the event has been processed.
+    pub lsn: Lsn,
+    /// The change that was made to the `Tree`.
+    pub event: Change,
+}
+
+/// A named, durable consumer of the changes made to a `Tree`,
+/// created with `Tree::change_feed`.
+///
+/// Unlike a `Subscriber`, a `ChangeFeed` keeps its place across
+/// restarts: every change that has not been acknowledged with
+/// `ack` is replayed from the log by the next call to `iter`,
+/// even if the `Db` was restarted in the meantime. The log
+/// segments holding unacknowledged changes are not reused, so
+/// a feed that is never acknowledged causes the storage file
+/// to grow until it is removed with `remove`.
+///
+/// Only `Change::Set`, `Change::Del` and `Change::DelRange` are
+/// produced, in the order in which they were written, and a
+/// `merge` produces the value that its merge operator returned.
+/// Merges that were logged as operands by older versions, with
+/// the deprecated `ConfigBuilder::merge_operator`, are replayed
+/// as a `Change::Merge` of the operand.
+/// A range deletion produces a `Change::DelRange` for the part
+/// of the range covered by each leaf that it removed keys from.
+/// A change may be replayed more than once if it was not
+/// acknowledged before a crash, so consumers should apply
+/// them idempotently.
+///
+/// # Examples
+///
+/// ```
+/// use sled::{Change, ConfigBuilder, Db};
+///
+/// let config = ConfigBuilder::new().temporary(true).build();
+/// let db = Db::start(config).unwrap();
+///
+/// let feed = db.change_feed("replicator").unwrap();
+///
+/// db.set(b"a", vec![1]).unwrap();
+/// db.del(b"a").unwrap();
+///
+/// let changes: Vec<_> = feed.iter().unwrap().map(|c| c.unwrap()).collect();
+/// assert_eq!(changes[0].event, Change::Set(b"a".to_vec(), vec![1].into()));
+/// assert_eq!(changes[1].event, Change::Del(b"a".to_vec()));
+///
+/// // everything up to and including the delete was processed
+/// feed.ack(changes[1].lsn).unwrap();
+/// assert_eq!(feed.iter().unwrap().count(), 0);
+/// ```
+#[derive(Clone)]
+pub struct ChangeFeed {
+    tree: Tree,
+    consumer: Vec<u8>,
+}
+
+impl ChangeFeed {
+    pub(crate) fn register(tree: &Tree, name: &str) -> Result<ChangeFeed> {
+        if tree.context.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+
+        // the consumer name is scoped to its tree, and length
+        // prefixed so that no two (tree, name) pairs collide.
+        let mut consumer = (tree.tree_id.len() as u64).to_be_bytes().to_vec();
+        consumer.extend_from_slice(&tree.tree_id);
+        consumer.extend_from_slice(name.as_bytes());
+
+        // NB pausing writers ensures that every write either
+        // happens before the feed's starting position, or is
+        // replayed by it.
+        let _pause = tree.context.concurrency_control.pause();
+        let leaves = current_leaves(tree)?;
+        tree.context
+            .pagecache
+            .register_log_consumer(&consumer, &encode_leaves(&leaves))?;
+
+        Ok(ChangeFeed {
+            tree: tree.clone(),
+            consumer,
+        })
+    }
+
+    /// The `Lsn` from which the next call to `iter` replays
+    /// changes. Every change below it has been acknowledged.
+    pub fn position(&self) -> Result<Lsn> {
+        self.state().map(|(position, _leaves)| position)
+    }
+
+    // Returns our position, and the leaves of our
+    // tree as they were at that position.
+    fn state(&self) -> Result<(Lsn, Leaves)> {
+        let (position, leaves) = self
+            .tree
+            .context
+            .pagecache
+            .log_consumer(&self.consumer)
+            .ok_or_else(|| {
+                Error::Unsupported(
+                    "this change feed has been removed".to_owned(),
+                )
+            })?;
+
+        Ok((position, decode_leaves(&leaves)?))
+    }
+
+    /// Returns an iterator over the unacknowledged changes to
+    /// the `Tree`, up to the most recent stable write at the
+    /// time it was created. Flushes the log before returning.
+    pub fn iter(&self) -> Result<ChangeFeedIter> {
+        let (from, leaves) = self.state()?;
+        let replay = self.tree.context.pagecache.replay(from)?;
+
+        Ok(ChangeFeedIter { replay, leaves })
+    }
+
+    /// Acknowledges every change up to and including the one
+    /// at `lsn`, durably recording that it should not be
+    /// replayed again and allowing the log segments that only
+    /// hold acknowledged changes to be reused. Acknowledging a
+    /// change that is already acknowledged has no effect.
+    pub fn ack(&self, lsn: Lsn) -> Result<()> {
+        let next = lsn + 1;
+
+        let (position, mut leaves) = self.state()?;
+        if next <= position {
+            return Ok(());
+        }
+
+        // bring our leaves up to date with the new position
+        for item in self.tree.context.pagecache.replay(position)? {
+            let (lsn, pid, replayed) = item?;
+            if lsn >= next {
+                break;
+            }
+            track(&mut leaves, pid, replayed);
+        }
+
+        self.tree.context.pagecache.advance_log_consumer(
+            &self.consumer,
+            next,
+            &encode_leaves(&leaves),
+        )
+    }
+
+    /// Durably removes this change feed, releasing any log
+    /// segments that were only retained for it.
+    pub fn remove(self) -> Result<()> {
+        self.tree
+            .context
+            .pagecache
+            .remove_log_consumer(&self.consumer)?;
+        Ok(())
+    }
+}
+
+/// An iterator over the changes replayed by a `ChangeFeed`.
+/// While it is alive, the log segments that it reads from
+/// will not be reused.
+pub struct ChangeFeedIter {
+    replay: LogReplay<Frag>,
+    leaves: Leaves,
+}
+
+// The leaves of a tree, along with the low key that
+// the keys logged for each of them are prefix-encoded
+// against. Pages may be freed and reused by other trees,
+// so this is tracked as the log is replayed, rather than
+// read from the current tree.
+type Leaves = FastMap8<PageId, IVec>;
+
+// Applies a page update replayed from the log to `leaves`.
+// Returns the low key of the leaf and the fragment if it
+// wrote a key to one of our leaves.
+fn track(
+    leaves: &mut Leaves,
+    pid: PageId,
+    replayed: Replayed<Frag>,
+) -> Option<(IVec, Frag)> {
+    let frag = match replayed {
+        Replayed::Append(frag) => frag,
+        Replayed::Free => {
+            // the pid may be reused by any tree
+            leaves.remove(&pid);
+            return None;
+        }
+    };
+
+    let lo = leaves.get(&pid)?.clone();
+
+    match frag {
+        Frag::ChildSplit(ref split) => {
+            // the new right sibling is one of our leaves too
+            leaves.insert(split.to, split.at.clone());
+            None
+        }
+        Frag::Set(..)
+        | Frag::SetWithTtl(..)
+        | Frag::Del(..)
+        | Frag::Merge(..)
+        | Frag::DelRange(..) => Some((lo, frag)),
+        _ => None,
+    }
+}
+
+// Walks the current leaves of `tree` from left to right.
+// Writers must be paused while this runs.
+fn current_leaves(tree: &Tree) -> Result<Leaves> {
+    let pagecache = &tree.context.pagecache;
+    let tx = pagecache.begin()?;
+
+    let mut leaves = Leaves::default();
+
+    let mut pid = tree.root.load(SeqCst);
+
+    loop {
+        let node = match pagecache.get(pid, &tx)? {
+            PageGet::Materialized(frag, _ptr) => frag.unwrap_base(),
+            PageGet::Free(_) => {
+                // a leaf was merged into its left sibling, which
+                // readers may complete while writers are paused.
+                leaves.clear();
+                pid = tree.root.load(SeqCst);
+                continue;
+            }
+            broken => {
+                return Err(Error::ReportableBug(format!(
+                    "got non-base node while loading leaves: {:?}",
+                    broken
+                )));
+            }
+        };
+
+        match &node.data {
+            Data::Index(ptrs) => {
+                pid =
+                    ptrs.first().expect("index nodes should never be empty").1;
+            }
+            Data::Leaf(_) => {
+                leaves.insert(pid, node.lo.clone());
+
+                match node.next {
+                    Some(next) => pid = next,
+                    None => break,
+                }
+            }
+        }
+    }
+
+    tx.flush();
+
+    Ok(leaves)
+}
+
+// Each leaf is stored as its pid and the length of
+// its low key as big-endian `u64`s, then the low key.
+fn encode_leaves(leaves: &Leaves) -> Vec<u8> {
+    let mut buf = vec![];
+    for (pid, lo) in leaves {
+        buf.extend_from_slice(&pid.to_be_bytes());
+        buf.extend_from_slice(&(lo.len() as u64).to_be_bytes());
+        buf.extend_from_slice(lo);
+    }
+    buf
+}
+
+fn decode_leaves(mut buf: &[u8]) -> Result<Leaves> {
+    fn take_u64(buf: &mut &[u8]) -> Option<u64> {
+        if buf.len() < 8 {
+            return None;
+        }
+        let mut arr = [0; 8];
+        arr.copy_from_slice(&buf[..8]);
+        *buf = &buf[8..];
+        Some(u64::from_be_bytes(arr))
+    }
+
+    let corrupt = || {
+        Error::ReportableBug(
+            "the stored leaves of a change feed are corrupt".to_owned(),
+        )
+    };
+
+    let mut leaves = Leaves::default();
+
+    while !buf.is_empty() {
+        let pid = take_u64(&mut buf).ok_or_else(corrupt)?;
+        let len = take_u64(&mut buf).ok_or_else(corrupt)? as usize;
+        if buf.len() < len {
+            return Err(corrupt());
+        }
+        leaves.insert(pid, IVec::from(&buf[..len]));
+        buf = &buf[len..];
+    }
+
+    Ok(leaves)
+}
+
+impl Iterator for ChangeFeedIter {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (lsn, pid, replayed) = match self.replay.next()? {
+                Ok(next) => next,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let (lo, frag) = match track(&mut self.leaves, pid, replayed) {
+                Some(write) => write,
+                None => continue,
+            };
+
+            // NB the bounds of a range deletion are not encoded
+            // against the low key of the leaf.
+            if let Frag::DelRange(start, end) = frag {
+                let event = Change::DelRange(
+                    start.to_vec(),
+                    end.map(|end| end.to_vec()),
+                );
+                return Some(Ok(ChangeEvent { lsn, event }));
+            }
+
+            let encoded_key = match frag {
+                Frag::Set(ref k, _)
+                | Frag::SetWithTtl(ref k, ..)
+                | Frag::Del(ref k)
+                | Frag::Merge(ref k, _) => k.clone(),
+                _ => unreachable!(),
+            };
+
+            if encoded_key.is_empty() || encoded_key[0] as usize > lo.len() {
+                return Some(Err(Error::ReportableBug(format!(
+                    "replayed key {:?} at lsn {} can not be decoded \
+                     against the low key {:?} of leaf {}",
+                    encoded_key, lsn, lo, pid
+                ))));
+            }
+
+            let key = prefix_decode(&lo, &encoded_key);
+
+            let event = match frag {
+                Frag::Set(_, value) | Frag::SetWithTtl(_, value, _) => {
+                    Change::Set(key, value)
+                }
+                Frag::Merge(_, value) => Change::Merge(key, value),
+                Frag::Del(_) => Change::Del(key),
+                _ => unreachable!(),
+            };
+
+            return Some(Ok(ChangeEvent { lsn, event }));
+        }
+    }
+}
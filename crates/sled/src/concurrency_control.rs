@@ -0,0 +1,363 @@
+//! Keeps the writes of multi-key commits from interleaving
+//! with other writes, without making single-key writers take
+//! a lock.
+//!
+//! A commit marks the leaves that hold the keys it reads or
+//! writes by bumping their read timestamp (RTS) to its own
+//! timestamp. A writer checks the RTS of its leaf right before
+//! linking to it, from within a short section that is tracked
+//! by an epoch of its own, and waits for the commit to finish
+//! if the leaf is marked. Once its marks are set, a commit
+//! waits for every section that might not have observed them
+//! to end, so no writer can still be about to link to one of
+//! its leaves.
+//!
+//! Splits and merges carry the RTS of a leaf over to the
+//! leaves that take over its keys, and a commit locates and
+//! marks its leaves again until it finds all of them marked,
+//! so that its keys stay covered while the tree changes shape.
+//!
+//! A commit that must not be observed partially applied, like
+//! a range removal that writes to its leaves one at a time,
+//! also hides its leaves from readers. Readers check the RTS
+//! of their leaf from within a section as well, and the
+//! commit waits for the sections that began before it hid
+//! its leaves before it writes to any of them.
+//!
+//! Operations that need every writer to stop, like taking a
+//! `Snapshot` or building an index, pause them all at once in
+//! the same way.
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::SeqCst},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+};
+
+use pagecache::{Backoff, Collector, Guard, LocalHandle};
+
+use super::*;
+
+thread_local! {
+    // the handles of this thread on the sections of each
+    // `ConcurrencyControl` that it entered one of.
+    static SECTION_HANDLES: RefCell<Vec<(Weak<Collector>, LocalHandle)>> =
+        RefCell::default();
+
+    // set while this thread commits or pauses writers, so that
+    // its own writes are not held up by its marks.
+    static EXCLUSIVE: Cell<bool> = Cell::default();
+}
+
+pub(crate) struct ConcurrencyControl {
+    // NB iterators stay pinned to the default collector for
+    // as long as they live, so waiting for it to advance
+    // would hold commits up for just as long.
+    sections: Arc<Collector>,
+    exclusive: Mutex<()>,
+    // the timestamp of the commit in progress, or 0
+    committing: AtomicU64,
+    // set while readers must wait for the commit in progress
+    hiding: AtomicBool,
+    paused: AtomicBool,
+}
+
+impl Default for ConcurrencyControl {
+    fn default() -> ConcurrencyControl {
+        ConcurrencyControl {
+            sections: Arc::new(Collector::new()),
+            exclusive: Mutex::default(),
+            committing: AtomicU64::default(),
+            hiding: AtomicBool::default(),
+            paused: AtomicBool::default(),
+        }
+    }
+}
+
+impl ConcurrencyControl {
+    /// Enters a section, within which a writer checks that
+    /// it `may_write` to a leaf and links to it. Commits and
+    /// pauses wait for the sections that began before them.
+    pub(crate) fn section(&self) -> Guard {
+        self.with_section_handle(LocalHandle::pin)
+    }
+
+    /// Returns `false` if the page `pid` is marked by the
+    /// commit in progress, or if writers are paused. The caller
+    /// must then leave its section, `wait`, and try again.
+    pub(crate) fn may_write(
+        &self,
+        pagecache: &PageCache<BLinkMaterializer, Frag>,
+        pid: PageId,
+        tx: &Tx,
+    ) -> bool {
+        if EXCLUSIVE.with(Cell::get) {
+            return true;
+        }
+
+        if self.paused.load(SeqCst) {
+            return false;
+        }
+
+        let committing = self.committing.load(SeqCst);
+        if committing == 0 {
+            return true;
+        }
+
+        // NB transactions that began after this commit may
+        // have bumped the RTS above its timestamp.
+        match pagecache.get_page_rts(pid, tx) {
+            Some(rts) => rts < committing,
+            None => true,
+        }
+    }
+
+    /// Returns `false` if the page `pid` is hidden by the
+    /// commit in progress. The caller must then leave its
+    /// section, `wait`, and read it again.
+    pub(crate) fn may_read(
+        &self,
+        pagecache: &PageCache<BLinkMaterializer, Frag>,
+        pid: PageId,
+        tx: &Tx,
+    ) -> bool {
+        if EXCLUSIVE.with(Cell::get) || !self.hiding.load(SeqCst) {
+            return true;
+        }
+
+        match pagecache.get_page_rts(pid, tx) {
+            Some(rts) => rts < self.committing.load(SeqCst),
+            None => true,
+        }
+    }
+
+    /// Blocks until the commit or pause in progress is done.
+    pub(crate) fn wait(&self) {
+        drop(self.exclusive.lock().unwrap());
+    }
+
+    /// Starts a commit, which excludes other commits and pauses
+    /// until it is dropped. Writers are only held up at the
+    /// leaves that it marks.
+    pub(crate) fn commit<'a>(
+        &'a self,
+        pagecache: &'a PageCache<BLinkMaterializer, Frag>,
+    ) -> Result<Commit<'a>> {
+        let exclusive = self.exclusive.lock().unwrap();
+
+        // NB the timestamp is generated after excluding other
+        // commits, so that any RTS above it belongs to a
+        // transaction that read the leaf while we commit.
+        let ts = pagecache.generate_id()?;
+
+        self.committing.store(ts, SeqCst);
+        EXCLUSIVE.with(|e| e.set(true));
+
+        Ok(Commit {
+            cc: self,
+            pagecache,
+            ts,
+            marked: HashSet::new(),
+            unsynchronized: false,
+            _exclusive: exclusive,
+        })
+    }
+
+    /// Pauses every writer until the returned guard is dropped,
+    /// after waiting for the writes that are in progress.
+    pub(crate) fn pause(&self) -> Pause<'_> {
+        let exclusive = self.exclusive.lock().unwrap();
+
+        self.paused.store(true, SeqCst);
+        EXCLUSIVE.with(|e| e.set(true));
+
+        self.synchronize();
+
+        Pause {
+            cc: self,
+            _exclusive: exclusive,
+        }
+    }
+
+    // Blocks until every section that began before it ended.
+    fn synchronize(&self) {
+        let done = Arc::new(AtomicBool::new(false));
+
+        let guard = self.section();
+        let signal = done.clone();
+        guard.defer(move || signal.store(true, SeqCst));
+        guard.flush();
+        drop(guard);
+
+        let backoff = Backoff::new();
+        while !done.load(SeqCst) {
+            self.section().flush();
+            backoff.snooze();
+        }
+    }
+
+    fn with_section_handle<R>(&self, f: impl FnOnce(&LocalHandle) -> R) -> R {
+        SECTION_HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+
+            if let Some((_, handle)) = handles
+                .iter()
+                .find(|(_, handle)| *handle.collector() == *self.sections)
+            {
+                return f(handle);
+            }
+
+            // NB the handles of a dropped `ConcurrencyControl`
+            // are only released once this thread enters the
+            // sections of a new one, or exits.
+            handles.retain(|(sections, _)| sections.upgrade().is_some());
+
+            let handle = self.sections.register();
+            let ret = f(&handle);
+            handles.push((Arc::downgrade(&self.sections), handle));
+            ret
+        })
+    }
+}
+
+/// A commit in progress. See the module docs.
+pub(crate) struct Commit<'a> {
+    cc: &'a ConcurrencyControl,
+    pagecache: &'a PageCache<BLinkMaterializer, Frag>,
+    ts: u64,
+    marked: HashSet<PageId>,
+    unsynchronized: bool,
+    _exclusive: MutexGuard<'a, ()>,
+}
+
+impl<'a> Commit<'a> {
+    pub(crate) fn ts(&self) -> u64 {
+        self.ts
+    }
+
+    /// Marks the leaves of `keys`, and returns once no writer
+    /// other than this commit can write to them anymore.
+    pub(crate) fn mark_keys(&mut self, keys: &[(&Tree, &[u8])]) -> Result<()> {
+        loop {
+            let tx = self.pagecache.begin()?;
+
+            for (tree, key) in keys {
+                let path = tree.path_for_key(key, &tx)?;
+                let (leaf_id, _leaf_frag, _leaf_ptr) = path
+                    .last()
+                    .expect("path should always contain a last element");
+                self.mark(*leaf_id, &tx);
+            }
+
+            tx.flush();
+
+            if !self.synchronize() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Marks every leaf of `tree` that holds keys between
+    /// `start` and the exclusive `end`, like `mark_keys`.
+    pub(crate) fn mark_range(
+        &mut self,
+        tree: &Tree,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> Result<()> {
+        loop {
+            let tx = self.pagecache.begin()?;
+            let mut cursor = start.to_vec();
+
+            loop {
+                let path = tree.path_for_key(&cursor, &tx)?;
+                let (leaf_id, leaf_frag, _leaf_ptr) = path
+                    .last()
+                    .expect("path should always contain a last element");
+                self.mark(*leaf_id, &tx);
+
+                let node: &Node = leaf_frag.unwrap_base();
+
+                // a leaf with an empty hi key is the last one
+                let done = node.hi.is_empty()
+                    || match end {
+                        Some(end) => *node.hi >= *end,
+                        None => false,
+                    };
+                if done {
+                    break;
+                }
+
+                cursor = node.hi.to_vec();
+            }
+
+            tx.flush();
+
+            if !self.synchronize() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Makes readers of the marked leaves wait until this
+    /// commit is done, and returns once every reader that
+    /// may have missed that is done reading.
+    pub(crate) fn hide(&mut self) {
+        self.cc.hiding.store(true, SeqCst);
+        self.cc.synchronize();
+    }
+
+    fn mark(&mut self, pid: PageId, tx: &Tx) {
+        self.pagecache.bump_page_rts(pid, self.ts as Lsn, tx);
+
+        if self.marked.insert(pid) {
+            self.unsynchronized = true;
+        }
+    }
+
+    // Waits for the writers that may have missed our latest
+    // marks, and returns `false` if there were none.
+    fn synchronize(&mut self) -> bool {
+        if !self.unsynchronized {
+            return false;
+        }
+
+        self.cc.synchronize();
+        self.unsynchronized = false;
+
+        true
+    }
+}
+
+impl<'a> Drop for Commit<'a> {
+    fn drop(&mut self) {
+        self.cc.hiding.store(false, SeqCst);
+        self.cc.committing.store(0, SeqCst);
+        EXCLUSIVE.with(|e| e.set(false));
+    }
+}
+
+/// Writers are paused while this is alive.
+pub(crate) struct Pause<'a> {
+    cc: &'a ConcurrencyControl,
+    _exclusive: MutexGuard<'a, ()>,
+}
+
+impl<'a> Drop for Pause<'a> {
+    fn drop(&mut self) {
+        self.cc.paused.store(false, SeqCst);
+        EXCLUSIVE.with(|e| e.set(false));
+    }
+}
+
+#[test]
+fn sections_are_per_concurrency_control() {
+    let a = ConcurrencyControl::default();
+    let b = ConcurrencyControl::default();
+
+    // a section of one does not hold up the other
+    let _section = a.section();
+    b.synchronize();
+}
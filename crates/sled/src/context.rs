@@ -1,4 +1,9 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicU64, Arc, Mutex, RwLock},
+};
+
+use crate::concurrency_control::ConcurrencyControl;
 
 use super::*;
 
@@ -14,6 +19,18 @@ pub(crate) struct Context {
     /// should trigger all background threads to clean
     /// up synchronously.
     pub(crate) _flusher: Arc<Mutex<Option<flusher::Flusher>>>,
+    /// Keeps the writes of transactions and batches from
+    /// interleaving with other writes to the leaves that
+    /// they touch, and pauses all writers when needed.
+    pub(crate) concurrency_control: Arc<ConcurrencyControl>,
+    /// The earliest time at which a value that was set with
+    /// a TTL, and that has not been swept by the `Flusher`
+    /// yet, expires. Starts at 0 so that expired values which
+    /// were recovered from a previous process get swept too.
+    pub(crate) next_expiry: Arc<AtomicU64>,
+    /// The merge operators that were registered with this
+    /// `Db`, by the name that trees record them under.
+    pub(crate) merge_operators: Arc<RwLock<HashMap<String, MergeOperator>>>,
     pub(crate) pagecache: Arc<PageCache<BLinkMaterializer, Frag>>,
 }
 
@@ -51,6 +68,12 @@ impl Drop for Context {
 
 impl Context {
     pub(crate) fn start(config: Config) -> Result<Context> {
+        // NB these have to happen before anything is read from
+        // the log, which may be in another format or hold merges
+        // that need the operator.
+        config.verify_format_version()?;
+        config.verify_merge_operator()?;
+
         #[cfg(any(test, feature = "check_snapshot_integrity"))]
         match config.verify_snapshot::<BLinkMaterializer, Frag>() {
             Ok(_) => {}
@@ -60,11 +83,15 @@ impl Context {
         }
 
         let pagecache = Arc::new(PageCache::start(config.clone())?);
+        let merge_operators = merge::config_merge_operators(&config);
 
         Ok(Context {
             config,
             pagecache,
             _flusher: Arc::new(Mutex::new(None)),
+            concurrency_control: Arc::default(),
+            next_expiry: Arc::new(AtomicU64::new(0)),
+            merge_operators: Arc::new(RwLock::new(merge_operators)),
         })
     }
 
@@ -0,0 +1,187 @@
+//! A repositionable cursor over the keys of a `Tree`.
+use std::ops;
+
+use super::*;
+
+/// A cursor over the keys and values of a `Tree`, created with
+/// `Tree::cursor`, which can be moved in both directions and
+/// repositioned with `seek` and `seek_for_prev`.
+///
+/// A cursor keeps the leaf that it is positioned in, so moving
+/// it to a neighbouring key does not traverse the tree again.
+/// Like an `Iter`, it holds a `Tx` for as long as it lives,
+/// which keeps the pages that it has read from being reclaimed.
+/// Use `refresh` to release it and read the current key again,
+/// when a cursor is held for a long time, for example between
+/// the pages of a paginated scan.
+///
+/// # Examples
+///
+/// ```
+/// use sled::{ConfigBuilder, Db};
+///
+/// let config = ConfigBuilder::new().temporary(true).build();
+/// let t = Db::start(config).unwrap();
+///
+/// for i in 0..10_u8 {
+///     t.set(&[i * 2], vec![i]).unwrap();
+/// }
+///
+/// let mut cursor = t.cursor().unwrap();
+///
+/// // the first key at or after 5
+/// assert!(cursor.seek(&[5]).unwrap());
+/// assert_eq!(cursor.key(), Some(&[6][..]));
+///
+/// assert!(cursor.next().unwrap());
+/// assert_eq!(cursor.key(), Some(&[8][..]));
+///
+/// assert!(cursor.prev().unwrap());
+/// assert!(cursor.prev().unwrap());
+/// assert_eq!(cursor.key(), Some(&[4][..]));
+///
+/// // the last key at or before 5
+/// assert!(cursor.seek_for_prev(&[5]).unwrap());
+/// assert_eq!(cursor.key(), Some(&[4][..]));
+///
+/// // moving past the last key unpositions the cursor
+/// assert!(cursor.seek(&[18]).unwrap());
+/// assert!(!cursor.next().unwrap());
+/// assert_eq!(cursor.key(), None);
+/// ```
+pub struct Cursor<'a> {
+    iter: Iter<'a>,
+    current: Option<(Key, IVec)>,
+}
+
+impl Tree {
+    /// Returns a `Cursor` over this `Tree`, which is not
+    /// positioned on any key until it is moved.
+    pub fn cursor(&self) -> Result<Cursor<'_>> {
+        Cursor::new(self)
+    }
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(tree: &'a Tree) -> Result<Cursor<'a>> {
+        let iter = Iter {
+            tree,
+            hi: ops::Bound::Unbounded,
+            lo: ops::Bound::Unbounded,
+            last_id: None,
+            last_key: None,
+            broken: None,
+            done: false,
+            is_scan: false,
+            tx: tree.context.pagecache.begin()?,
+        };
+
+        Ok(Cursor {
+            iter,
+            current: None,
+        })
+    }
+
+    /// Returns the key that the cursor is positioned on, if any.
+    pub fn key(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(k, _v)| k.as_slice())
+    }
+
+    /// Returns the value of the key that the cursor is
+    /// positioned on, if any.
+    pub fn value(&self) -> Option<&IVec> {
+        self.current.as_ref().map(|(_k, v)| v)
+    }
+
+    /// Positions the cursor on the first key that is equal to
+    /// or greater than `key`. Returns `false` if there is none,
+    /// leaving the cursor unpositioned.
+    pub fn seek<K: AsRef<[u8]>>(&mut self, key: K) -> Result<bool> {
+        self.unposition();
+        self.iter.lo = ops::Bound::Included(key.as_ref().to_vec());
+        self.step(true)
+    }
+
+    /// Positions the cursor on the last key that is equal to
+    /// or less than `key`. Returns `false` if there is none,
+    /// leaving the cursor unpositioned.
+    pub fn seek_for_prev<K: AsRef<[u8]>>(&mut self, key: K) -> Result<bool> {
+        self.unposition();
+        self.iter.hi = ops::Bound::Included(key.as_ref().to_vec());
+        self.step(false)
+    }
+
+    /// Moves the cursor to the next key, or to the first key of
+    /// the `Tree` if it is not positioned. Returns `false` if
+    /// there is none, leaving the cursor unpositioned.
+    #[allow(clippy::should_implement_trait)] // moves in both directions
+    pub fn next(&mut self) -> Result<bool> {
+        self.step(true)
+    }
+
+    /// Moves the cursor to the previous key, or to the last key
+    /// of the `Tree` if it is not positioned. Returns `false` if
+    /// there is none, leaving the cursor unpositioned.
+    pub fn prev(&mut self) -> Result<bool> {
+        self.step(false)
+    }
+
+    /// Releases the `Tx` of this cursor for a new one, and
+    /// positions the cursor on the first key that is equal to
+    /// or greater than the key it was positioned on, reading
+    /// its value again. Returns `false` if there is none, or if
+    /// the cursor was not positioned.
+    pub fn refresh(&mut self) -> Result<bool> {
+        // NB the pages read with the old `Tx` may be
+        // reclaimed after it is dropped, so the leaf
+        // that we are on is looked up again.
+        self.iter.tx = self.iter.tree.context.pagecache.begin()?;
+
+        match self.current.take() {
+            Some((key, _value)) => self.seek(key),
+            None => {
+                self.unposition();
+                Ok(false)
+            }
+        }
+    }
+
+    // Moves the underlying `Iter` one key forward or backward
+    // from its last key, or from its bounds if it has none.
+    fn step(&mut self, forward: bool) -> Result<bool> {
+        let res = if forward {
+            self.iter.next()
+        } else {
+            self.iter.next_back()
+        };
+
+        // once positioned, the last key of the `Iter` is the
+        // only bound in either direction.
+        self.iter.lo = ops::Bound::Unbounded;
+        self.iter.hi = ops::Bound::Unbounded;
+
+        match res {
+            Some(Ok(item)) => {
+                self.current = Some(item);
+                Ok(true)
+            }
+            Some(Err(e)) => {
+                self.unposition();
+                Err(e)
+            }
+            None => {
+                self.unposition();
+                Ok(false)
+            }
+        }
+    }
+
+    fn unposition(&mut self) {
+        self.current = None;
+        self.iter.last_id = None;
+        self.iter.last_key = None;
+        self.iter.done = false;
+        self.iter.lo = ops::Bound::Unbounded;
+        self.iter.hi = ops::Bound::Unbounded;
+    }
+}
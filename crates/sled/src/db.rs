@@ -1,6 +1,9 @@
 use std::{
     ops::Deref,
-    sync::{atomic::AtomicU64, Arc, RwLock},
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc, RwLock,
+    },
 };
 
 use pagecache::FastMap8;
@@ -11,10 +14,13 @@ use super::*;
 #[derive(Clone)]
 pub struct Db {
     context: Context,
-    default: Arc<Tree>,
-    tenants: Arc<RwLock<FastMap8<Vec<u8>, Arc<Tree>>>>,
+    pub(crate) default: Arc<Tree>,
+    tenants: Arc<Tenants>,
 }
 
+/// The open trees of a `Db`, by name.
+pub(crate) type Tenants = RwLock<FastMap8<Vec<u8>, Arc<Tree>>>;
+
 unsafe impl Send for Db {}
 
 unsafe impl Sync for Db {}
@@ -36,16 +42,32 @@ impl Db {
 
     /// Load existing or create a new `Db`.
     pub fn start(config: Config) -> Result<Db> {
-        let _measure = Measure::new(&M.tree_start);
+        let metrics = config.metrics.clone();
+        let _measure = Measure::new(&metrics.tree_start);
 
         let context = Context::start(config)?;
 
+        let tenants: Arc<Tenants> = Arc::default();
+
         let flusher_pagecache = context.pagecache.clone();
+        let sweeper: Option<flusher::Sweeper> = if context.read_only {
+            None
+        } else {
+            let pagecache = context.pagecache.clone();
+            let next_expiry = context.next_expiry.clone();
+            // NB the trees hold on to the flusher, so it must
+            // not keep them alive.
+            let trees = Arc::downgrade(&tenants);
+            Some(Box::new(move || {
+                expiry::sweep(&pagecache, &next_expiry, &trees)
+            }))
+        };
         let flusher = context.flush_every_ms.map(move |fem| {
             flusher::Flusher::new(
                 "log flusher".to_owned(),
                 flusher_pagecache,
                 fem,
+                sweeper,
             )
         });
         *context._flusher.lock().unwrap() = flusher;
@@ -61,17 +83,28 @@ impl Db {
         let ret = Db {
             context: context.clone(),
             default,
-            tenants: Arc::new(RwLock::new(FastMap8::default())),
+            tenants,
         };
 
         let mut tenants = ret.tenants.write().unwrap();
 
         for (id, root) in context.pagecache.meta(&tx)?.tenants().into_iter() {
+            if is_index_tree(&id) {
+                continue;
+            }
+            // NB the default tree shares its handle, so that
+            // its root is never stale when it is replaced,
+            // like by `Tree::bulk_load`.
+            if id == DEFAULT_TREE_ID {
+                tenants.insert(id, ret.default.clone());
+                continue;
+            }
             let tree = Tree {
                 tree_id: id.clone(),
                 subscriptions: Arc::new(Subscriptions::default()),
                 context: context.clone(),
                 root: Arc::new(AtomicU64::new(root)),
+                indexes: Arc::default(),
             };
             tenants.insert(id, Arc::new(tree));
         }
@@ -92,10 +125,15 @@ impl Db {
 
     /// Open or create a new disk-backed Tree with its own keyspace,
     /// accessible from the `Db` via the provided identifier.
+    ///
+    /// Returns an `Error::Unsupported` if the tree recorded a
+    /// merge operator with `Tree::set_merge_operator` that has
+    /// not been registered with `register_merge_operator`.
     pub fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<Arc<Tree>> {
         let name = name.as_ref();
         let tenants = self.tenants.read().unwrap();
         if let Some(tree) = tenants.get(name) {
+            tree.verify_merge_operator()?;
             return Ok(tree.clone());
         }
         drop(tenants);
@@ -110,9 +148,159 @@ impl Db {
         )?);
         tenants.insert(name.to_vec(), tree.clone());
         drop(tenants);
+        tree.verify_merge_operator()?;
         Ok(tree)
     }
 
+    /// Open or create a `Tree`, and load the keys and values
+    /// of `iter` into it with `Tree::bulk_load`. The keys must
+    /// be in strictly ascending order, and the `Tree` must be
+    /// empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    ///
+    /// let letters = db
+    ///     .bulk_load_tree(b"letters", (b'a'..=b'z').map(|c| (vec![c], vec![c])))
+    ///     .unwrap();
+    /// assert_eq!(letters.len(), 26);
+    /// ```
+    pub fn bulk_load_tree<V, I, K, T>(
+        &self,
+        name: V,
+        iter: I,
+    ) -> Result<Arc<Tree>>
+    where
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, T)>,
+        K: AsRef<[u8]>,
+        IVec: From<T>,
+    {
+        let tree = self.open_tree(name)?;
+        tree.bulk_load(iter)?;
+        Ok(tree)
+    }
+
+    /// Registers a merge operator under `name`, replacing any
+    /// operator that was registered under it before. Trees that
+    /// recorded `name` with `Tree::set_merge_operator` in an
+    /// earlier process can only be opened, and merged into,
+    /// once an operator has been registered under it again.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use sled::{ConfigBuilder, Db, MergeOperator};
+    ///
+    /// let concatenate: MergeOperator = Arc::new(|_k, old, new| {
+    ///     let mut ret = old.map(<[u8]>::to_vec).unwrap_or_default();
+    ///     ret.extend_from_slice(new);
+    ///     Some(ret)
+    /// });
+    ///
+    /// let path = std::env::temp_dir()
+    ///     .join(format!("sled_merge_operator_doc_{}", std::process::id()));
+    /// let config = ConfigBuilder::new().path(&path).build();
+    ///
+    /// let db = Db::start(config.clone()).unwrap();
+    /// let logs = db.open_tree(b"logs").unwrap();
+    /// logs.set_merge_operator("concatenate", concatenate.clone())
+    ///     .unwrap();
+    /// drop(logs);
+    /// drop(db);
+    ///
+    /// let db = Db::start(config).unwrap();
+    /// assert!(db.open_tree(b"logs").is_err());
+    ///
+    /// db.register_merge_operator("concatenate", concatenate);
+    /// let logs = db.open_tree(b"logs").unwrap();
+    /// logs.merge(b"k", vec![1]).unwrap();
+    /// # drop(logs);
+    /// # drop(db);
+    /// # std::fs::remove_dir_all(path).unwrap();
+    /// ```
+    pub fn register_merge_operator(
+        &self,
+        name: &str,
+        merge_operator: MergeOperator,
+    ) {
+        self.context
+            .merge_operators
+            .write()
+            .unwrap()
+            .insert(name.to_owned(), merge_operator);
+    }
+
+    /// Open or create a `TypedTree` of keys of type `K` and
+    /// values of type `V`, stored using the `Codec` `C`.
+    /// The codec is recorded the first time that a tree
+    /// is opened as a `TypedTree`, and opening it again with
+    /// a different codec returns an `Error::Unsupported`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{Bincode, ConfigBuilder, Db, Ordered};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    ///
+    /// let users = db.open_typed_tree::<u64, String, Ordered>(b"users").unwrap();
+    /// users.set(&1, &"alice".to_owned()).unwrap();
+    /// assert_eq!(users.get(&1), Ok(Some("alice".to_owned())));
+    ///
+    /// assert!(db.open_typed_tree::<u64, String, Bincode>(b"users").is_err());
+    /// ```
+    pub fn open_typed_tree<K, V, C>(
+        &self,
+        name: &[u8],
+    ) -> Result<TypedTree<K, V, C>>
+    where
+        K: Serialize + DeserializeOwned,
+        V: Serialize + DeserializeOwned,
+        C: Codec,
+    {
+        let tree = self.open_tree(name)?;
+        let tx = self.context.pagecache.begin()?;
+
+        let recorded = if self.context.read_only {
+            let meta = self.context.pagecache.meta(&tx)?;
+            meta.get_property(name, CODEC_PROPERTY).map(<[u8]>::to_vec)
+        } else {
+            self.context
+                .pagecache
+                .cas_property_in_meta(
+                    name,
+                    CODEC_PROPERTY,
+                    None,
+                    Some(C::ID.as_bytes().to_vec()),
+                    &tx,
+                )?
+                .err()
+                .and_then(|current| current)
+        };
+
+        match recorded {
+            Some(ref id) if id.as_slice() != C::ID.as_bytes() => {
+                Err(Error::Unsupported(format!(
+                    "tree {:?} was created with the {:?} codec, \
+                     and can not be opened with the {:?} codec",
+                    String::from_utf8_lossy(name),
+                    String::from_utf8_lossy(id),
+                    C::ID,
+                )))
+            }
+            _ => Ok(TypedTree::new(tree)),
+        }
+    }
+
     /// Remove a disk-backed collection.
     pub fn drop_tree(&self, name: &[u8]) -> Result<bool> {
         if name == DEFAULT_TREE_ID {
@@ -130,34 +318,13 @@ impl Db {
             return Ok(false);
         };
 
-        let tx = self.context.pagecache.begin()?;
-
-        let mut root_id =
-            Some(self.context.pagecache.meta_pid_for_name(&name, &tx)?);
-
-        let leftmost_chain: Vec<PageId> = tree
-            .path_for_key(b"", &tx)?
-            .into_iter()
-            .map(|(id, _frag, _tp)| id)
-            .collect();
+        // indexes are dropped first, because the record of
+        // them is dropped along with the tree.
+        tree.drop_indexes()?;
 
-        loop {
-            let res = self.context.pagecache.cas_root_in_meta(
-                name.to_vec(),
-                root_id,
-                None,
-                &tx,
-            )?;
-
-            if let Err(actual_root) = res {
-                root_id = actual_root;
-            } else {
-                break;
-            }
-        }
+        let tx = self.context.pagecache.begin()?;
 
-        tree.root
-            .store(u64::max_value(), std::sync::atomic::Ordering::SeqCst);
+        let leftmost_chain = meta::remove_tree(&tree, &tx)?;
 
         // drop writer lock
         drop(tenants);
@@ -175,6 +342,228 @@ impl Db {
         tenants.iter().map(|(name, _)| name.clone()).collect()
     }
 
+    /// Create a read-only `Snapshot` of every `Tree` in
+    /// this `Db`, which answers `get`, `range` and `iter`
+    /// as of a single point in the log, regardless of
+    /// any writes that happen after it is created.
+    ///
+    /// Pauses every writer and walks every page id while
+    /// they are paused, so writes are held up for time
+    /// proportional to the number of pages. Writers resume
+    /// before the log is flushed to make the captured
+    /// state stable.
+    ///
+    /// While the `Snapshot` is alive, segments are not
+    /// rewritten and the blobs of overwritten values are
+    /// not removed, so the files on disk keep growing
+    /// until it is dropped. Blobs that are still held
+    /// when the process exits are removed during the
+    /// next recovery.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db, IVec};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    /// let users = db.open_tree(b"users").unwrap();
+    ///
+    /// users.set(b"alice", vec![1]).unwrap();
+    ///
+    /// let snapshot = db.snapshot().unwrap();
+    ///
+    /// users.del(b"alice").unwrap();
+    ///
+    /// let old_users = snapshot.open_tree(b"users").unwrap();
+    /// assert_eq!(old_users.get(b"alice"), Ok(Some(IVec::from(vec![1]))));
+    /// ```
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Snapshot::new(&self.context)
+    }
+
+    /// Write a consistent copy of this `Db` into the
+    /// directory at `path`, which must either not exist
+    /// or be empty. Reads and writes may continue while
+    /// the checkpoint is being written, and the copy
+    /// contains at least every write that completed
+    /// before this was called. The copy can be opened
+    /// directly with `Db::start`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db, IVec};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    /// db.set(b"k1", vec![1]).unwrap();
+    ///
+    /// let path = std::env::temp_dir()
+    ///     .join(format!("sled_checkpoint_doc_{}", std::process::id()));
+    /// db.checkpoint(&path).unwrap();
+    ///
+    /// let config = ConfigBuilder::new().path(&path).temporary(true).build();
+    /// let copy = Db::start(config).unwrap();
+    /// assert_eq!(copy.get(b"k1"), Ok(Some(IVec::from(vec![1]))));
+    /// ```
+    pub fn checkpoint<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        self.context.pagecache.checkpoint(path)
+    }
+
+    /// Checks the integrity of this `Db`, returning a report of
+    /// every problem found, rather than failing on the first one.
+    /// Every segment of the log and every message in it are
+    /// read and checksummed, every fragment of every page is
+    /// read from where the page table points, along with the
+    /// blob it is stored in if it is large, and every tree is walked to check that its
+    /// nodes are linked to their siblings and children at their
+    /// bounds, with their keys in order. Writers are paused
+    /// while the trees are walked. The `sled-check` binary runs
+    /// this on a database directory.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    /// db.set(b"k1", vec![1]).unwrap();
+    ///
+    /// let report = db.verify_integrity().unwrap();
+    /// assert!(report.is_ok(), "{}", report);
+    /// ```
+    pub fn verify_integrity(&self) -> Result<IntegrityReport> {
+        let mut report = self.context.pagecache.verify_integrity()?;
+
+        let _pause = self.context.concurrency_control.pause();
+
+        let tx = self.context.pagecache.begin()?;
+
+        for (tree_id, root) in self.context.pagecache.meta(&tx)?.tenants() {
+            integrity::verify_tree(
+                &self.context,
+                &tree_id,
+                root,
+                &mut report,
+                &tx,
+            )?;
+        }
+
+        Ok(report)
+    }
+
+    /// Returns statistics about the space that this `Db` uses
+    /// on disk, to explain how the size of its directory
+    /// compares to the size of the stored data: the sizes of
+    /// the log, blob and snapshot files, how many segments of
+    /// the log are in each state and how much of them is still
+    /// live, and an estimate of the space that cleaning
+    /// segments could reclaim.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    /// db.set(b"k1", vec![1]).unwrap();
+    /// db.flush().unwrap();
+    ///
+    /// let stats = db.storage_stats().unwrap();
+    /// assert!(stats.file_bytes > 0);
+    /// assert!(stats.pages > 0);
+    /// println!("{}", stats);
+    /// ```
+    pub fn storage_stats(&self) -> Result<StorageStats> {
+        self.context.pagecache.storage_stats()
+    }
+
+    /// Returns the counters and latency and size histograms
+    /// gathered by this `Db` since it was started, which can be
+    /// rendered for a monitoring system with
+    /// `MetricsSnapshot::to_prometheus` or `to_json`. Other
+    /// `Db`s in the same process have their own metrics.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    /// db.set(b"k1", vec![1]).unwrap();
+    /// db.get(b"k1").unwrap();
+    ///
+    /// let metrics = db.metrics();
+    /// # #[cfg(not(feature = "no_metrics"))]
+    /// assert_eq!(metrics.histogram("tree_get").unwrap().count, 1);
+    ///
+    /// let text = metrics.to_prometheus("sled");
+    /// assert!(text.contains("sled_tree_get_seconds_count"));
+    /// ```
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.context.metrics.snapshot()
+    }
+
+    /// Returns the replication `Primary` with the given name,
+    /// registering it if it does not exist yet. A new primary
+    /// retains every write from this point on, so a replica
+    /// should be started from a checkpoint that is written
+    /// after this returns. See the `Replica` documentation.
+    pub fn replication_primary(&self, name: &str) -> Result<Primary> {
+        Primary::register(&self.default, name)
+    }
+
+    // Reloads the trees and their roots from the `Meta` page,
+    // after it was changed by replicated writes.
+    pub(crate) fn reload_tenants(&self) -> Result<()> {
+        let tx = self.context.pagecache.begin()?;
+        let roots = self.context.pagecache.meta(&tx)?.tenants();
+
+        let mut tenants = self.tenants.write().unwrap();
+
+        tenants.retain(|name, tree| {
+            if roots.contains_key(name) {
+                true
+            } else {
+                tree.root.store(std::u64::MAX, SeqCst);
+                false
+            }
+        });
+
+        for (id, root) in roots {
+            if id == DEFAULT_TREE_ID {
+                self.default.root.store(root, SeqCst);
+                self.default.forget_indexes();
+            }
+
+            if is_index_tree(&id) {
+                continue;
+            }
+
+            if let Some(tree) = tenants.get(&id) {
+                tree.root.store(root, SeqCst);
+                // the roots of its indexes may have changed too
+                tree.forget_indexes();
+                continue;
+            }
+
+            let tree = Tree {
+                tree_id: id.clone(),
+                subscriptions: Arc::new(Subscriptions::default()),
+                context: self.context.clone(),
+                root: Arc::new(AtomicU64::new(root)),
+                indexes: Arc::default(),
+            };
+            tenants.insert(id, Arc::new(tree));
+        }
+
+        Ok(())
+    }
+
     /// Returns `true` if the database was
     /// recovered from a previous process.
     /// Note that database state is only
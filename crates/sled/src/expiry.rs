@@ -0,0 +1,217 @@
+//! Support for values that were set with a TTL.
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc, Weak,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::*;
+
+/// The current time in milliseconds since the unix epoch,
+/// which is how the expiration times of values are stored.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Rewrites every leaf that contains an expired value
+/// without it, if any value may have expired since the
+/// last sweep. `next_expiry` holds the earliest time at
+/// which a value that has not been swept yet expires.
+///
+/// Values are dropped from their leaves directly, so
+/// subscribers and change feeds are not notified about
+/// the keys that are swept. The values of `trees` that have
+/// indexes are deleted along with their index entries
+/// instead, which subscribers and change feeds observe.
+pub(crate) fn sweep(
+    pagecache: &PageCache<BLinkMaterializer, Frag>,
+    next_expiry: &AtomicU64,
+    trees: &Weak<Tenants>,
+) -> Result<()> {
+    let now = now_millis();
+    if next_expiry.load(SeqCst) > now {
+        return Ok(());
+    }
+
+    // NB values that are set with a TTL while we sweep
+    // lower this again.
+    next_expiry.store(std::u64::MAX, SeqCst);
+
+    match sweep_leaves(pagecache, trees, now) {
+        Ok(remaining) => {
+            lower_next_expiry(next_expiry, remaining);
+            Ok(())
+        }
+        Err(e) => {
+            // the leaves that were not swept may still hold
+            // expired values, so the next sweep retries them.
+            lower_next_expiry(next_expiry, now);
+            Err(e)
+        }
+    }
+}
+
+/// Lowers `next_expiry` to `at`, unless a value expires
+/// earlier already.
+pub(crate) fn lower_next_expiry(next_expiry: &AtomicU64, at: u64) {
+    let mut current = next_expiry.load(SeqCst);
+    while at < current {
+        match next_expiry.compare_exchange(current, at, SeqCst, SeqCst) {
+            Ok(_) => return,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+// Sweeps the leaves of every tree, returning the earliest
+// time at which a remaining value expires.
+fn sweep_leaves(
+    pagecache: &PageCache<BLinkMaterializer, Frag>,
+    trees: &Weak<Tenants>,
+    now: u64,
+) -> Result<u64> {
+    let trees = match trees.upgrade() {
+        Some(trees) => trees,
+        // the `Db` is being dropped, and the trees with
+        // indexes can not be told apart anymore.
+        None => return Ok(now),
+    };
+
+    let roots = {
+        let tx = Tx::new(0);
+        pagecache.meta(&tx)?.tenants()
+    };
+
+    let mut remaining = std::u64::MAX;
+
+    for (name, root) in roots {
+        let indexed = match indexed_tree(&trees, &name) {
+            Ok(indexed) => indexed,
+            Err(e) => {
+                // the indexes are not registered yet, so its
+                // values can not be swept until they are.
+                debug!("not sweeping tree {:?}: {}", name, e);
+                remaining = now;
+                continue;
+            }
+        };
+
+        let mut cursor = Some(leftmost_leaf(pagecache, root)?);
+
+        while let Some(pid) = cursor.take() {
+            let tx = Tx::new(0);
+
+            let (frag, ptr) = match pagecache.get(pid, &tx)? {
+                PageGet::Materialized(frag, ptr) => (frag, ptr),
+                // the tree was dropped while we swept it
+                _ => break,
+            };
+            let node = frag.unwrap_base();
+            cursor = node.next;
+
+            let next = node.next_expiry().unwrap_or(std::u64::MAX);
+            if next > now {
+                remaining = remaining.min(next);
+                continue;
+            }
+
+            if let Some(ref tree) = indexed {
+                for (key, at) in &node.expiries {
+                    if *at <= now {
+                        sweep_indexed(tree, key, now)?;
+                    } else {
+                        remaining = remaining.min(*at);
+                    }
+                }
+                continue;
+            }
+
+            let mut swept = node.clone();
+            swept.drop_expired(now);
+
+            if let Some(at) = swept.next_expiry() {
+                remaining = remaining.min(at);
+            }
+
+            if pagecache
+                .replace(pid, ptr, Frag::Base(swept), &tx)?
+                .is_err()
+            {
+                // the leaf was written to concurrently,
+                // so we try again during the next sweep.
+                remaining = now;
+            }
+        }
+    }
+
+    Ok(remaining)
+}
+
+// Returns the tree named `name` if it has indexes, which
+// need to be updated when its values are swept.
+fn indexed_tree(trees: &Tenants, name: &[u8]) -> Result<Option<Arc<Tree>>> {
+    let tree = trees.read().unwrap().get(name).cloned();
+
+    match tree {
+        Some(tree) => {
+            if tree.is_indexed()? {
+                Ok(Some(tree))
+            } else {
+                Ok(None)
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+// Deletes the value of `key` from `tree` along with its
+// index entries, if it is still expired at `now`.
+fn sweep_indexed(tree: &Tree, key: &[u8], now: u64) -> Result<()> {
+    tree.write_indexed(key, |deferred| {
+        // NB the key can not be written to concurrently
+        // anymore, but it may have been before.
+        let tx = tree.context.pagecache.begin()?;
+        let path = tree.path_for_key(key, &tx)?;
+        let (_pid, frag, _ptr) =
+            path.last().expect("path should contain at least one node");
+        let node = frag.unwrap_base();
+
+        match node.expires_at(key) {
+            Some(at) if at <= now => {}
+            _ => return Ok(()),
+        }
+
+        let value = node.leaf_value(&prefix_encode(&node.lo, key)).cloned();
+
+        tree.del_inner(key, deferred)?;
+        tree.update_indexes(key, value.as_ref().map(AsRef::as_ref), None)?;
+
+        Ok(())
+    })
+}
+
+// Follows the leftmost child of each index node from `root`.
+fn leftmost_leaf(
+    pagecache: &PageCache<BLinkMaterializer, Frag>,
+    root: PageId,
+) -> Result<PageId> {
+    let tx = Tx::new(0);
+    let mut pid = root;
+
+    loop {
+        let node = match pagecache.get(pid, &tx)? {
+            PageGet::Materialized(frag, _ptr) => frag.unwrap_base(),
+            _ => return Ok(pid),
+        };
+
+        match node.data {
+            Data::Index(ref ptrs) => pid = ptrs[0].1,
+            Data::Leaf(_) => return Ok(pid),
+        }
+    }
+}
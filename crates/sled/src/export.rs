@@ -0,0 +1,481 @@
+//! Export and import of whole databases in a portable,
+//! versioned format that does not depend on the on-disk
+//! layout of any particular sled version.
+//!
+//! # Stream format
+//!
+//! A stream starts with the 8 byte magic value `b"sled-exp"`,
+//! followed by the format version as a little-endian `u32`.
+//! The current version is `2`. The rest of the stream is a
+//! sequence of frames, each of which is encoded as:
+//!
+//! | field     | size        | contents                             |
+//! |-----------|-------------|--------------------------------------|
+//! | `len`     | 8 bytes     | little-endian length of `payload`    |
+//! | `crc`     | 4 bytes     | little-endian crc32 of `payload`     |
+//! | `payload` | `len` bytes | one byte of frame kind, then its body |
+//!
+//! There are two kinds of frames:
+//!
+//! * `1`, a record: the length of the tree name as a
+//!   little-endian `u64`, the tree name, the length of
+//!   the key as a little-endian `u64`, the key, the time
+//!   at which the value expires as a little-endian `u64`
+//!   of milliseconds since the unix epoch, or `u64::MAX`
+//!   if it was not set with a TTL, and then the value,
+//!   which makes up the rest of the payload.
+//! * `2`, the end of the stream: the number of records
+//!   in the stream as a little-endian `u64`.
+//!
+//! Records of a single tree are contiguous and sorted by key.
+//! A stream that does not end with an end frame is considered
+//! to be truncated. Streams of version `1` are still read,
+//! and their records have no expiration time.
+//!
+//! # Examples
+//!
+//! ```
+//! use sled::{ConfigBuilder, Db, ExportReader, IVec};
+//!
+//! let config = ConfigBuilder::new().temporary(true).build();
+//! let old = Db::start(config).unwrap();
+//! old.set(b"k1", vec![1]).unwrap();
+//! old.open_tree(b"users").unwrap().set(b"alice", vec![2]).unwrap();
+//!
+//! let mut stream = vec![];
+//! old.export().unwrap().write_to(&mut stream).unwrap();
+//!
+//! let config = ConfigBuilder::new().temporary(true).build();
+//! let new = Db::start(config).unwrap();
+//! new.import(|| ExportReader::new(&stream[..])).unwrap();
+//!
+//! assert_eq!(new.get(b"k1"), Ok(Some(IVec::from(vec![1]))));
+//! assert_eq!(
+//!     new.open_tree(b"users").unwrap().get(b"alice"),
+//!     Ok(Some(IVec::from(vec![2])))
+//! );
+//! ```
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::TryFrom,
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+use pagecache::crc32;
+
+use super::*;
+
+const MAGIC: &[u8; 8] = b"sled-exp";
+const FORMAT_VERSION: u32 = 2;
+
+// the version before records had an expiration time
+const FORMAT_VERSION_WITHOUT_TTL: u32 = 1;
+
+// the expiration time of a record that never expires
+const NO_EXPIRY: u64 = std::u64::MAX;
+
+const RECORD_FRAME: u8 = 1;
+const END_FRAME: u8 = 2;
+
+/// A `(tree_name, key, value, expires_at)` record of an
+/// export, where `expires_at` is the time at which a value
+/// that was set with a TTL expires, in milliseconds since
+/// the unix epoch.
+pub type ExportRecord = (Vec<u8>, Vec<u8>, IVec, Option<u64>);
+
+/// An iterator over every `(tree_name, key, value, expires_at)`
+/// in a `Db`, as of the moment it was created with `Db::export`.
+pub struct Export {
+    snapshot: Snapshot,
+    tree_names: VecDeque<Vec<u8>>,
+    current: Option<(Vec<u8>, SnapshotIter)>,
+}
+
+impl Export {
+    /// Write every remaining item of this export
+    /// to `writer` using the framed stream format
+    /// described in the module documentation.
+    /// Returns the number of records written.
+    pub fn write_to<W: Write>(self, mut writer: W) -> Result<u64> {
+        let metrics = self.snapshot.context().metrics.clone();
+        let _measure = Measure::new(&metrics.tree_export);
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+        let mut count = 0_u64;
+
+        for item in self {
+            let (tree_name, key, value, expires_at) = item?;
+
+            let mut payload = Vec::with_capacity(
+                1 + 24 + tree_name.len() + key.len() + value.len(),
+            );
+            payload.push(RECORD_FRAME);
+            payload.extend_from_slice(&(tree_name.len() as u64).to_le_bytes());
+            payload.extend_from_slice(&tree_name);
+            payload.extend_from_slice(&(key.len() as u64).to_le_bytes());
+            payload.extend_from_slice(&key);
+            payload.extend_from_slice(
+                &expires_at.unwrap_or(NO_EXPIRY).to_le_bytes(),
+            );
+            payload.extend_from_slice(&value);
+
+            write_frame(&mut writer, &payload)?;
+
+            count += 1;
+        }
+
+        let mut payload = vec![END_FRAME];
+        payload.extend_from_slice(&count.to_le_bytes());
+        write_frame(&mut writer, &payload)?;
+
+        writer.flush()?;
+
+        Ok(count)
+    }
+}
+
+impl Iterator for Export {
+    type Item = Result<ExportRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((ref tree_name, ref mut iter)) = self.current {
+                match iter.next_with_expiry() {
+                    Some(Ok((k, v, expires_at))) => {
+                        return Some(Ok((tree_name.clone(), k, v, expires_at)));
+                    }
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {}
+                }
+            }
+
+            let tree_name = self.tree_names.pop_front()?;
+
+            let tree = match self.snapshot.open_tree(&tree_name) {
+                Ok(tree) => tree,
+                Err(e) => return Some(Err(e)),
+            };
+
+            self.current = Some((tree_name, tree.iter()));
+        }
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    writer.write_all(&crc32(payload).to_le_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+// Reads every record of `export`, checking that the records
+// of each tree are contiguous and in strictly ascending order
+// of keys, as `Export::write_to` writes them.
+fn validate_export<I>(export: I) -> Result<()>
+where
+    I: IntoIterator<Item = Result<ExportRecord>>,
+{
+    let mut seen: HashSet<Vec<u8>> = HashSet::new();
+    let mut last: Option<(Vec<u8>, Vec<u8>)> = None;
+
+    for item in export {
+        let (tree_name, key, ..) = item?;
+
+        if is_index_tree(&tree_name) {
+            continue;
+        }
+
+        match last {
+            Some((ref last_tree, ref last_key)) if *last_tree == tree_name => {
+                if key <= *last_key {
+                    return Err(invalid_data(
+                        "export records of a tree are not in \
+                         ascending order of keys",
+                    ));
+                }
+            }
+            _ => {
+                if !seen.insert(tree_name.clone()) {
+                    return Err(invalid_data(
+                        "export records of a tree are not contiguous",
+                    ));
+                }
+            }
+        }
+
+        last = Some((tree_name, key));
+    }
+
+    Ok(())
+}
+
+fn invalid_data(why: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, why))
+}
+
+/// Reads the `(tree_name, key, value, expires_at)` records
+/// of a stream written by `Export::write_to`, verifying the
+/// checksum of every frame. Yields an error if the stream
+/// has been corrupted or truncated.
+pub struct ExportReader<R: Read> {
+    reader: R,
+    version: u32,
+    count: u64,
+    done: bool,
+}
+
+impl<R: Read> ExportReader<R> {
+    /// Create a new `ExportReader`, verifying
+    /// the header of the provided stream.
+    pub fn new(mut reader: R) -> Result<ExportReader<R>> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a sled export stream"));
+        }
+
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION && version != FORMAT_VERSION_WITHOUT_TTL {
+            return Err(Error::Unsupported(format!(
+                "unsupported export format version {}, \
+                 expected version {}",
+                version, FORMAT_VERSION
+            )));
+        }
+
+        Ok(ExportReader {
+            reader,
+            version,
+            count: 0,
+            done: false,
+        })
+    }
+
+    fn read_frame(&mut self) -> Result<Vec<u8>> {
+        let mut header = [0; 12];
+        self.reader.read_exact(&mut header)?;
+
+        let mut len = [0; 8];
+        len.copy_from_slice(&header[..8]);
+        let len = u64::from_le_bytes(len);
+
+        let mut crc_expected = [0; 4];
+        crc_expected.copy_from_slice(&header[8..]);
+        let crc_expected = u32::from_le_bytes(crc_expected);
+
+        // NB read through `take` so that a corrupted length
+        // can not cause a huge allocation up-front.
+        let mut payload = vec![];
+        (&mut self.reader).take(len).read_to_end(&mut payload)?;
+        if payload.len() as u64 != len {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "export stream ended in the middle of a frame",
+            )));
+        }
+
+        if crc32(&payload) != crc_expected {
+            return Err(invalid_data("export frame failed its crc check"));
+        }
+
+        Ok(payload)
+    }
+
+    fn read_record(&mut self) -> Result<Option<ExportRecord>> {
+        let payload = self.read_frame()?;
+
+        fn take_len(buf: &mut &[u8]) -> Result<usize> {
+            if buf.len() < 8 {
+                return Err(invalid_data("export record is too short"));
+            }
+            let mut len = [0; 8];
+            len.copy_from_slice(&buf[..8]);
+            *buf = &buf[8..];
+            let len = usize::try_from(u64::from_le_bytes(len))
+                .map_err(|_| invalid_data("export record is too long"))?;
+            if buf.len() < len {
+                return Err(invalid_data("export record is too short"));
+            }
+            Ok(len)
+        }
+
+        match payload.split_first() {
+            Some((&RECORD_FRAME, mut buf)) => {
+                let name_len = take_len(&mut buf)?;
+                let tree_name = buf[..name_len].to_vec();
+                buf = &buf[name_len..];
+
+                let key_len = take_len(&mut buf)?;
+                let key = buf[..key_len].to_vec();
+                buf = &buf[key_len..];
+
+                let expires_at = if self.version == FORMAT_VERSION_WITHOUT_TTL {
+                    None
+                } else {
+                    if buf.len() < 8 {
+                        return Err(invalid_data("export record is too short"));
+                    }
+                    let mut expires_at = [0; 8];
+                    expires_at.copy_from_slice(&buf[..8]);
+                    buf = &buf[8..];
+                    Some(u64::from_le_bytes(expires_at))
+                        .filter(|at| *at != NO_EXPIRY)
+                };
+
+                let value = IVec::from(buf);
+
+                self.count += 1;
+
+                Ok(Some((tree_name, key, value, expires_at)))
+            }
+            Some((&END_FRAME, buf)) => {
+                if buf.len() != 8 {
+                    return Err(invalid_data("invalid export end frame"));
+                }
+                let mut expected = [0; 8];
+                expected.copy_from_slice(buf);
+                if u64::from_le_bytes(expected) != self.count {
+                    return Err(invalid_data(
+                        "export stream record count does not match \
+                         its end frame",
+                    ));
+                }
+                Ok(None)
+            }
+            _ => Err(invalid_data("unknown export frame kind")),
+        }
+    }
+}
+
+impl<R: Read> Iterator for ExportReader<R> {
+    type Item = Result<ExportRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.read_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Db {
+    /// Returns an iterator over every `(tree_name, key, value,
+    /// expires_at)` in every `Tree` of this `Db`, as of the
+    /// moment this is called. See `Export::write_to` for
+    /// writing it out as a portable stream that can be
+    /// imported into databases created by other versions
+    /// of sled.
+    pub fn export(&self) -> Result<Export> {
+        let snapshot = self.snapshot()?;
+        let tree_names = snapshot.tree_names().into();
+
+        Ok(Export {
+            snapshot,
+            tree_names,
+            current: None,
+        })
+    }
+
+    /// Load every `(tree_name, key, value, expires_at)` from
+    /// an `Export` or an `ExportReader` into this `Db`, creating
+    /// trees as needed. `export` is called twice to open the
+    /// export: the first time, every record is read and
+    /// validated without writing anything, so a corrupted or
+    /// truncated stream leaves this `Db` untouched. The second
+    /// time, the records of each tree are streamed into it with
+    /// `Tree::bulk_load`, so the export is never held in memory.
+    /// Returns `Error::Unsupported` if this `Db` already
+    /// contains any data.
+    ///
+    /// Each tree becomes visible all at once, and subscribers
+    /// are not notified of the imported keys. If writing fails
+    /// part of the way through, some trees may already have
+    /// been imported, so it should be retried on a fresh `Db`.
+    /// Records of index trees are skipped, as indexes are
+    /// built from the contents of their tree when registered.
+    ///
+    /// Values that were set with a TTL keep the time at which
+    /// they expire, and the ones that have already expired are
+    /// skipped.
+    pub fn import<F, I>(&self, mut export: F) -> Result<()>
+    where
+        F: FnMut() -> Result<I>,
+        I: IntoIterator<Item = Result<ExportRecord>>,
+    {
+        let _measure = Measure::new(&self.context.metrics.tree_import);
+
+        for tree_name in self.tree_names() {
+            if !self.open_tree(&tree_name)?.is_empty() {
+                return Err(Error::Unsupported(
+                    "can only import into an empty Db".to_owned(),
+                ));
+            }
+        }
+
+        validate_export(export()?)?;
+
+        let now = expiry::now_millis();
+        let mut records = export()?
+            .into_iter()
+            .filter(|item| match item {
+                Ok((tree_name, _k, _v, expires_at)) => {
+                    !is_index_tree(tree_name)
+                        && expires_at.map_or(true, |at| at > now)
+                }
+                Err(_) => true,
+            })
+            .peekable();
+
+        loop {
+            let tree_name = match records.peek() {
+                Some(Ok((tree_name, ..))) => tree_name.clone(),
+                Some(Err(_)) => {
+                    return Err(records.next().unwrap().unwrap_err())
+                }
+                None => break,
+            };
+
+            // NB errors are passed on to `try_bulk_load`, which
+            // then leaves the tree empty.
+            let tree_records = std::iter::from_fn(|| {
+                match records.peek() {
+                    Some(Ok((name, ..))) if *name != tree_name => return None,
+                    _ => {}
+                }
+                records.next().map(|item| {
+                    item.map(|(_, k, v, expires_at)| (k, v, expires_at))
+                })
+            });
+
+            self.import_tree(&tree_name)?.try_bulk_load(tree_records)?;
+        }
+
+        self.flush()?;
+
+        Ok(())
+    }
+
+    fn import_tree(&self, name: &[u8]) -> Result<Arc<Tree>> {
+        if name == DEFAULT_TREE_ID {
+            Ok(self.default.clone())
+        } else {
+            self.open_tree(name)
+        }
+    }
+}
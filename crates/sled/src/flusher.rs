@@ -36,12 +36,22 @@ pub(crate) struct Flusher {
     join_handle: Mutex<Option<std::thread::JoinHandle<()>>>,
 }
 
+/// Work that the `Flusher` does periodically, at most once
+/// every `SWEEP_EVERY`, whether or not there was dirty data
+/// to flush.
+pub(crate) type Sweeper = Box<dyn Fn() -> Result<()> + Send>;
+
+// the shortest interval between two calls to the `Sweeper`,
+// unless the flush interval is longer.
+const SWEEP_EVERY: Duration = Duration::from_secs(1);
+
 impl Flusher {
     /// Spawns a thread that periodically calls `callback` until dropped.
     pub(crate) fn new<PM, P>(
         name: String,
         pagecache: Arc<PageCache<PM, P>>,
         flush_every_ms: u64,
+        sweeper: Option<Sweeper>,
     ) -> Flusher
     where
         PM: 'static + Send + Sync + pagecache::Materializer<PageFrag = P>,
@@ -62,7 +72,7 @@ impl Flusher {
             .spawn({
                 let shutdown = shutdown.clone();
                 let sc = sc.clone();
-                move || run(shutdown, sc, pagecache, flush_every_ms)
+                move || run(shutdown, sc, pagecache, flush_every_ms, sweeper)
             })
             .unwrap();
 
@@ -79,6 +89,7 @@ fn run<PM, P>(
     sc: Arc<Condvar>,
     pagecache: Arc<PageCache<PM, P>>,
     flush_every_ms: u64,
+    sweeper: Option<Sweeper>,
 ) where
     PM: 'static + Send + Sync + pagecache::Materializer<PageFrag = P>,
     P: 'static
@@ -90,6 +101,8 @@ fn run<PM, P>(
         + serde::de::DeserializeOwned,
 {
     let flush_every = Duration::from_millis(flush_every_ms);
+    let sweep_every = std::cmp::max(flush_every, SWEEP_EVERY);
+    let mut last_sweep = std::time::Instant::now();
     let mut shutdown = shutdown.lock().unwrap();
     let mut wrote_data = false;
     while shutdown.is_running() || wrote_data {
@@ -144,6 +157,17 @@ fn run<PM, P>(
             }
         }
 
+        // NB sweeping is not only done while there is no dirty
+        // data, so that constant writes can not put it off. A
+        // failed sweep is retried later, and does not stop the
+        // flushing.
+        if shutdown.is_running() && last_sweep.elapsed() >= sweep_every {
+            last_sweep = std::time::Instant::now();
+            if let Some(Err(e)) = sweeper.as_ref().map(|sweep| sweep()) {
+                error!("failed to sweep from async flush thread: {}", e);
+            }
+        }
+
         let sleep_duration = flush_every
             .checked_sub(before.elapsed())
             .unwrap_or(Duration::from_millis(1));
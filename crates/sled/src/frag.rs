@@ -1,9 +1,6 @@
 use super::*;
 
 // TODO
-// Merged
-// LeftMerge(head: Raw, rhs: PageId, hi: Bound)
-// ParentMerge(lhs: PageId, rhs: PageId)
 // TxBegin(TxID), // in-mem
 // TxCommit(TxID), // in-mem
 // TxAbort(TxID), // in-mem
@@ -12,10 +9,31 @@ use super::*;
 pub(crate) enum Frag {
     Set(IVec, IVec),
     Del(IVec),
+    /// A merge operand, which was logged by versions that
+    /// applied the merge operator set with the deprecated
+    /// `ConfigBuilder::merge_operator` when a page was read.
+    /// It is no longer written, but still replayed with that
+    /// operator.
     Merge(IVec, IVec),
     Base(Node),
     ChildSplit(ChildSplit),
     ParentSplit(ParentSplit),
+    SetWithTtl(IVec, IVec, u64),
+    /// Removes every key of a leaf from the first (decoded)
+    /// key, up to but excluding the second one, if any.
+    DelRange(IVec, Option<IVec>),
+    /// Records on an index that the child with this pid is
+    /// being merged into its left sibling.
+    ParentMergeIntention(PageId),
+    /// Marks a node that is being merged into its left
+    /// sibling, so that it is no longer written to.
+    ChildMergeCap,
+    /// Appends the keys of a merged right sibling to
+    /// its left sibling, which takes over its bounds.
+    LeftMerge(Node),
+    /// Removes the child of an index that was merged into
+    /// its left sibling.
+    ParentMergeConfirm,
 }
 
 impl Frag {
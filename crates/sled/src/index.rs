@@ -0,0 +1,614 @@
+//! Secondary indexes, which are kept in sync with the `Tree`
+//! that they index in the same atomic batches as its writes.
+//!
+//! Each index is stored in its own hidden tenant, whose keys
+//! are the escaped index key followed by the primary key, so
+//! that every primary key with a given index key can be found
+//! with a scan of the index tenant.
+use std::{
+    fmt::{self, Debug},
+    ops::Bound,
+    sync::Arc,
+};
+
+use super::*;
+
+/// Extracts the index keys of a key and value of a `Tree`.
+pub type IndexExtractor =
+    Arc<dyn Fn(&[u8], &[u8]) -> Vec<Vec<u8>> + Send + Sync>;
+
+const INDEX_PROPERTY_PREFIX: &str = "index:";
+
+const INDEX_TREE_PREFIX: &[u8] = b"__sled__index";
+
+/// Returns `true` for the names of the hidden tenants that
+/// store indexes, which are not opened as trees of a `Db`.
+pub(crate) fn is_index_tree(name: &[u8]) -> bool {
+    name.starts_with(INDEX_TREE_PREFIX)
+}
+
+fn index_tree_name(tree_id: &[u8], name: &str) -> Vec<u8> {
+    let mut ret = INDEX_TREE_PREFIX.to_vec();
+    ret.extend_from_slice(&(tree_id.len() as u64).to_be_bytes());
+    ret.extend_from_slice(tree_id);
+    ret.extend_from_slice(name.as_bytes());
+    ret
+}
+
+// The escaped index key, which is a prefix of every entry
+// that it maps to a primary key.
+fn entry_prefix(index_key: &[u8]) -> Result<Vec<u8>> {
+    ordered::to_bytes(serde_bytes::Bytes::new(index_key))
+}
+
+fn entry_key(index_key: &[u8], primary_key: &[u8]) -> Result<Vec<u8>> {
+    let mut ret = entry_prefix(index_key)?;
+    ret.extend_from_slice(primary_key);
+    Ok(ret)
+}
+
+#[derive(Clone)]
+pub(crate) struct Index {
+    name: String,
+    tree: Arc<Tree>,
+    // `None` until the index is registered after a restart
+    extractor: Option<IndexExtractor>,
+}
+
+impl Index {
+    fn entries(&self, key: &[u8], value: Option<&[u8]>) -> Vec<Vec<u8>> {
+        match (value, &self.extractor) {
+            (Some(value), Some(extractor)) => {
+                let mut ret = extractor(key, value);
+                ret.sort_unstable();
+                ret.dedup();
+                ret
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// The indexes of a `Tree`, which are loaded from the
+/// `Meta` the first time that they are needed.
+#[derive(Default)]
+pub(crate) struct Indexes {
+    loaded: bool,
+    all: Vec<Index>,
+}
+
+impl Tree {
+    /// Registers an index of this `Tree`, which maps each of
+    /// the index keys returned by `extractor` for a key and
+    /// value to that key. Use `index_lookup` to find the keys
+    /// and values with a given index key.
+    ///
+    /// The index is stored in the `Db`, and is updated in the
+    /// same atomic batch as each `set`, `del`, `cas`, `merge`,
+    /// `Batch` and transaction that writes to this `Tree`.
+    /// The first time that an index is registered, it is
+    /// built from the existing contents of this `Tree`.
+    ///
+    /// Writes to an indexed `Tree` are more expensive: each
+    /// one takes the lock that transactions and batches of the
+    /// `Db` commit under, so they do not run concurrently with
+    /// each other or with those. They are recovered atomically
+    /// along with their index updates as part of a batch that
+    /// is sealed by the next flush. Until then, change feeds
+    /// and replicas, which read the log, do not see them or
+    /// any write to the `Db` that follows them.
+    ///
+    /// After a restart, indexes need to be registered again
+    /// before this `Tree` can be written to, so that they
+    /// can not fall behind. Registering an index with an
+    /// extractor that returns different index keys than the
+    /// one it was built with requires a `rebuild_index`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db, IVec};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    /// let users = db.open_tree(b"users").unwrap();
+    /// users.set(b"alice", b"paris").unwrap();
+    ///
+    /// // index users by city
+    /// users
+    ///     .register_index("city", |_k, v| vec![v.to_vec()])
+    ///     .unwrap();
+    /// users.set(b"bob", b"paris").unwrap();
+    /// users.set(b"carol", b"rome").unwrap();
+    ///
+    /// let parisians: Vec<(Vec<u8>, IVec)> = users
+    ///     .index_lookup("city", b"paris")
+    ///     .unwrap()
+    ///     .collect::<Result<_, _>>()
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     parisians,
+    ///     vec![
+    ///         (b"alice".to_vec(), IVec::from(b"paris")),
+    ///         (b"bob".to_vec(), IVec::from(b"paris")),
+    ///     ]
+    /// );
+    /// ```
+    pub fn register_index<F>(&self, name: &str, extractor: F) -> Result<()>
+    where
+        F: 'static + Fn(&[u8], &[u8]) -> Vec<Vec<u8>> + Send + Sync,
+    {
+        if self.context.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+
+        self.load_indexes()?;
+
+        let extractor: IndexExtractor = Arc::new(extractor);
+
+        // NB writers that would need this index must wait
+        // until it has been built.
+        let _pause = self.context.concurrency_control.pause();
+        let mut indexes = self.indexes.write().unwrap();
+
+        if let Some(index) = indexes.all.iter_mut().find(|i| i.name == name) {
+            index.extractor = Some(extractor);
+            return Ok(());
+        }
+
+        let tx = self.context.pagecache.begin()?;
+        let index_tree_name = index_tree_name(&self.tree_id, name);
+        let index = Index {
+            name: name.to_owned(),
+            tree: Arc::new(meta::open_tree(
+                self.context.clone(),
+                index_tree_name.clone(),
+                &tx,
+            )?),
+            extractor: Some(extractor),
+        };
+
+        // NB the index is only recorded once it is complete,
+        // so that a crash while building it leaves it out.
+        self.build_index(&index)?;
+
+        let recorded = self.context.pagecache.cas_property_in_meta(
+            &self.tree_id,
+            &format!("{}{}", INDEX_PROPERTY_PREFIX, name),
+            None,
+            Some(index_tree_name),
+            &tx,
+        )?;
+
+        if recorded.is_err() {
+            return Err(Error::Unsupported(format!(
+                "the index {:?} was concurrently registered \
+                 through another handle to this tree",
+                name
+            )));
+        }
+
+        indexes.all.push(index);
+
+        Ok(())
+    }
+
+    /// Rebuilds an index from the contents of this `Tree`,
+    /// which is needed after its extractor changes, or to
+    /// remove the entries of values that have expired.
+    pub fn rebuild_index(&self, name: &str) -> Result<()> {
+        if self.context.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+
+        let index = self.registered_index(name)?;
+
+        let _pause = self.context.concurrency_control.pause();
+
+        self.build_index(&index)
+    }
+
+    /// Removes an index of this `Tree`, returning `true`
+    /// if it existed.
+    pub fn drop_index(&self, name: &str) -> Result<bool> {
+        if self.context.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+
+        self.load_indexes()?;
+
+        let _pause = self.context.concurrency_control.pause();
+        let mut indexes = self.indexes.write().unwrap();
+
+        let idx = match indexes.all.iter().position(|i| i.name == name) {
+            Some(idx) => idx,
+            None => return Ok(false),
+        };
+        let index = indexes.all.remove(idx);
+
+        self.remove_index(&index)?;
+
+        Ok(true)
+    }
+
+    /// Returns an iterator over the keys and values of this
+    /// `Tree` that `index_key` is an index key of, in the
+    /// order of their keys.
+    pub fn index_lookup<K: AsRef<[u8]>>(
+        &self,
+        name: &str,
+        index_key: K,
+    ) -> Result<IndexIter<'_>> {
+        self.load_indexes()?;
+
+        let index = self
+            .indexes
+            .read()
+            .unwrap()
+            .all
+            .iter()
+            .find(|i| i.name == name)
+            .cloned()
+            .ok_or_else(|| {
+                Error::Unsupported(format!("no index named {:?}", name))
+            })?;
+
+        let prefix = entry_prefix(index_key.as_ref())?;
+
+        Ok(IndexIter {
+            tree: self,
+            index,
+            index_key: index_key.as_ref().to_vec(),
+            lo: Bound::Included(prefix.clone()),
+            prefix,
+        })
+    }
+
+    /// Drops every index of this `Tree`, before the
+    /// `Tree` itself is dropped.
+    pub(crate) fn drop_indexes(&self) -> Result<()> {
+        self.load_indexes()?;
+
+        let mut indexes = self.indexes.write().unwrap();
+
+        for index in indexes.all.drain(..) {
+            self.remove_index(&index)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the indexes that need to be updated when
+    /// writing to this `Tree`, or an error if any of them
+    /// has not been registered since the last restart.
+    pub(crate) fn indexes(&self) -> Result<Vec<Index>> {
+        self.load_indexes()?;
+
+        let indexes = self.indexes.read().unwrap();
+
+        if let Some(index) = indexes.all.iter().find(|i| i.extractor.is_none())
+        {
+            return Err(Error::Unsupported(format!(
+                "the index {:?} must be registered with \
+                 Tree::register_index before writing to this tree",
+                index.name
+            )));
+        }
+
+        Ok(indexes.all.clone())
+    }
+
+    /// Updates the entries of each index of this `Tree`
+    /// after the value of `key` changed from `old` to `new`.
+    /// Callers must be committing, and write in an atomic
+    /// batch, if this `Tree` has any indexes. An `old` value
+    /// that expired but was still in its leaf is passed too,
+    /// as its entries are only removed with it.
+    pub(crate) fn update_indexes(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<()> {
+        for index in self.indexes()? {
+            let old_entries = index.entries(key, old);
+            let new_entries = index.entries(key, new);
+
+            for index_key in &old_entries {
+                if new_entries.binary_search(index_key).is_err() {
+                    let entry = entry_key(index_key, key)?;
+                    index.tree.del_inner(&entry, None)?;
+                }
+            }
+
+            for index_key in &new_entries {
+                if old_entries.binary_search(index_key).is_err() {
+                    let entry = entry_key(index_key, key)?;
+                    index.tree.set_inner(&entry, IVec::from(vec![]), None)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if this `Tree` has any indexes.
+    pub(crate) fn is_indexed(&self) -> Result<bool> {
+        Ok(!self.indexes()?.is_empty())
+    }
+
+    /// Forgets the loaded indexes of this `Tree`, so that
+    /// they are loaded again with the roots in the `Meta`.
+    pub(crate) fn forget_indexes(&self) {
+        *self.indexes.write().unwrap() = Indexes::default();
+    }
+
+    /// Applies a single write of `key` to this `Tree`. If it
+    /// has any indexes, the write and the updates of its indexes
+    /// are applied as one atomic batch, and subscribers are
+    /// notified once the batch is complete.
+    pub(crate) fn write_indexed<F, R>(&self, key: &[u8], f: F) -> Result<R>
+    where
+        F: FnOnce(Option<&mut DeferredEvents>) -> Result<R>,
+    {
+        // NB a writer that misses an index which is registered
+        // concurrently finds it once it waits for the pause that
+        // registers it, and comes back here.
+        if !self.is_indexed()? {
+            return f(None);
+        }
+
+        let mut commit = self
+            .context
+            .concurrency_control
+            .commit(&self.context.pagecache)?;
+        commit.mark_keys(&[(self, key)])?;
+
+        // NB unlike a `Batch`, this does not write or sync
+        // anything to seal the write, which the next flush
+        // does for every write that joined the shared batch.
+        let shared_batch = self.context.pagecache.join_shared_batch()?;
+        let mut deferred = DeferredEvents::default();
+
+        let res = f(Some(&mut deferred));
+
+        // NB an error partway through undoes what was applied
+        let ret = deferred.undo_in_shared_batch(shared_batch, res)?;
+
+        drop(commit);
+
+        deferred.complete();
+
+        Ok(ret)
+    }
+
+    fn load_indexes(&self) -> Result<()> {
+        if self.indexes.read().unwrap().loaded {
+            return Ok(());
+        }
+
+        let mut indexes = self.indexes.write().unwrap();
+        if indexes.loaded {
+            return Ok(());
+        }
+
+        let tx = self.context.pagecache.begin()?;
+        let properties =
+            self.context.pagecache.meta(&tx)?.properties(&self.tree_id);
+
+        for (property, index_tree_name) in properties {
+            if !property.starts_with(INDEX_PROPERTY_PREFIX) {
+                continue;
+            }
+
+            let tree =
+                meta::open_tree(self.context.clone(), index_tree_name, &tx)?;
+
+            indexes.all.push(Index {
+                name: property[INDEX_PROPERTY_PREFIX.len()..].to_owned(),
+                tree: Arc::new(tree),
+                extractor: None,
+            });
+        }
+
+        indexes.loaded = true;
+
+        Ok(())
+    }
+
+    fn registered_index(&self, name: &str) -> Result<Index> {
+        self.load_indexes()?;
+
+        let indexes = self.indexes.read().unwrap();
+        match indexes.all.iter().find(|i| i.name == name) {
+            Some(index) if index.extractor.is_some() => Ok(index.clone()),
+            Some(_) => Err(Error::Unsupported(format!(
+                "the index {:?} must be registered with \
+                 Tree::register_index first",
+                name
+            ))),
+            None => {
+                Err(Error::Unsupported(format!("no index named {:?}", name)))
+            }
+        }
+    }
+
+    // Replaces the entries of `index` with ones built from
+    // the current contents of this `Tree`, in one atomic
+    // batch. Callers must pause writers.
+    fn build_index(&self, index: &Index) -> Result<()> {
+        let recovery_guard = self.context.pagecache.pin_log()?;
+
+        for key in index.tree.iter().keys() {
+            index.tree.del_inner(&key?, None)?;
+        }
+
+        for res in self.iter() {
+            let (key, value) = res?;
+            for index_key in index.entries(&key, Some(&value)) {
+                let entry = entry_key(&index_key, &key)?;
+                index.tree.set_inner(&entry, IVec::from(vec![]), None)?;
+            }
+        }
+
+        recovery_guard.seal_batch()?;
+
+        Ok(())
+    }
+
+    fn remove_index(&self, index: &Index) -> Result<()> {
+        let tx = self.context.pagecache.begin()?;
+
+        let property = format!("{}{}", INDEX_PROPERTY_PREFIX, index.name);
+        let mut current = self
+            .context
+            .pagecache
+            .meta(&tx)?
+            .get_property(&self.tree_id, &property)
+            .map(<[u8]>::to_vec);
+
+        while let Err(actual) = self.context.pagecache.cas_property_in_meta(
+            &self.tree_id,
+            &property,
+            current.as_ref().map(AsRef::as_ref),
+            None,
+            &tx,
+        )? {
+            current = actual;
+        }
+
+        let leftmost_chain = meta::remove_tree(&index.tree, &tx)?;
+        index.tree.gc_pages(leftmost_chain)?;
+
+        tx.flush();
+
+        Ok(())
+    }
+}
+
+/// An iterator over the keys and values of a `Tree` that
+/// have a given index key, created by `Tree::index_lookup`.
+pub struct IndexIter<'a> {
+    tree: &'a Tree,
+    index: Index,
+    index_key: Vec<u8>,
+    prefix: Vec<u8>,
+    lo: Bound<Vec<u8>>,
+}
+
+impl<'a> Debug for IndexIter<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "IndexIter {{ index: {:?}, index_key: {:?} }}",
+            self.index.name, self.index_key
+        )
+    }
+}
+
+impl<'a> IndexIter<'a> {
+    fn next_entry(&mut self) -> Result<Option<(Key, IVec)>> {
+        loop {
+            let bounds = (self.lo.clone(), Bound::Unbounded);
+            let entry = match self.index.tree.range(bounds).keys().next() {
+                Some(entry) => entry?,
+                None => return Ok(None),
+            };
+
+            if !entry.starts_with(&self.prefix) {
+                return Ok(None);
+            }
+
+            let key = entry[self.prefix.len()..].to_vec();
+            self.lo = Bound::Excluded(entry);
+
+            // NB the value may have changed since the entry
+            // was read, so we only return it if it still has
+            // the index key.
+            let value = match self.tree.get(&key)? {
+                Some(value) => value,
+                None => continue,
+            };
+            let matches = self.index.extractor.is_none()
+                || self
+                    .index
+                    .entries(&key, Some(&value))
+                    .contains(&self.index_key);
+
+            if matches {
+                return Ok(Some((key, value)));
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for IndexIter<'a> {
+    type Item = Result<(Key, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+#[test]
+fn expired_values_leave_no_index_entries() {
+    use std::time::{Duration, Instant};
+
+    use crate::{ConfigBuilder, Db};
+
+    fn entries(t: &Tree) -> usize {
+        t.indexes().unwrap()[0].tree.iter().count()
+    }
+
+    let short = Duration::from_millis(1);
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let db = Db::start(config).unwrap();
+    db.register_index("value", |_k, v| vec![v.to_vec()])
+        .unwrap();
+
+    // writing to an expired value removes its index entries
+    db.set_with_ttl(b"a", vec![1], short).unwrap();
+    db.set_with_ttl(b"b", vec![2], short).unwrap();
+    db.set_with_ttl(b"c", vec![3], short).unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    db.set(b"a", vec![4]).unwrap();
+    db.del(b"b").unwrap();
+    db.delete_range(b"c".to_vec()..).unwrap();
+    assert_eq!(entries(&db), 1);
+    drop(db);
+
+    // and so does sweeping it
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(Some(10))
+        .build();
+    let db = Db::start(config).unwrap();
+    db.register_index("value", |_k, v| vec![v.to_vec()])
+        .unwrap();
+
+    for i in 0..100_u8 {
+        if i % 2 == 0 {
+            db.set_with_ttl(vec![i], vec![1], short).unwrap();
+        } else {
+            db.set(vec![i], vec![2]).unwrap();
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while entries(&db) > 50 {
+        assert!(Instant::now() < deadline, "expired values were not swept");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(db.len(), 50);
+    assert_eq!(db.index_lookup("value", &[2]).unwrap().count(), 50);
+}
@@ -0,0 +1,126 @@
+//! Inspection of the on-disk structures of a database that
+//! is not in use, for debugging recovery, with the page
+//! fragments of its trees described for display.
+use pagecache::Inspection;
+
+use super::*;
+
+/// A page fragment read by `Db::inspect`, described for display.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FragInfo {
+    /// The kind of fragment, like `"Set"` or `"Base"`.
+    pub kind: String,
+    /// Its contents. The keys of fragments that are appended
+    /// to a node are prefix-encoded against its `lo` bound.
+    pub detail: String,
+}
+
+impl From<Frag> for FragInfo {
+    fn from(frag: Frag) -> FragInfo {
+        let (kind, detail) = match frag {
+            Frag::Set(k, v) => {
+                ("Set", format!("key {:?}, {} byte value", k, v.len()))
+            }
+            Frag::Del(k) => ("Del", format!("key {:?}", k)),
+            Frag::Merge(k, v) => {
+                ("Merge", format!("key {:?}, {} byte operand", k, v.len()))
+            }
+            Frag::Base(node) => ("Base", describe_node(&node)),
+            Frag::ChildSplit(ChildSplit { at, to }) => {
+                ("ChildSplit", format!("at {:?} to {}", at, to))
+            }
+            Frag::ParentSplit(ParentSplit { at, to }) => {
+                ("ParentSplit", format!("at {:?} to {}", at, to))
+            }
+            Frag::SetWithTtl(k, v, expires_at) => (
+                "SetWithTtl",
+                format!(
+                    "key {:?}, {} byte value, expires at {} ms",
+                    k,
+                    v.len(),
+                    expires_at
+                ),
+            ),
+            Frag::DelRange(start, end) => {
+                ("DelRange", format!("from {:?} to {:?}", start, end))
+            }
+            Frag::ParentMergeIntention(pid) => {
+                ("ParentMergeIntention", format!("child {}", pid))
+            }
+            Frag::ChildMergeCap => ("ChildMergeCap", String::new()),
+            Frag::LeftMerge(node) => ("LeftMerge", describe_node(&node)),
+            Frag::ParentMergeConfirm => ("ParentMergeConfirm", String::new()),
+        };
+
+        FragInfo {
+            kind: kind.to_owned(),
+            detail,
+        }
+    }
+}
+
+fn describe_node(node: &Node) -> String {
+    let mut detail = match node.data {
+        Data::Leaf(ref items) => format!("leaf with {} items", items.len()),
+        Data::Index(ref ptrs) => {
+            let children: Vec<PageId> =
+                ptrs.iter().map(|(_k, child)| *child).collect();
+            format!("index with children {:?}", children)
+        }
+    };
+    detail.push_str(&format!(
+        ", lo {:?}, hi {:?}, next {:?}",
+        node.lo, node.hi, node.next
+    ));
+    if !node.expiries.is_empty() {
+        detail.push_str(&format!(", {} expiries", node.expiries.len()));
+    }
+    if let Some(child) = node.merging_child {
+        detail.push_str(&format!(", merging child {}", child));
+    }
+    if node.merging {
+        detail.push_str(", merging");
+    }
+    detail
+}
+
+impl Db {
+    /// Reads the segments, messages and snapshot of the database
+    /// configured by `config`, which must not be in use, without
+    /// starting it or writing to any of its files. Unlike
+    /// recovery, every segment with a valid header is read, along
+    /// with every message in it up to the first one that cannot
+    /// be, so stale and torn segments can be examined too.
+    ///
+    /// This is meant for debugging, and is what the `sled-inspect`
+    /// binary prints.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db, Update};
+    ///
+    /// let path = std::env::temp_dir()
+    ///     .join(format!("sled_inspect_doc_{}", std::process::id()));
+    ///
+    /// let db = Db::start(ConfigBuilder::new().path(&path).build()).unwrap();
+    /// db.open_tree(b"users").unwrap().set(b"alice", vec![1]).unwrap();
+    /// db.flush().unwrap();
+    /// drop(db);
+    ///
+    /// let config = ConfigBuilder::new().path(&path).build();
+    /// let inspection = Db::inspect(config).unwrap();
+    ///
+    /// let (_lsn, meta) = inspection.meta().unwrap();
+    /// assert!(meta.get_root(b"users").is_some());
+    ///
+    /// assert!(inspection.messages().any(|message| match message.update {
+    ///     Some((_pid, Update::Append(ref frag))) => frag.kind == "Set",
+    ///     _ => false,
+    /// }));
+    /// # std::fs::remove_dir_all(path).unwrap();
+    /// ```
+    pub fn inspect(config: Config) -> Result<Inspection<FragInfo>> {
+        pagecache::inspect::<Frag, FragInfo, _>(&config, FragInfo::from)
+    }
+}
@@ -0,0 +1,166 @@
+//! Verification of the structure of the trees in a `Db`,
+//! for `Db::verify_integrity`.
+//!
+//! Every level of a tree is walked from its leftmost node
+//! along the `next` pointers. The `lo` bound of each node
+//! must be the `hi` bound of its left sibling, only the last
+//! node of a level may be unbounded, and the keys of a node
+//! must be sorted and within its bounds. The children of an
+//! index must begin at the keys that point to them.
+use pagecache::{FastSet8, IntegrityProblem, IntegrityReport};
+
+use super::*;
+
+/// Checks the tree named `tree_id` with the root `root`,
+/// adding the nodes checked and the problems found to `report`.
+pub(crate) fn verify_tree(
+    context: &Context,
+    tree_id: &[u8],
+    root: PageId,
+    report: &mut IntegrityReport,
+    tx: &Tx,
+) -> Result<()> {
+    let mut problems = vec![];
+    let mut invalid = |pid, reason: String| {
+        problems.push(IntegrityProblem::InvalidNode {
+            tree: tree_id.to_vec(),
+            pid,
+            reason,
+        })
+    };
+
+    let mut visited = FastSet8::default();
+    let mut level = Some(root);
+
+    while let Some(leftmost) = level.take() {
+        let mut pid = leftmost;
+        let mut expected_lo = IVec::from(vec![]);
+        let mut is_leaf = None;
+
+        loop {
+            if !visited.insert(pid) {
+                invalid(pid, "was reached more than once".to_owned());
+                break;
+            }
+
+            let node = match context.pagecache.get(pid, tx)? {
+                PageGet::Materialized(frag, _ptr) => frag.unwrap_base(),
+                other => {
+                    invalid(pid, format!("is not a node: {:?}", other));
+                    break;
+                }
+            };
+            report.nodes += 1;
+
+            if node.lo != expected_lo {
+                invalid(
+                    pid,
+                    format!(
+                        "has lo {:?}, but {:?} was expected",
+                        node.lo, expected_lo
+                    ),
+                );
+            }
+            if !node.hi.is_empty() && node.hi <= node.lo {
+                invalid(
+                    pid,
+                    format!("has hi {:?} <= lo {:?}", node.hi, node.lo),
+                );
+            }
+
+            let node_is_leaf = node.data.leaf_ref().is_some();
+            if *is_leaf.get_or_insert(node_is_leaf) != node_is_leaf {
+                invalid(pid, "is on the same level as other kinds".to_owned());
+            }
+
+            let keys: Vec<Key> = match node.data {
+                Data::Leaf(ref items) => items
+                    .iter()
+                    .map(|(k, _v)| prefix_decode(&node.lo, k))
+                    .collect(),
+                Data::Index(ref ptrs) => ptrs
+                    .iter()
+                    .map(|(k, _child)| prefix_decode(&node.lo, k))
+                    .collect(),
+            };
+
+            if keys.windows(2).any(|w| w[0] >= w[1]) {
+                invalid(pid, "has keys out of order".to_owned());
+            }
+            if keys.iter().any(|k| {
+                **k < *node.lo || (!node.hi.is_empty() && **k >= *node.hi)
+            }) {
+                invalid(pid, "has keys outside of its bounds".to_owned());
+            }
+
+            if let Data::Index(ref ptrs) = node.data {
+                if ptrs.is_empty() {
+                    invalid(pid, "is an index without children".to_owned());
+                }
+                if pid == leftmost {
+                    level = ptrs.first().map(|(_k, child)| *child);
+                }
+                for (key, (_k, child)) in keys.iter().zip(ptrs.iter()) {
+                    if let Some(reason) =
+                        verify_child(context, pid, key, *child, tx)?
+                    {
+                        invalid(*child, reason);
+                    }
+                }
+            }
+
+            match (node.next, node.hi.is_empty()) {
+                (Some(next), false) => {
+                    expected_lo = node.hi.clone();
+                    pid = next;
+                }
+                (None, true) => break,
+                (Some(_next), true) => {
+                    invalid(pid, "is unbounded, but has a sibling".to_owned());
+                    break;
+                }
+                (None, false) => {
+                    invalid(pid, "is bounded, but has no sibling".to_owned());
+                    break;
+                }
+            }
+        }
+    }
+
+    report.problems.extend(problems);
+
+    Ok(())
+}
+
+// Returns why `child` does not begin at the key that points
+// to it from `parent`, if it does not.
+fn verify_child(
+    context: &Context,
+    parent: PageId,
+    key: &[u8],
+    child: PageId,
+    tx: &Tx,
+) -> Result<Option<String>> {
+    let reason = match context.pagecache.get(child, tx)? {
+        PageGet::Materialized(frag, _ptr) => {
+            let lo = &frag.unwrap_base().lo;
+            if **lo == *key {
+                return Ok(None);
+            }
+            format!("has lo {:?}, but its parent points to it at {:?}", lo, key)
+        }
+        other => format!("is not a node: {:?}", other),
+    };
+
+    // NB merges are completed by readers too, so the parent
+    // may have stopped pointing to the child since it was read.
+    let still_linked = match context.pagecache.get(parent, tx)? {
+        PageGet::Materialized(frag, _ptr) => match frag.unwrap_base().data {
+            Data::Index(ref ptrs) => ptrs.iter().any(|(_k, c)| *c == child),
+            Data::Leaf(_) => false,
+        },
+        _ => false,
+    };
+
+    Ok(if still_linked { Some(reason) } else { None })
+}
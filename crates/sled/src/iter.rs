@@ -1,10 +1,13 @@
 use std::ops;
 
-use pagecache::{Measure, M};
+use pagecache::Measure;
 
 use super::*;
 
-fn lower_bound_includes<'a>(lb: &ops::Bound<Vec<u8>>, item: &'a [u8]) -> bool {
+pub(crate) fn lower_bound_includes(
+    lb: &ops::Bound<Vec<u8>>,
+    item: &[u8],
+) -> bool {
     match lb {
         ops::Bound::Included(ref start) => start.as_slice() <= item,
         ops::Bound::Excluded(ref start) => start.as_slice() < item,
@@ -12,7 +15,10 @@ fn lower_bound_includes<'a>(lb: &ops::Bound<Vec<u8>>, item: &'a [u8]) -> bool {
     }
 }
 
-fn upper_bound_includes<'a>(ub: &ops::Bound<Vec<u8>>, item: &'a [u8]) -> bool {
+pub(crate) fn upper_bound_includes(
+    ub: &ops::Bound<Vec<u8>>,
+    item: &[u8],
+) -> bool {
     match ub {
         ops::Bound::Included(ref end) => item <= end.as_ref(),
         ops::Bound::Excluded(ref end) => item < end.as_ref(),
@@ -50,7 +56,7 @@ impl<'a> Iterator for Iter<'a> {
     type Item = Result<(Vec<u8>, IVec)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let _measure = Measure::new(&M.tree_scan);
+        let _measure = Measure::new(&self.tree.context.metrics.tree_scan);
 
         if self.done {
             return None;
@@ -81,8 +87,14 @@ impl<'a> Iterator for Iter<'a> {
             );
 
             if self.last_id.is_none() {
-                // initialize iterator based on valid bound
-                let path_res = self.tree.path_for_key(start, &self.tx);
+                // initialize iterator based on valid bound, or
+                // find the leaf of our last key again if the
+                // one we were on was merged away.
+                let seek_key = match self.last_key {
+                    Some(ref last_key) => last_key.as_ref(),
+                    None => start,
+                };
+                let path_res = self.tree.path_for_key(seek_key, &self.tx);
                 if let Err(e) = path_res {
                     error!("iteration failed: {:?}", e);
                     self.done = true;
@@ -104,25 +116,27 @@ impl<'a> Iterator for Iter<'a> {
                 ops::Bound::Excluded(..) => false,
             };
 
-            let res = self
-                .tree
-                .context
-                .pagecache
-                .get(last_id, &self.tx)
-                .map(|page_get| page_get.unwrap());
-
-            if let Err(e) = res {
-                error!("iteration failed: {:?}", e);
-                self.done = true;
-                return Some(Err(e));
-            }
-
-            // TODO (when implementing merge support) this could
-            // be None if the node was removed since the last
-            // iteration, and we need to just get the inner
-            // node again...
-            let (frag, _ptr) = res.unwrap();
-            let node = frag.unwrap_base();
+            let node = match self.tree.context.pagecache.get(last_id, &self.tx)
+            {
+                Ok(PageGet::Materialized(frag, _ptr))
+                    if !frag.unwrap_base().merging =>
+                {
+                    frag.unwrap_base()
+                }
+                Ok(_) => {
+                    // the node was merged into its left sibling
+                    // since the last iteration, and seeking to our
+                    // last key again helps to complete the merge.
+                    self.last_id = None;
+                    spins = 0;
+                    continue;
+                }
+                Err(e) => {
+                    error!("iteration failed: {:?}", e);
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
             let leaf = node.data.leaf_ref().expect("node should be a leaf");
             let prefix = &node.lo;
 
@@ -161,6 +175,12 @@ impl<'a> Iterator for Iter<'a> {
 
                 self.last_key = Some(decoded_k.clone());
 
+                if node.is_expired(&decoded_k) {
+                    // skip values whose TTL has passed
+                    spins = 0;
+                    continue;
+                }
+
                 let ret = Ok((decoded_k, v.clone()));
                 return Some(ret);
             }
@@ -192,7 +212,8 @@ impl<'a> Iterator for Iter<'a> {
 
 impl<'a> DoubleEndedIterator for Iter<'a> {
     fn next_back(&mut self) -> Option<Self::Item> {
-        let _measure = Measure::new(&M.tree_reverse_scan);
+        let _measure =
+            Measure::new(&self.tree.context.metrics.tree_reverse_scan);
 
         if self.done {
             return None;
@@ -234,9 +255,14 @@ impl<'a> DoubleEndedIterator for Iter<'a> {
             );
 
             if self.last_id.is_none() {
-                // initialize iterator based on valid bound
-
-                let path_res = self.tree.path_for_key(end, &self.tx);
+                // initialize iterator based on valid bound, or
+                // find the leaf of our last key again if the
+                // one we were on was merged away.
+                let seek_key = match self.last_key {
+                    Some(ref last_key) => last_key.as_ref(),
+                    None => end,
+                };
+                let path_res = self.tree.path_for_key(seek_key, &self.tx);
                 if let Err(e) = path_res {
                     error!("iteration failed: {:?}", e);
                     self.done = true;
@@ -250,22 +276,30 @@ impl<'a> DoubleEndedIterator for Iter<'a> {
                 let mut last_node = last_frag.unwrap_base();
 
                 // (when hi is empty, it means it's the rightmost node)
-                while unbounded && !last_node.hi.is_empty() {
+                while unbounded
+                    && self.last_key.is_none()
+                    && !last_node.hi.is_empty()
+                {
                     // if we're unbounded, scan to the end
                     let res = self
                         .tree
                         .context
                         .pagecache
-                        .get(last_node.next.unwrap(), &self.tx)
-                        .map(|page_get| page_get.unwrap());
-
-                    if let Err(e) = res {
-                        error!("iteration failed: {:?}", e);
-                        self.done = true;
-                        return Some(Err(e));
-                    }
-                    let (frag, _ptr) = res.unwrap();
-                    last_node = frag.unwrap_base();
+                        .get(last_node.next.unwrap(), &self.tx);
+
+                    last_node = match res {
+                        Ok(PageGet::Materialized(frag, _ptr)) => {
+                            frag.unwrap_base()
+                        }
+                        // the node was merged into its left sibling,
+                        // which already holds the keys we look for.
+                        Ok(_) => break,
+                        Err(e) => {
+                            error!("iteration failed: {:?}", e);
+                            self.done = true;
+                            return Some(Err(e));
+                        }
+                    };
                 }
 
                 self.last_id = Some(*last_id);
@@ -278,25 +312,27 @@ impl<'a> DoubleEndedIterator for Iter<'a> {
                 ops::Bound::Excluded(..) => false,
             };
 
-            let res = self
-                .tree
-                .context
-                .pagecache
-                .get(last_id, &self.tx)
-                .map(|page_get| page_get.unwrap());
-
-            if let Err(e) = res {
-                error!("iteration failed: {:?}", e);
-                self.done = true;
-                return Some(Err(e));
-            }
-
-            // TODO (when implementing merge support) this could
-            // be None if the node was removed since the last
-            // iteration, and we need to just get the inner
-            // node again...
-            let (frag, _ptr) = res.unwrap();
-            let node = frag.unwrap_base();
+            let node = match self.tree.context.pagecache.get(last_id, &self.tx)
+            {
+                Ok(PageGet::Materialized(frag, _ptr))
+                    if !frag.unwrap_base().merging =>
+                {
+                    frag.unwrap_base()
+                }
+                Ok(_) => {
+                    // the node was merged into its left sibling
+                    // since the last iteration, and seeking to our
+                    // last key again helps to complete the merge.
+                    self.last_id = None;
+                    spins = 0;
+                    continue;
+                }
+                Err(e) => {
+                    error!("iteration failed: {:?}", e);
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
             let leaf = node.data.leaf_ref().expect("node should be a leaf");
             let prefix = &node.lo;
             let mut split_detected = false;
@@ -349,6 +385,12 @@ impl<'a> DoubleEndedIterator for Iter<'a> {
 
                 self.last_key = Some(decoded_k.clone());
 
+                if node.is_expired(&decoded_k) {
+                    // skip values whose TTL has passed
+                    spins = 0;
+                    continue;
+                }
+
                 let ret = Ok((decoded_k, v.clone()));
                 return Some(ret);
             }
@@ -381,6 +423,8 @@ impl<'a> DoubleEndedIterator for Iter<'a> {
                 }
             };
 
+            let mut merged_away = false;
+
             // If we did not detect a split, we need to
             // seek until the node that points to our last one.
             // If we detected a split, we need to seek until
@@ -390,23 +434,39 @@ impl<'a> DoubleEndedIterator for Iter<'a> {
                 && next_node.lo < node.lo)
                 || (split_detected && *next_node.hi < *split_key)
             {
-                let res = self
-                    .tree
-                    .context
-                    .pagecache
-                    .get(next_node.next?, &self.tx)
-                    .map(|page_get| page_get.unwrap());
-
-                if let Err(e) = res {
-                    error!("iteration failed: {:?}", e);
-                    self.done = true;
-                    return Some(Err(e));
-                }
-                let (frag, _ptr) = res.unwrap();
-                next_id = next_node.next.unwrap();
+                let next = match next_node.next {
+                    Some(next) => next,
+                    // the rightmost node took over the keys
+                    // of the one we were looking for.
+                    None => break,
+                };
+
+                let res = self.tree.context.pagecache.get(next, &self.tx);
+
+                let frag = match res {
+                    Ok(PageGet::Materialized(frag, _ptr)) => frag,
+                    Ok(_) => {
+                        // the node was merged into its left sibling
+                        // since we read the pointer to it.
+                        merged_away = true;
+                        break;
+                    }
+                    Err(e) => {
+                        error!("iteration failed: {:?}", e);
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                };
+                next_id = next;
                 next_node = frag.unwrap_base();
             }
 
+            if merged_away {
+                self.last_id = None;
+                spins = 0;
+                continue;
+            }
+
             if split_detected && next_node.data.is_empty() {
                 // we want to mark this node's lo key
                 // as our last key to prevent infinite
@@ -415,7 +475,14 @@ impl<'a> DoubleEndedIterator for Iter<'a> {
                 self.last_key = Some(next_node.lo.to_vec());
             }
 
-            if !split_detected {
+            // NB the node may have been merged into the one we
+            // seeked to, and we must not skip past the keys that
+            // it took over.
+            let lowered = match self.last_key {
+                Some(ref last_key) => node.lo.as_ref() < last_key.as_slice(),
+                None => true,
+            };
+            if !split_detected && lowered {
                 self.last_key = Some(node.lo.to_vec());
             }
 
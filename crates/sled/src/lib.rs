@@ -33,32 +33,73 @@
 #![cfg_attr(test, deny(clippy::rust_2018_compatibility))]
 #![cfg_attr(test, deny(clippy::rust_2018_idioms))]
 
+mod batch;
 mod binary_search;
+mod bulk_load;
+mod cdc;
+mod concurrency_control;
 mod context;
+mod cursor;
 mod data;
 mod db;
+mod expiry;
+mod export;
 mod flusher;
 mod frag;
+mod index;
+mod inspect;
+mod integrity;
 mod iter;
 mod ivec;
 mod materializer;
+mod merge;
 mod meta;
 mod node;
+mod node_merge;
+mod ordered;
+mod par_iter;
 mod prefix;
+mod replication;
+mod salvage;
+mod snapshot;
+mod stats;
 mod subscription;
+mod transaction;
 mod tree;
+mod typed;
 
 const DEFAULT_TREE_ID: &[u8] = b"__sled__default";
 
 pub use {
     self::{
+        batch::Batch,
+        cdc::{Change, ChangeEvent, ChangeFeed, ChangeFeedIter},
+        cursor::Cursor,
         db::Db,
+        export::{Export, ExportReader, ExportRecord},
+        index::{IndexExtractor, IndexIter},
+        inspect::FragInfo,
         iter::Iter,
         ivec::IVec,
-        subscription::{Event, Subscriber},
+        merge::MergeOperator,
+        par_iter::ParIter,
+        replication::{ChannelTransport, Primary, Replica, Transport},
+        salvage::{KeyRange, SalvageReport, SalvagedTree},
+        snapshot::{Snapshot, SnapshotIter, SnapshotTree},
+        subscription::{Backpressure, Event, Subscriber},
+        transaction::{TransactionError, TransactionResult, TransactionalTree},
         tree::Tree,
+        typed::{
+            Bincode, Codec, Ordered, TypedEvent, TypedIter, TypedSubscriber,
+            TypedTree,
+        },
+    },
+    pagecache::{
+        Config, ConfigBuilder, Error, FlushFuture, HistogramSnapshot,
+        Inspection, IntegrityProblem, IntegrityReport, Lsn, MessageInfo,
+        MessageKind, MetricUnit, MetricsSnapshot, Result, SegmentInfo,
+        SnapshotInfo, StorageStats, Update,
     },
-    pagecache::{Config, ConfigBuilder, Error, Result},
 };
 
 use {
@@ -68,21 +109,25 @@ use {
         },
         context::Context,
         data::Data,
+        db::Tenants,
         frag::{ChildSplit, Frag, ParentSplit},
+        index::{is_index_tree, Indexes},
         materializer::BLinkMaterializer,
         node::Node,
         prefix::{
             prefix_cmp, prefix_cmp_encoded, prefix_decode, prefix_encode,
-            prefix_reencode,
+            prefix_reencode, prefix_successor,
         },
-        subscription::Subscriptions,
+        subscription::{DeferredEvents, Subscriptions},
+        tree::half_open_bounds,
+        typed::CODEC_PROPERTY,
     },
     log::{debug, error, trace},
     pagecache::{
-        debug_delay, Materializer, Measure, MergeOperator, PageCache, PageGet,
-        PageId, Tx, M,
+        debug_delay, Materializer, Measure, MergeOperatorFn, PageCache,
+        PageGet, PageId, Tx,
     },
-    serde::{Deserialize, Serialize},
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
 };
 
 type Key = Vec<u8>;
@@ -18,8 +18,11 @@ impl Materializer for BLinkMaterializer {
         match possible_base {
             Frag::Base(ref base_node_ref) => {
                 let mut base_node = base_node_ref.clone();
+                // NB expired values are kept until they are swept,
+                // which also removes them from the indexes of
+                // their tree.
                 for frag in frag_iter {
-                    base_node.apply(frag, config.merge_operator);
+                    base_node.apply(frag, config.merge_operator_fn);
                 }
 
                 Frag::Base(base_node)
@@ -30,8 +33,10 @@ impl Materializer for BLinkMaterializer {
 
     fn size_in_bytes(frag: &Frag) -> u64 {
         match *frag {
-            Frag::Base(ref node) => (std::mem::size_of::<Frag>() as u64)
-                .saturating_add(node.size_in_bytes()),
+            Frag::Base(ref node) | Frag::LeftMerge(ref node) => {
+                (std::mem::size_of::<Frag>() as u64)
+                    .saturating_add(node.size_in_bytes())
+            }
             _ => std::mem::size_of::<Frag>() as u64,
         }
     }
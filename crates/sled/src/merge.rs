@@ -0,0 +1,171 @@
+//! Merge operators, which are registered with a `Db` by name.
+//!
+//! A `Tree` records the name of its merge operator in the
+//! `Meta`, so that after a restart it can not be merged into
+//! until an operator has been registered under that name
+//! again. Merges are applied when they are written, and are
+//! logged as the value that the operator returned, so pages
+//! can be consolidated and replayed without the operator.
+use std::{collections::HashMap, sync::Arc};
+
+use super::*;
+
+/// Combines the current value of a key, if it has one, with
+/// the value passed to `Tree::merge`, returning the new value
+/// of the key, or `None` to remove it.
+pub type MergeOperator =
+    Arc<dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+pub(crate) const MERGE_OPERATOR_PROPERTY: &str = "merge_operator";
+
+/// The name that the operator of the deprecated
+/// `ConfigBuilder::merge_operator` is registered under, which
+/// is used by trees that never set one of their own.
+pub(crate) const CONFIG_MERGE_OPERATOR: &str =
+    "sled::ConfigBuilder::merge_operator";
+
+/// Returns the merge operators that a `Db` starts with, which
+/// is the one of the deprecated `ConfigBuilder::merge_operator`
+/// if it was set.
+pub(crate) fn config_merge_operators(
+    config: &Config,
+) -> HashMap<String, MergeOperator> {
+    let mut merge_operators = HashMap::new();
+
+    if let Some(merge_fn) = config.merge_operator_fn {
+        let merge_operator: MergeOperator = Arc::new(merge_fn.0);
+        merge_operators
+            .insert(CONFIG_MERGE_OPERATOR.to_owned(), merge_operator);
+    }
+
+    merge_operators
+}
+
+impl Tree {
+    /// Sets the merge operator that `merge` uses for this
+    /// `Tree`, registering it with the `Db` under `name`. Any
+    /// operator that was registered under the same name before
+    /// is replaced, for every `Tree` that uses it.
+    ///
+    /// The name is recorded in the `Db`, and after a restart
+    /// this `Tree` can only be opened with `Db::open_tree` once
+    /// an operator has been registered under that name again
+    /// with `Db::register_merge_operator`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use sled::{ConfigBuilder, Db, IVec};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    /// let counters = db.open_tree(b"counters").unwrap();
+    ///
+    /// counters
+    ///     .set_merge_operator(
+    ///         "add",
+    ///         Arc::new(|_k, old: Option<&[u8]>, n: &[u8]| {
+    ///             Some(vec![old.map_or(0, |old| old[0]) + n[0]])
+    ///         }),
+    ///     )
+    ///     .unwrap();
+    ///
+    /// counters.merge(b"hits", vec![2]).unwrap();
+    /// counters.merge(b"hits", vec![3]).unwrap();
+    /// assert_eq!(counters.get(b"hits"), Ok(Some(IVec::from(vec![5]))));
+    /// ```
+    pub fn set_merge_operator(
+        &self,
+        name: &str,
+        merge_operator: MergeOperator,
+    ) -> Result<()> {
+        if self.context.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+
+        self.context
+            .merge_operators
+            .write()
+            .unwrap()
+            .insert(name.to_owned(), merge_operator);
+
+        let tx = self.context.pagecache.begin()?;
+
+        let mut current = self
+            .context
+            .pagecache
+            .meta(&tx)?
+            .get_property(&self.tree_id, MERGE_OPERATOR_PROPERTY)
+            .map(<[u8]>::to_vec);
+
+        while let Err(actual) = self.context.pagecache.cas_property_in_meta(
+            &self.tree_id,
+            MERGE_OPERATOR_PROPERTY,
+            current.as_ref().map(AsRef::as_ref),
+            Some(name.as_bytes().to_vec()),
+            &tx,
+        )? {
+            current = actual;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the merge operator of this `Tree`, or an error
+    /// if it has none, or if the one that it recorded has not
+    /// been registered with the `Db` since the last restart.
+    /// A `Tree` that never set one falls back to the operator
+    /// of the deprecated `ConfigBuilder::merge_operator`.
+    pub(crate) fn merge_operator(&self) -> Result<MergeOperator> {
+        let merge_operators = self.context.merge_operators.read().unwrap();
+
+        let name = match self.merge_operator_name()? {
+            Some(name) => name,
+            None => {
+                return merge_operators
+                    .get(CONFIG_MERGE_OPERATOR)
+                    .cloned()
+                    .ok_or_else(|| {
+                        Error::Unsupported(
+                            "must set a merge operator with \
+                             Tree::set_merge_operator before calling merge"
+                                .to_owned(),
+                        )
+                    });
+            }
+        };
+
+        merge_operators.get(&name).cloned().ok_or_else(|| {
+            Error::Unsupported(format!(
+                "tree {:?} uses the merge operator {:?}, which \
+                 must be registered with Db::register_merge_operator \
+                 before the tree is used",
+                String::from_utf8_lossy(&self.tree_id),
+                name,
+            ))
+        })
+    }
+
+    /// Returns an error if this `Tree` recorded a merge operator
+    /// that has not been registered with the `Db`.
+    pub(crate) fn verify_merge_operator(&self) -> Result<()> {
+        if self.merge_operator_name()?.is_some() {
+            self.merge_operator()?;
+        }
+
+        Ok(())
+    }
+
+    fn merge_operator_name(&self) -> Result<Option<String>> {
+        let tx = self.context.pagecache.begin()?;
+        let meta = self.context.pagecache.meta(&tx)?;
+
+        Ok(meta
+            .get_property(&self.tree_id, MERGE_OPERATOR_PROPERTY)
+            .map(|name| String::from_utf8_lossy(name).into_owned()))
+    }
+}
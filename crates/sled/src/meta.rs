@@ -1,4 +1,7 @@
-use std::sync::{atomic::AtomicU64, Arc};
+use std::sync::{
+    atomic::{AtomicU64, Ordering::SeqCst},
+    Arc,
+};
 
 use super::*;
 
@@ -19,6 +22,7 @@ pub(crate) fn open_tree<'a>(
                     context: context.clone(),
                     subscriptions: Arc::new(Subscriptions::default()),
                     root: Arc::new(AtomicU64::new(root_id)),
+                    indexes: Arc::default(),
                 });
             }
             Err(Error::CollectionNotFound(_)) => {}
@@ -31,6 +35,9 @@ pub(crate) fn open_tree<'a>(
             next: None,
             lo: vec![].into(),
             hi: vec![].into(),
+            expiries: vec![],
+            merging_child: None,
+            merging: false,
         });
 
         let (leaf_id, leaf_ptr) = context.pagecache.allocate(leaf, &tx)?;
@@ -51,6 +58,9 @@ pub(crate) fn open_tree<'a>(
             next: None,
             lo: vec![].into(),
             hi: vec![].into(),
+            expiries: vec![],
+            merging_child: None,
+            merging: false,
         });
 
         let (root_id, root_ptr) = context.pagecache.allocate(root, &tx)?;
@@ -83,6 +93,42 @@ pub(crate) fn open_tree<'a>(
             subscriptions: Arc::new(Subscriptions::default()),
             context: context.clone(),
             root: Arc::new(AtomicU64::new(root_id)),
+            indexes: Arc::default(),
         });
     }
 }
+
+/// Removes a tree from the `Meta`, and returns the pages on its
+/// leftmost path, which should be passed to `Tree::gc_pages` to
+/// free the whole tree once it can not be opened again.
+pub(crate) fn remove_tree(tree: &Tree, tx: &Tx) -> Result<Vec<PageId>> {
+    let context = &tree.context;
+    let name = &tree.tree_id;
+
+    let mut root_id = Some(context.pagecache.meta_pid_for_name(name, tx)?);
+
+    let leftmost_chain: Vec<PageId> = tree
+        .path_for_key(b"", tx)?
+        .into_iter()
+        .map(|(id, _frag, _tp)| id)
+        .collect();
+
+    loop {
+        let res = context.pagecache.cas_root_in_meta(
+            name.clone(),
+            root_id,
+            None,
+            tx,
+        )?;
+
+        if let Err(actual_root) = res {
+            root_id = actual_root;
+        } else {
+            break;
+        }
+    }
+
+    tree.root.store(std::u64::MAX, SeqCst);
+
+    Ok(leftmost_chain)
+}
@@ -8,6 +8,16 @@ pub(crate) struct Node {
     pub(crate) next: Option<PageId>,
     pub(crate) lo: IVec,
     pub(crate) hi: IVec,
+    /// The keys of a leaf that were set with a TTL, sorted,
+    /// along with the time at which they expire, in
+    /// milliseconds since the unix epoch.
+    pub(crate) expiries: Vec<(IVec, u64)>,
+    /// The child of an index that is being merged into
+    /// its left sibling, if any.
+    pub(crate) merging_child: Option<PageId>,
+    /// Whether this node is being merged into its left
+    /// sibling, after which it must not be written to.
+    pub(crate) merging: bool,
 }
 
 impl Node {
@@ -17,14 +27,23 @@ impl Node {
         let lo_sz = self.lo.size_in_bytes();
         let hi_sz = self.hi.size_in_bytes();
         let data_sz = self.data.size_in_bytes();
+        let expiries_sz = self.expiries.iter().fold(0_u64, |sz, (k, _at)| {
+            sz.saturating_add(k.size_in_bytes())
+                .saturating_add(size_of::<u64>() as u64)
+        });
 
         self_sz
             .saturating_add(lo_sz)
             .saturating_add(hi_sz)
             .saturating_add(data_sz)
+            .saturating_add(expiries_sz)
     }
 
-    pub(crate) fn apply(&mut self, frag: &Frag, merge_operator: Option<usize>) {
+    pub(crate) fn apply(
+        &mut self,
+        frag: &Frag,
+        merge_operator: Option<MergeOperatorFn>,
+    ) {
         use self::Frag::*;
 
         match *frag {
@@ -35,6 +54,19 @@ impl Node {
                         == std::cmp::Ordering::Less
                 {
                     self.set_leaf(k.clone(), v.clone());
+                    self.set_expiry(k, None);
+                } else {
+                    panic!("tried to consolidate set at key <= hi")
+                }
+            }
+            SetWithTtl(ref k, ref v, expires_at) => {
+                // (when hi is empty, it means it's unbounded)
+                if self.hi.is_empty()
+                    || prefix_cmp_encoded(k, &self.hi, &self.lo)
+                        == std::cmp::Ordering::Less
+                {
+                    self.set_leaf(k.clone(), v.clone());
+                    self.set_expiry(k, Some(expires_at));
                 } else {
                     panic!("tried to consolidate set at key <= hi")
                 }
@@ -45,12 +77,28 @@ impl Node {
                     || prefix_cmp_encoded(k, &self.hi, &self.lo)
                         == std::cmp::Ordering::Less
                 {
-                    let merge_fn_ptr =
-                        merge_operator.expect("must have a merge operator set");
-                    unsafe {
-                        let merge_fn: MergeOperator =
-                            std::mem::transmute(merge_fn_ptr);
-                        self.merge_leaf(k.clone(), v.clone(), merge_fn);
+                    // merging into an expired value starts over
+                    // from no value, without a TTL.
+                    let has_expiries = !self.expiries.is_empty();
+                    if has_expiries
+                        && self.is_expired(&prefix_decode(&self.lo, k))
+                    {
+                        self.del_leaf(k);
+                        self.set_expiry(k, None);
+                    }
+
+                    // NB merges were only logged as operands with the
+                    // operator of the deprecated config option, and
+                    // `Context::start` refuses to recover a system
+                    // that logged them without it.
+                    let merge_fn = merge_operator.expect(
+                        "merges are only recovered with the merge \
+                         operator that they were logged with",
+                    );
+                    self.merge_leaf(k.clone(), v.clone(), merge_fn.0);
+
+                    if has_expiries && self.leaf_value(k).is_none() {
+                        self.set_expiry(k, None);
                     }
                 } else {
                     panic!("tried to consolidate set at key <= hi")
@@ -69,10 +117,30 @@ impl Node {
                         == std::cmp::Ordering::Less
                 {
                     self.del_leaf(k);
+                    self.set_expiry(k, None);
                 } else {
                     panic!("tried to consolidate del at key <= hi")
                 }
             }
+            DelRange(ref start, ref end) => {
+                self.del_range(start, end.as_ref());
+            }
+            ParentMergeIntention(pid) => {
+                assert!(
+                    self.merging_child.is_none(),
+                    "tried to merge two children of an index at once"
+                );
+                self.merging_child = Some(pid);
+            }
+            ChildMergeCap => {
+                self.merging = true;
+            }
+            LeftMerge(ref rhs) => {
+                self.receive_merge(rhs);
+            }
+            ParentMergeConfirm => {
+                self.parent_merge_confirm();
+            }
             Base(_) => panic!("encountered base page in middle of chain"),
         }
     }
@@ -89,11 +157,125 @@ impl Node {
         }
     }
 
+    // Records when the value of the prefix-encoded `key`
+    // expires, or that it never does.
+    fn set_expiry(&mut self, key: &[u8], expires_at: Option<u64>) {
+        if self.expiries.is_empty() && expires_at.is_none() {
+            return;
+        }
+
+        let key = IVec::from(prefix_decode(&self.lo, key));
+        let search = self.expiries.binary_search_by(|(k, _)| k.cmp(&key));
+        match (search, expires_at) {
+            (Ok(idx), Some(at)) => self.expiries[idx].1 = at,
+            (Err(idx), Some(at)) => self.expiries.insert(idx, (key, at)),
+            (Ok(idx), None) => {
+                self.expiries.remove(idx);
+            }
+            (Err(_), None) => {}
+        }
+    }
+
+    /// Returns `true` if the value of the (decoded) `key`
+    /// was set with a TTL that has passed.
+    pub(crate) fn is_expired(&self, key: &[u8]) -> bool {
+        match self.expires_at(key) {
+            Some(at) => at <= expiry::now_millis(),
+            None => false,
+        }
+    }
+
+    /// Returns the time at which the value of the (decoded)
+    /// `key` expires, if it was set with a TTL.
+    pub(crate) fn expires_at(&self, key: &[u8]) -> Option<u64> {
+        if self.expiries.is_empty() {
+            return None;
+        }
+
+        self.expiries
+            .binary_search_by(|(k, _)| (**k).cmp(key))
+            .ok()
+            .map(|idx| self.expiries[idx].1)
+    }
+
+    /// The value of the (decoded) `key` if it has expired,
+    /// but has not been removed from this leaf yet.
+    pub(crate) fn expired_value(&self, key: &[u8]) -> Option<&IVec> {
+        if !self.is_expired(key) {
+            return None;
+        }
+
+        self.leaf_value(&prefix_encode(&self.lo, key))
+    }
+
+    /// The earliest time at which a value of this
+    /// node expires, if any of them were set with a TTL.
+    pub(crate) fn next_expiry(&self) -> Option<u64> {
+        self.expiries.iter().map(|(_k, at)| *at).min()
+    }
+
+    /// Removes every value that expired at or before `now`.
+    pub(crate) fn drop_expired(&mut self, now: u64) {
+        if self.expiries.iter().all(|(_k, at)| *at > now) {
+            return;
+        }
+
+        let (expired, live) =
+            self.expiries.drain(..).partition(|(_k, at)| *at <= now);
+        self.expiries = live;
+
+        for (key, _at) in expired {
+            let encoded_key = prefix_encode(&self.lo, &key);
+            self.del_leaf(&encoded_key);
+        }
+    }
+
+    /// Removes every value whose (decoded) key is at least
+    /// `start`, and below `end` if there is one.
+    pub(crate) fn del_range(&mut self, start: &[u8], end: Option<&IVec>) {
+        let lo = &self.lo;
+        let in_range = |k: &[u8]| {
+            let below_end = match end {
+                Some(end) => {
+                    prefix_cmp_encoded(k, end, lo) == std::cmp::Ordering::Less
+                }
+                None => true,
+            };
+            prefix_cmp_encoded(k, start, lo) != std::cmp::Ordering::Less
+                && below_end
+        };
+
+        if let Data::Leaf(ref mut records) = self.data {
+            records.retain(|(k, _)| !in_range(k));
+        } else {
+            panic!("tried to DelRange from an index");
+        }
+
+        self.expiries.retain(|(k, _)| {
+            &**k < start
+                || match end {
+                    Some(end) => k >= end,
+                    None => false,
+                }
+        });
+    }
+
+    /// The value of the prefix-encoded `key` in this leaf,
+    /// even if it has expired.
+    pub(crate) fn leaf_value(&self, key: &[u8]) -> Option<&IVec> {
+        let records = self.data.leaf_ref()?;
+        records
+            .binary_search_by(|(k, _)| prefix_cmp(k, key))
+            .ok()
+            .map(|idx| &records[idx].1)
+    }
+
+    #[allow(deprecated)]
     pub(crate) fn merge_leaf(
         &mut self,
         key: IVec,
         val: IVec,
-        merge_fn: MergeOperator,
+        merge_fn: pagecache::MergeOperator,
     ) {
         if let Data::Leaf(ref mut records) = self.data {
             let search = records.binary_search_by(|(k, _)| prefix_cmp(k, &key));
@@ -124,6 +306,7 @@ impl Node {
 
     pub(crate) fn child_split(&mut self, cs: &ChildSplit) {
         self.data.drop_gte(&cs.at, &self.lo);
+        self.expiries.retain(|(k, _at)| *k < cs.at);
         self.hi = cs.at.clone();
         self.next = Some(cs.to);
     }
@@ -140,6 +323,47 @@ impl Node {
         }
     }
 
+    /// Absorbs the keys and bounds of `rhs`, which must be
+    /// the right sibling of this node.
+    pub(crate) fn receive_merge(&mut self, rhs: &Node) {
+        assert_eq!(
+            self.hi, rhs.lo,
+            "tried to merge a node into a node that is not its left sibling"
+        );
+        // NB the keys of `rhs` share at most as long a
+        // prefix with our lo key as with its own.
+        let (lo, rhs_lo) = (&self.lo, &rhs.lo);
+        let reencode = |k: &IVec| prefix_encode(lo, &prefix_decode(rhs_lo, k));
+        match (&mut self.data, &rhs.data) {
+            (Data::Leaf(ref mut records), Data::Leaf(ref rhs_records)) => {
+                records.extend(
+                    rhs_records.iter().map(|(k, v)| (reencode(k), v.clone())),
+                );
+            }
+            (Data::Index(ref mut ptrs), Data::Index(ref rhs_ptrs)) => {
+                ptrs.extend(
+                    rhs_ptrs.iter().map(|(k, pid)| (reencode(k), *pid)),
+                );
+            }
+            _ => panic!("tried to merge a leaf and an index"),
+        }
+        self.expiries.extend(rhs.expiries.iter().cloned());
+        self.hi = rhs.hi.clone();
+        self.next = rhs.next;
+    }
+
+    pub(crate) fn parent_merge_confirm(&mut self) {
+        let child = self
+            .merging_child
+            .take()
+            .expect("confirmed a merge that was never intended");
+        if let Data::Index(ref mut ptrs) = self.data {
+            ptrs.retain(|(_k, pid)| *pid != child);
+        } else {
+            panic!("tried to attach a ParentMergeConfirm to a Leaf chain");
+        }
+    }
+
     pub(crate) fn del_leaf(&mut self, key: &IVec) {
         if let Data::Leaf(ref mut records) = self.data {
             let search = records
@@ -153,16 +377,40 @@ impl Node {
     }
 
     pub(crate) fn should_split(&self, max_sz: u64) -> bool {
-        self.data.len() > 2 && self.size_in_bytes() > max_sz
+        // NB nodes that take part in a merge are split once
+        // it is complete.
+        self.merging_child.is_none()
+            && !self.merging
+            && self.data.len() > 2
+            && self.size_in_bytes() > max_sz
+    }
+
+    pub(crate) fn should_merge(&self, max_sz: u64) -> bool {
+        self.data.len() < 2 || self.size_in_bytes() < max_sz / 4
+    }
+
+    /// Whether removing a key from this node may leave it
+    /// underfull, which is checked before reading it again.
+    pub(crate) fn may_underflow(&self, max_sz: u64) -> bool {
+        self.data.len() <= 2 || self.should_merge(max_sz)
     }
 
     pub(crate) fn split(&self) -> Node {
         let (split, right_data) = self.data.split(&self.lo);
+        let right_expiries = self
+            .expiries
+            .iter()
+            .filter(|(k, _at)| *k >= split)
+            .cloned()
+            .collect();
         Node {
             data: right_data,
             next: self.next,
             lo: split,
             hi: self.hi.clone(),
+            expiries: right_expiries,
+            merging_child: None,
+            merging: false,
         }
     }
 }
@@ -0,0 +1,234 @@
+//! Merging of underfull leaves into their left siblings.
+//!
+//! A merge is logged in four steps, each of which is a
+//! fragment linked to a page:
+//!
+//! 1. `ParentMergeIntention` on the parent index records
+//!    which child is merged, and keeps the parent from
+//!    splitting or starting another merge until it is done.
+//! 2. `ChildMergeCap` marks the child, after which it is no
+//!    longer written to, and traversals that reach it start
+//!    over from the root.
+//! 3. `LeftMerge` appends the keys of the child to its left
+//!    sibling, which takes over its `hi` bound and `next`
+//!    pointer.
+//! 4. `ParentMergeConfirm` removes the child from its parent,
+//!    after which the page of the child is freed.
+//!
+//! Like the second half of a split, a merge that was
+//! interrupted, including by a crash, is completed by the
+//! next traversal that reaches its parent.
+use super::*;
+
+impl Tree {
+    /// Merges the leaf `child_id` with a sibling if it is
+    /// underfull after a deletion, as long as both are children
+    /// of the last node of `path`, and the merged node would
+    /// not be split again right away. A leftmost child takes
+    /// over its right sibling instead of being merged away.
+    pub(crate) fn merge_node<'g>(
+        &self,
+        path: &[(PageId, &'g Frag, TreePtr<'g>)],
+        child_id: PageId,
+        tx: &'g Tx,
+    ) -> Result<()> {
+        let pagecache = &self.context.pagecache;
+        let max_sz = self.context.blink_node_split_size as u64;
+
+        let (parent_id, parent_frag, parent_ptr) = match path.last() {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+        let parent = parent_frag.unwrap_base();
+
+        let ptrs = match parent.data {
+            Data::Index(ref ptrs) if parent.merging_child.is_none() => ptrs,
+            _ => return Ok(()),
+        };
+
+        // NB a child that the parent does not point to
+        // yet is still being split.
+        let (left_id, right_id) =
+            match ptrs.iter().position(|(_k, pid)| *pid == child_id) {
+                Some(0) if ptrs.len() > 1 => (child_id, ptrs[1].1),
+                Some(idx) if idx > 0 => (ptrs[idx - 1].1, child_id),
+                _ => return Ok(()),
+            };
+
+        let get_node = |pid| -> Result<Option<&'g Node>> {
+            match pagecache.get(pid, tx)? {
+                PageGet::Materialized(frag, _ptr) => {
+                    Ok(Some(frag.unwrap_base()))
+                }
+                _ => Ok(None),
+            }
+        };
+
+        // the deletion is applied to the child
+        // when it is read again.
+        let (left, right) = match (get_node(left_id)?, get_node(right_id)?) {
+            (Some(left), Some(right)) => (left, right),
+            _ => return Ok(()),
+        };
+        let child = if child_id == left_id { left } else { right };
+
+        if !child.should_merge(max_sz)
+            || left.merging
+            || right.merging
+            || left.next != Some(right_id)
+        {
+            return Ok(());
+        }
+
+        let mut merged = left.clone();
+        merged.receive_merge(right);
+        if merged.should_split(max_sz) {
+            return Ok(());
+        }
+
+        let link = pagecache.link(
+            *parent_id,
+            parent_ptr.clone(),
+            Frag::ParentMergeIntention(right_id),
+            tx,
+        )?;
+
+        if link.is_err() {
+            // the parent changed, and the child is merged
+            // after a later deletion instead.
+            return Ok(());
+        }
+
+        self.complete_merge(*parent_id, right_id, tx)
+    }
+
+    /// Completes the merge of `child_id` into its left sibling
+    /// that was recorded on `parent_id`. Every step can be
+    /// retried, so any number of threads may help at once.
+    pub(crate) fn complete_merge(
+        &self,
+        parent_id: PageId,
+        child_id: PageId,
+        tx: &Tx,
+    ) -> Result<()> {
+        let pagecache = &self.context.pagecache;
+
+        let child = loop {
+            let (frag, ptr) = match pagecache.get(child_id, tx)? {
+                PageGet::Materialized(frag, ptr) => (frag, ptr),
+                // the merge was completed concurrently
+                _ => return Ok(()),
+            };
+
+            let child = frag.unwrap_base();
+            if child.merging {
+                break child;
+            }
+
+            let _ = pagecache.link(child_id, ptr, Frag::ChildMergeCap, tx)?;
+        };
+
+        let parent = match pagecache.get(parent_id, tx)? {
+            PageGet::Materialized(frag, _ptr) => frag.unwrap_base(),
+            _ => return Ok(()),
+        };
+
+        if parent.merging_child != Some(child_id) {
+            // the merge was completed concurrently
+            return Ok(());
+        }
+
+        let ptrs = match parent.data {
+            Data::Index(ref ptrs) => ptrs,
+            Data::Leaf(_) => {
+                return Err(Error::ReportableBug(
+                    "merge intention was linked to a leaf".to_owned(),
+                ));
+            }
+        };
+
+        let idx = ptrs
+            .iter()
+            .position(|(_k, pid)| *pid == child_id)
+            .expect("a parent must point to the child it is merging");
+
+        // NB the left sibling may have been split since the
+        // parent pointed to it, so we walk right until we
+        // reach the node that points to the child.
+        let mut cursor = ptrs[idx - 1].1;
+        loop {
+            let (frag, ptr) = match pagecache.get(cursor, tx)? {
+                PageGet::Materialized(frag, ptr) => (frag, ptr),
+                // the left sibling can only be merged away once
+                // the merge of the child was completed concurrently,
+                // and the parent has moved on to another one.
+                _ => return Ok(()),
+            };
+            let node = frag.unwrap_base();
+
+            if node.next == Some(child_id) {
+                // NB a commit may have marked the child for the
+                // keys that the left sibling takes over, so it
+                // takes over its RTS as well.
+                let _section = self.context.concurrency_control.section();
+                if let Some(rts) = pagecache.get_page_rts(child_id, tx) {
+                    pagecache.bump_page_rts(cursor, rts as Lsn, tx);
+                }
+
+                let link = pagecache.link(
+                    cursor,
+                    ptr,
+                    Frag::LeftMerge(child.clone()),
+                    tx,
+                )?;
+                if link.is_ok() {
+                    break;
+                }
+                continue;
+            }
+
+            // (when hi is empty, it means it's unbounded)
+            if node.hi.is_empty() || node.hi > child.lo {
+                // the left sibling already took over
+                // the keys of the child.
+                break;
+            }
+
+            cursor = node.next.expect(
+                "if our hi bound is not Inf (inity), \
+                 we should have a right sibling",
+            );
+        }
+
+        loop {
+            let (frag, ptr) = match pagecache.get(parent_id, tx)? {
+                PageGet::Materialized(frag, ptr) => (frag, ptr),
+                _ => return Ok(()),
+            };
+
+            if frag.unwrap_base().merging_child != Some(child_id) {
+                return Ok(());
+            }
+
+            let link =
+                pagecache.link(parent_id, ptr, Frag::ParentMergeConfirm, tx)?;
+            if link.is_ok() {
+                break;
+            }
+        }
+
+        // NB only the thread that removed the child from its
+        // parent frees it, and a crash before it does leaves
+        // it orphaned, like a tree that is dropped.
+        loop {
+            let ptr = match pagecache.get(child_id, tx)? {
+                PageGet::Materialized(_frag, ptr) => ptr,
+                _ => return Ok(()),
+            };
+
+            if pagecache.free(child_id, ptr, tx)?.is_ok() {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,765 @@
+//! An order-preserving encoding for the keys of typed trees.
+//!
+//! Encoded keys compare in the same order as the values that
+//! they were encoded from, so that iterating over a `Tree`
+//! yields typed keys in their natural order:
+//!
+//! * integers are stored big-endian, with the sign bit of
+//!   signed integers flipped so that negative numbers
+//!   sort first.
+//! * floats are stored so that their bits sort in numeric
+//!   order, with `NaN`s sorting after positive infinity.
+//! * strings and byte slices have each `0` byte escaped as
+//!   `0, 255`, and are terminated by `0, 0`, so that a
+//!   string sorts before every string that it is a prefix of.
+//! * tuples and structs are the concatenation of their
+//!   fields, and so sort by their first field, then by
+//!   their second field, and so on. The encoding of a
+//!   tuple's leading fields is a prefix of the encoding
+//!   of the whole tuple.
+//! * options, sequences and maps use one byte to mark
+//!   each element, and enums start with their variant
+//!   index as a big-endian `u32`.
+//!
+//! The encoding is not self-describing, so keys can only
+//! be decoded as the type that they were encoded from.
+use std::fmt;
+
+use serde::{
+    de::{self, DeserializeSeed, IntoDeserializer, Visitor},
+    ser,
+};
+
+use super::*;
+
+/// Encodes `key` so that its bytes sort like the key does.
+pub(crate) fn to_bytes<T: Serialize + ?Sized>(key: &T) -> Result<Vec<u8>> {
+    let mut serializer = Serializer { out: vec![] };
+    key.serialize(&mut serializer)?;
+    Ok(serializer.out)
+}
+
+/// Decodes a key that was encoded with `to_bytes`.
+pub(crate) fn from_bytes<'de, T: Deserialize<'de>>(
+    bytes: &'de [u8],
+) -> Result<T> {
+    let mut deserializer = Deserializer { input: bytes };
+    let ret = T::deserialize(&mut deserializer)?;
+    if deserializer.input.is_empty() {
+        Ok(ret)
+    } else {
+        Err(KeyError("trailing bytes after the encoded key".into()).into())
+    }
+}
+
+const ELEMENT: u8 = 1;
+const END: u8 = 0;
+
+#[derive(Debug)]
+pub(crate) struct KeyError(String);
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+impl ser::Error for KeyError {
+    fn custom<T: fmt::Display>(msg: T) -> KeyError {
+        KeyError(msg.to_string())
+    }
+}
+
+impl de::Error for KeyError {
+    fn custom<T: fmt::Display>(msg: T) -> KeyError {
+        KeyError(msg.to_string())
+    }
+}
+
+impl From<KeyError> for Error {
+    fn from(e: KeyError) -> Error {
+        Error::Unsupported(format!("failed to encode or decode a key: {}", e))
+    }
+}
+
+type KeyResult<T> = std::result::Result<T, KeyError>;
+
+struct Serializer {
+    out: Vec<u8>,
+}
+
+impl Serializer {
+    fn escaped(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.out.push(*byte);
+            if *byte == 0 {
+                self.out.push(255);
+            }
+        }
+        self.out.extend_from_slice(&[0, 0]);
+    }
+
+    fn variant(&mut self, variant_index: u32) {
+        self.out.extend_from_slice(&variant_index.to_be_bytes());
+    }
+}
+
+macro_rules! serialize_unsigned {
+    ($($f:ident: $t:ty),*) => {
+        $(
+            fn $f(self, v: $t) -> KeyResult<()> {
+                self.out.extend_from_slice(&v.to_be_bytes());
+                Ok(())
+            }
+        )*
+    };
+}
+
+macro_rules! serialize_signed {
+    ($($f:ident: $t:ty as $u:ty),*) => {
+        $(
+            fn $f(self, v: $t) -> KeyResult<()> {
+                let flipped = (v as $u) ^ (1 << (<$u>::BITS - 1));
+                self.out.extend_from_slice(&flipped.to_be_bytes());
+                Ok(())
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for &mut Serializer {
+    type Ok = ();
+    type Error = KeyError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    serialize_unsigned!(
+        serialize_u8: u8,
+        serialize_u16: u16,
+        serialize_u32: u32,
+        serialize_u64: u64,
+        serialize_u128: u128
+    );
+
+    serialize_signed!(
+        serialize_i8: i8 as u8,
+        serialize_i16: i16 as u16,
+        serialize_i32: i32 as u32,
+        serialize_i64: i64 as u64,
+        serialize_i128: i128 as u128
+    );
+
+    fn serialize_bool(self, v: bool) -> KeyResult<()> {
+        self.out.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> KeyResult<()> {
+        let bits = v.to_bits();
+        let ordered = if bits >> 31 == 1 {
+            !bits
+        } else {
+            bits | 1 << 31
+        };
+        self.serialize_u32(ordered)
+    }
+
+    fn serialize_f64(self, v: f64) -> KeyResult<()> {
+        let bits = v.to_bits();
+        let ordered = if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits | 1 << 63
+        };
+        self.serialize_u64(ordered)
+    }
+
+    fn serialize_char(self, v: char) -> KeyResult<()> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> KeyResult<()> {
+        self.escaped(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> KeyResult<()> {
+        self.escaped(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> KeyResult<()> {
+        self.out.push(END);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, v: &T) -> KeyResult<()> {
+        self.out.push(ELEMENT);
+        v.serialize(self)
+    }
+
+    fn serialize_unit(self) -> KeyResult<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> KeyResult<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> KeyResult<()> {
+        self.variant(variant_index);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        v: &T,
+    ) -> KeyResult<()> {
+        v.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        v: &T,
+    ) -> KeyResult<()> {
+        self.variant(variant_index);
+        v.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> KeyResult<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> KeyResult<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> KeyResult<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> KeyResult<Self> {
+        self.variant(variant_index);
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> KeyResult<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> KeyResult<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> KeyResult<Self> {
+        self.variant(variant_index);
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut Serializer {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        v: &T,
+    ) -> KeyResult<()> {
+        self.out.push(ELEMENT);
+        v.serialize(&mut **self)
+    }
+
+    fn end(self) -> KeyResult<()> {
+        self.out.push(END);
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Serializer {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, k: &T) -> KeyResult<()> {
+        self.out.push(ELEMENT);
+        k.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(
+        &mut self,
+        v: &T,
+    ) -> KeyResult<()> {
+        v.serialize(&mut **self)
+    }
+
+    fn end(self) -> KeyResult<()> {
+        self.out.push(END);
+        Ok(())
+    }
+}
+
+macro_rules! serialize_fields {
+    ($($t:ident),*) => {
+        $(
+            impl ser::$t for &mut Serializer {
+                type Ok = ();
+                type Error = KeyError;
+
+                fn serialize_field<T: Serialize + ?Sized>(
+                    &mut self,
+                    v: &T,
+                ) -> KeyResult<()> {
+                    v.serialize(&mut **self)
+                }
+
+                fn end(self) -> KeyResult<()> {
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+serialize_fields!(SerializeTupleStruct, SerializeTupleVariant);
+
+impl ser::SerializeTuple for &mut Serializer {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        v: &T,
+    ) -> KeyResult<()> {
+        v.serialize(&mut **self)
+    }
+
+    fn end(self) -> KeyResult<()> {
+        Ok(())
+    }
+}
+
+macro_rules! serialize_named_fields {
+    ($($t:ident),*) => {
+        $(
+            impl ser::$t for &mut Serializer {
+                type Ok = ();
+                type Error = KeyError;
+
+                fn serialize_field<T: Serialize + ?Sized>(
+                    &mut self,
+                    _key: &'static str,
+                    v: &T,
+                ) -> KeyResult<()> {
+                    v.serialize(&mut **self)
+                }
+
+                fn end(self) -> KeyResult<()> {
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+serialize_named_fields!(SerializeStruct, SerializeStructVariant);
+
+struct Deserializer<'de> {
+    input: &'de [u8],
+}
+
+impl<'de> Deserializer<'de> {
+    fn take(&mut self, len: usize) -> KeyResult<&'de [u8]> {
+        if self.input.len() < len {
+            return Err(KeyError("the encoded key ended early".into()));
+        }
+        let (ret, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(ret)
+    }
+
+    fn byte(&mut self) -> KeyResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn marker(&mut self) -> KeyResult<bool> {
+        match self.byte()? {
+            ELEMENT => Ok(true),
+            END => Ok(false),
+            other => Err(KeyError(format!("invalid marker byte {}", other))),
+        }
+    }
+
+    fn escaped(&mut self) -> KeyResult<Vec<u8>> {
+        let mut ret = vec![];
+        loop {
+            match self.byte()? {
+                0 => match self.byte()? {
+                    0 => return Ok(ret),
+                    255 => ret.push(0),
+                    other => {
+                        return Err(KeyError(format!(
+                            "invalid escape byte {}",
+                            other
+                        )));
+                    }
+                },
+                byte => ret.push(byte),
+            }
+        }
+    }
+}
+
+macro_rules! read_be {
+    ($de:expr, $t:ty) => {{
+        let mut buf = [0; std::mem::size_of::<$t>()];
+        buf.copy_from_slice($de.take(std::mem::size_of::<$t>())?);
+        <$t>::from_be_bytes(buf)
+    }};
+}
+
+macro_rules! deserialize_unsigned {
+    ($($f:ident: $t:ty => $visit:ident),*) => {
+        $(
+            fn $f<V: Visitor<'de>>(self, visitor: V) -> KeyResult<V::Value> {
+                visitor.$visit(read_be!(self, $t))
+            }
+        )*
+    };
+}
+
+macro_rules! deserialize_signed {
+    ($($f:ident: $t:ty as $u:ty => $visit:ident),*) => {
+        $(
+            fn $f<V: Visitor<'de>>(self, visitor: V) -> KeyResult<V::Value> {
+                let flipped = read_be!(self, $u);
+                visitor.$visit((flipped ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = KeyError;
+
+    deserialize_unsigned!(
+        deserialize_u8: u8 => visit_u8,
+        deserialize_u16: u16 => visit_u16,
+        deserialize_u32: u32 => visit_u32,
+        deserialize_u64: u64 => visit_u64,
+        deserialize_u128: u128 => visit_u128
+    );
+
+    deserialize_signed!(
+        deserialize_i8: i8 as u8 => visit_i8,
+        deserialize_i16: i16 as u16 => visit_i16,
+        deserialize_i32: i32 as u32 => visit_i32,
+        deserialize_i64: i64 as u64 => visit_i64,
+        deserialize_i128: i128 as u128 => visit_i128
+    );
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> KeyResult<V::Value> {
+        Err(KeyError(
+            "the ordered key encoding is not self-describing".into(),
+        ))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, v: V) -> KeyResult<V::Value> {
+        match self.byte()? {
+            0 => v.visit_bool(false),
+            1 => v.visit_bool(true),
+            other => Err(KeyError(format!("invalid bool byte {}", other))),
+        }
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, v: V) -> KeyResult<V::Value> {
+        let ordered = read_be!(self, u32);
+        let bits = if ordered >> 31 == 1 {
+            ordered ^ 1 << 31
+        } else {
+            !ordered
+        };
+        v.visit_f32(f32::from_bits(bits))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, v: V) -> KeyResult<V::Value> {
+        let ordered = read_be!(self, u64);
+        let bits = if ordered >> 63 == 1 {
+            ordered ^ 1 << 63
+        } else {
+            !ordered
+        };
+        v.visit_f64(f64::from_bits(bits))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, v: V) -> KeyResult<V::Value> {
+        let c = read_be!(self, u32);
+        match std::char::from_u32(c) {
+            Some(c) => v.visit_char(c),
+            None => Err(KeyError(format!("invalid char {}", c))),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, v: V) -> KeyResult<V::Value> {
+        self.deserialize_string(v)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, v: V) -> KeyResult<V::Value> {
+        let bytes = self.escaped()?;
+        match String::from_utf8(bytes) {
+            Ok(s) => v.visit_string(s),
+            Err(e) => Err(KeyError(e.to_string())),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, v: V) -> KeyResult<V::Value> {
+        self.deserialize_byte_buf(v)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(
+        self,
+        v: V,
+    ) -> KeyResult<V::Value> {
+        v.visit_byte_buf(self.escaped()?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, v: V) -> KeyResult<V::Value> {
+        if self.marker()? {
+            v.visit_some(self)
+        } else {
+            v.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, v: V) -> KeyResult<V::Value> {
+        v.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        v: V,
+    ) -> KeyResult<V::Value> {
+        v.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        v: V,
+    ) -> KeyResult<V::Value> {
+        v.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, v: V) -> KeyResult<V::Value> {
+        v.visit_seq(Marked { de: self })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        v: V,
+    ) -> KeyResult<V::Value> {
+        v.visit_seq(Fixed { de: self, len })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        v: V,
+    ) -> KeyResult<V::Value> {
+        v.visit_seq(Fixed { de: self, len })
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, v: V) -> KeyResult<V::Value> {
+        v.visit_map(Marked { de: self })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        v: V,
+    ) -> KeyResult<V::Value> {
+        v.visit_seq(Fixed {
+            de: self,
+            len: fields.len(),
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        v: V,
+    ) -> KeyResult<V::Value> {
+        v.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(
+        self,
+        v: V,
+    ) -> KeyResult<V::Value> {
+        self.deserialize_u32(v)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        v: V,
+    ) -> KeyResult<V::Value> {
+        self.deserialize_any(v)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+// Elements of sequences and maps, which are each
+// preceded by `ELEMENT`, and followed by `END`.
+struct Marked<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for Marked<'a, 'de> {
+    type Error = KeyError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> KeyResult<Option<T::Value>> {
+        if self.de.marker()? {
+            seed.deserialize(&mut *self.de).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<'a, 'de> de::MapAccess<'de> for Marked<'a, 'de> {
+    type Error = KeyError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> KeyResult<Option<K::Value>> {
+        de::SeqAccess::next_element_seed(self, seed)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> KeyResult<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+// The fields of tuples and structs, which are
+// concatenated without any markers.
+struct Fixed<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    len: usize,
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for Fixed<'a, 'de> {
+    type Error = KeyError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> KeyResult<Option<T::Value>> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Deserializer<'de> {
+    type Error = KeyError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> KeyResult<(V::Value, Self)> {
+        let variant_index = read_be!(self, u32);
+        let de: de::value::U32Deserializer<KeyError> =
+            variant_index.into_deserializer();
+        Ok((seed.deserialize(de)?, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Deserializer<'de> {
+    type Error = KeyError;
+
+    fn unit_variant(self) -> KeyResult<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> KeyResult<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        v: V,
+    ) -> KeyResult<V::Value> {
+        v.visit_seq(Fixed { de: self, len })
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        v: V,
+    ) -> KeyResult<V::Value> {
+        v.visit_seq(Fixed {
+            de: self,
+            len: fields.len(),
+        })
+    }
+}
@@ -0,0 +1,201 @@
+//! Parallel scans of a range of a `Tree`.
+use std::ops::{self, RangeBounds};
+
+use rayon::iter::{
+    plumbing::{
+        bridge_unindexed, Folder, UnindexedConsumer, UnindexedProducer,
+    },
+    ParallelIterator,
+};
+
+use super::*;
+
+// The bounds of the keys that one `Iter` scans.
+type Partition = (ops::Bound<Key>, ops::Bound<Key>);
+
+/// A rayon `ParallelIterator` over the keys and values in a
+/// range of a `Tree`, created with `Tree::par_range`.
+///
+/// The range is split into partitions at the low keys of
+/// leaves, and each partition is scanned by an independent
+/// `Iter` with its own `Tx`. Items are in order within a
+/// partition, but partitions are scanned concurrently, and
+/// each of them observes the `Tree` at a different time.
+pub struct ParIter<'a> {
+    tree: &'a Tree,
+    partitions: Vec<Partition>,
+}
+
+impl<'a> ParIter<'a> {
+    /// Returns the bounds of each partition, in ascending
+    /// order, to scan them with `Tree::range` without rayon.
+    pub fn partitions(&self) -> &[(ops::Bound<Key>, ops::Bound<Key>)] {
+        &self.partitions
+    }
+}
+
+impl<'a> ParallelIterator for ParIter<'a> {
+    type Item = Result<(Key, IVec)>;
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+    where
+        C: UnindexedConsumer<Self::Item>,
+    {
+        let producer = Partitions {
+            tree: self.tree,
+            partitions: self.partitions,
+        };
+        bridge_unindexed(producer, consumer)
+    }
+}
+
+// Splits the partitions of a `ParIter` between rayon's
+// threads, and scans each of them with its own `Iter`.
+struct Partitions<'a> {
+    tree: &'a Tree,
+    partitions: Vec<Partition>,
+}
+
+impl<'a> UnindexedProducer for Partitions<'a> {
+    type Item = Result<(Key, IVec)>;
+
+    fn split(mut self) -> (Self, Option<Self>) {
+        if self.partitions.len() < 2 {
+            return (self, None);
+        }
+
+        let right = self.partitions.split_off(self.partitions.len() / 2);
+        let tree = self.tree;
+
+        (
+            self,
+            Some(Partitions {
+                tree,
+                partitions: right,
+            }),
+        )
+    }
+
+    fn fold_with<F>(self, mut folder: F) -> F
+    where
+        F: Folder<Self::Item>,
+    {
+        for partition in self.partitions {
+            // NB an `Iter` pins its thread's epoch, so it is
+            // created on the thread that consumes it.
+            folder = folder.consume_iter(self.tree.range(partition));
+            if folder.full() {
+                break;
+            }
+        }
+        folder
+    }
+}
+
+impl Tree {
+    /// Returns a rayon `ParallelIterator` over the keys and
+    /// values in `range`, which scans up to `n` partitions of
+    /// the range on rayon's thread pool. The partitions are
+    /// split at the low keys of leaves, which are read from the
+    /// index nodes above them, so that each one covers about the
+    /// same number of leaves. Fewer partitions are used if the
+    /// range spans fewer than `n` leaves.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rayon::iter::ParallelIterator;
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let t = Db::start(config).unwrap();
+    ///
+    /// for i in 0..1000_u32 {
+    ///     t.set(i.to_be_bytes(), i.to_be_bytes().to_vec()).unwrap();
+    /// }
+    ///
+    /// let start = 100_u32.to_be_bytes();
+    /// let end = 200_u32.to_be_bytes();
+    /// let sum: u64 = t
+    ///     .par_range(start..end, 4)
+    ///     .unwrap()
+    ///     .map(|res| {
+    ///         let (_k, v) = res.unwrap();
+    ///         let mut buf = [0; 4];
+    ///         buf.copy_from_slice(&v);
+    ///         u64::from(u32::from_be_bytes(buf))
+    ///     })
+    ///     .sum();
+    ///
+    /// assert_eq!(sum, (100..200).sum());
+    /// ```
+    pub fn par_range<K, R>(&self, range: R, n: usize) -> Result<ParIter<'_>>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let to_key = |bound: ops::Bound<&K>| match bound {
+            ops::Bound::Included(k) => {
+                ops::Bound::Included(k.as_ref().to_vec())
+            }
+            ops::Bound::Excluded(k) => {
+                ops::Bound::Excluded(k.as_ref().to_vec())
+            }
+            ops::Bound::Unbounded => ops::Bound::Unbounded,
+        };
+        let lo = to_key(range.start_bound());
+        let hi = to_key(range.end_bound());
+
+        let (start, end) = half_open_bounds(&range);
+        let empty = match end {
+            Some(ref end) => *end <= start,
+            None => false,
+        };
+        if empty {
+            return Ok(ParIter {
+                tree: self,
+                partitions: vec![(lo, hi)],
+            });
+        }
+
+        let tx = self.context.pagecache.begin()?;
+
+        // the low keys of the leaves that begin inside of the range
+        let candidates: Vec<Key> = self
+            .leaves(&tx)?
+            .into_iter()
+            .map(|(lo, _pid)| lo)
+            .filter(|lo| {
+                *lo > start
+                    && match end {
+                        Some(ref end) => lo < end,
+                        None => true,
+                    }
+            })
+            .map(|lo| lo.to_vec())
+            .collect();
+
+        // the candidates divide the range into one more segment
+        // than there are candidates, which are grouped evenly.
+        let segments = candidates.len() + 1;
+        let n = std::cmp::max(1, std::cmp::min(n, segments));
+        let mut splits: Vec<Key> = (1..n)
+            .map(|i| candidates[segments * i / n - 1].clone())
+            .collect();
+        splits.dedup();
+
+        let mut partitions = Vec::with_capacity(splits.len() + 1);
+        let mut partition_lo = lo;
+        for split in splits {
+            partitions
+                .push((partition_lo, ops::Bound::Excluded(split.clone())));
+            partition_lo = ops::Bound::Included(split);
+        }
+        partitions.push((partition_lo, hi));
+
+        Ok(ParIter {
+            tree: self,
+            partitions,
+        })
+    }
+}
@@ -122,6 +122,20 @@ pub(crate) fn prefix_cmp_encoded(
     a_suffix.cmp(b)
 }
 
+/// Returns the smallest key that is greater than every key
+/// starting with `prefix`, or `None` if there is no such key,
+/// because the prefix is empty or only made of `0xff` bytes.
+pub(crate) fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < std::u8::MAX {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+    None
+}
+
 #[test]
 fn test_prefix() {
     let prefix = b"cat";
@@ -189,3 +203,13 @@ fn test_prefix_cmp_encoded() {
     assert_pce(&[1, 3], &[1, 1], prefix, Ordering::Greater);
     assert_pce(&[1, 1], &[3, 3], prefix, Ordering::Less);
 }
+
+#[test]
+fn test_prefix_successor() {
+    assert_eq!(prefix_successor(b""), None);
+    assert_eq!(prefix_successor(&[255, 255]), None);
+    assert_eq!(prefix_successor(&[0]), Some(vec![1]));
+    assert_eq!(prefix_successor(b"cat"), Some(b"cau".to_vec()));
+    assert_eq!(prefix_successor(&[1, 255]), Some(vec![2]));
+    assert_eq!(prefix_successor(&[1, 254, 255]), Some(vec![1, 255]));
+}
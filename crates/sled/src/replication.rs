@@ -0,0 +1,688 @@
+//! Primary/replica log shipping, for keeping a read-only
+//! warm standby of a `Db` up to date.
+//!
+//! A replica starts out as a checkpoint of the primary,
+//! written by `Db::checkpoint` after the `Primary` was
+//! registered with `Db::replication_primary`. From then on,
+//! the replica repeatedly asks the primary for the updates
+//! that were written to its log after the replica's position,
+//! and applies them to its own pages in the same order. The
+//! primary retains the log segments that a replica has not
+//! caught up with yet, including across restarts, until the
+//! `Primary` is removed.
+//!
+//! The two sides talk to each other through a `Transport`,
+//! which only has to deliver whole messages in order. A
+//! `ChannelTransport` connects a primary and a replica in
+//! the same process, and `Transport` is implemented for
+//! `UnixStream` on unix platforms.
+//!
+//! # Protocol
+//!
+//! Every message starts with a byte of message kind, and
+//! all integers are little-endian:
+//!
+//! * `1`, a request from the replica: the `Lsn` from which
+//!   the replica wants to receive updates, as an `i64`. It
+//!   also tells the primary that every update below it has
+//!   been durably applied by the replica.
+//! * `2`, a chunk of updates from the primary: the `Lsn`
+//!   to request next as an `i64`, a byte that is `1` if the
+//!   primary has more updates from that `Lsn` and `0`
+//!   otherwise, the number of updates as a `u64`, and then
+//!   for each update its `Lsn` as an `i64`, its length as a
+//!   `u64`, and the update itself.
+//! * `3`, a refusal from the primary: a UTF-8 explanation
+//!   that makes up the rest of the message.
+//!
+//! A chunk holds the updates that were stable on the primary
+//! when it was read, up to the limits set with
+//! `Primary::chunk_limits`, and the replica requests the next
+//! chunk for as long as the primary has more of them. Chunks
+//! never end in the middle of a write batch, and end before
+//! any write batch that had not been sealed, so each chunk is
+//! applied to the replica atomically with respect to crashes,
+//! and without exposing a part of a batch to readers.
+//!
+//! # Examples
+//!
+//! ```
+//! use sled::{ChannelTransport, ConfigBuilder, Db, IVec, Replica};
+//!
+//! let config = ConfigBuilder::new().temporary(true).build();
+//! let db = Db::start(config).unwrap();
+//! let primary = db.replication_primary("standby").unwrap();
+//!
+//! let path = std::env::temp_dir()
+//!     .join(format!("sled_replication_doc_{}", std::process::id()));
+//! db.checkpoint(&path).unwrap();
+//!
+//! let config = ConfigBuilder::new()
+//!     .path(&path)
+//!     .temporary(true)
+//!     .read_only(true)
+//!     .build();
+//! let replica = Replica::start(config).unwrap();
+//!
+//! let (mut client, server) = ChannelTransport::pair();
+//! let server = std::thread::spawn(move || primary.serve(server));
+//!
+//! db.set(b"k1", vec![1]).unwrap();
+//! replica.sync(&mut client).unwrap();
+//! assert_eq!(replica.get(b"k1"), Ok(Some(IVec::from(vec![1]))));
+//!
+//! drop(client);
+//! server.join().unwrap().unwrap();
+//! ```
+use std::{
+    convert::TryFrom,
+    fs,
+    io::{self, Read, Write},
+    ops::Deref,
+    path::PathBuf,
+    sync::{mpsc, Mutex},
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use pagecache::crc32;
+
+use super::*;
+
+const REQUEST: u8 = 1;
+const CHUNK: u8 = 2;
+const REFUSAL: u8 = 3;
+
+// the longest message that is sent or received over a
+// `UnixStream`, so that a corrupted length can not make
+// us allocate an arbitrary amount of memory.
+const MAX_MESSAGE_LEN: usize = 1 << 30;
+
+// the default limits of a chunk of updates, which are only
+// exceeded by a write batch that is larger than them.
+const MAX_CHUNK_BYTES: usize = 8 << 20;
+const MAX_CHUNK_UPDATES: usize = 64 * 1024;
+
+/// A connection between a `Primary` and a `Replica`, which
+/// delivers whole messages, in the order they were sent.
+pub trait Transport {
+    /// Sends a message to the other side.
+    fn send(&mut self, message: &[u8]) -> Result<()>;
+
+    /// Blocks until the next message from the other side
+    /// arrives, returning `None` if the other side has
+    /// closed the connection.
+    fn recv(&mut self) -> Result<Option<Vec<u8>>>;
+}
+
+/// A `Transport` between two threads of the same process,
+/// created in connected pairs with `ChannelTransport::pair`.
+pub struct ChannelTransport {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl ChannelTransport {
+    /// Creates two `ChannelTransport`s that are connected
+    /// to each other. Dropping either of them closes the
+    /// connection.
+    pub fn pair() -> (ChannelTransport, ChannelTransport) {
+        let (tx1, rx1) = mpsc::channel();
+        let (tx2, rx2) = mpsc::channel();
+
+        (
+            ChannelTransport { tx: tx1, rx: rx2 },
+            ChannelTransport { tx: tx2, rx: rx1 },
+        )
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        self.tx.send(message.to_vec()).map_err(|_| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the other side of the transport was dropped",
+            ))
+        })
+    }
+
+    fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        Ok(self.rx.recv().ok())
+    }
+}
+
+/// Each message is sent as its length as a little-endian
+/// `u64`, followed by the message itself. Messages longer
+/// than 1 GiB are rejected on both sides.
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        if message.len() > MAX_MESSAGE_LEN {
+            return Err(invalid_data("replication message is too long"));
+        }
+
+        self.write_all(&(message.len() as u64).to_le_bytes())?;
+        self.write_all(message)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut len = [0; 8];
+        let mut read = 0;
+
+        while read < len.len() {
+            match self.read(&mut len[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the connection was closed in the middle of a message",
+                    )));
+                }
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let len = usize::try_from(u64::from_le_bytes(len))
+            .ok()
+            .filter(|&len| len <= MAX_MESSAGE_LEN)
+            .ok_or_else(|| invalid_data("replication message is too long"))?;
+
+        let mut message = vec![0; len];
+        self.read_exact(&mut message)?;
+
+        Ok(Some(message))
+    }
+}
+
+/// The primary side of a replica, created with
+/// `Db::replication_primary`, which answers the requests
+/// that the `Replica` sends over a `Transport`.
+///
+/// The primary retains the log segments holding updates that
+/// the replica has not acknowledged yet, so a replica that
+/// stops syncing causes the storage file of the primary to
+/// grow until the `Primary` is removed with `remove`.
+#[derive(Clone)]
+pub struct Primary {
+    tree: Tree,
+    consumer: Vec<u8>,
+    max_chunk_bytes: usize,
+    max_chunk_updates: usize,
+}
+
+impl Primary {
+    pub(crate) fn register(tree: &Tree, name: &str) -> Result<Primary> {
+        if tree.context.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+
+        // NB change feed consumers start with the length of
+        // their tree's name, which can never be this large.
+        let mut consumer = std::u64::MAX.to_be_bytes().to_vec();
+        consumer.extend_from_slice(name.as_bytes());
+
+        tree.context
+            .pagecache
+            .register_log_consumer(&consumer, &[])?;
+
+        Ok(Primary {
+            tree: tree.clone(),
+            consumer,
+            max_chunk_bytes: MAX_CHUNK_BYTES,
+            max_chunk_updates: MAX_CHUNK_UPDATES,
+        })
+    }
+
+    /// Limits each chunk of updates that is sent to the
+    /// replica to about `max_bytes` of updates, and to at
+    /// most `max_updates` of them, leaving the rest for the
+    /// next request. A chunk only exceeds these limits if it
+    /// would otherwise end in the middle of a write batch.
+    /// Defaults to 8 MiB and 65536 updates.
+    pub fn chunk_limits(
+        mut self,
+        max_bytes: usize,
+        max_updates: usize,
+    ) -> Primary {
+        self.max_chunk_bytes = max_bytes;
+        self.max_chunk_updates = std::cmp::max(1, max_updates);
+        self
+    }
+
+    /// The `Lsn` from which the replica will next request
+    /// updates. Every update below it has been durably
+    /// applied by the replica.
+    pub fn position(&self) -> Result<Lsn> {
+        self.tree
+            .context
+            .pagecache
+            .log_consumer(&self.consumer)
+            .map(|(position, _state)| position)
+            .ok_or_else(|| {
+                Error::Unsupported(
+                    "this replication primary has been removed".to_owned(),
+                )
+            })
+    }
+
+    /// Answers requests from the replica until it closes the
+    /// `Transport`.
+    pub fn serve<T: Transport>(&self, mut transport: T) -> Result<()> {
+        while self.serve_one(&mut transport)? {}
+        Ok(())
+    }
+
+    /// Answers the next request from the replica. Returns
+    /// `false` without doing anything if the replica has
+    /// closed the `Transport`.
+    pub fn serve_one<T: Transport>(&self, transport: &mut T) -> Result<bool> {
+        let request = match transport.recv()? {
+            Some(request) => request,
+            None => return Ok(false),
+        };
+
+        let from = match request.split_first() {
+            Some((&REQUEST, body)) if body.len() == 8 => {
+                let mut from = [0; 8];
+                from.copy_from_slice(body);
+                Lsn::from_le_bytes(from)
+            }
+            _ => return Err(invalid_data("malformed replication request")),
+        };
+
+        let position = self.position()?;
+        if from < position {
+            let mut refusal = vec![REFUSAL];
+            refusal.extend_from_slice(
+                format!(
+                    "the replica requested updates from lsn {}, but \
+                     the primary only retains them from lsn {}",
+                    from, position
+                )
+                .as_bytes(),
+            );
+            transport.send(&refusal)?;
+            return Ok(true);
+        }
+
+        let pagecache = &self.tree.context.pagecache;
+
+        if from > position {
+            pagecache.advance_log_consumer(&self.consumer, from, &[])?;
+        }
+
+        let mut to = from;
+        let mut more = false;
+        let mut count = 0_usize;
+        let mut body = vec![];
+
+        let mut shipper = pagecache.ship(from)?;
+        while let Some((lsn, update)) = shipper.next() {
+            // a chunk has 18 bytes of header, and each update
+            // is preceded by its lsn and length.
+            if 18 + body.len() + 16 + update.len() > MAX_MESSAGE_LEN {
+                let mut refusal = vec![REFUSAL];
+                refusal.extend_from_slice(
+                    format!(
+                        "the updates from lsn {} belong to a write batch \
+                         that is too large to be replicated",
+                        from
+                    )
+                    .as_bytes(),
+                );
+                transport.send(&refusal)?;
+                return Ok(true);
+            }
+
+            body.extend_from_slice(&lsn.to_le_bytes());
+            body.extend_from_slice(&(update.len() as u64).to_le_bytes());
+            body.extend_from_slice(&update);
+            to = lsn + 1;
+            count += 1;
+
+            let full = body.len() >= self.max_chunk_bytes
+                || count >= self.max_chunk_updates;
+            if full && !shipper.in_batch(lsn) {
+                more = shipper.next().is_some();
+                break;
+            }
+        }
+        drop(shipper);
+
+        let mut chunk = Vec::with_capacity(18 + body.len());
+        chunk.push(CHUNK);
+        chunk.extend_from_slice(&to.to_le_bytes());
+        chunk.push(more as u8);
+        chunk.extend_from_slice(&(count as u64).to_le_bytes());
+        chunk.extend_from_slice(&body);
+
+        transport.send(&chunk)?;
+
+        Ok(true)
+    }
+
+    /// Durably removes this primary, releasing any log
+    /// segments that were only retained for its replica.
+    pub fn remove(self) -> Result<()> {
+        self.tree
+            .context
+            .pagecache
+            .remove_log_consumer(&self.consumer)?;
+        Ok(())
+    }
+}
+
+/// A read-only copy of a `Db` that is kept up to date with
+/// the writes made to a `Primary`.
+///
+/// A replica is started from a checkpoint of the primary
+/// with `Replica::start`, and then brought up to date with
+/// `sync`. Its trees can be read through `Deref<Target = Db>`
+/// while it is syncing, but any attempt to write to them is
+/// rejected. The position of the replica is stored next to
+/// its storage file, so a restarted replica resumes from the
+/// last chunk of updates that it durably applied.
+pub struct Replica {
+    db: Db,
+    position: Mutex<Lsn>,
+}
+
+impl Deref for Replica {
+    type Target = Db;
+
+    fn deref(&self) -> &Db {
+        &self.db
+    }
+}
+
+// The durable position of a replica. A chunk is `Pending`
+// while it is being applied inside of a write batch that
+// starts at `manifest`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReplicaState {
+    Applied(Lsn),
+    Pending { from: Lsn, to: Lsn, manifest: Lsn },
+    Failed,
+}
+
+impl Replica {
+    /// Starts a replica from a checkpoint of a primary, or
+    /// restarts an existing one. `config` must have been
+    /// built with `read_only(true)`.
+    pub fn start(config: Config) -> Result<Replica> {
+        if !config.read_only {
+            return Err(Error::Unsupported(
+                "a replica must be started in read-only mode".to_owned(),
+            ));
+        }
+
+        let db = Db::start(config.clone())?;
+        let pagecache = &db.context.pagecache;
+
+        let position = match read_state(&config)? {
+            None => {
+                // this is a fresh checkpoint of the primary, whose
+                // log consumers are only meaningful to the primary.
+                for consumer in pagecache.log_consumers() {
+                    pagecache.remove_log_consumer(&consumer)?;
+                }
+
+                pagecache.recovered_lsn() + 1
+            }
+            Some(ReplicaState::Applied(position)) => position,
+            Some(ReplicaState::Pending { from, to, manifest }) => {
+                // a batch is only recovered if it was sealed,
+                // and every applied chunk writes to the log
+                // after its manifest.
+                if pagecache.recovered_lsn() > manifest {
+                    to
+                } else {
+                    from
+                }
+            }
+            Some(ReplicaState::Failed) => {
+                return Err(Error::Unsupported(
+                    "this replica failed while applying updates, \
+                     and must be started again from a new \
+                     checkpoint of the primary"
+                        .to_owned(),
+                ));
+            }
+        };
+
+        write_state(&config, ReplicaState::Applied(position))?;
+
+        Ok(Replica {
+            db,
+            position: Mutex::new(position),
+        })
+    }
+
+    /// The `Lsn` of the primary from which the next call to
+    /// `sync` requests updates.
+    pub fn position(&self) -> Lsn {
+        *self.position.lock().unwrap()
+    }
+
+    /// Requests every update that the primary has written
+    /// since the last call, one chunk at a time, and durably
+    /// applies each chunk before requesting the next one.
+    /// Returns the number of updates that were applied.
+    ///
+    /// If applying the updates fails, this replica can no
+    /// longer be synced, and must be started again from a
+    /// new checkpoint of the primary.
+    pub fn sync<T: Transport>(&self, transport: &mut T) -> Result<usize> {
+        let mut position = self.position.lock().unwrap();
+        let mut applied = 0;
+
+        loop {
+            let (updates, more) = self.sync_chunk(transport, &mut position)?;
+            applied += updates;
+            if !more {
+                return Ok(applied);
+            }
+        }
+    }
+
+    // Requests and applies the next chunk of updates, returning
+    // the number of updates that were applied, and whether the
+    // primary has more of them.
+    fn sync_chunk<T: Transport>(
+        &self,
+        transport: &mut T,
+        position: &mut Lsn,
+    ) -> Result<(usize, bool)> {
+        let from = *position;
+
+        let mut request = vec![REQUEST];
+        request.extend_from_slice(&from.to_le_bytes());
+        transport.send(&request)?;
+
+        let response = transport.recv()?.ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the primary closed the transport",
+            ))
+        })?;
+
+        let (to, more, updates) = decode_chunk(&response)?;
+
+        let config = &self.db.context;
+
+        if updates.is_empty() {
+            if to != from {
+                write_state(config, ReplicaState::Applied(to))?;
+                *position = to;
+            }
+            return Ok((0, more));
+        }
+
+        let pagecache = &self.db.context.pagecache;
+
+        let guard = pagecache.pin_log()?;
+        let manifest = guard.lsn();
+
+        write_state(config, ReplicaState::Pending { from, to, manifest })?;
+
+        let applied = updates
+            .iter()
+            .try_for_each(|update| pagecache.apply_shipped(update))
+            .and_then(|()| guard.seal_batch().map(|_| ()))
+            .and_then(|()| pagecache.flush().map(|_| ()));
+
+        if let Err(e) = applied {
+            error!("failed to apply replicated updates: {:?}", e);
+            // NB the updates that were applied before the failure
+            // may be recovered, so our position is meaningless.
+            if let Err(e) = write_state(config, ReplicaState::Failed) {
+                error!("failed to mark replica as failed: {:?}", e);
+            }
+            return Err(e);
+        }
+
+        write_state(config, ReplicaState::Applied(to))?;
+        *position = to;
+
+        self.db.reload_tenants()?;
+
+        Ok((updates.len(), more))
+    }
+}
+
+fn invalid_data(why: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, why))
+}
+
+fn decode_chunk(message: &[u8]) -> Result<(Lsn, bool, Vec<&[u8]>)> {
+    fn take_u64(buf: &mut &[u8]) -> Result<[u8; 8]> {
+        if buf.len() < 8 {
+            return Err(invalid_data("replication chunk is too short"));
+        }
+        let mut ret = [0; 8];
+        ret.copy_from_slice(&buf[..8]);
+        *buf = &buf[8..];
+        Ok(ret)
+    }
+
+    let mut buf = match message.split_first() {
+        Some((&CHUNK, buf)) => buf,
+        Some((&REFUSAL, why)) => {
+            return Err(Error::Unsupported(
+                String::from_utf8_lossy(why).into_owned(),
+            ));
+        }
+        _ => return Err(invalid_data("malformed replication chunk")),
+    };
+
+    let to = Lsn::from_le_bytes(take_u64(&mut buf)?);
+    let more = match buf.split_first() {
+        Some((&more, rest)) if more <= 1 => {
+            buf = rest;
+            more == 1
+        }
+        _ => return Err(invalid_data("malformed replication chunk")),
+    };
+    let count = u64::from_le_bytes(take_u64(&mut buf)?);
+
+    let mut updates = vec![];
+    for _ in 0..count {
+        let _lsn = take_u64(&mut buf)?;
+        let len = usize::try_from(u64::from_le_bytes(take_u64(&mut buf)?))
+            .map_err(|_| invalid_data("replication chunk is too long"))?;
+        if buf.len() < len {
+            return Err(invalid_data("replication chunk is too short"));
+        }
+        updates.push(&buf[..len]);
+        buf = &buf[len..];
+    }
+
+    if !buf.is_empty() {
+        return Err(invalid_data("replication chunk has trailing bytes"));
+    }
+
+    Ok((to, more, updates))
+}
+
+fn state_path(config: &Config) -> PathBuf {
+    config.get_path().join("replica")
+}
+
+fn read_state(config: &Config) -> Result<Option<ReplicaState>> {
+    let mut buf = match fs::read(state_path(config)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        other => other?,
+    };
+
+    if buf.len() < 4 {
+        return Err(invalid_data("replica state file is too short"));
+    }
+
+    let crc_at = buf.len() - 4;
+    let mut crc_expected = [0; 4];
+    crc_expected.copy_from_slice(&buf[crc_at..]);
+    buf.truncate(crc_at);
+
+    if crc32(&buf) != u32::from_le_bytes(crc_expected) {
+        return Err(invalid_data("replica state file is corrupted"));
+    }
+
+    let lsn_at = |i: usize| {
+        let mut lsn = [0; 8];
+        lsn.copy_from_slice(&buf[1 + 8 * i..9 + 8 * i]);
+        Lsn::from_le_bytes(lsn)
+    };
+
+    match (buf.first(), buf.len()) {
+        (Some(0), 9) => Ok(Some(ReplicaState::Applied(lsn_at(0)))),
+        (Some(1), 25) => Ok(Some(ReplicaState::Pending {
+            from: lsn_at(0),
+            to: lsn_at(1),
+            manifest: lsn_at(2),
+        })),
+        (Some(2), 1) => Ok(Some(ReplicaState::Failed)),
+        _ => Err(invalid_data("replica state file is corrupted")),
+    }
+}
+
+fn write_state(config: &Config, state: ReplicaState) -> Result<()> {
+    let mut buf = vec![];
+    match state {
+        ReplicaState::Applied(position) => {
+            buf.push(0);
+            buf.extend_from_slice(&position.to_le_bytes());
+        }
+        ReplicaState::Pending { from, to, manifest } => {
+            buf.push(1);
+            buf.extend_from_slice(&from.to_le_bytes());
+            buf.extend_from_slice(&to.to_le_bytes());
+            buf.extend_from_slice(&manifest.to_le_bytes());
+        }
+        ReplicaState::Failed => buf.push(2),
+    }
+    let crc = crc32(&buf).to_le_bytes();
+    buf.extend_from_slice(&crc);
+
+    let path = state_path(config);
+    let tmp_path = path.with_extension("generating");
+
+    let mut f = fs::File::create(&tmp_path)?;
+    f.write_all(&buf)?;
+    f.sync_all()?;
+    drop(f);
+
+    fs::rename(&tmp_path, &path)?;
+
+    #[cfg(unix)]
+    fs::File::open(config.get_path())?.sync_all()?;
+
+    Ok(())
+}
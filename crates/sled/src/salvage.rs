@@ -0,0 +1,374 @@
+//! Salvaging the items of a damaged database into a new one.
+//!
+//! The pages that can still be read from the damaged log are
+//! materialized by `pagecache::salvage`, and each tree is
+//! walked over them from its root in the `Meta`, like a
+//! traversal that completes every pending split, so that
+//! every key range is covered by the node that held it last.
+//! The ranges whose nodes could not be read are reported.
+use std::{fmt, time::Duration};
+
+use pagecache::{FastMap8, FastSet8, IntegrityProblem};
+
+use super::*;
+
+/// What was salvaged from a damaged database by `Db::salvage`,
+/// and what was lost.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SalvageReport {
+    /// The trees that were salvaged, in the order of
+    /// their names.
+    pub trees: Vec<SalvagedTree>,
+    /// The pages that the salvaged trees point to, but that
+    /// could not be read, and why.
+    pub lost_pages: Vec<(u64, String)>,
+    /// The problems found while reading the log.
+    pub problems: Vec<IntegrityProblem>,
+}
+
+impl SalvageReport {
+    /// Returns `true` if every tree was salvaged without
+    /// losing any key range.
+    pub fn is_complete(&self) -> bool {
+        self.trees
+            .iter()
+            .all(|tree| tree.lost.is_empty() && tree.damaged.is_empty())
+            && self.lost_pages.is_empty()
+    }
+}
+
+impl fmt::Display for SalvageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for tree in &self.trees {
+            writeln!(
+                f,
+                "tree {:?}: salvaged {} items",
+                String::from_utf8_lossy(&tree.name),
+                tree.items
+            )?;
+            for range in &tree.lost {
+                writeln!(f, "  lost {}", range)?;
+            }
+            for range in &tree.damaged {
+                writeln!(f, "  damaged {}", range)?;
+            }
+        }
+        for (pid, reason) in &self.lost_pages {
+            writeln!(f, "lost page {}: {}", pid, reason)?;
+        }
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// A tree that was salvaged by `Db::salvage`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SalvagedTree {
+    /// The name of the tree.
+    pub name: Vec<u8>,
+    /// The number of items that were written to the
+    /// new database.
+    pub items: usize,
+    /// The key ranges whose items were lost.
+    pub lost: Vec<KeyRange>,
+    /// The key ranges whose items were salvaged, but may be
+    /// missing some of the updates that were made to them.
+    pub damaged: Vec<KeyRange>,
+}
+
+/// A range of keys in a tree, held by a single page.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyRange {
+    /// The first key in the range.
+    pub start: Vec<u8>,
+    /// The key after the range, or `None` if it is unbounded.
+    pub end: Option<Vec<u8>>,
+    /// The page that held the range.
+    pub pid: u64,
+    /// Why the range was lost or damaged.
+    pub reason: String,
+}
+
+impl fmt::Display for KeyRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}..", self.start)?;
+        if let Some(ref end) = self.end {
+            write!(f, "{:?}", end)?;
+        }
+        write!(f, " in page {}: {}", self.pid, self.reason)
+    }
+}
+
+impl Db {
+    /// Salvages the items of the damaged database configured
+    /// by `damaged` into the trees of the same names in `into`,
+    /// which should be a new database, reporting exactly which
+    /// key ranges and pages were lost. The damaged database is
+    /// not started, and its log is never written to. Every page
+    /// is read from the last of its fragments that can still be
+    /// read, skipping the log segments, messages and blobs that
+    /// cannot, which makes this a last resort for a database
+    /// that fails to start, or that `Db::verify_integrity`
+    /// finds problems in.
+    ///
+    /// Indexes are not salvaged, and should be registered again
+    /// on `into`, where they are built from the salvaged items.
+    /// Merge operators and other properties of trees are not
+    /// salvaged either. Values that were set with a TTL keep
+    /// the rest of it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db, IVec};
+    ///
+    /// let path = std::env::temp_dir()
+    ///     .join(format!("sled_salvage_doc_{}", std::process::id()));
+    /// let db = Db::start(ConfigBuilder::new().path(&path).build()).unwrap();
+    /// db.open_tree(b"users").unwrap().set(b"alice", vec![1]).unwrap();
+    /// db.flush().unwrap();
+    /// drop(db);
+    ///
+    /// let config = ConfigBuilder::new().path(&path).build();
+    /// let new_config = ConfigBuilder::new().temporary(true).build();
+    /// let new_db = Db::start(new_config).unwrap();
+    ///
+    /// let report = Db::salvage(config, &new_db).unwrap();
+    /// assert!(report.is_complete(), "{}", report);
+    ///
+    /// let users = new_db.open_tree(b"users").unwrap();
+    /// assert_eq!(users.get(b"alice"), Ok(Some(IVec::from(vec![1]))));
+    /// # std::fs::remove_dir_all(path).unwrap();
+    /// ```
+    pub fn salvage(damaged: Config, into: &Db) -> Result<SalvageReport> {
+        let salvage = pagecache::salvage::<BLinkMaterializer, Frag>(&damaged)?;
+
+        let mut report = SalvageReport {
+            trees: vec![],
+            lost_pages: vec![],
+            problems: salvage.report.problems.clone(),
+        };
+
+        let tenants = match salvage.meta {
+            Some(ref meta) => meta.tenants(),
+            None => {
+                report
+                    .lost_pages
+                    .push((0, "the meta page could not be read".to_owned()));
+                return Ok(report);
+            }
+        };
+
+        for (name, root) in tenants {
+            if is_index_tree(&name) || root == std::u64::MAX {
+                continue;
+            }
+
+            let tree = if name == DEFAULT_TREE_ID {
+                into.default.clone()
+            } else {
+                into.open_tree(&name)?
+            };
+
+            let mut walk = Walk {
+                pages: &salvage.pages,
+                unreadable: &salvage.lost,
+                damaged_pages: &salvage.damaged,
+                into: &tree,
+                now: expiry::now_millis(),
+                visited: FastSet8::default(),
+                lost_pages: &mut report.lost_pages,
+                salvaged: SalvagedTree {
+                    name,
+                    ..SalvagedTree::default()
+                },
+            };
+            walk.range(root, vec![], None)?;
+
+            report.trees.push(walk.salvaged);
+        }
+
+        report.lost_pages.sort();
+        report.lost_pages.dedup();
+
+        Ok(report)
+    }
+}
+
+// The state of salvaging a single tree.
+struct Walk<'a> {
+    pages: &'a FastMap8<PageId, Frag>,
+    unreadable: &'a FastMap8<PageId, String>,
+    damaged_pages: &'a FastSet8<PageId>,
+    into: &'a Tree,
+    now: u64,
+    visited: FastSet8<PageId>,
+    lost_pages: &'a mut Vec<(u64, String)>,
+    salvaged: SalvagedTree,
+}
+
+impl<'a> Walk<'a> {
+    // Salvages the items in `start..end` from the node `pid`
+    // and the nodes to its right, and from their children.
+    fn range(
+        &mut self,
+        mut pid: PageId,
+        mut start: Key,
+        end: Option<Key>,
+    ) -> Result<()> {
+        loop {
+            let node = match self.pages.get(&pid) {
+                Some(Frag::Base(node)) if self.visited.insert(pid) => node,
+                Some(Frag::Base(_node)) => {
+                    self.lost(pid, start, end, "the page was reached twice");
+                    return Ok(());
+                }
+                Some(_) | None => {
+                    let reason = match self.unreadable.get(&pid) {
+                        Some(reason) => reason.clone(),
+                        None => "the page has no readable fragments".to_owned(),
+                    };
+                    self.lost(pid, start, end, &reason);
+                    self.lost_pages.push((pid, reason));
+                    return Ok(());
+                }
+            };
+
+            if node.lo.as_ref() > start.as_slice() {
+                // the node that held the start of the range
+                // is gone, and this one was linked in its place.
+                self.lost(
+                    pid,
+                    start,
+                    Some(node.lo.to_vec()),
+                    "no node holds the range before the page",
+                );
+                start = node.lo.to_vec();
+            }
+
+            // the part of the range that this node holds
+            let node_end = if node.hi.is_empty() {
+                end.clone()
+            } else {
+                match end {
+                    Some(ref end) if end.as_slice() <= node.hi.as_ref() => {
+                        Some(end.clone())
+                    }
+                    _ => Some(node.hi.to_vec()),
+                }
+            };
+            let in_range = |key: &[u8]| {
+                key >= start.as_slice()
+                    && match node_end {
+                        Some(ref end) => key < end.as_slice(),
+                        None => true,
+                    }
+            };
+
+            match node.data {
+                Data::Leaf(ref items) => {
+                    if self.damaged_pages.contains(&pid) {
+                        self.salvaged.damaged.push(KeyRange {
+                            start: start.clone(),
+                            end: node_end.clone(),
+                            pid,
+                            reason: "may be missing updates".to_owned(),
+                        });
+                    }
+                    for (k, v) in items {
+                        let key = prefix_decode(&node.lo, k);
+                        if !in_range(&key) {
+                            continue;
+                        }
+                        let expires_at = node
+                            .expiries
+                            .binary_search_by(|(ek, _at)| ek.cmp(k))
+                            .ok()
+                            .map(|idx| node.expiries[idx].1);
+                        match expires_at {
+                            Some(at) if at <= self.now => continue,
+                            Some(at) => {
+                                let ttl = Duration::from_millis(at - self.now);
+                                self.into.set_with_ttl(key, v.clone(), ttl)?;
+                            }
+                            None => {
+                                self.into.set(key, v.clone())?;
+                            }
+                        }
+                        self.salvaged.items += 1;
+                    }
+                }
+                Data::Index(ref ptrs) => {
+                    let keys: Vec<Key> = ptrs
+                        .iter()
+                        .map(|(k, _child)| prefix_decode(&node.lo, k))
+                        .collect();
+                    for (idx, (_k, child)) in ptrs.iter().enumerate() {
+                        let child_start =
+                            std::cmp::max(keys[idx].clone(), start.clone());
+                        let child_end = match keys.get(idx + 1) {
+                            Some(next) => match node_end {
+                                Some(ref end) if end < next => {
+                                    Some(end.clone())
+                                }
+                                _ => Some(next.clone()),
+                            },
+                            None => node_end.clone(),
+                        };
+                        let empty = match child_end {
+                            Some(ref end) => *end <= child_start,
+                            None => false,
+                        };
+                        if empty {
+                            continue;
+                        }
+                        self.range(*child, child_start, child_end)?;
+                    }
+                }
+            }
+
+            // continue to the right if the node ends before the range
+            let next_start = match (node_end, end.as_ref()) {
+                (Some(ref node_end), Some(end)) if node_end >= end => {
+                    return Ok(());
+                }
+                (None, _) => return Ok(()),
+                (Some(node_end), _) => node_end,
+            };
+
+            match node.next {
+                Some(next) => {
+                    pid = next;
+                    start = next_start;
+                }
+                None => {
+                    self.lost(
+                        pid,
+                        next_start,
+                        end,
+                        "the page has no right sibling to hold the rest \
+                         of the range",
+                    );
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn lost(
+        &mut self,
+        pid: PageId,
+        start: Key,
+        end: Option<Key>,
+        reason: &str,
+    ) {
+        self.salvaged.lost.push(KeyRange {
+            start,
+            end,
+            pid,
+            reason: reason.to_owned(),
+        });
+    }
+}
@@ -0,0 +1,550 @@
+//! Consistent point-in-time views of a `Db`.
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{self, Debug},
+    ops::{self, Deref, RangeBounds},
+    sync::Arc,
+};
+
+use pagecache::PageView;
+
+use super::*;
+use crate::iter::{lower_bound_includes, upper_bound_includes};
+
+struct View {
+    context: Context,
+    pages: PageView,
+    roots: BTreeMap<Vec<u8>, PageId>,
+}
+
+impl View {
+    fn node(&self, pid: PageId) -> Result<Node> {
+        match self.context.pagecache.get_from_view(&self.pages, pid)? {
+            Some(Frag::Base(node)) => Ok(node),
+            other => Err(Error::ReportableBug(format!(
+                "got non-base node while traversing snapshot: {:?}",
+                other
+            ))),
+        }
+    }
+
+    // Returns the leaf that is responsible for `key`.
+    fn leaf_for_key(&self, root: PageId, key: &[u8]) -> Result<Node> {
+        let mut node = self.node(root)?;
+
+        loop {
+            // (when hi is empty, it means it's unbounded)
+            if !node.hi.is_empty() && node.hi.as_ref() <= key {
+                let next = node.next.expect(
+                    "if our hi bound is not Inf (inity), \
+                     we should have a right sibling",
+                );
+                node = self.node(next)?;
+                continue;
+            }
+
+            let next = match node.data {
+                Data::Index(ref ptrs) => {
+                    let search = binary_search_lub(ptrs, |(k, _v)| {
+                        prefix_cmp_encoded(k, key, &node.lo)
+                    });
+                    ptrs[search.expect("failed to traverse index")].1
+                }
+                Data::Leaf(_) => return Ok(node),
+            };
+
+            node = self.node(next)?;
+        }
+    }
+
+    // Returns the leaf that is responsible for the keys
+    // immediately before `key`, or the last leaf of the
+    // tree if `key` is `None`.
+    fn leaf_before_key(
+        &self,
+        root: PageId,
+        key: Option<&[u8]>,
+    ) -> Result<Node> {
+        let mut node = self.node(root)?;
+
+        loop {
+            let overshot = match key {
+                None => !node.hi.is_empty(),
+                Some(key) => !node.hi.is_empty() && node.hi.as_ref() < key,
+            };
+
+            if overshot {
+                let next = node.next.expect(
+                    "if our hi bound is not Inf (inity), \
+                     we should have a right sibling",
+                );
+                node = self.node(next)?;
+                continue;
+            }
+
+            let next = match (&node.data, key) {
+                (Data::Index(ref ptrs), None) => {
+                    ptrs.last().expect("index nodes should never be empty").1
+                }
+                (Data::Index(ref ptrs), Some(key)) => {
+                    let search = binary_search_lt(ptrs, |(k, _v)| {
+                        prefix_cmp_encoded(k, key, &node.lo)
+                    });
+                    ptrs[search.expect("failed to traverse index")].1
+                }
+                (Data::Leaf(_), _) => return Ok(node),
+            };
+
+            node = self.node(next)?;
+        }
+    }
+}
+
+/// A read-only, point-in-time view of every `Tree` in a
+/// `Db`, created with `Db::snapshot`. Writes that happen
+/// after the snapshot was created are not visible through
+/// it, no matter how long it is kept around.
+///
+/// A `Snapshot` derefs to a view of the default `Tree`.
+///
+/// While a `Snapshot` is alive, the log segments that it
+/// reads from will not be reused, so long-lived snapshots
+/// cause the storage file to grow.
+///
+/// # Examples
+///
+/// ```
+/// use sled::{ConfigBuilder, Db, IVec};
+///
+/// let config = ConfigBuilder::new().temporary(true).build();
+/// let db = Db::start(config).unwrap();
+///
+/// db.set(b"a", vec![1]).unwrap();
+///
+/// let snapshot = db.snapshot().unwrap();
+///
+/// db.set(b"a", vec![2]).unwrap();
+/// db.set(b"b", vec![3]).unwrap();
+///
+/// assert_eq!(snapshot.get(b"a"), Ok(Some(IVec::from(vec![1]))));
+/// assert_eq!(snapshot.get(b"b"), Ok(None));
+/// assert_eq!(snapshot.iter().count(), 1);
+/// ```
+#[derive(Clone)]
+pub struct Snapshot {
+    view: Arc<View>,
+    default: SnapshotTree,
+}
+
+impl Deref for Snapshot {
+    type Target = SnapshotTree;
+
+    fn deref(&self) -> &SnapshotTree {
+        &self.default
+    }
+}
+
+impl Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Snapshot {{ lsn: {} }}", self.lsn())
+    }
+}
+
+impl Snapshot {
+    pub(crate) fn new(context: &Context) -> Result<Snapshot> {
+        let _measure = Measure::new(&context.metrics.tree_snapshot);
+
+        // NB pausing writers prevents them from modifying
+        // pages while their state is captured, so that the
+        // view is consistent across trees.
+        let pause = context.concurrency_control.pause();
+
+        let tx = context.pagecache.begin()?;
+        let roots = context.pagecache.meta(&tx)?.tenants();
+        let pages = context.pagecache.view()?;
+        tx.flush();
+
+        // writers may proceed while we wait for
+        // the captured pages to become stable.
+        drop(pause);
+        context.pagecache.make_stable(pages.lsn())?;
+
+        let view = Arc::new(View {
+            context: context.clone(),
+            pages,
+            roots,
+        });
+
+        let default = SnapshotTree::open(&view, DEFAULT_TREE_ID)?;
+
+        Ok(Snapshot { view, default })
+    }
+
+    pub(crate) fn context(&self) -> &Context {
+        &self.view.context
+    }
+
+    /// The stable log sequence number at which
+    /// this snapshot was taken.
+    pub fn lsn(&self) -> Lsn {
+        self.view.pages.lsn()
+    }
+
+    /// Open the view of the `Tree` with the provided name,
+    /// as it was when this snapshot was taken. Returns
+    /// `Error::CollectionNotFound` if it did not exist.
+    pub fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<SnapshotTree> {
+        SnapshotTree::open(&self.view, name.as_ref())
+    }
+
+    /// Returns the names of the trees that
+    /// existed when this snapshot was taken.
+    pub fn tree_names(&self) -> Vec<Vec<u8>> {
+        self.view
+            .roots
+            .keys()
+            .filter(|name| !is_index_tree(name))
+            .cloned()
+            .collect()
+    }
+}
+
+/// A read-only view of a single `Tree`, as it
+/// was when its `Snapshot` was taken.
+#[derive(Clone)]
+pub struct SnapshotTree {
+    view: Arc<View>,
+    tree_id: Vec<u8>,
+    root: PageId,
+}
+
+impl Debug for SnapshotTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SnapshotTree {{ tree_id: {:?}, lsn: {} }}",
+            self.tree_id,
+            self.view.pages.lsn()
+        )
+    }
+}
+
+impl SnapshotTree {
+    fn open(view: &Arc<View>, name: &[u8]) -> Result<SnapshotTree> {
+        match view.roots.get(name) {
+            Some(root) => Ok(SnapshotTree {
+                view: view.clone(),
+                tree_id: name.to_vec(),
+                root: *root,
+            }),
+            None => Err(Error::CollectionNotFound(name.to_vec())),
+        }
+    }
+
+    /// Retrieve a value as it was when the snapshot was taken.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+        let _measure = Measure::new(&self.view.context.metrics.tree_get);
+
+        let key = key.as_ref();
+        let node = self.view.leaf_for_key(self.root, key)?;
+        let leaf = node.data.leaf_ref().expect("node should be a leaf");
+
+        let search = leaf
+            .binary_search_by(|(k, _v)| prefix_cmp_encoded(k, key, &node.lo));
+
+        Ok(search
+            .ok()
+            .filter(|_| !node.is_expired(key))
+            .map(|idx| leaf[idx].1.clone()))
+    }
+
+    /// Returns `true` if the key was present
+    /// when the snapshot was taken.
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        self.get(key).map(|v| v.is_some())
+    }
+
+    /// Create a double-ended iterator over the tuples of
+    /// keys and values that were present when the snapshot
+    /// was taken.
+    pub fn iter(&self) -> SnapshotIter {
+        self.range::<Vec<u8>, _>(..)
+    }
+
+    /// Create a double-ended iterator over tuples of keys
+    /// and values, where the keys fall within the specified
+    /// range.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    /// for i in 0..10 {
+    ///     db.set(vec![i], vec![i]).unwrap();
+    /// }
+    ///
+    /// let snapshot = db.snapshot().unwrap();
+    /// db.clear().unwrap();
+    ///
+    /// let keys: Vec<Vec<u8>> = snapshot
+    ///     .range(vec![2]..vec![5])
+    ///     .rev()
+    ///     .map(|r| r.unwrap().0)
+    ///     .collect();
+    ///
+    /// assert_eq!(keys, vec![vec![4], vec![3], vec![2]]);
+    /// ```
+    pub fn range<K, R>(&self, range: R) -> SnapshotIter
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let lo = match range.start_bound() {
+            ops::Bound::Included(start) => {
+                ops::Bound::Included(start.as_ref().to_vec())
+            }
+            ops::Bound::Excluded(start) => {
+                ops::Bound::Excluded(start.as_ref().to_vec())
+            }
+            ops::Bound::Unbounded => ops::Bound::Unbounded,
+        };
+
+        let hi = match range.end_bound() {
+            ops::Bound::Included(end) => {
+                ops::Bound::Included(end.as_ref().to_vec())
+            }
+            ops::Bound::Excluded(end) => {
+                ops::Bound::Excluded(end.as_ref().to_vec())
+            }
+            ops::Bound::Unbounded => ops::Bound::Unbounded,
+        };
+
+        SnapshotIter {
+            tree: self.clone(),
+            lo,
+            hi,
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+            front_cursor: Cursor::Start,
+            back_cursor: Cursor::Start,
+            last_front: None,
+            last_back: None,
+            done: false,
+        }
+    }
+
+    /// Returns the number of elements that were
+    /// present when the snapshot was taken.
+    /// This is O(n) and not cheap.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns `true` if the tree was empty
+    /// when the snapshot was taken.
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+}
+
+enum Cursor {
+    Start,
+    At(PageId),
+    Before(Vec<u8>),
+    Done,
+}
+
+// A key and value, along with the time at which
+// the value expires, if it was set with a TTL.
+type ExpiringItem = (Vec<u8>, IVec, Option<u64>);
+
+/// A double-ended iterator over the keys and values
+/// of a `SnapshotTree`.
+pub struct SnapshotIter {
+    tree: SnapshotTree,
+    lo: ops::Bound<Vec<u8>>,
+    hi: ops::Bound<Vec<u8>>,
+    front: VecDeque<ExpiringItem>,
+    back: VecDeque<ExpiringItem>,
+    front_cursor: Cursor,
+    back_cursor: Cursor,
+    last_front: Option<Vec<u8>>,
+    last_back: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl SnapshotIter {
+    fn in_bounds(&self, key: &[u8]) -> bool {
+        lower_bound_includes(&self.lo, key)
+            && upper_bound_includes(&self.hi, key)
+    }
+
+    fn decoded_items(&self, node: &Node) -> Vec<ExpiringItem> {
+        node.data
+            .leaf_ref()
+            .expect("node should be a leaf")
+            .iter()
+            .map(|(k, v)| (prefix_decode(&node.lo, k), v.clone()))
+            .filter(|(k, _v)| self.in_bounds(k) && !node.is_expired(k))
+            .map(|(k, v)| {
+                let expires_at = node.expires_at(&k);
+                (k, v, expires_at)
+            })
+            .collect()
+    }
+
+    /// Like `next`, but also returns the time at which the
+    /// value expires, if it was set with a TTL.
+    pub(crate) fn next_with_expiry(&mut self) -> Option<Result<ExpiringItem>> {
+        let metrics = self.tree.view.context.metrics.clone();
+        let _measure = Measure::new(&metrics.tree_scan);
+
+        loop {
+            if self.done {
+                return None;
+            }
+
+            if let Some((k, v, expires_at)) = self.front.pop_front() {
+                if let Some(ref last_back) = self.last_back {
+                    if &k >= last_back {
+                        self.done = true;
+                        return None;
+                    }
+                }
+                self.last_front = Some(k.clone());
+                return Some(Ok((k, v, expires_at)));
+            }
+
+            if let Cursor::Done = self.front_cursor {
+                self.done = true;
+                return None;
+            }
+
+            if let Err(e) = self.advance_front() {
+                error!("snapshot iteration failed: {:?}", e);
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+
+    // Loads the next leaf into the front buffer.
+    fn advance_front(&mut self) -> Result<()> {
+        let view = &self.tree.view;
+
+        let node = match self.front_cursor {
+            Cursor::Start => {
+                let start: &[u8] = match self.lo {
+                    ops::Bound::Included(ref start)
+                    | ops::Bound::Excluded(ref start) => start,
+                    ops::Bound::Unbounded => b"",
+                };
+                view.leaf_for_key(self.tree.root, start)?
+            }
+            Cursor::At(pid) => view.node(pid)?,
+            Cursor::Before(_) | Cursor::Done => {
+                unreachable!("front cursor only moves forward")
+            }
+        };
+
+        self.front.extend(self.decoded_items(&node));
+
+        // (when hi is empty, it means it's the rightmost node)
+        self.front_cursor = if node.hi.is_empty()
+            || !upper_bound_includes(&self.hi, &node.hi)
+        {
+            Cursor::Done
+        } else {
+            Cursor::At(node.next.expect(
+                "if our hi bound is not Inf (inity), \
+                 we should have a right sibling",
+            ))
+        };
+
+        Ok(())
+    }
+
+    // Loads the previous leaf into the back buffer.
+    fn advance_back(&mut self) -> Result<()> {
+        let view = &self.tree.view;
+
+        let node = match self.back_cursor {
+            Cursor::Start => match self.hi {
+                ops::Bound::Included(ref end)
+                | ops::Bound::Excluded(ref end) => {
+                    view.leaf_for_key(self.tree.root, end)?
+                }
+                ops::Bound::Unbounded => {
+                    view.leaf_before_key(self.tree.root, None)?
+                }
+            },
+            Cursor::Before(ref key) => {
+                view.leaf_before_key(self.tree.root, Some(key))?
+            }
+            Cursor::At(_) | Cursor::Done => {
+                unreachable!("back cursor only moves backward")
+            }
+        };
+
+        self.back
+            .extend(self.decoded_items(&node).into_iter().rev());
+
+        // (when lo is empty, it means it's the leftmost node)
+        self.back_cursor = if node.lo.is_empty()
+            || !lower_bound_includes(&self.lo, &node.lo)
+        {
+            Cursor::Done
+        } else {
+            Cursor::Before(node.lo.to_vec())
+        };
+
+        Ok(())
+    }
+}
+
+impl Iterator for SnapshotIter {
+    type Item = Result<(Vec<u8>, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_expiry()
+            .map(|res| res.map(|(k, v, _expires_at)| (k, v)))
+    }
+}
+
+impl DoubleEndedIterator for SnapshotIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let metrics = self.tree.view.context.metrics.clone();
+        let _measure = Measure::new(&metrics.tree_reverse_scan);
+
+        loop {
+            if self.done {
+                return None;
+            }
+
+            if let Some((k, v, _expires_at)) = self.back.pop_front() {
+                if let Some(ref last_front) = self.last_front {
+                    if &k <= last_front {
+                        self.done = true;
+                        return None;
+                    }
+                }
+                self.last_back = Some(k.clone());
+                return Some(Ok((k, v)));
+            }
+
+            if let Cursor::Done = self.back_cursor {
+                self.done = true;
+                return None;
+            }
+
+            if let Err(e) = self.advance_back() {
+                error!("snapshot iteration failed: {:?}", e);
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
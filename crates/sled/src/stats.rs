@@ -0,0 +1,336 @@
+//! Approximate statistics about a `Tree`, estimated without
+//! a full scan.
+//!
+//! Every leaf of a tree is pointed to by an index node on the
+//! lowest index level, so the low keys of all leaves are known
+//! from the index nodes alone, which are far fewer than the
+//! leaves. The number and size of the items in the leaves is
+//! then estimated from at most `SAMPLED_LEAVES` of them, spread
+//! evenly over the leaves in question.
+use std::{ops::RangeBounds, sync::atomic::Ordering::SeqCst};
+
+use super::*;
+
+// The number of leaves that are read to estimate the
+// number and size of the items in the other leaves.
+const SAMPLED_LEAVES: usize = 64;
+
+// The low key and pid of every leaf, in key order.
+type Leaves = Vec<(IVec, PageId)>;
+
+impl Tree {
+    /// Returns an estimate of the number of items in this
+    /// `Tree`, read from its index nodes and a sample of its
+    /// leaves. Unlike `len`, this does not read every leaf,
+    /// and is exact for small trees.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let t = Db::start(config).unwrap();
+    ///
+    /// for i in 0..100_u32 {
+    ///     t.set(i.to_be_bytes(), vec![0; 10]).unwrap();
+    /// }
+    ///
+    /// assert_eq!(t.approximate_len(), Ok(100));
+    /// ```
+    pub fn approximate_len(&self) -> Result<usize> {
+        let tx = self.context.pagecache.begin()?;
+        let leaves = self.leaves(&tx)?;
+        let sample = self.sample(&leaves, &tx)?;
+        Ok(sample.estimate(leaves.len()).0 as usize)
+    }
+
+    /// Returns an estimate of the total number of bytes in the
+    /// keys and values of this `Tree`, read from its index nodes
+    /// and a sample of its leaves. This does not include the
+    /// overhead of the nodes that hold them, or of the log.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let t = Db::start(config).unwrap();
+    ///
+    /// for i in 0..100_u32 {
+    ///     t.set(i.to_be_bytes(), vec![0; 10]).unwrap();
+    /// }
+    ///
+    /// assert_eq!(t.approximate_size_in_bytes(), Ok(100 * (4 + 10)));
+    /// ```
+    pub fn approximate_size_in_bytes(&self) -> Result<u64> {
+        let tx = self.context.pagecache.begin()?;
+        let leaves = self.leaves(&tx)?;
+        let sample = self.sample(&leaves, &tx)?;
+        Ok(sample.estimate(leaves.len()).1)
+    }
+
+    /// Returns an estimate of the number of items with keys in
+    /// `range`. The leaves at either end of the range are
+    /// counted exactly, and the leaves in between are estimated
+    /// from a sample of them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let t = Db::start(config).unwrap();
+    ///
+    /// for i in 0..100_u32 {
+    ///     t.set(i.to_be_bytes(), vec![0; 10]).unwrap();
+    /// }
+    ///
+    /// let start = 10_u32.to_be_bytes();
+    /// let end = 20_u32.to_be_bytes();
+    /// assert_eq!(t.approximate_count_in_range(start..end), Ok(10));
+    /// assert_eq!(t.approximate_count_in_range(start..=end), Ok(11));
+    /// ```
+    pub fn approximate_count_in_range<K, R>(&self, range: R) -> Result<usize>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let (start, end) = half_open_bounds(&range);
+        let empty = match end {
+            Some(ref end) => *end <= start,
+            None => false,
+        };
+        if empty {
+            return Ok(0);
+        }
+
+        let tx = self.context.pagecache.begin()?;
+        let leaves = self.leaves(&tx)?;
+
+        // the first leaf is the last one with a low key <= start,
+        // and the last leaf is the last one with a low key < end.
+        let first = leaves
+            .iter()
+            .rposition(|(lo, _pid)| *lo <= start)
+            .unwrap_or(0);
+        let last = match end {
+            Some(ref end) => {
+                leaves.iter().rposition(|(lo, _pid)| lo < end).unwrap_or(0)
+            }
+            None => leaves.len() - 1,
+        };
+
+        let count_in_leaf = |pid| -> Result<usize> {
+            let node = match self.context.pagecache.get(pid, &tx)? {
+                PageGet::Materialized(frag, _ptr) => frag.unwrap_base(),
+                _ => return Ok(0),
+            };
+            let records = node.data.leaf_ref().expect("leaves hold items");
+            let count = records
+                .iter()
+                .map(|(k, _v)| prefix_decode(&node.lo, k))
+                .filter(|k| {
+                    **k >= *start
+                        && match end {
+                            Some(ref end) => **k < **end,
+                            None => true,
+                        }
+                })
+                .count();
+            Ok(count)
+        };
+
+        if first == last {
+            return count_in_leaf(leaves[first].1);
+        }
+
+        let inner = &leaves[first + 1..last];
+        let inner_count = self.sample(inner, &tx)?.estimate(inner.len()).0;
+
+        Ok(count_in_leaf(leaves[first].1)?
+            + inner_count as usize
+            + count_in_leaf(leaves[last].1)?)
+    }
+
+    /// Returns up to `n - 1` keys, in ascending order, that divide
+    /// this `Tree` into `n` ranges holding roughly equal numbers
+    /// of items, for example to process it in parallel. The keys
+    /// are chosen from the bounds of leaves when the tree has
+    /// many of them, in which case only its index nodes are read.
+    /// Fewer keys are returned if the tree has fewer than `n`
+    /// items.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let t = Db::start(config).unwrap();
+    ///
+    /// for i in 0..100_u8 {
+    ///     t.set(&[i], vec![i]).unwrap();
+    /// }
+    ///
+    /// let points = t.split_points(4).unwrap();
+    /// assert_eq!(points, vec![vec![25_u8], vec![50], vec![75]]);
+    /// ```
+    pub fn split_points(&self, n: usize) -> Result<Vec<IVec>> {
+        let tx = self.context.pagecache.begin()?;
+        let leaves = self.leaves(&tx)?;
+
+        if n <= 1 {
+            return Ok(vec![]);
+        }
+
+        let candidates: Vec<IVec> = if leaves.len() >= n * 2
+            && leaves.len() > SAMPLED_LEAVES
+        {
+            // leaves are split and merged to hold similar
+            // numbers of items, so their bounds are spread
+            // evenly enough over the items.
+            leaves.into_iter().map(|(lo, _pid)| lo).collect()
+        } else {
+            let mut keys = vec![];
+            for (_lo, pid) in leaves {
+                let node = match self.context.pagecache.get(pid, &tx)? {
+                    PageGet::Materialized(frag, _ptr) => frag.unwrap_base(),
+                    _ => continue,
+                };
+                let records = node.data.leaf_ref().expect("leaves hold items");
+                keys.extend(
+                    records
+                        .iter()
+                        .map(|(k, _v)| IVec::from(prefix_decode(&node.lo, k))),
+                );
+            }
+            keys
+        };
+
+        let mut points: Vec<IVec> = (1..n)
+            .map(|i| candidates.len() * i / n)
+            .filter(|&idx| idx > 0 && idx < candidates.len())
+            .map(|idx| candidates[idx].clone())
+            .collect();
+        points.dedup();
+
+        Ok(points)
+    }
+
+    // Returns the low key and pid of every leaf, read from
+    // the index nodes on the lowest level of the tree.
+    pub(crate) fn leaves(&self, tx: &Tx) -> Result<Leaves> {
+        let pagecache = &self.context.pagecache;
+
+        let get_node = |pid| -> Result<Option<&Node>> {
+            if pid == std::u64::MAX {
+                // this collection has been explicitly removed
+                return Err(Error::CollectionNotFound(self.tree_id.clone()));
+            }
+            match pagecache.get(pid, tx)? {
+                PageGet::Materialized(frag, _ptr) => {
+                    Ok(Some(frag.unwrap_base()))
+                }
+                _ => Ok(None),
+            }
+        };
+
+        'restart: loop {
+            // find the leftmost node on the lowest index level
+            let mut pid = self.root.load(SeqCst);
+            loop {
+                let node = match get_node(pid)? {
+                    Some(node) => node,
+                    None => continue 'restart,
+                };
+                let ptrs = match node.data {
+                    Data::Index(ref ptrs) => ptrs,
+                    Data::Leaf(_) => {
+                        return Err(Error::ReportableBug(
+                            "the root of a tree should be an index".to_owned(),
+                        ));
+                    }
+                };
+                let child = ptrs[0].1;
+                match get_node(child)? {
+                    Some(child) if child.data.leaf_ref().is_none() => {}
+                    Some(_leaf) => break,
+                    None => continue 'restart,
+                }
+                pid = child;
+            }
+
+            // and walk right along it
+            let mut leaves = vec![];
+            loop {
+                let node = match get_node(pid)? {
+                    Some(node) => node,
+                    None => continue 'restart,
+                };
+                if let Data::Index(ref ptrs) = node.data {
+                    leaves.extend(ptrs.iter().map(|(k, child)| {
+                        (IVec::from(prefix_decode(&node.lo, k)), *child)
+                    }));
+                }
+                match node.next {
+                    Some(next) => pid = next,
+                    None => return Ok(leaves),
+                }
+            }
+        }
+    }
+
+    // Reads up to `SAMPLED_LEAVES` of `leaves`, spread evenly
+    // over them, or all of them if there are fewer.
+    fn sample(&self, leaves: &[(IVec, PageId)], tx: &Tx) -> Result<Sample> {
+        let mut sample = Sample::default();
+
+        let stride = std::cmp::max(
+            1,
+            (leaves.len() + SAMPLED_LEAVES - 1) / SAMPLED_LEAVES,
+        );
+        for (_lo, pid) in leaves.iter().step_by(stride) {
+            let node = match self.context.pagecache.get(*pid, tx)? {
+                PageGet::Materialized(frag, _ptr) => frag.unwrap_base(),
+                // the leaf was merged away concurrently
+                _ => continue,
+            };
+            let records = node.data.leaf_ref().expect("leaves hold items");
+
+            sample.leaves += 1;
+            sample.items += records.len() as u64;
+            // NB the first byte of an encoded key is the
+            // length of the prefix it shares with `lo`.
+            sample.bytes += records.iter().fold(0, |sz, (k, v)| {
+                sz + k[0] as u64 + k.len() as u64 - 1 + v.len() as u64
+            });
+        }
+
+        Ok(sample)
+    }
+}
+
+#[derive(Default)]
+struct Sample {
+    leaves: u64,
+    items: u64,
+    bytes: u64,
+}
+
+impl Sample {
+    // Returns the estimated number of items and bytes
+    // in `leaves` leaves, each like the sampled ones.
+    fn estimate(&self, leaves: usize) -> (u64, u64) {
+        if self.leaves == 0 {
+            return (0, 0);
+        }
+        let leaves = leaves as u64;
+        (
+            self.items * leaves / self.leaves,
+            self.bytes * leaves / self.leaves,
+        )
+    }
+}
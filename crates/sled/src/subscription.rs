@@ -1,24 +1,24 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, VecDeque},
     sync::{
         atomic::{AtomicUsize, Ordering::Relaxed},
-        mpsc::{sync_channel, Receiver, SyncSender},
-        Arc, RwLock,
+        mpsc::{RecvTimeoutError, TryRecvError},
+        Arc, Condvar, Mutex, MutexGuard, RwLock,
     },
+    time::{Duration, Instant},
 };
 
-use futures::{
-    future::Future,
-    sync::oneshot::{
-        channel as future_channel, Receiver as FutureReceiver,
-        Sender as FutureSender,
-    },
-};
+use futures::{stream::Stream, task::AtomicTask, Async, Poll};
+
+use log::error;
+use pagecache::{Lsn, RecoveryGuard, SharedBatch};
 
-use crate::ivec::IVec;
+use crate::{expiry, ivec::IVec, prefix_successor, Result, Tree};
 
 static ID_GEN: AtomicUsize = AtomicUsize::new(0);
 
+const DEFAULT_CAPACITY: usize = 1024;
+
 /// An event that happened to a key that a subscriber is interested in.
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Event {
@@ -51,31 +51,285 @@ impl Clone for Event {
     }
 }
 
-type Senders = Vec<(usize, SyncSender<FutureReceiver<Event>>)>;
+/// What happens to a write when a `Subscriber` that
+/// is interested in it has a full buffer, because it
+/// is not keeping up with new writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Block the writer until the subscriber has
+    /// made room in its buffer. This is the default.
+    Block,
+    /// Discard the oldest buffered event to make room.
+    /// The discarded events are counted by
+    /// `Subscriber::lagged`.
+    DropOldest,
+    /// Stop delivering events to the subscriber. It
+    /// receives the events that were already buffered,
+    /// and then it ends, after which `Subscriber::lagged`
+    /// returns the number of events that it missed.
+    Disconnect,
+}
+
+// The result of trying to receive from an `Inbox`.
+enum Recv {
+    Ready(Event),
+    Pending,
+    Ended,
+}
+
+#[derive(Default)]
+struct Queue {
+    // events are reserved in order before the write that
+    // causes them is attempted, and filled in once it has
+    // succeeded, so only a filled front slot can be delivered.
+    slots: VecDeque<(u64, Option<Event>)>,
+    // reservations that will not be delivered, which are
+    // counted as lagged only if their write succeeds
+    missed: Vec<u64>,
+    next_seq: u64,
+    lagged: usize,
+    disconnected: bool,
+    ended: bool,
+    closed: bool,
+}
+
+impl Queue {
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+}
+
+struct Inbox {
+    capacity: usize,
+    backpressure: Backpressure,
+    queue: Mutex<Queue>,
+    readable: Condvar,
+    writable: Condvar,
+    task: AtomicTask,
+}
+
+impl Inbox {
+    fn reserve(&self) -> Option<u64> {
+        let mut queue = self.queue.lock().unwrap();
+
+        loop {
+            if queue.closed || queue.ended {
+                return None;
+            }
+
+            if queue.disconnected {
+                let seq = queue.next_seq();
+                queue.missed.push(seq);
+                return Some(seq);
+            }
+
+            if queue.slots.len() < self.capacity {
+                break;
+            }
+
+            match self.backpressure {
+                Backpressure::Block => {
+                    queue = self.writable.wait(queue).unwrap();
+                }
+                Backpressure::DropOldest => {
+                    let (seq, event) = queue.slots.pop_front().unwrap();
+                    if event.is_some() {
+                        queue.lagged += 1;
+                    } else {
+                        queue.missed.push(seq);
+                    }
+                    // the new front slot may already be filled
+                    self.notify_readers();
+                }
+                Backpressure::Disconnect => {
+                    queue.disconnected = true;
+                    drop(queue);
+                    self.notify_readers();
+                    queue = self.queue.lock().unwrap();
+                }
+            }
+        }
+
+        let seq = queue.next_seq();
+        queue.slots.push_back((seq, None));
+
+        Some(seq)
+    }
+
+    fn fill(&self, seq: u64, event: Event) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Ok(idx) = queue.slots.binary_search_by_key(&seq, |s| s.0) {
+            queue.slots[idx].1 = Some(event);
+            if idx == 0 {
+                drop(queue);
+                self.notify_readers();
+            }
+        } else if let Some(idx) = queue.missed.iter().position(|s| *s == seq) {
+            queue.missed.swap_remove(idx);
+            if !queue.ended {
+                queue.lagged += 1;
+                drop(queue);
+                self.notify_readers();
+            }
+        }
+    }
+
+    fn abort(&self, seq: u64) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Ok(idx) = queue.slots.binary_search_by_key(&seq, |s| s.0) {
+            queue.slots.remove(idx);
+            drop(queue);
+            self.writable.notify_all();
+            self.notify_readers();
+        } else if let Some(idx) = queue.missed.iter().position(|s| *s == seq) {
+            queue.missed.swap_remove(idx);
+            if queue.missed.is_empty() && queue.disconnected {
+                drop(queue);
+                self.notify_readers();
+            }
+        }
+    }
+
+    fn notify_readers(&self) {
+        self.readable.notify_all();
+        self.task.notify();
+    }
+
+    fn recv(&self, queue: &mut MutexGuard<'_, Queue>) -> Recv {
+        if queue.ended {
+            return Recv::Ended;
+        }
+
+        match queue.slots.front() {
+            Some((_, Some(_))) => {
+                let (_, event) = queue.slots.pop_front().unwrap();
+                self.writable.notify_all();
+                Recv::Ready(event.unwrap())
+            }
+            Some((_, None)) => Recv::Pending,
+            // wait until the writes that were in flight when we
+            // disconnected have finished, so the count is complete
+            None if queue.disconnected && queue.missed.is_empty() => {
+                queue.ended = true;
+                Recv::Ended
+            }
+            None => Recv::Pending,
+        }
+    }
+}
+
+type Senders = Vec<(usize, Arc<Inbox>)>;
 
-/// A subscriber listening on a specified prefix
+/// A subscriber listening on a specified prefix.
+///
+/// This is both a blocking `Iterator` and a non-blocking
+/// `Stream` of `Event`s, and events can also be polled
+/// with `try_next` or waited on with `next_timeout`.
+/// What happens when a subscriber can not keep up with
+/// new writes is determined by its `Backpressure`.
 pub struct Subscriber {
     id: usize,
-    rx: Receiver<FutureReceiver<Event>>,
+    inbox: Arc<Inbox>,
     home: Arc<RwLock<Senders>>,
 }
 
 impl Drop for Subscriber {
     fn drop(&mut self) {
+        // wake up any writers that are blocked on us
+        // before we wait for them to release `home`.
+        self.inbox.queue.lock().unwrap().closed = true;
+        self.inbox.writable.notify_all();
+
         let mut w_senders = self.home.write().unwrap();
         w_senders.retain(|(id, _)| *id != self.id);
     }
 }
 
+impl Subscriber {
+    /// Returns the total number of events that were not
+    /// delivered to this subscriber because it fell behind,
+    /// according to its `Backpressure`. For a subscriber
+    /// using `Backpressure::Disconnect`, the count is final
+    /// once it has ended.
+    pub fn lagged(&self) -> usize {
+        self.inbox.queue.lock().unwrap().lagged
+    }
+
+    /// Returns the next `Event` if one is ready, without blocking.
+    /// Returns `TryRecvError::Disconnected` after a subscriber
+    /// using `Backpressure::Disconnect` has received all of its
+    /// events.
+    pub fn try_next(&mut self) -> std::result::Result<Event, TryRecvError> {
+        let mut queue = self.inbox.queue.lock().unwrap();
+        match self.inbox.recv(&mut queue) {
+            Recv::Ready(event) => Ok(event),
+            Recv::Pending => Err(TryRecvError::Empty),
+            Recv::Ended => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    /// Blocks until the next `Event` is ready, or until
+    /// the timeout has elapsed.
+    pub fn next_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> std::result::Result<Event, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.inbox.queue.lock().unwrap();
+        loop {
+            match self.inbox.recv(&mut queue) {
+                Recv::Ready(event) => return Ok(event),
+                Recv::Ended => return Err(RecvTimeoutError::Disconnected),
+                Recv::Pending => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    queue = self
+                        .inbox
+                        .readable
+                        .wait_timeout(queue, deadline - now)
+                        .unwrap()
+                        .0;
+                }
+            }
+        }
+    }
+}
+
 impl Iterator for Subscriber {
     type Item = Event;
 
     fn next(&mut self) -> Option<Event> {
+        let mut queue = self.inbox.queue.lock().unwrap();
         loop {
-            let future_rx = self.rx.recv().ok()?;
-            match future_rx.wait() {
-                Ok(event) => return Some(event),
-                Err(_cancelled) => continue,
+            match self.inbox.recv(&mut queue) {
+                Recv::Ready(event) => return Some(event),
+                Recv::Ended => return None,
+                Recv::Pending => {
+                    queue = self.inbox.readable.wait(queue).unwrap();
+                }
+            }
+        }
+    }
+}
+
+impl Stream for Subscriber {
+    type Item = Event;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Event>, ()> {
+        let mut queue = self.inbox.queue.lock().unwrap();
+        match self.inbox.recv(&mut queue) {
+            Recv::Ready(event) => Ok(Async::Ready(Some(event))),
+            Recv::Ended => Ok(Async::Ready(None)),
+            Recv::Pending => {
+                // NB registered while holding the queue mutex,
+                // which writers hold while making events ready.
+                self.inbox.task.register();
+                Ok(Async::NotReady)
             }
         }
     }
@@ -88,6 +342,15 @@ pub(crate) struct Subscriptions {
 
 impl Subscriptions {
     pub(crate) fn register(&self, prefix: Vec<u8>) -> Subscriber {
+        self.register_with(prefix, DEFAULT_CAPACITY, Backpressure::Block)
+    }
+
+    pub(crate) fn register_with(
+        &self,
+        prefix: Vec<u8>,
+        capacity: usize,
+        backpressure: Backpressure,
+    ) -> Subscriber {
         let r_mu = {
             let r_mu = self.watched.read().unwrap();
             if r_mu.contains_key(&prefix) {
@@ -103,22 +366,58 @@ impl Subscriptions {
             }
         };
 
-        let (tx, rx) = sync_channel(1024);
+        let inbox = Arc::new(Inbox {
+            capacity: std::cmp::max(capacity, 1),
+            backpressure,
+            queue: Mutex::new(Queue::default()),
+            readable: Condvar::new(),
+            writable: Condvar::new(),
+            task: AtomicTask::new(),
+        });
 
         let arc_senders = &r_mu[&prefix];
         let mut w_senders = arc_senders.write().unwrap();
 
         let id = ID_GEN.fetch_add(1, Relaxed);
 
-        w_senders.push((id, tx));
+        w_senders.push((id, inbox.clone()));
 
         Subscriber {
             id,
-            rx,
+            inbox,
             home: arc_senders.clone(),
         }
     }
 
+    // Returns `true` if any subscriber's prefix covers `key`.
+    fn is_watched(&self, key: &[u8]) -> bool {
+        let r_mu = self.watched.read().unwrap();
+        r_mu.iter().any(|(k, subs_rwl)| {
+            key.starts_with(k) && !subs_rwl.read().unwrap().is_empty()
+        })
+    }
+
+    // Returns `true` if any subscriber's prefix covers a key
+    // that is at least `start`, and below `end` if there is one.
+    pub(crate) fn is_watched_range(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> bool {
+        let r_mu = self.watched.read().unwrap();
+        r_mu.iter().any(|(k, subs_rwl)| {
+            let below_end = match end {
+                Some(end) => &**k < end,
+                None => true,
+            };
+            let above_start = match prefix_successor(k) {
+                Some(successor) => &*successor > start,
+                None => true,
+            };
+            below_end && above_start && !subs_rwl.read().unwrap().is_empty()
+        })
+    }
+
     pub(crate) fn reserve<R: AsRef<[u8]>>(
         &self,
         key: R,
@@ -131,12 +430,10 @@ impl Subscriptions {
         for (_, subs_rwl) in prefixes {
             let subs = subs_rwl.read().unwrap();
 
-            for (_id, sender) in subs.iter() {
-                let (tx, rx) = future_channel();
-                if sender.send(rx).is_err() {
-                    continue;
+            for (_id, inbox) in subs.iter() {
+                if let Some(seq) = inbox.reserve() {
+                    subscribers.push((inbox.clone(), seq));
                 }
-                subscribers.push(tx);
             }
         }
 
@@ -148,26 +445,173 @@ impl Subscriptions {
     }
 }
 
+/// Subscriber notifications for writes that must only be
+/// delivered once every write of their batch is applied.
+/// Nothing is reserved until `complete` is called, so a
+/// deferred batch never waits on a full subscriber while
+/// other writers wait for its commit. The values that the
+/// writes replaced are kept as well, so that a batch that
+/// fails partway through can be undone.
+#[derive(Default)]
+pub(crate) struct DeferredEvents {
+    pending: Vec<(Arc<Subscriptions>, Event)>,
+    // previous values and expiries, in the order written
+    undo: Vec<(Tree, Vec<u8>, Option<OldValue>)>,
+}
+
+// a replaced value, and when it was to expire
+type OldValue = (IVec, Option<u64>);
+
+impl DeferredEvents {
+    pub(crate) fn push(
+        &mut self,
+        subscriptions: &Arc<Subscriptions>,
+        event: Event,
+    ) {
+        if subscriptions.is_watched(event.key()) {
+            self.pending.push((subscriptions.clone(), event));
+        }
+    }
+
+    /// Records the value and expiry that a write of the
+    /// batch replaced, or `None` if the key was not set.
+    pub(crate) fn record(
+        &mut self,
+        tree: &Tree,
+        key: &[u8],
+        old: Option<OldValue>,
+    ) {
+        self.undo.push((tree.clone(), key.to_vec(), old));
+    }
+
+    /// Seals the batch if `res` is `Ok`, and returns the last
+    /// LSN of the batch along with `res`. Otherwise the writes
+    /// that were applied are undone before the batch is
+    /// sealed, and their events are dropped. If that fails
+    /// too, the batch is aborted, which fails every later
+    /// operation of the system, so that the partially applied
+    /// batch is neither read nor recovered. A batch that can
+    /// not be sealed fails the system in the same way.
+    pub(crate) fn seal_or_undo<R>(
+        &mut self,
+        recovery_guard: RecoveryGuard<'_>,
+        res: Result<R>,
+    ) -> Result<(R, Lsn)> {
+        let err = match res {
+            Ok(ret) => {
+                let last_lsn = recovery_guard.seal_batch()?;
+                return Ok((ret, last_lsn));
+            }
+            Err(err) => err,
+        };
+
+        if let Err(undo_err) = self.discard() {
+            recovery_guard.abort(undo_err);
+            return Err(err);
+        }
+
+        recovery_guard.seal_batch()?;
+
+        Err(err)
+    }
+
+    /// Like `seal_or_undo`, for a write that joined the batch
+    /// shared by the writes since the last flush, which that
+    /// flush seals.
+    pub(crate) fn undo_in_shared_batch<R>(
+        &mut self,
+        shared_batch: SharedBatch<'_>,
+        res: Result<R>,
+    ) -> Result<R> {
+        let err = match res {
+            Ok(ret) => return Ok(ret),
+            Err(err) => err,
+        };
+
+        if let Err(undo_err) = self.discard() {
+            shared_batch.abort(undo_err);
+        }
+
+        Err(err)
+    }
+
+    // Drops the events of a batch that failed, and undoes
+    // the part of it that was applied.
+    fn discard(&mut self) -> Result<()> {
+        self.pending.clear();
+
+        self.undo().map_err(|undo_err| {
+            error!(
+                "failed to undo a partially applied batch, \
+                 aborting it: {:?}",
+                undo_err
+            );
+            undo_err
+        })
+    }
+
+    // Restores the recorded values in reverse order, which
+    // also restores the index entries that they had.
+    fn undo(&mut self) -> Result<()> {
+        // NB the batch's events are dropped, so undoing it
+        // notifies nobody either.
+        let mut scratch = DeferredEvents::default();
+
+        while let Some((tree, key, old)) = self.undo.pop() {
+            if let Some((value, expires_at)) = old {
+                tree.set_with_expiry(
+                    &key,
+                    value,
+                    expires_at,
+                    Some(&mut scratch),
+                )?;
+                if let Some(at) = expires_at {
+                    expiry::lower_next_expiry(&tree.context.next_expiry, at);
+                }
+            } else {
+                tree.del_inner(&key, Some(&mut scratch))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn complete(self) {
+        for (subscriptions, event) in self.pending {
+            if let Some(res) = subscriptions.reserve(event.key()) {
+                res.complete(event);
+            }
+        }
+    }
+}
+
+/// Slots in the buffers of interested subscribers that
+/// are filled in when the write succeeds, or released
+/// when this is dropped without being completed.
 pub(crate) struct ReservedBroadcast {
-    subscribers: Vec<FutureSender<Event>>,
+    subscribers: Vec<(Arc<Inbox>, u64)>,
 }
 
 impl ReservedBroadcast {
-    pub fn complete(self, event: Event) {
-        let len = self.subscribers.len();
-        let mut iter = self.subscribers.into_iter();
+    pub fn complete(mut self, event: Event) {
+        let mut subscribers = std::mem::replace(&mut self.subscribers, vec![]);
+
+        let last = subscribers.pop();
 
-        let mut sent = 0;
+        for (inbox, seq) in subscribers {
+            inbox.fill(seq, event.clone());
+        }
 
-        while sent + 1 < len {
-            sent += 1;
-            let tx = iter.next().unwrap();
-            let _ = tx.send(event.clone());
+        if let Some((inbox, seq)) = last {
+            inbox.fill(seq, event);
         }
+    }
+}
 
-        if len != 0 {
-            let tx = iter.next().unwrap();
-            let _ = tx.send(event);
+impl Drop for ReservedBroadcast {
+    fn drop(&mut self) {
+        for (inbox, seq) in self.subscribers.drain(..) {
+            inbox.abort(seq);
         }
     }
 }
@@ -229,3 +673,128 @@ fn basic_subscription() {
 
     assert_eq!(s4.next().unwrap().key(), &*k8);
 }
+
+#[test]
+fn stream_subscription() {
+    use futures::future::Future;
+
+    let subs = Subscriptions::default();
+
+    let mut s1 = subs.register(vec![]);
+
+    // nothing has been reserved yet
+    let poll = futures::future::poll_fn(|| s1.poll().map(Async::Ready));
+    assert_eq!(poll.wait(), Ok(Async::NotReady));
+
+    let k1 = vec![1];
+    let r1 = subs.reserve(&k1).unwrap();
+
+    // reserved, but not yet completed
+    let poll = futures::future::poll_fn(|| s1.poll().map(Async::Ready));
+    assert_eq!(poll.wait(), Ok(Async::NotReady));
+
+    r1.complete(Event::Del(k1.clone()));
+
+    let k2 = vec![2];
+    let r2 = subs.reserve(&k2).unwrap();
+    drop(r2);
+
+    let k3 = vec![3];
+    let r3 = subs.reserve(&k3).unwrap();
+    r3.complete(Event::Set(k3.clone(), IVec::from(k3.clone())));
+
+    let mut stream = s1.wait();
+    assert_eq!(stream.next().unwrap().unwrap().key(), &*k1);
+    assert_eq!(stream.next().unwrap().unwrap().key(), &*k3);
+}
+
+#[test]
+fn backpressure_subscription() {
+    let subs = Subscriptions::default();
+
+    let mut dropper = subs.register_with(vec![], 2, Backpressure::DropOldest);
+    let mut disconnector =
+        subs.register_with(vec![], 2, Backpressure::Disconnect);
+
+    assert_eq!(dropper.try_next(), Err(TryRecvError::Empty));
+    assert_eq!(
+        disconnector.next_timeout(Duration::from_millis(1)),
+        Err(RecvTimeoutError::Timeout)
+    );
+
+    for i in 0..5_u8 {
+        let res = subs.reserve([i]).unwrap();
+        res.complete(Event::Del(vec![i]));
+    }
+
+    assert_eq!(dropper.lagged(), 3);
+    assert_eq!(dropper.try_next(), Ok(Event::Del(vec![3])));
+    assert_eq!(dropper.try_next(), Ok(Event::Del(vec![4])));
+    assert_eq!(dropper.try_next(), Err(TryRecvError::Empty));
+
+    assert_eq!(disconnector.next(), Some(Event::Del(vec![0])));
+    assert_eq!(disconnector.next(), Some(Event::Del(vec![1])));
+    assert_eq!(disconnector.next(), None);
+    assert_eq!(disconnector.lagged(), 3);
+    assert_eq!(disconnector.try_next(), Err(TryRecvError::Disconnected));
+
+    // a blocked writer is released when its subscriber is dropped
+    let subs = Arc::new(Subscriptions::default());
+    let blocker = subs.register_with(vec![], 1, Backpressure::Block);
+    subs.reserve([0]).unwrap().complete(Event::Del(vec![0]));
+
+    let subs2 = subs.clone();
+    let writer = std::thread::spawn(move || subs2.reserve([1]).is_none());
+
+    std::thread::sleep(Duration::from_millis(10));
+    drop(blocker);
+    assert!(writer.join().unwrap());
+}
+
+#[test]
+fn undo_partially_applied_batch() {
+    use crate::{ConfigBuilder, Db, Error};
+
+    let config = ConfigBuilder::new().temporary(true).build();
+    let db = Db::start(config).unwrap();
+
+    db.set(b"a", vec![1]).unwrap();
+    db.set_with_ttl(b"b", vec![2], Duration::from_secs(3600))
+        .unwrap();
+
+    let expires_at = |key: &[u8]| {
+        let tx = db.context.pagecache.begin().unwrap();
+        let (path, _) = db.get_internal(key, &tx).unwrap();
+        path.last().unwrap().1.unwrap_base().expires_at(key)
+    };
+    let b_expires_at = expires_at(b"b");
+    assert!(b_expires_at.is_some());
+
+    let mut events = db.watch_prefix(vec![]);
+
+    let recovery_guard = db.context.pagecache.pin_log().unwrap();
+    let mut deferred = DeferredEvents::default();
+
+    db.set_inner(b"a", IVec::from(vec![10]), Some(&mut deferred))
+        .unwrap();
+    db.del_inner(b"b", Some(&mut deferred)).unwrap();
+    db.set_inner(b"c", IVec::from(vec![30]), Some(&mut deferred))
+        .unwrap();
+    db.set_inner(b"a", IVec::from(vec![11]), Some(&mut deferred))
+        .unwrap();
+
+    let res: Result<()> = Err(Error::Unsupported("failed partway".into()));
+    assert_eq!(
+        deferred.seal_or_undo(recovery_guard, res).map(|_| ()),
+        Err(Error::Unsupported("failed partway".into()))
+    );
+    deferred.complete();
+
+    assert_eq!(db.get(b"a"), Ok(Some(IVec::from(vec![1]))));
+    assert_eq!(db.get(b"b"), Ok(Some(IVec::from(vec![2]))));
+    assert_eq!(expires_at(b"b"), b_expires_at);
+    assert_eq!(db.get(b"c"), Ok(None));
+
+    // subscribers hear of neither the batch nor its undoing
+    assert_eq!(events.try_next(), Err(TryRecvError::Empty));
+}
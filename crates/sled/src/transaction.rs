@@ -0,0 +1,395 @@
+//! Multi-key serializable transactions.
+//!
+//! A transaction buffers its writes in memory and records
+//! every value it reads. When the transaction function
+//! returns successfully, the read set is validated and the
+//! write set is applied atomically. Transactions that
+//! lose a conflict are transparently retried.
+//!
+//! # Examples
+//!
+//! ```
+//! use sled::{ConfigBuilder, Db, IVec};
+//!
+//! let config = ConfigBuilder::new().temporary(true).build();
+//! let db = Db::start(config).unwrap();
+//!
+//! db.set(b"alice", vec![10]).unwrap();
+//! db.set(b"bob", vec![0]).unwrap();
+//!
+//! db.transaction(|tx| {
+//!     let alice = tx.get(b"alice")?.unwrap()[0];
+//!     let bob = tx.get(b"bob")?.unwrap()[0];
+//!     tx.set(b"alice", vec![alice - 5])?;
+//!     tx.set(b"bob", vec![bob + 5])?;
+//!     Ok(())
+//! })
+//! .unwrap();
+//!
+//! assert_eq!(db.get(b"alice"), Ok(Some(IVec::from(vec![5]))));
+//! assert_eq!(db.get(b"bob"), Ok(Some(IVec::from(vec![5]))));
+//! ```
+use std::{cell::RefCell, collections::BTreeMap, fmt, sync::Arc};
+
+use super::*;
+
+/// An error that may be returned from a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionError {
+    /// The transaction function requested that the
+    /// transaction be aborted. None of its writes
+    /// will be applied.
+    Abort,
+    /// An error was encountered while interacting
+    /// with the underlying storage.
+    Storage(Error),
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::Abort => write!(f, "Transaction was aborted"),
+            TransactionError::Storage(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+impl From<Error> for TransactionError {
+    fn from(error: Error) -> TransactionError {
+        TransactionError::Storage(error)
+    }
+}
+
+/// The result of a transaction or of an operation
+/// performed from within a transaction.
+pub type TransactionResult<T> = std::result::Result<T, TransactionError>;
+
+type TxKey = (Vec<u8>, Vec<u8>);
+
+/// The read and write sets of a single transaction
+/// attempt, shared by every `TransactionalTree` that
+/// participates in it.
+pub(crate) struct TransactionState {
+    ts: u64,
+    reads: RefCell<BTreeMap<TxKey, (Tree, Option<IVec>)>>,
+    writes: RefCell<BTreeMap<TxKey, (Tree, Option<IVec>)>>,
+}
+
+impl TransactionState {
+    fn new(ts: u64) -> TransactionState {
+        TransactionState {
+            ts,
+            reads: RefCell::new(BTreeMap::new()),
+            writes: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+/// A view of a `Tree` from within a transaction.
+/// Reads observe the writes that were previously
+/// performed by the same transaction.
+pub struct TransactionalTree<'a> {
+    tree: &'a Tree,
+    state: &'a TransactionState,
+}
+
+impl<'a> TransactionalTree<'a> {
+    /// Retrieve a value from the `Tree` if it exists.
+    pub fn get<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> TransactionResult<Option<IVec>> {
+        let tx_key = (self.tree.tree_id.clone(), key.as_ref().to_vec());
+
+        if let Some((_tree, value)) = self.state.writes.borrow().get(&tx_key) {
+            return Ok(value.clone());
+        }
+
+        if let Some((_tree, value)) = self.state.reads.borrow().get(&tx_key) {
+            return Ok(value.clone());
+        }
+
+        let tx = self.tree.context.pagecache.begin()?;
+
+        let (path, value) = self.tree.get_internal(key.as_ref(), &tx)?;
+        let value = value.cloned();
+
+        let (leaf_id, _leaf_frag, _leaf_ptr) = path
+            .last()
+            .expect("path should always contain a last element");
+
+        // mark the leaf as having been read at our timestamp,
+        // which will prevent older transactions from
+        // committing writes to it.
+        self.tree.context.pagecache.bump_page_rts(
+            *leaf_id,
+            self.state.ts as Lsn,
+            &tx,
+        );
+
+        tx.flush();
+
+        self.state
+            .reads
+            .borrow_mut()
+            .insert(tx_key, (self.tree.clone(), value.clone()));
+
+        Ok(value)
+    }
+
+    /// Set a key to a new value, returning the last value
+    /// if it was set.
+    pub fn set<K, V>(&self, key: K, value: V) -> TransactionResult<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+        IVec: From<V>,
+    {
+        let last = self.get(key.as_ref())?;
+        let tx_key = (self.tree.tree_id.clone(), key.as_ref().to_vec());
+
+        self.state
+            .writes
+            .borrow_mut()
+            .insert(tx_key, (self.tree.clone(), Some(IVec::from(value))));
+
+        Ok(last)
+    }
+
+    /// Delete a value, returning the old value if it existed.
+    pub fn del<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> TransactionResult<Option<IVec>> {
+        let last = self.get(key.as_ref())?;
+        let tx_key = (self.tree.tree_id.clone(), key.as_ref().to_vec());
+
+        self.state
+            .writes
+            .borrow_mut()
+            .insert(tx_key, (self.tree.clone(), None));
+
+        Ok(last)
+    }
+
+    /// Include another `Tree` from the same `Db` in this
+    /// transaction. Reads and writes performed through
+    /// the returned `TransactionalTree` are validated and
+    /// applied atomically with the rest of the transaction.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db, IVec};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    /// let users = db.open_tree(b"users").unwrap();
+    /// let emails = db.open_tree(b"emails").unwrap();
+    ///
+    /// users
+    ///     .transaction(|tx| {
+    ///         let emails = tx.with_tree(&emails)?;
+    ///         tx.set(b"alice", b"alice@example.com".to_vec())?;
+    ///         emails.set(b"alice@example.com", b"alice".to_vec())?;
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    ///
+    /// assert_eq!(
+    ///     emails.get(b"alice@example.com"),
+    ///     Ok(Some(IVec::from(b"alice")))
+    /// );
+    /// ```
+    pub fn with_tree<'b>(
+        &'b self,
+        tree: &'b Tree,
+    ) -> TransactionResult<TransactionalTree<'b>> {
+        if !Arc::ptr_eq(&self.tree.context.pagecache, &tree.context.pagecache) {
+            return Err(TransactionError::Storage(Error::Unsupported(
+                "transactions may only span Trees \
+                 that belong to the same Db"
+                    .into(),
+            )));
+        }
+
+        Ok(TransactionalTree {
+            tree,
+            state: self.state,
+        })
+    }
+}
+
+impl Tree {
+    /// Perform a multi-key serializable transaction.
+    ///
+    /// The provided function may be called several times,
+    /// because it is transparently retried whenever the
+    /// transaction conflicts with a concurrent write. It
+    /// should therefore not have any side effects other
+    /// than the ones it performs through the provided
+    /// `TransactionalTree`. Returning
+    /// `Err(TransactionError::Abort)` from the function
+    /// discards all of its writes.
+    ///
+    /// Reads bump the read timestamp (RTS) of the leaf they
+    /// touched, and writes are buffered until the function
+    /// returns. At commit time every written leaf is checked
+    /// against its RTS, to make sure that no transaction which
+    /// began after this one started committing has observed
+    /// it. The commit then marks the leaves of every key it
+    /// read or writes with its own timestamp, which makes
+    /// other writers of those leaves wait until it is done,
+    /// validates each read against the current state of the
+    /// tree, and applies the write set as a single atomic
+    /// batch in the log, so it is either fully recovered after
+    /// a crash or not at all. If writing it fails partway, the
+    /// writes that were applied are undone. Otherwise the batch
+    /// is durable before subscribers hear of it and this
+    /// returns. Commits are serialized with each other, while
+    /// writes to other leaves proceed concurrently.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db, IVec, TransactionError};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    ///
+    /// // writes are only applied if the transaction succeeds
+    /// let res: Result<(), _> = db.transaction(|tx| {
+    ///     tx.set(b"k1", vec![1])?;
+    ///     Err(TransactionError::Abort)
+    /// });
+    ///
+    /// assert_eq!(res, Err(TransactionError::Abort));
+    /// assert_eq!(db.get(b"k1"), Ok(None));
+    ///
+    /// let old = db.transaction(|tx| tx.set(b"k1", vec![2])).unwrap();
+    /// assert_eq!(old, None);
+    /// assert_eq!(db.get(b"k1"), Ok(Some(IVec::from(vec![2]))));
+    /// ```
+    pub fn transaction<F, R>(&self, f: F) -> TransactionResult<R>
+    where
+        F: Fn(&TransactionalTree<'_>) -> TransactionResult<R>,
+    {
+        let _measure = Measure::new(&self.context.metrics.tree_transaction);
+
+        if self.context.read_only {
+            return Err(TransactionError::Storage(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            )));
+        }
+
+        loop {
+            let ts = self.context.pagecache.generate_id()?;
+            let state = TransactionState::new(ts);
+
+            let ret = {
+                let tx_tree = TransactionalTree {
+                    tree: self,
+                    state: &state,
+                };
+
+                f(&tx_tree)?
+            };
+
+            if self.commit(&state)? {
+                return Ok(ret);
+            }
+
+            trace!("transaction with ts {} conflicted, retrying", ts);
+            self.context.metrics.tree_looped();
+        }
+    }
+
+    // Validates the read set of a transaction and applies
+    // its write set atomically. Returns `Ok(false)` if the
+    // transaction conflicted and needs to be retried.
+    fn commit(&self, state: &TransactionState) -> Result<bool> {
+        let writes = state.writes.borrow();
+        let reads = state.reads.borrow();
+
+        let mut commit = self
+            .context
+            .concurrency_control
+            .commit(&self.context.pagecache)?;
+
+        let tx = self.context.pagecache.begin()?;
+
+        for ((_tree_id, key), (tree, _value)) in writes.iter() {
+            let path = tree.path_for_key(key, &tx)?;
+            let (leaf_id, _leaf_frag, _leaf_ptr) = path
+                .last()
+                .expect("path should always contain a last element");
+
+            let rts = self.context.pagecache.get_page_rts(*leaf_id, &tx);
+            if rts.map(|rts| rts > commit.ts()).unwrap_or(false) {
+                return Ok(false);
+            }
+        }
+
+        tx.flush();
+
+        // NB once the leaves of our keys are marked, nothing
+        // else can write to them until we are done, so no read
+        // can change between validating it and our writes.
+        let keys: Vec<(&Tree, &[u8])> = reads
+            .iter()
+            .chain(writes.iter())
+            .map(|((_tree_id, key), (tree, _value))| (tree, &**key))
+            .collect();
+        commit.mark_keys(&keys)?;
+
+        let tx = self.context.pagecache.begin()?;
+
+        for ((_tree_id, key), (tree, observed)) in reads.iter() {
+            let (_path, current) = tree.get_internal(key, &tx)?;
+            if current != observed.as_ref() {
+                return Ok(false);
+            }
+        }
+
+        tx.flush();
+
+        if writes.is_empty() {
+            return Ok(true);
+        }
+
+        // NB this fails before anything is written if an
+        // index of a written tree has not been registered yet.
+        for (tree, _value) in writes.values() {
+            tree.indexes()?;
+        }
+
+        let recovery_guard = self.context.pagecache.pin_log()?;
+        let mut deferred = DeferredEvents::default();
+
+        let mut res = Ok(());
+        for ((_tree_id, key), (tree, value)) in writes.iter() {
+            res = if let Some(value) = value {
+                tree.set_inner(key, value.clone(), Some(&mut deferred))
+                    .map(|_| ())
+            } else {
+                tree.del_inner(key, Some(&mut deferred)).map(|_| ())
+            };
+            if res.is_err() {
+                break;
+            }
+        }
+
+        // NB an error partway through undoes what was applied
+        let ((), last_lsn) = deferred.seal_or_undo(recovery_guard, res)?;
+
+        drop(commit);
+
+        // NB subscribers only hear of the batch once it is durable
+        self.context.pagecache.make_batch_stable(last_lsn)?;
+
+        deferred.complete();
+
+        Ok(true)
+    }
+}
@@ -1,12 +1,14 @@
 use std::{
     borrow::Cow,
     cmp::Ordering::{Greater, Less},
+    convert::TryFrom,
     fmt::{self, Debug},
     ops::{self, RangeBounds},
     sync::{
         atomic::{AtomicU64, Ordering::SeqCst},
-        Arc,
+        Arc, RwLock,
     },
+    time::Duration,
 };
 
 use super::*;
@@ -54,6 +56,7 @@ pub struct Tree {
     pub(crate) context: Context,
     pub(crate) subscriptions: Arc<Subscriptions>,
     pub(crate) root: Arc<AtomicU64>,
+    pub(crate) indexes: Arc<RwLock<Indexes>>,
 }
 
 unsafe impl Send for Tree {}
@@ -80,7 +83,7 @@ impl Tree {
         IVec: From<V>,
     {
         trace!("setting key {:?}", key.as_ref());
-        let _measure = Measure::new(&M.tree_set);
+        let _measure = Measure::new(&self.context.metrics.tree_set);
 
         if self.context.read_only {
             return Err(Error::Unsupported(
@@ -88,35 +91,172 @@ impl Tree {
             ));
         }
 
-        let value = IVec::from(value);
+        self.write_indexed(key.as_ref(), |deferred| {
+            self.set_inner(key.as_ref(), IVec::from(value), deferred)
+        })
+    }
 
+    /// Set a key to a new value that expires once `ttl` has
+    /// passed, returning the last value if it was set. An
+    /// expired value is no longer returned by any read, and
+    /// is physically removed when it is swept by the background
+    /// flush thread, or written to again. Expiration times are
+    /// measured with the system clock, and survive restarts.
+    /// Neither subscribers nor change feeds are notified when
+    /// a value expires or is swept, unless the `Tree` has
+    /// indexes, as then the value is deleted along with its
+    /// index entries.
+    ///
+    /// Setting the key again without a TTL, or deleting it,
+    /// removes the TTL. A `merge` into a value that has not
+    /// expired yet keeps its TTL, while a `merge` into an
+    /// expired value starts over from no value, without one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use sled::{ConfigBuilder, Db, IVec};
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let t = Db::start(config).unwrap();
+    ///
+    /// t.set_with_ttl(b"session", vec![1], Duration::from_millis(50))
+    ///     .unwrap();
+    /// assert_eq!(t.get(b"session"), Ok(Some(IVec::from(vec![1]))));
+    ///
+    /// std::thread::sleep(Duration::from_millis(100));
+    /// assert_eq!(t.get(b"session"), Ok(None));
+    /// ```
+    pub fn set_with_ttl<K, V>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+        IVec: From<V>,
+    {
+        trace!("setting key {:?} with ttl {:?}", key.as_ref(), ttl);
+        let _measure = Measure::new(&self.context.metrics.tree_set);
+
+        if self.context.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+
+        let ttl_ms = u64::try_from(ttl.as_millis()).unwrap_or(std::u64::MAX);
+        let expires_at = expiry::now_millis().saturating_add(ttl_ms);
+
+        let ret = self.write_indexed(key.as_ref(), |deferred| {
+            self.set_with_expiry(
+                key.as_ref(),
+                IVec::from(value),
+                Some(expires_at),
+                deferred,
+            )
+        });
+
+        expiry::lower_next_expiry(&self.context.next_expiry, expires_at);
+
+        ret
+    }
+
+    // Links a `Frag::Set` to the leaf responsible for `key`.
+    // If `deferred` is provided, subscribers are notified
+    // when the caller completes it rather than immediately.
+    pub(crate) fn set_inner(
+        &self,
+        key: &[u8],
+        value: IVec,
+        deferred: Option<&mut DeferredEvents>,
+    ) -> Result<Option<IVec>> {
+        self.set_with_expiry(key, value, None, deferred)
+    }
+
+    // Links a `Frag::Set`, or a `Frag::SetWithTtl` if the value
+    // expires, to the leaf responsible for `key`.
+    pub(crate) fn set_with_expiry(
+        &self,
+        key: &[u8],
+        value: IVec,
+        expires_at: Option<u64>,
+        mut deferred: Option<&mut DeferredEvents>,
+    ) -> Result<Option<IVec>> {
         loop {
             let tx = self.context.pagecache.begin()?;
-            let (mut path, existing_val) =
-                self.get_internal(key.as_ref(), &tx)?;
+            let (mut path, existing_val) = self.get_internal(key, &tx)?;
             let (leaf_id, leaf_frag, leaf_ptr) = path.pop().expect(
                 "path_for_key should always return a path \
                  of length >= 2 (root + leaf)",
             );
             let node: &Node = leaf_frag.unwrap_base();
-            let encoded_key = prefix_encode(&node.lo, key.as_ref());
+            let encoded_key = prefix_encode(&node.lo, key);
+
+            // deferred events are only reserved once the caller
+            // completes them, after it is done committing.
+            let mut subscriber_reservation = if deferred.is_some() {
+                None
+            } else {
+                self.subscriptions.reserve(key)
+            };
+
+            let frag = if let Some(expires_at) = expires_at {
+                Frag::SetWithTtl(encoded_key, value.clone(), expires_at)
+            } else {
+                Frag::Set(encoded_key, value.clone())
+            };
 
-            let mut subscriber_reservation = self.subscriptions.reserve(&key);
+            let cc = &self.context.concurrency_control;
+            let section = cc.section();
+            if !cc.may_write(&self.context.pagecache, leaf_id, &tx) {
+                drop(section);
+                drop(subscriber_reservation);
+                drop(tx);
+                cc.wait();
+
+                // NB an index may have been registered while we
+                // waited, which would have to be updated as well.
+                if deferred.is_none() && self.is_indexed()? {
+                    return self.write_indexed(key, |deferred| {
+                        self.set_with_expiry(key, value, expires_at, deferred)
+                    });
+                }
+                continue;
+            }
 
-            let frag = Frag::Set(encoded_key, value.clone());
             let link = self.context.pagecache.link(
                 leaf_id,
                 leaf_ptr.clone(),
                 frag.clone(),
                 &tx,
             )?;
+            drop(section);
             if let Ok(new_cas_key) = link {
                 // success
-                if let Some(res) = subscriber_reservation.take() {
-                    let event =
-                        subscription::Event::Set(key.as_ref().to_vec(), value);
+                if let Some(deferred) = deferred.as_mut() {
+                    let old =
+                        existing_val.map(|v| (v.clone(), node.expires_at(key)));
+                    deferred.record(self, key, old);
+                }
 
-                    res.complete(event);
+                self.update_indexes(
+                    key,
+                    existing_val
+                        .or_else(|| node.expired_value(key))
+                        .map(|v| &**v),
+                    Some(&value),
+                )?;
+
+                if let Some(deferred) = deferred.take() {
+                    deferred.push(
+                        &self.subscriptions,
+                        subscription::Event::Set(key.to_vec(), value),
+                    );
+                } else if let Some(res) = subscriber_reservation.take() {
+                    res.complete(subscription::Event::Set(key.to_vec(), value));
                 }
 
                 if node.should_split(self.context.blink_node_split_size as u64)
@@ -128,7 +268,7 @@ impl Tree {
                         })
                         .collect::<Vec<(PageId, Cow<'_, Frag>, _)>>();
                     let mut node2 = node.clone();
-                    node2.apply(&frag, self.context.merge_operator);
+                    node2.apply(&frag, self.context.merge_operator_fn);
                     let frag2 = Cow::Owned(Frag::Base(node2));
                     path2.push((leaf_id, frag2, new_cas_key));
                     self.recursive_split(path2, &tx)?;
@@ -138,7 +278,7 @@ impl Tree {
 
                 return Ok(existing_val.cloned());
             }
-            M.tree_looped();
+            self.context.metrics.tree_looped();
         }
     }
 
@@ -156,7 +296,7 @@ impl Tree {
     /// assert_eq!(t.get(&[1]), Ok(None));
     /// ```
     pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
-        let _measure = Measure::new(&M.tree_get);
+        let _measure = Measure::new(&self.context.metrics.tree_get);
 
         let tx = self.context.pagecache.begin()?;
 
@@ -179,41 +319,96 @@ impl Tree {
     /// assert_eq!(t.del(&[1]), Ok(None));
     /// ```
     pub fn del<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
-        let _measure = Measure::new(&M.tree_del);
+        let _measure = Measure::new(&self.context.metrics.tree_del);
 
         if self.context.read_only {
             return Ok(None);
         }
 
+        self.write_indexed(key.as_ref(), |deferred| {
+            self.del_inner(key.as_ref(), deferred)
+        })
+    }
+
+    pub(crate) fn del_inner(
+        &self,
+        key: &[u8],
+        mut deferred: Option<&mut DeferredEvents>,
+    ) -> Result<Option<IVec>> {
         loop {
             let tx = self.context.pagecache.begin()?;
 
-            let (mut path, existing_val) =
-                self.get_internal(key.as_ref(), &tx)?;
+            let (mut path, existing_val) = self.get_internal(key, &tx)?;
 
-            let mut subscriber_reservation = self.subscriptions.reserve(&key);
+            let mut subscriber_reservation = if deferred.is_some() {
+                None
+            } else {
+                self.subscriptions.reserve(key)
+            };
 
             let (leaf_id, leaf_frag, leaf_ptr) = path.pop().expect(
                 "path_for_key should always return a path \
                  of length >= 2 (root + leaf)",
             );
             let node: &Node = leaf_frag.unwrap_base();
-            let encoded_key = prefix_encode(&node.lo, key.as_ref());
+            let encoded_key = prefix_encode(&node.lo, key);
 
             let frag = Frag::Del(encoded_key);
+
+            let cc = &self.context.concurrency_control;
+            let section = cc.section();
+            if !cc.may_write(&self.context.pagecache, leaf_id, &tx) {
+                drop(section);
+                drop(subscriber_reservation);
+                drop(tx);
+                cc.wait();
+
+                // NB an index may have been registered while we
+                // waited, which would have to be updated as well.
+                if deferred.is_none() && self.is_indexed()? {
+                    return self.write_indexed(key, |deferred| {
+                        self.del_inner(key, deferred)
+                    });
+                }
+                continue;
+            }
+
             let link = self.context.pagecache.link(
                 leaf_id,
                 leaf_ptr.clone(),
                 frag,
                 &tx,
             )?;
+            drop(section);
 
             if link.is_ok() {
                 // success
-                if let Some(res) = subscriber_reservation.take() {
-                    let event = subscription::Event::Del(key.as_ref().to_vec());
+                if let Some(deferred) = deferred.as_mut() {
+                    let old =
+                        existing_val.map(|v| (v.clone(), node.expires_at(key)));
+                    deferred.record(self, key, old);
+                }
 
-                    res.complete(event);
+                self.update_indexes(
+                    key,
+                    existing_val
+                        .or_else(|| node.expired_value(key))
+                        .map(|v| &**v),
+                    None,
+                )?;
+
+                if let Some(deferred) = deferred.take() {
+                    deferred.push(
+                        &self.subscriptions,
+                        subscription::Event::Del(key.to_vec()),
+                    );
+                } else if let Some(res) = subscriber_reservation.take() {
+                    res.complete(subscription::Event::Del(key.to_vec()));
+                }
+
+                if node.may_underflow(self.context.blink_node_split_size as u64)
+                {
+                    self.merge_node(&path, leaf_id, &tx)?;
                 }
 
                 tx.flush();
@@ -256,7 +451,7 @@ impl Tree {
         IVec: From<NV>,
     {
         trace!("casing key {:?}", key.as_ref());
-        let _measure = Measure::new(&M.tree_cas);
+        let _measure = Measure::new(&self.context.metrics.tree_cas);
 
         if self.context.read_only {
             return Err(Error::Unsupported(
@@ -266,15 +461,32 @@ impl Tree {
 
         let new = new.map(IVec::from);
 
+        self.write_indexed(key.as_ref(), |deferred| {
+            self.cas_inner(
+                key.as_ref(),
+                old.as_ref().map(AsRef::as_ref),
+                new,
+                deferred,
+            )
+        })
+    }
+
+    fn cas_inner(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<IVec>,
+        mut deferred: Option<&mut DeferredEvents>,
+    ) -> Result<std::result::Result<(), Option<IVec>>> {
         // we need to retry caps until old != cur, since just because
         // cap fails it doesn't mean our value was changed.
         loop {
             let tx = self.context.pagecache.begin()?;
-            let (mut path, cur) = self.get_internal(key.as_ref(), &tx)?;
+            let (mut path, cur) = self.get_internal(key, &tx)?;
 
-            let matches = match (&old, &cur) {
+            let matches = match (old, &cur) {
                 (None, None) => true,
-                (Some(ref o), Some(ref c)) => o.as_ref() == &***c,
+                (Some(o), Some(c)) => o == &***c,
                 _ => false,
             };
 
@@ -282,39 +494,82 @@ impl Tree {
                 return Ok(Err(cur.cloned()));
             }
 
-            let mut subscriber_reservation = self.subscriptions.reserve(&key);
+            let mut subscriber_reservation = if deferred.is_some() {
+                None
+            } else {
+                self.subscriptions.reserve(key)
+            };
 
             let (leaf_id, leaf_frag, leaf_ptr) = path
                 .pop()
                 .expect("get_internal somehow returned a path of length zero");
 
-            let (node_id, encoded_key) = {
-                let node: &Node = leaf_frag.unwrap_base();
-                (leaf_id, prefix_encode(&node.lo, key.as_ref()))
-            };
+            let node: &Node = leaf_frag.unwrap_base();
+            let encoded_key = prefix_encode(&node.lo, key);
             let frag = if let Some(ref new) = new {
                 Frag::Set(encoded_key, new.clone())
             } else {
                 Frag::Del(encoded_key)
             };
+
+            let cc = &self.context.concurrency_control;
+            let section = cc.section();
+            if !cc.may_write(&self.context.pagecache, leaf_id, &tx) {
+                drop(section);
+                drop(subscriber_reservation);
+                drop(tx);
+                cc.wait();
+
+                // NB an index may have been registered while we
+                // waited, which would have to be updated as well.
+                if deferred.is_none() && self.is_indexed()? {
+                    return self.write_indexed(key, |deferred| {
+                        self.cas_inner(key, old, new, deferred)
+                    });
+                }
+                continue;
+            }
+
             let link =
-                self.context.pagecache.link(node_id, leaf_ptr, frag, &tx)?;
+                self.context.pagecache.link(leaf_id, leaf_ptr, frag, &tx)?;
+            drop(section);
 
             if link.is_ok() {
-                if let Some(res) = subscriber_reservation.take() {
-                    let event = if let Some(new) = new {
-                        subscription::Event::Set(key.as_ref().to_vec(), new)
-                    } else {
-                        subscription::Event::Del(key.as_ref().to_vec())
-                    };
+                if let Some(deferred) = deferred.as_mut() {
+                    let old = cur.map(|v| (v.clone(), node.expires_at(key)));
+                    deferred.record(self, key, old);
+                }
 
+                if new.is_none()
+                    && node.may_underflow(
+                        self.context.blink_node_split_size as u64,
+                    )
+                {
+                    self.merge_node(&path, leaf_id, &tx)?;
+                }
+
+                self.update_indexes(
+                    key,
+                    cur.or_else(|| node.expired_value(key)).map(|v| &**v),
+                    new.as_ref().map(|v| v.as_ref()),
+                )?;
+
+                let event = if let Some(new) = new {
+                    subscription::Event::Set(key.to_vec(), new)
+                } else {
+                    subscription::Event::Del(key.to_vec())
+                };
+
+                if let Some(deferred) = deferred.take() {
+                    deferred.push(&self.subscriptions, event);
+                } else if let Some(res) = subscriber_reservation.take() {
                     res.complete(event);
                 }
 
                 tx.flush();
                 return Ok(Ok(()));
             }
-            M.tree_looped();
+            self.context.metrics.tree_looped();
         }
     }
 
@@ -456,8 +711,10 @@ impl Tree {
     /// of `Event`s across different keys. If subscribers don't
     /// keep up with new writes, they will cause new writes
     /// to block. There is a buffer of 1024 items per
-    /// `Subscriber`. This can be used to build reactive
-    /// and replicated systems.
+    /// `Subscriber`. See `watch_prefix_with_backpressure`
+    /// for other ways of handling slow subscribers.
+    /// This can be used to build reactive and replicated
+    /// systems.
     ///
     /// # Examples
     /// ```
@@ -474,7 +731,8 @@ impl Tree {
     ///     tree.set(vec![0], vec![1]).unwrap();
     /// });
     ///
-    /// // events is a blocking `Iterator` over `Event`s
+    /// // events is a blocking `Iterator` over `Event`s,
+    /// // and also a `Stream` for use in async code
     /// for event in events.take(1) {
     ///     match event {
     ///         Event::Set(key, value) => assert_eq!(key, vec![0]),
@@ -489,15 +747,95 @@ impl Tree {
         self.subscriptions.register(prefix)
     }
 
+    /// Subscribe to `Event`s that happen to keys that have
+    /// the specified prefix, buffering up to `capacity`
+    /// events. When the buffer is full, new writes are
+    /// handled according to `backpressure`, so a stuck
+    /// subscriber does not need to stall the write path.
+    ///
+    /// # Examples
+    /// ```
+    /// use sled::{Backpressure, ConfigBuilder, Event};
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    ///
+    /// let tree = sled::Db::start(config).unwrap();
+    ///
+    /// let mut events = tree.watch_prefix_with_backpressure(
+    ///     vec![],
+    ///     2,
+    ///     Backpressure::DropOldest,
+    /// );
+    ///
+    /// for i in 0..5 {
+    ///     tree.set(vec![i], vec![i]).unwrap();
+    /// }
+    ///
+    /// // the three oldest events were dropped
+    /// assert_eq!(events.lagged(), 3);
+    /// assert_eq!(events.try_next(), Ok(Event::Set(vec![3], vec![3].into())));
+    /// assert_eq!(events.try_next(), Ok(Event::Set(vec![4], vec![4].into())));
+    /// assert!(events.try_next().is_err());
+    /// ```
+    pub fn watch_prefix_with_backpressure(
+        &self,
+        prefix: Vec<u8>,
+        capacity: usize,
+        backpressure: Backpressure,
+    ) -> Subscriber {
+        self.subscriptions
+            .register_with(prefix, capacity, backpressure)
+    }
+
+    /// Returns the durable `ChangeFeed` with the given name
+    /// for this `Tree`, registering it if it does not exist
+    /// yet. A new feed starts with the next write to the `Tree`,
+    /// and an existing one resumes from its last acknowledged
+    /// change, including after a restart. See `ChangeFeed`.
+    pub fn change_feed(&self, name: &str) -> Result<ChangeFeed> {
+        ChangeFeed::register(self, name)
+    }
+
     /// Flushes all dirty IO buffers and calls fsync.
     /// If this succeeds, it is guaranteed that
     /// all previous writes will be recovered if
     /// the system crashes. Returns the number
     /// of bytes flushed during this call.
     pub fn flush(&self) -> Result<usize> {
+        // NB nothing is stable anymore once the system has failed,
+        // even if everything that was written made it to disk.
+        self.context.global_error()?;
         self.context.pagecache.flush()
     }
 
+    /// Returns a future that resolves once all dirty IO
+    /// buffers have been written and fsynced, after which
+    /// all previous writes will be recovered if the system
+    /// crashes. The writing happens on the IO thread pool
+    /// when `async_io` is configured, rather than blocking
+    /// the caller. Resolves to the number of bytes flushed.
+    ///
+    /// If `async_io` is disabled, there is no thread pool to
+    /// hand the writes to, so the current buffer is written
+    /// and fsynced on the calling thread before this returns,
+    /// and the returned future is usually already resolved.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use futures::Future;
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let db = Db::start(config).unwrap();
+    ///
+    /// db.set(b"k1", vec![1]).unwrap();
+    /// let flushed = db.flush_async().wait().unwrap();
+    /// assert!(flushed > 0);
+    /// ```
+    pub fn flush_async(&self) -> FlushFuture {
+        self.context.pagecache.flush_async()
+    }
+
     /// Returns `true` if the `Tree` contains a value for
     /// the specified key.
     ///
@@ -540,7 +878,7 @@ impl Tree {
         &self,
         key: K,
     ) -> Result<Option<(Key, IVec)>> {
-        let _measure = Measure::new(&M.tree_get);
+        let _measure = Measure::new(&self.context.metrics.tree_get);
 
         // the double tx is a hack that maintains
         // correctness of the ret value
@@ -556,6 +894,11 @@ impl Tree {
         let items = data.leaf_ref().expect("last_node should be a leaf");
         let search = leaf_search(Less, items, |&(ref k, ref _v)| {
             prefix_cmp_encoded(k, key.as_ref(), &last_node.lo)
+        })
+        .filter(|&idx| {
+            // expired values are skipped by iterators
+            let decoded_key = prefix_decode(&last_node.lo, &items[idx].0);
+            !last_node.is_expired(&decoded_key)
         });
 
         let ret = if search.is_none() {
@@ -604,7 +947,7 @@ impl Tree {
         &self,
         key: K,
     ) -> Result<Option<(Key, IVec)>> {
-        let _measure = Measure::new(&M.tree_get);
+        let _measure = Measure::new(&self.context.metrics.tree_get);
 
         let tx = self.context.pagecache.begin()?;
 
@@ -618,6 +961,11 @@ impl Tree {
         let items = data.leaf_ref().expect("last_node should be a leaf");
         let search = leaf_search(Greater, items, |&(ref k, ref _v)| {
             prefix_cmp_encoded(k, key.as_ref(), &last_node.lo)
+        })
+        .filter(|&idx| {
+            // expired values are skipped by iterators
+            let decoded_key = prefix_decode(&last_node.lo, &items[idx].0);
+            !last_node.is_expired(&decoded_key)
         });
 
         let ret = if search.is_none() {
@@ -642,20 +990,24 @@ impl Tree {
         Ok(ret)
     }
 
-    /// Merge state directly into a given key's value using the
-    /// configured merge operator. This allows state to be written
-    /// into a value directly, without any read-modify-write steps.
-    /// Merge operators can be used to implement arbitrary data
-    /// structures.
+    /// Merge state into a given key's value using the merge
+    /// operator of this `Tree`. The operator is applied to the
+    /// current value when the merge is written, and the merge
+    /// is retried against the new value if the key's leaf was
+    /// changed concurrently, so the operator may be called more
+    /// than once per merge. Merge operators can be used to
+    /// implement arbitrary data structures.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Calling `merge` will panic if no merge operator has been
-    /// configured.
+    /// Calling `merge` returns an `Error::Unsupported` if no merge
+    /// operator has been set with `set_merge_operator`.
     ///
     /// # Examples
     ///
     /// ```
+    /// use std::sync::Arc;
+    ///
     /// use sled::{ConfigBuilder, Db, IVec};
     ///
     /// fn concatenate_merge(
@@ -672,12 +1024,11 @@ impl Tree {
     ///   Some(ret)
     /// }
     ///
-    /// let config = ConfigBuilder::new()
-    ///   .temporary(true)
-    ///   .merge_operator(concatenate_merge)
-    ///   .build();
+    /// let config = ConfigBuilder::new().temporary(true).build();
     ///
     /// let tree = Db::start(config).unwrap();
+    /// tree.set_merge_operator("concatenate", Arc::new(concatenate_merge))
+    ///   .unwrap();
     ///
     /// let k = b"k1";
     ///
@@ -703,7 +1054,7 @@ impl Tree {
         IVec: From<V>,
     {
         trace!("merging key {:?}", key.as_ref());
-        let _measure = Measure::new(&M.tree_merge);
+        let _measure = Measure::new(&self.context.metrics.tree_merge);
 
         if self.context.read_only {
             return Err(Error::Unsupported(
@@ -711,30 +1062,73 @@ impl Tree {
             ));
         }
 
-        if self.context.merge_operator.is_none() {
-            return Err(Error::Unsupported(
-                "must set a merge_operator on config \
-                 before calling merge"
-                    .to_owned(),
-            ));
-        }
+        self.merge_operator()?;
 
-        let value = IVec::from(value);
+        self.write_indexed(key.as_ref(), |deferred| {
+            self.merge_inner(key.as_ref(), IVec::from(value), deferred)
+        })
+    }
+
+    // Applies the merge operator to the current value of `key`,
+    // and links the result to its leaf as a `Frag::Set`, or a
+    // `Frag::Del` if the operator removed the value. Linking
+    // fails if the leaf changed since it was read, so the merge
+    // is retried against the new value.
+    pub(crate) fn merge_inner(
+        &self,
+        key: &[u8],
+        value: IVec,
+        mut deferred: Option<&mut DeferredEvents>,
+    ) -> Result<()> {
+        let merge_operator = self.merge_operator()?;
 
         loop {
             let tx = self.context.pagecache.begin()?;
 
-            let mut path = self.path_for_key(key.as_ref(), &tx)?;
+            let (mut path, cur) = self.get_internal(key, &tx)?;
             let (leaf_id, leaf_frag, leaf_ptr) = path.pop().expect(
                 "path_for_key should always return a path \
                  of length >= 2 (root + leaf)",
             );
             let node: &Node = leaf_frag.unwrap_base();
 
-            let mut subscriber_reservation = self.subscriptions.reserve(&key);
+            let new =
+                merge_operator(key, cur.map(|v| &**v), &value).map(IVec::from);
 
-            let encoded_key = prefix_encode(&node.lo, key.as_ref());
-            let frag = Frag::Merge(encoded_key, value.clone());
+            let mut subscriber_reservation = if deferred.is_some() {
+                None
+            } else {
+                self.subscriptions.reserve(key)
+            };
+
+            // NB a value that has not expired yet keeps its TTL,
+            // while `cur` is `None` for an expired one.
+            let encoded_key = prefix_encode(&node.lo, key);
+            let frag = match (&new, node.expires_at(key)) {
+                (Some(new), Some(at)) if cur.is_some() => {
+                    Frag::SetWithTtl(encoded_key, new.clone(), at)
+                }
+                (Some(new), _) => Frag::Set(encoded_key, new.clone()),
+                (None, _) => Frag::Del(encoded_key),
+            };
+
+            let cc = &self.context.concurrency_control;
+            let section = cc.section();
+            if !cc.may_write(&self.context.pagecache, leaf_id, &tx) {
+                drop(section);
+                drop(subscriber_reservation);
+                drop(tx);
+                cc.wait();
+
+                // NB an index may have been registered while we
+                // waited, which would have to be updated as well.
+                if deferred.is_none() && self.is_indexed()? {
+                    return self.write_indexed(key, |deferred| {
+                        self.merge_inner(key, value, deferred)
+                    });
+                }
+                continue;
+            }
 
             let link = self.context.pagecache.link(
                 leaf_id,
@@ -742,15 +1136,30 @@ impl Tree {
                 frag.clone(),
                 &tx,
             )?;
+            drop(section);
             if let Ok(new_cas_key) = link {
                 // success
-                if let Some(res) = subscriber_reservation.take() {
-                    let event = subscription::Event::Merge(
-                        key.as_ref().to_vec(),
-                        value,
-                    );
+                if let Some(deferred) = deferred.as_mut() {
+                    let old = cur.map(|v| (v.clone(), node.expires_at(key)));
+                    deferred.record(self, key, old);
+                }
 
-                    res.complete(event);
+                self.update_indexes(
+                    key,
+                    cur.or_else(|| node.expired_value(key)).map(|v| &**v),
+                    new.as_ref().map(|v| v.as_ref()),
+                )?;
+
+                if let Some(deferred) = deferred.take() {
+                    deferred.push(
+                        &self.subscriptions,
+                        subscription::Event::Merge(key.to_vec(), value),
+                    );
+                } else if let Some(res) = subscriber_reservation.take() {
+                    res.complete(subscription::Event::Merge(
+                        key.to_vec(),
+                        value,
+                    ));
                 }
                 if node.should_split(self.context.blink_node_split_size as u64)
                 {
@@ -761,15 +1170,21 @@ impl Tree {
                         })
                         .collect::<Vec<(PageId, Cow<'_, Frag>, _)>>();
                     let mut node2 = node.clone();
-                    node2.apply(&frag, self.context.merge_operator);
+                    node2.apply(&frag, self.context.merge_operator_fn);
                     let frag2 = Cow::Owned(Frag::Base(node2));
                     path2.push((leaf_id, frag2, new_cas_key));
                     self.recursive_split(path2, &tx)?;
+                } else if new.is_none()
+                    && node.may_underflow(
+                        self.context.blink_node_split_size as u64,
+                    )
+                {
+                    self.merge_node(&path, leaf_id, &tx)?;
                 }
                 tx.flush();
                 return Ok(());
             }
-            M.tree_looped();
+            self.context.metrics.tree_looped();
         }
     }
 
@@ -868,7 +1283,7 @@ impl Tree {
         K: AsRef<[u8]>,
         R: RangeBounds<K>,
     {
-        let _measure = Measure::new(&M.tree_scan);
+        let _measure = Measure::new(&self.context.metrics.tree_scan);
 
         let tx = match self.context.pagecache.begin() {
             Ok(tx) => tx,
@@ -919,6 +1334,45 @@ impl Tree {
         }
     }
 
+    /// Create a double-ended iterator over tuples of keys and values,
+    /// where the keys start with the provided prefix.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db, IVec};
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let t = Db::start(config).unwrap();
+    ///
+    /// t.set(&[0, 0, 0], vec![0, 0, 0]).unwrap();
+    /// t.set(&[0, 0, 1], vec![0, 0, 1]).unwrap();
+    /// t.set(&[0, 0, 2], vec![0, 0, 2]).unwrap();
+    /// t.set(&[0, 0, 3], vec![0, 0, 3]).unwrap();
+    /// t.set(&[0, 1, 0], vec![0, 1, 0]).unwrap();
+    /// t.set(&[0, 1, 1], vec![0, 1, 1]).unwrap();
+    ///
+    /// let prefix: &[u8] = &[0, 0];
+    /// let mut r = t.scan_prefix(prefix);
+    /// assert_eq!(r.next(), Some(Ok((vec![0, 0, 0], IVec::from(vec![0, 0, 0])))));
+    /// assert_eq!(r.next(), Some(Ok((vec![0, 0, 1], IVec::from(vec![0, 0, 1])))));
+    /// assert_eq!(r.next(), Some(Ok((vec![0, 0, 2], IVec::from(vec![0, 0, 2])))));
+    /// assert_eq!(r.next(), Some(Ok((vec![0, 0, 3], IVec::from(vec![0, 0, 3])))));
+    /// assert_eq!(r.next(), None);
+    ///
+    /// let mut r = t.scan_prefix(prefix).rev();
+    /// assert_eq!(r.next(), Some(Ok((vec![0, 0, 3], IVec::from(vec![0, 0, 3])))));
+    /// ```
+    pub fn scan_prefix<P>(&self, prefix: P) -> Iter<'_>
+    where
+        P: AsRef<[u8]>,
+    {
+        let prefix = prefix.as_ref();
+        match prefix_successor(prefix) {
+            Some(end) => self.range(prefix..&*end),
+            None => self.range(prefix..),
+        }
+    }
+
     /// Create a double-ended iterator over keys, starting at the provided key.
     ///
     /// # Examples
@@ -992,17 +1446,380 @@ impl Tree {
         self.iter().next().is_none()
     }
 
-    /// Clears the `Tree`, removing all values.
+    /// Returns the number of keys that start with `prefix`.
+    ///
+    /// Unlike `len`, this only reads the leaves that may hold
+    /// such keys, and the keys of a leaf are not decoded when
+    /// both of its bounds start with the prefix, as all of
+    /// them do then too.
     ///
-    /// Note that this is not atomic.
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Db::start(config).unwrap();
+    /// t.set(b"user/1", vec![0]);
+    /// t.set(b"user/2", vec![1]);
+    /// t.set(b"users", vec![2]);
+    /// assert_eq!(t.count_prefix(b"user/"), Ok(2));
+    /// assert_eq!(t.count_prefix(b"user"), Ok(3));
+    /// ```
+    pub fn count_prefix<P>(&self, prefix: P) -> Result<usize>
+    where
+        P: AsRef<[u8]>,
+    {
+        let prefix = prefix.as_ref();
+        let end = prefix_successor(prefix);
+        let tx = self.context.pagecache.begin()?;
+
+        let mut count = 0;
+
+        // the keys below this one have been counted
+        let mut cursor = IVec::from(prefix);
+
+        loop {
+            let path = self.path_for_key(&cursor, &tx)?;
+            let (_leaf_id, leaf_frag, _leaf_ptr) = path.last().expect(
+                "path_for_key should always return a path \
+                 of length >= 2 (root + leaf)",
+            );
+            let mut node: &Node = leaf_frag.unwrap_base();
+
+            loop {
+                let records = node
+                    .data
+                    .leaf_ref()
+                    .expect("path_for_key should end with a leaf");
+
+                if node.lo >= cursor
+                    && node.lo.starts_with(prefix)
+                    && !node.hi.is_empty()
+                    && node.hi.starts_with(prefix)
+                    && node.expiries.is_empty()
+                {
+                    count += records.len();
+                } else {
+                    count += records
+                        .iter()
+                        .map(|(k, _v)| prefix_decode(&node.lo, k))
+                        .filter(|k| {
+                            **k >= *cursor
+                                && match end {
+                                    Some(ref end) => k < end,
+                                    None => true,
+                                }
+                                && !node.is_expired(k)
+                        })
+                        .count();
+                }
+
+                // (when hi is empty, it means it's unbounded)
+                let past_end = match end {
+                    Some(ref end) => node.hi.is_empty() || *node.hi >= **end,
+                    None => node.hi.is_empty(),
+                };
+                if past_end {
+                    tx.flush();
+                    return Ok(count);
+                }
+
+                cursor = node.hi.clone();
+
+                let next = node.next.expect(
+                    "if our hi bound is not Inf (inity), \
+                     we should have a right sibling",
+                );
+                node = match self.context.pagecache.get(next, &tx)? {
+                    PageGet::Materialized(frag, _ptr)
+                        if !frag.unwrap_base().merging =>
+                    {
+                        frag.unwrap_base()
+                    }
+                    // the right sibling is being merged away,
+                    // so we find the leaf of our cursor again.
+                    _ => break,
+                };
+            }
+        }
+    }
+
+    /// Clears the `Tree`, removing all values atomically,
+    /// with a single `delete_range`.
     pub fn clear(&self) -> Result<()> {
-        for k in self.keys(b"") {
-            let key = k?;
-            self.del(key)?;
+        self.delete_range::<&[u8], _>(..)
+    }
+
+    /// Removes every key in `range`. Each leaf that holds keys
+    /// in the range is written to once, with a single range
+    /// tombstone, and the tombstones of all leaves are logged
+    /// as one batch, which is either recovered in full after a
+    /// crash or not at all. Writers of keys in the range wait
+    /// until it is removed. If removing it fails partway, the
+    /// leaves that were already written to are restored.
+    ///
+    /// The removal is atomic: readers of the leaves in the
+    /// range wait while the leaves are updated one after
+    /// another, so no read observes the range partially
+    /// removed, and once one read sees it removed, every
+    /// later read does too. An iterator that yielded part of
+    /// the range before it was removed is not rewound, as with
+    /// any other concurrent write. Use a `Snapshot` to read
+    /// the whole tree at a single point in time.
+    ///
+    /// Removing a range takes time proportional to the number
+    /// of leaves that it covers, unless the tree has indexes or
+    /// subscribers watch keys in the range, in which case every
+    /// removed key is read to update them, and subscribers
+    /// receive an `Event::Del` for each of those keys.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use sled::{ConfigBuilder, Db};
+    ///
+    /// let config = ConfigBuilder::new().temporary(true).build();
+    /// let t = Db::start(config).unwrap();
+    ///
+    /// for i in 0..10_u8 {
+    ///     t.set(&[i], vec![i]).unwrap();
+    /// }
+    ///
+    /// t.delete_range(&[2_u8][..]..&[8][..]).unwrap();
+    ///
+    /// let keys: Vec<Vec<u8>> = t.iter().keys().map(Result::unwrap).collect();
+    /// assert_eq!(keys, vec![vec![0], vec![1], vec![8], vec![9]]);
+    /// ```
+    pub fn delete_range<K, R>(&self, range: R) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let _measure = Measure::new(&self.context.metrics.tree_del);
+
+        if self.context.read_only {
+            return Err(Error::Unsupported(
+                "the database is in read-only mode".to_owned(),
+            ));
+        }
+
+        let (start, end) = half_open_bounds(&range);
+
+        let empty = match end {
+            Some(ref end) => *end <= start,
+            None => false,
+        };
+        if empty {
+            return Ok(());
+        }
+
+        let mut commit = self
+            .context
+            .concurrency_control
+            .commit(&self.context.pagecache)?;
+        commit.mark_range(self, &start, end.as_ref().map(AsRef::as_ref))?;
+
+        // NB this fails before anything is written if an
+        // index of this tree has not been registered yet.
+        self.indexes()?;
+
+        // NB the removed keys are only read if something
+        // has to be told about each one of them.
+        let read_keys = self.is_indexed()?
+            || self
+                .subscriptions
+                .is_watched_range(&start, end.as_ref().map(AsRef::as_ref));
+
+        let recovery_guard = self.context.pagecache.pin_log()?;
+        let mut deferred = DeferredEvents::default();
+
+        // NB the leaves are written to one at a time, so readers
+        // of the range wait until all of them are.
+        commit.hide();
+
+        // NB if this fails partway through, the removed records
+        // are restored from the leaves as they were before, so
+        // those stay pinned by a single `Tx` until we are done.
+        let tx = self.context.pagecache.begin()?;
+        let mut applied: Vec<(&Node, IVec, Option<IVec>)> = vec![];
+
+        let res = self.remove_range_leaves(
+            &start,
+            end.as_ref(),
+            read_keys,
+            &tx,
+            &mut deferred,
+            &mut applied,
+        );
+
+        if res.is_err() {
+            for (node, leaf_start, leaf_end) in applied {
+                for (key, value) in
+                    live_records(node, &leaf_start, leaf_end.as_ref())
+                {
+                    let expires_at = node.expires_at(&key);
+                    deferred.record(
+                        self,
+                        &key,
+                        Some((value.clone(), expires_at)),
+                    );
+                }
+            }
         }
+
+        // NB an error partway through undoes what was applied
+        deferred.seal_or_undo(recovery_guard, res)?;
+
+        drop(tx);
+
+        drop(commit);
+
+        deferred.complete();
+
         Ok(())
     }
 
+    // Links a `Frag::DelRange` to every leaf that holds keys
+    // from `start` to the exclusive `end`, and pushes each leaf
+    // that was written to onto `applied`, along with the part
+    // of the range that it removed.
+    fn remove_range_leaves<'g>(
+        &self,
+        start: &IVec,
+        end: Option<&IVec>,
+        read_keys: bool,
+        tx: &'g Tx,
+        deferred: &mut DeferredEvents,
+        applied: &mut Vec<(&'g Node, IVec, Option<IVec>)>,
+    ) -> Result<()> {
+        let mut cursor = start.clone();
+
+        loop {
+            let mut path = self.path_for_key(&cursor, tx)?;
+            let (leaf_id, leaf_frag, leaf_ptr) = path.pop().expect(
+                "path_for_key should always return a path \
+                 of length >= 2 (root + leaf)",
+            );
+            let node: &Node = leaf_frag.unwrap_base();
+
+            // the part of the range that this leaf is responsible for
+            let leaf_start = std::cmp::max(start, &node.lo).clone();
+            let leaf_end = match (end, node.hi.is_empty()) {
+                (Some(end), false) => Some(std::cmp::min(end, &node.hi)),
+                (Some(end), true) => Some(end),
+                (None, false) => Some(&node.hi),
+                (None, true) => None,
+            }
+            .cloned();
+
+            // NB expired values are removed from the indexes
+            // too, but subscribers only hear of live ones.
+            let (any_removed, removed): (bool, Vec<(Key, IVec, bool)>) =
+                if read_keys {
+                    let removed: Vec<(Key, IVec, bool)> =
+                        records_in_range(node, &leaf_start, leaf_end.as_ref())
+                            .map(|(k, v)| {
+                                let expired = node.is_expired(&k);
+                                (k, v.clone(), expired)
+                            })
+                            .collect();
+                    (!removed.is_empty(), removed)
+                } else {
+                    let records = node
+                        .data
+                        .leaf_ref()
+                        .expect("path_for_key should end with a leaf");
+
+                    // the first record at or after the start of the range
+                    let idx = records
+                        .binary_search_by(|(k, _v)| {
+                            match prefix_cmp_encoded(k, &leaf_start, &node.lo) {
+                                std::cmp::Ordering::Less => {
+                                    std::cmp::Ordering::Less
+                                }
+                                _ => std::cmp::Ordering::Greater,
+                            }
+                        })
+                        .unwrap_err();
+                    let any_removed = match (records.get(idx), &leaf_end) {
+                        (Some((k, _v)), Some(end)) => {
+                            prefix_cmp_encoded(k, end, &node.lo)
+                                == std::cmp::Ordering::Less
+                        }
+                        (Some(_), None) => true,
+                        (None, _) => false,
+                    };
+                    (any_removed, vec![])
+                };
+
+            if any_removed {
+                let frag = Frag::DelRange(leaf_start.clone(), leaf_end.clone());
+                let link =
+                    self.context.pagecache.link(leaf_id, leaf_ptr, frag, tx)?;
+
+                if link.is_err() {
+                    self.context.metrics.tree_looped();
+                    continue;
+                }
+
+                applied.push((node, leaf_start, leaf_end));
+
+                self.merge_node(&path, leaf_id, tx)?;
+
+                for (key, value, expired) in removed {
+                    self.update_indexes(&key, Some(&value), None)?;
+                    if !expired {
+                        deferred.push(
+                            &self.subscriptions,
+                            subscription::Event::Del(key),
+                        );
+                    }
+                }
+            }
+
+            tx.flush();
+
+            // a leaf with an empty hi key is the last one
+            let done = node.hi.is_empty()
+                || match end {
+                    Some(end) => node.hi >= *end,
+                    None => false,
+                };
+            if done {
+                return Ok(());
+            }
+
+            cursor = node.hi.clone();
+        }
+    }
+
+    /// Removes every key that starts with `prefix`, in the same
+    /// way as `delete_range` over the keys with that prefix.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = sled::ConfigBuilder::new().temporary(true).build();
+    /// let t = sled::Db::start(config).unwrap();
+    /// t.set(b"user/1", vec![0]);
+    /// t.set(b"user/2", vec![1]);
+    /// t.set(b"users", vec![2]);
+    ///
+    /// t.delete_prefix(b"user/").unwrap();
+    ///
+    /// let keys: Vec<Vec<u8>> = t.iter().keys().map(Result::unwrap).collect();
+    /// assert_eq!(keys, vec![b"users".to_vec()]);
+    /// ```
+    pub fn delete_prefix<P>(&self, prefix: P) -> Result<()>
+    where
+        P: AsRef<[u8]>,
+    {
+        let prefix = prefix.as_ref();
+        match prefix_successor(prefix) {
+            Some(end) => self.delete_range(prefix..&*end),
+            None => self.delete_range(prefix..),
+        }
+    }
+
     /// Returns the name of the tree.
     pub fn name(&self) -> Vec<u8> {
         self.tree_id.clone()
@@ -1046,13 +1863,13 @@ impl Tree {
             let node: &Node = node_frag.unwrap_base();
             if node.should_split(adjusted_max(height)) {
                 // try to child split
-                M.tree_child_split_attempt();
+                self.context.metrics.tree_child_split_attempt();
 
                 if self
                     .child_split(*node_id, node, node_ptr.clone(), tx)?
                     .is_some()
                 {
-                    M.tree_child_split_success();
+                    self.context.metrics.tree_child_split_success();
                 } else {
                     return Ok(());
                 }
@@ -1063,7 +1880,7 @@ impl Tree {
         let root_node: &Node = root_frag.unwrap_base();
 
         if root_node.should_split(adjusted_max(path.len())) {
-            M.tree_root_split_attempt();
+            self.context.metrics.tree_root_split_attempt();
             if let Some(parent_split) =
                 self.child_split(*root_id, &root_node, root_ptr.clone(), tx)?
             {
@@ -1076,7 +1893,7 @@ impl Tree {
                     )
                     .is_ok()
                 {
-                    M.tree_root_split_success();
+                    self.context.metrics.tree_root_split_success();
                 }
             }
         }
@@ -1101,12 +1918,24 @@ impl Tree {
             to: 0,
         };
 
+        // NB a commit may have marked the node for keys that
+        // move to the right side, which takes over its RTS
+        // before it can be reached.
+        let _section = self.context.concurrency_control.section();
+        let rts = self.context.pagecache.get_page_rts(node_id, tx);
+
         // install the new right side
         let (new_pid, new_ptr) =
             self.context.pagecache.allocate(Frag::Base(rhs), tx)?;
 
         trace!("allocated pid {} in child_split", new_pid);
 
+        if let Some(rts) = rts {
+            self.context
+                .pagecache
+                .bump_page_rts(new_pid, rts as Lsn, tx);
+        }
+
         child_split.to = new_pid;
 
         let parent_split = ParentSplit {
@@ -1154,6 +1983,9 @@ impl Tree {
             next: None,
             lo: vec![].into(),
             hi: vec![].into(),
+            expiries: vec![],
+            merging_child: None,
+            merging: false,
         });
 
         let (new_root_pid, new_root_ptr) =
@@ -1194,7 +2026,7 @@ impl Tree {
         }
     }
 
-    fn get_internal<'g, K: AsRef<[u8]>>(
+    pub(crate) fn get_internal<'g, K: AsRef<[u8]>>(
         &self,
         key: K,
         tx: &'g Tx,
@@ -1209,7 +2041,8 @@ impl Tree {
                 .binary_search_by(|&(ref k, ref _v)| {
                     prefix_cmp_encoded(k, key.as_ref(), &last_node.lo)
                 })
-                .ok();
+                .ok()
+                .filter(|_| !last_node.is_expired(key.as_ref()));
 
             search.map(|idx| &items[idx].1)
         });
@@ -1242,7 +2075,10 @@ impl Tree {
         key: K,
         tx: &'g Tx,
     ) -> Result<Path<'g>> {
-        let _measure = Measure::new(&M.tree_traverse);
+        let _measure = Measure::new(&self.context.metrics.tree_traverse);
+
+        let cc = &self.context.concurrency_control;
+        let mut section = cc.section();
 
         let mut cursor = self.root.load(SeqCst);
         let mut path: Vec<(PageId, &'g Frag, TreePtr<'g>)> = vec![];
@@ -1283,7 +2119,29 @@ impl Tree {
 
             let node = frag.unwrap_base();
 
-            // TODO this may need to change when handling (half) merges
+            if node.merging {
+                // the node is being merged into its left sibling,
+                // which we help to complete from its parent,
+                // where the merge was recorded first.
+                not_found_loops += 1;
+                debug_assert_ne!(
+                    not_found_loops, 10_000,
+                    "cannot complete the merge of pid {} in path_for_key",
+                    cursor
+                );
+                path.clear();
+                unsplit_parent = None;
+                cursor = self.root.load(SeqCst);
+                continue;
+            }
+
+            if let Some(child) = node.merging_child {
+                // half-complete merge completion, after
+                // which we read this node again.
+                self.complete_merge(cursor, child, tx)?;
+                continue;
+            }
+
             assert!(node.lo.as_ref() <= key.as_ref(), "overshot key somehow");
 
             // half-complete split detect & completion
@@ -1309,7 +2167,7 @@ impl Tree {
                     to: cursor,
                 });
 
-                M.tree_parent_split_attempt();
+                self.context.metrics.tree_parent_split_attempt();
                 let link = self.context.pagecache.link(
                     *parent_id,
                     parent_ptr.clone(),
@@ -1321,7 +2179,7 @@ impl Tree {
                     // new_key in the path, along with updating the
                     // parent's node in the path vec. if we don't do
                     // both, we lose the newly appended parent split.
-                    M.tree_parent_split_success();
+                    self.context.metrics.tree_parent_split_success();
                 }
             }
 
@@ -1352,11 +2210,25 @@ impl Tree {
                     }
                 }
                 Data::Leaf(_) => {
-                    break;
+                    if cc.may_read(&self.context.pagecache, cursor, tx) {
+                        break;
+                    }
+
+                    // the leaf is hidden by a commit, after which
+                    // we search again from the root.
+                    drop(section);
+                    cc.wait();
+                    section = cc.section();
+
+                    path.clear();
+                    unsplit_parent = None;
+                    cursor = self.root.load(SeqCst);
                 }
             }
         }
 
+        drop(section);
+
         Ok(path)
     }
 
@@ -1471,3 +2343,61 @@ impl Debug for Tree {
         Ok(())
     }
 }
+
+// Returns the inclusive start and the exclusive end, if it
+// has one, of the keys in `range`.
+pub(crate) fn half_open_bounds<K, R>(range: &R) -> (IVec, Option<IVec>)
+where
+    K: AsRef<[u8]>,
+    R: RangeBounds<K>,
+{
+    let successor = |k: &K| {
+        let mut k = k.as_ref().to_vec();
+        k.push(0);
+        IVec::from(k)
+    };
+    let start = match range.start_bound() {
+        ops::Bound::Included(k) => IVec::from(k.as_ref()),
+        ops::Bound::Excluded(k) => successor(k),
+        ops::Bound::Unbounded => IVec::from(vec![]),
+    };
+    let end = match range.end_bound() {
+        ops::Bound::Included(k) => Some(successor(k)),
+        ops::Bound::Excluded(k) => Some(IVec::from(k.as_ref())),
+        ops::Bound::Unbounded => None,
+    };
+    (start, end)
+}
+
+// Returns the decoded keys and the values of the records of
+// the leaf `node` from `start` to the exclusive `end`,
+// including the ones that have expired.
+fn records_in_range<'n>(
+    node: &'n Node,
+    start: &'n [u8],
+    end: Option<&'n IVec>,
+) -> impl Iterator<Item = (Key, &'n IVec)> {
+    node.data
+        .leaf_ref()
+        .expect("path_for_key should end with a leaf")
+        .iter()
+        .map(move |(k, v)| (prefix_decode(&node.lo, k), v))
+        .filter(move |(k, _v)| {
+            **k >= *start
+                && match end {
+                    Some(end) => **k < **end,
+                    None => true,
+                }
+        })
+}
+
+// Like `records_in_range`, but only the records that
+// have not expired.
+fn live_records<'n>(
+    node: &'n Node,
+    start: &'n [u8],
+    end: Option<&'n IVec>,
+) -> impl Iterator<Item = (Key, &'n IVec)> {
+    records_in_range(node, start, end)
+        .filter(move |(k, _v)| !node.is_expired(k))
+}
@@ -0,0 +1,399 @@
+//! Trees of typed keys and values.
+use std::{
+    fmt::{self, Debug},
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use serde::de::DeserializeOwned;
+
+use super::*;
+
+/// The `Meta` property that records the `Codec::ID` of a typed tree.
+pub(crate) const CODEC_PROPERTY: &str = "codec";
+
+// Ties a wrapper to the types that it decodes into,
+// without owning any of them.
+type Marker<K, V, C> = PhantomData<fn() -> (K, V, C)>;
+
+/// How the keys and values of a `TypedTree` are stored.
+pub trait Codec {
+    /// Identifies the encoding. It is recorded when a typed
+    /// tree is first opened, and a tree can not be opened
+    /// again with a codec that has a different `ID`.
+    const ID: &'static str;
+
+    /// Encodes a key.
+    fn encode_key<K: Serialize>(key: &K) -> Result<Vec<u8>>;
+
+    /// Decodes a key that was encoded by `encode_key`.
+    fn decode_key<K: DeserializeOwned>(bytes: &[u8]) -> Result<K>;
+
+    /// Encodes a value.
+    fn encode_value<V: Serialize>(value: &V) -> Result<Vec<u8>>;
+
+    /// Decodes a value that was encoded by `encode_value`.
+    fn decode_value<V: DeserializeOwned>(bytes: &[u8]) -> Result<V>;
+}
+
+/// Stores keys with an order-preserving encoding, so that
+/// typed trees iterate over integers in numeric order,
+/// over strings in lexicographic order, and over tuples
+/// by their first field, then their second field, and so
+/// on. Values are stored with `bincode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ordered;
+
+impl Codec for Ordered {
+    const ID: &'static str = "ordered+bincode";
+
+    fn encode_key<K: Serialize>(key: &K) -> Result<Vec<u8>> {
+        ordered::to_bytes(key)
+    }
+
+    fn decode_key<K: DeserializeOwned>(bytes: &[u8]) -> Result<K> {
+        ordered::from_bytes(bytes)
+    }
+
+    fn encode_value<V: Serialize>(value: &V) -> Result<Vec<u8>> {
+        Bincode::encode_value(value)
+    }
+
+    fn decode_value<V: DeserializeOwned>(bytes: &[u8]) -> Result<V> {
+        Bincode::decode_value(bytes)
+    }
+}
+
+/// Stores both keys and values with `bincode`. This is
+/// more compact than `Ordered`, but keys do not sort
+/// in a meaningful order, so it is only suitable for
+/// trees that are not iterated over in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    const ID: &'static str = "bincode";
+
+    fn encode_key<K: Serialize>(key: &K) -> Result<Vec<u8>> {
+        Bincode::encode_value(key)
+    }
+
+    fn decode_key<K: DeserializeOwned>(bytes: &[u8]) -> Result<K> {
+        Bincode::decode_value(bytes)
+    }
+
+    fn encode_value<V: Serialize>(value: &V) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| {
+            Error::Unsupported(format!("failed to encode a value: {}", e))
+        })
+    }
+
+    fn decode_value<V: DeserializeOwned>(bytes: &[u8]) -> Result<V> {
+        bincode::deserialize(bytes).map_err(|e| {
+            Error::Unsupported(format!("failed to decode a value: {}", e))
+        })
+    }
+}
+
+/// A `Tree` of keys of type `K` and values of type `V`,
+/// which are stored using the `Codec` `C`. Create one
+/// with `Db::open_typed_tree`.
+///
+/// # Examples
+///
+/// ```
+/// use sled::{ConfigBuilder, Db, Ordered};
+///
+/// let config = ConfigBuilder::new().temporary(true).build();
+/// let db = Db::start(config).unwrap();
+///
+/// let scores = db.open_typed_tree::<u64, String, Ordered>(b"scores").unwrap();
+/// scores.set(&256, &"b".to_owned()).unwrap();
+/// scores.set(&3, &"a".to_owned()).unwrap();
+///
+/// // keys are iterated over in numeric order
+/// let keys: Vec<u64> =
+///     scores.iter().map(|res| res.unwrap().0).collect();
+/// assert_eq!(keys, vec![3, 256]);
+/// ```
+pub struct TypedTree<K, V, C = Ordered> {
+    tree: Arc<Tree>,
+    _marker: Marker<K, V, C>,
+}
+
+impl<K, V, C> Clone for TypedTree<K, V, C> {
+    fn clone(&self) -> TypedTree<K, V, C> {
+        TypedTree {
+            tree: self.tree.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, C: Codec> Debug for TypedTree<K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "TypedTree {{ codec: {:?}, tree: {:?} }}",
+            C::ID,
+            self.tree
+        )
+    }
+}
+
+impl<K, V, C> TypedTree<K, V, C>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    pub(crate) fn new(tree: Arc<Tree>) -> TypedTree<K, V, C> {
+        TypedTree {
+            tree,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The underlying `Tree` of encoded keys and values.
+    pub fn tree(&self) -> &Arc<Tree> {
+        &self.tree
+    }
+
+    /// Retrieve a value from the `TypedTree` if it exists.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let key = C::encode_key(key)?;
+        self.decode_value(self.tree.get(key)?)
+    }
+
+    /// Set a key to a new value, returning the last value
+    /// if it was set.
+    pub fn set(&self, key: &K, value: &V) -> Result<Option<V>> {
+        let key = C::encode_key(key)?;
+        let value = C::encode_value(value)?;
+        self.decode_value(self.tree.set(key, value)?)
+    }
+
+    /// Delete a value, returning the old value if it existed.
+    pub fn del(&self, key: &K) -> Result<Option<V>> {
+        let key = C::encode_key(key)?;
+        self.decode_value(self.tree.del(key)?)
+    }
+
+    /// Compare and swap. Capable of unique creation,
+    /// conditional modification, or deletion. If old is
+    /// `None`, this will only set the value if it doesn't
+    /// exist yet. If new is `None`, will delete the value
+    /// if old is correct. If both old and new are
+    /// `Some`, will modify the value if old is correct.
+    /// Values are compared by their encoding.
+    ///
+    /// It returns `Ok(Ok(()))` if operation finishes
+    /// successfully and `Ok(Err(current_value))` if the
+    /// operation failed to set the new value.
+    pub fn cas(
+        &self,
+        key: &K,
+        old: Option<&V>,
+        new: Option<&V>,
+    ) -> Result<std::result::Result<(), Option<V>>> {
+        let key = C::encode_key(key)?;
+        let old = old.map(C::encode_value).transpose()?;
+        let new = new.map(C::encode_value).transpose()?;
+
+        match self.tree.cas(key, old, new)? {
+            Ok(()) => Ok(Ok(())),
+            Err(current) => Ok(Err(self.decode_value(current)?)),
+        }
+    }
+
+    /// Fetch the value, apply a function to it and return
+    /// the result. Returning `None` from the function
+    /// deletes the value.
+    ///
+    /// # Note
+    ///
+    /// This may call the function multiple times if the
+    /// value has been changed from other threads in the
+    /// meantime.
+    pub fn update_and_fetch<F>(&self, key: &K, f: F) -> Result<Option<V>>
+    where
+        F: Fn(Option<V>) -> Option<V>,
+    {
+        let key = C::encode_key(key)?;
+        let mut current = self.tree.get(&key)?;
+
+        loop {
+            let next = f(self.decode_value(current.clone())?);
+            let encoded = next.as_ref().map(C::encode_value).transpose()?;
+            match self.tree.cas(&key, current, encoded)? {
+                Ok(()) => return Ok(next),
+                Err(new_current) => current = new_current,
+            }
+        }
+    }
+
+    /// Create a double-ended iterator over the keys and
+    /// values of the `TypedTree`, in the order of their
+    /// encoded keys.
+    pub fn iter(&self) -> TypedIter<'_, K, V, C> {
+        self.range(..)
+    }
+
+    /// Create a double-ended iterator over the keys and
+    /// values of the `TypedTree` whose keys fall within
+    /// the specified range. Keys are compared by their
+    /// encoding, which matches the order of the keys
+    /// themselves when using the `Ordered` codec.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> TypedIter<'_, K, V, C> {
+        let bounds = encode_bound::<K, C>(range.start_bound())
+            .and_then(|lo| Ok((lo, encode_bound::<K, C>(range.end_bound())?)));
+
+        match bounds {
+            Ok(bounds) => TypedIter {
+                iter: Ok(self.tree.range::<Vec<u8>, _>(bounds)),
+                _marker: PhantomData,
+            },
+            Err(e) => TypedIter {
+                iter: Err(Some(e)),
+                _marker: PhantomData,
+            },
+        }
+    }
+
+    /// Subscribe to `TypedEvent`s that happen to keys whose
+    /// encoding starts with the encoding of `prefix`. With
+    /// the `Ordered` codec, the leading fields of a tuple
+    /// key can be used to watch every key that starts with
+    /// them, such as `&(user_id,)` for `(user_id, item)` keys.
+    pub fn watch_prefix<P: Serialize>(
+        &self,
+        prefix: &P,
+    ) -> Result<TypedSubscriber<K, V, C>> {
+        let prefix = C::encode_key(prefix)?;
+        Ok(TypedSubscriber {
+            subscriber: self.tree.watch_prefix(prefix),
+            _marker: PhantomData,
+        })
+    }
+
+    fn decode_value(&self, value: Option<IVec>) -> Result<Option<V>> {
+        value.map(|v| C::decode_value(&v)).transpose()
+    }
+}
+
+fn encode_bound<K: Serialize, C: Codec>(
+    bound: Bound<&K>,
+) -> Result<Bound<Vec<u8>>> {
+    Ok(match bound {
+        Bound::Included(key) => Bound::Included(C::encode_key(key)?),
+        Bound::Excluded(key) => Bound::Excluded(C::encode_key(key)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+/// A double-ended iterator over the decoded keys and
+/// values of a `TypedTree`.
+pub struct TypedIter<'a, K, V, C> {
+    iter: std::result::Result<Iter<'a>, Option<Error>>,
+    _marker: Marker<K, V, C>,
+}
+
+impl<'a, K, V, C> TypedIter<'a, K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    fn decode(
+        &mut self,
+        next: impl FnOnce(&mut Iter<'a>) -> Option<Result<(Key, IVec)>>,
+    ) -> Option<Result<(K, V)>> {
+        let iter = match self.iter {
+            Ok(ref mut iter) => iter,
+            // a bound could not be encoded
+            Err(ref mut e) => return e.take().map(Err),
+        };
+
+        Some(
+            next(iter)?.and_then(|(k, v)| {
+                Ok((C::decode_key(&k)?, C::decode_value(&v)?))
+            }),
+        )
+    }
+}
+
+impl<'a, K, V, C> Iterator for TypedIter<'a, K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decode(Iterator::next)
+    }
+}
+
+impl<'a, K, V, C> DoubleEndedIterator for TypedIter<'a, K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.decode(DoubleEndedIterator::next_back)
+    }
+}
+
+/// An event that happened to a key of a `TypedTree`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedEvent<K, V> {
+    /// A new (key, value) pair
+    Set(K, V),
+    /// A partial value that was merged into the underlying
+    /// `Tree`, which is not decoded because a merge operator
+    /// may use its own encoding for it
+    Merge(K, IVec),
+    /// A deleted key
+    Del(K),
+}
+
+/// A subscriber listening on a specified prefix of a
+/// `TypedTree`, which decodes the `Event`s of the
+/// underlying `Tree`.
+pub struct TypedSubscriber<K, V, C> {
+    subscriber: Subscriber,
+    _marker: Marker<K, V, C>,
+}
+
+impl<K, V, C> TypedSubscriber<K, V, C> {
+    /// The underlying `Subscriber` of encoded events.
+    pub fn subscriber(&mut self) -> &mut Subscriber {
+        &mut self.subscriber
+    }
+}
+
+impl<K, V, C> Iterator for TypedSubscriber<K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    type Item = Result<TypedEvent<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let decode = |event| {
+            Ok(match event {
+                Event::Set(k, v) => {
+                    TypedEvent::Set(C::decode_key(&k)?, C::decode_value(&v)?)
+                }
+                Event::Merge(k, v) => TypedEvent::Merge(C::decode_key(&k)?, v),
+                Event::Del(k) => TypedEvent::Del(C::decode_key(&k)?),
+            })
+        };
+
+        self.subscriber.next().map(decode)
+    }
+}
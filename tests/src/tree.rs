@@ -1,4 +1,4 @@
-use std::{collections::BTreeMap, fmt, panic};
+use std::{collections::BTreeMap, fmt, panic, sync::Arc};
 
 use quickcheck::{Arbitrary, Gen, RngCore};
 use rand::distributions::{Distribution, Gamma};
@@ -219,11 +219,12 @@ pub fn prop_tree_matches_btreemap(
         .blink_node_split_size(1 << std::cmp::min(blink_node_exponent, 20))
         .cache_capacity(40)
         .cache_bits(0)
-        .merge_operator(test_merge_operator)
         .idgen_persist_interval(1)
         .build();
 
     let mut tree = sled::Db::start(config.clone()).unwrap();
+    tree.set_merge_operator("test", Arc::new(test_merge_operator))
+        .unwrap();
     let mut reference: BTreeMap<Key, u16> = BTreeMap::new();
 
     for op in ops.into_iter() {
@@ -303,6 +304,8 @@ pub fn prop_tree_matches_btreemap(
             Restart => {
                 drop(tree);
                 tree = sled::Db::start(config.clone()).unwrap();
+                tree.set_merge_operator("test", Arc::new(test_merge_operator))
+                    .unwrap();
             }
         }
     }
@@ -1,4 +1,8 @@
-use std::sync::{Arc, Barrier};
+use std::ops::Bound;
+use std::sync::{
+    atomic::{AtomicBool, Ordering::SeqCst},
+    Arc, Barrier,
+};
 use std::thread;
 
 use pagecache::ConfigBuilder;
@@ -10,6 +14,7 @@ use tests::tree::{
 
 use log::{debug, warn};
 use quickcheck::{QuickCheck, StdGen};
+use rayon::iter::ParallelIterator;
 
 const N_THREADS: usize = 10;
 const N_PER_THREAD: usize = 100;
@@ -535,6 +540,2471 @@ fn tree_range() {
     assert_eq!(r.next(), None);
 }
 
+#[test]
+fn tree_transactions() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+
+    let db = sled::Db::start(config).unwrap();
+    let checking = db.open_tree(b"checking")?;
+    let savings = db.open_tree(b"savings")?;
+
+    fn balance(v: Option<IVec>) -> u64 {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&v.unwrap());
+        u64::from_be_bytes(buf)
+    }
+
+    for i in 0..10_u8 {
+        checking.set(vec![i], 100_u64.to_be_bytes().to_vec())?;
+        savings.set(vec![i], 100_u64.to_be_bytes().to_vec())?;
+    }
+
+    let mut threads = vec![];
+    for tn in 0..N_THREADS {
+        let checking = checking.clone();
+        let savings = savings.clone();
+        let thread = thread::spawn(move || {
+            for i in 0..N_PER_THREAD {
+                let account = vec![((tn + i) % 10) as u8];
+                checking
+                    .transaction(|tx| {
+                        let savings = tx.with_tree(&savings)?;
+                        let from = balance(tx.get(&account)?);
+                        let to = balance(savings.get(&account)?);
+                        if from == 0 {
+                            return Ok(());
+                        }
+                        tx.set(&account, (from - 1).to_be_bytes().to_vec())?;
+                        savings
+                            .set(&account, (to + 1).to_be_bytes().to_vec())?;
+                        Ok(())
+                    })
+                    .unwrap();
+            }
+        });
+        threads.push(thread);
+    }
+
+    for thread in threads.into_iter() {
+        thread.join().unwrap();
+    }
+
+    for i in 0..10_u8 {
+        let total = checking
+            .transaction(|tx| {
+                let savings = tx.with_tree(&savings)?;
+                Ok(balance(tx.get([i])?) + balance(savings.get([i])?))
+            })
+            .unwrap();
+        assert_eq!(total, 200);
+    }
+
+    let total: u64 = checking
+        .iter()
+        .chain(savings.iter())
+        .map(|res| balance(Some(res.unwrap().1)))
+        .sum();
+    assert_eq!(total, 2_000);
+
+    let aborted: std::result::Result<(), _> = checking.transaction(|tx| {
+        tx.del([0])?;
+        Err(TransactionError::Abort)
+    });
+    assert_eq!(aborted, Err(TransactionError::Abort));
+    assert!(checking.contains_key([0])?);
+
+    Ok(())
+}
+
+#[test]
+fn tree_transactions_with_concurrent_writes() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new().temporary(true).build();
+    let db = sled::Db::start(config).unwrap();
+
+    fn number(v: Option<&[u8]>) -> u64 {
+        let mut buf = [0; 8];
+        if let Some(v) = v {
+            buf.copy_from_slice(v);
+        }
+        u64::from_be_bytes(buf)
+    }
+
+    fn count(v: Option<IVec>) -> u64 {
+        number(v.as_ref().map(AsRef::as_ref))
+    }
+
+    fn increment(v: Option<&[u8]>) -> Option<Vec<u8>> {
+        Some((number(v) + 1).to_be_bytes().to_vec())
+    }
+
+    // single-key writers must not be lost between a
+    // transaction validating its reads and its writes.
+    let mut threads = vec![];
+    for tn in 0..N_THREADS {
+        let db = db.clone();
+        let thread = thread::spawn(move || {
+            for _ in 0..N_PER_THREAD {
+                if tn % 2 == 0 {
+                    db.update_and_fetch(b"a", increment).unwrap();
+                } else {
+                    db.transaction(|tx| {
+                        let a = count(tx.get(b"a")?);
+                        let b = count(tx.get(b"b")?);
+                        tx.set(b"a", (a + 1).to_be_bytes().to_vec())?;
+                        tx.set(b"b", (b + 1).to_be_bytes().to_vec())?;
+                        Ok(())
+                    })
+                    .unwrap();
+                }
+            }
+        });
+        threads.push(thread);
+    }
+
+    for thread in threads.into_iter() {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(count(db.get(b"a")?), (N_THREADS * N_PER_THREAD) as u64);
+    assert_eq!(count(db.get(b"b")?), (N_THREADS / 2 * N_PER_THREAD) as u64);
+
+    Ok(())
+}
+
+#[test]
+fn tree_large_transaction() -> Result<()> {
+    tests::setup_logger();
+
+    const WRITES: usize = 2000;
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .build();
+
+    let t = sled::Db::start(config.clone())?;
+
+    let subscriber = t.watch_prefix(vec![]);
+    let drainer = thread::spawn(move || subscriber.take(WRITES).count());
+
+    t.transaction(|tx| {
+        for i in 0..WRITES {
+            tx.set((i as u64).to_be_bytes(), vec![7; 32])?;
+        }
+        Ok(())
+    })
+    .unwrap();
+
+    assert_eq!(drainer.join().unwrap(), WRITES);
+
+    drop(t);
+
+    let t = sled::Db::start(config)?;
+    assert_eq!(t.iter().count(), WRITES);
+
+    Ok(())
+}
+
+#[test]
+fn tree_batch() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_node_split_size(0)
+        .flush_every_ms(None)
+        .build();
+
+    let t = sled::Db::start(config.clone())?;
+    let mut subscriber = t.watch_prefix(vec![]);
+
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), kv(i))?;
+    }
+    for _ in 0..N_PER_THREAD {
+        subscriber.next().unwrap();
+    }
+
+    // replace the even keys and remove the odd ones
+    let mut batch = Batch::default();
+    for i in 0..N_PER_THREAD {
+        if i % 2 == 0 {
+            batch.set(kv(i), vec![0]);
+        } else {
+            batch.del(kv(i));
+        }
+    }
+    assert_eq!(batch.len(), N_PER_THREAD);
+    t.apply_batch(batch)?;
+
+    for i in 0..N_PER_THREAD {
+        let expected = if i % 2 == 0 {
+            Event::Set(kv(i), vec![0].into())
+        } else {
+            Event::Del(kv(i))
+        };
+        assert_eq!(subscriber.next(), Some(expected));
+    }
+
+    let mut batch = Batch::default();
+    batch.merge(kv(0), vec![1]);
+    assert_eq!(
+        t.apply_batch(batch),
+        Err(Error::Unsupported(
+            "must set a merge operator with \
+             Tree::set_merge_operator before calling merge"
+                .to_owned()
+        ))
+    );
+
+    drop(subscriber);
+    drop(t);
+
+    let t = sled::Db::start(config)?;
+    for i in 0..N_PER_THREAD {
+        let expected = if i % 2 == 0 {
+            Some(IVec::from(vec![0]))
+        } else {
+            None
+        };
+        assert_eq!(t.get(kv(i))?, expected);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_large_batch() -> Result<()> {
+    tests::setup_logger();
+
+    const BATCH: usize = 2000;
+
+    // the batch is many times larger than an io buffer
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .build();
+
+    let t = sled::Db::start(config.clone())?;
+
+    // keep draining while the batch is applied, so that the
+    // subscriber's queue fills up and then empties again.
+    let subscriber = t.watch_prefix(vec![]);
+    let drainer = thread::spawn(move || {
+        let mut seen = 0;
+        for event in subscriber {
+            if let Event::Set(..) = event {
+                seen += 1;
+            }
+            if seen == BATCH {
+                break;
+            }
+        }
+        seen
+    });
+
+    let mut batch = Batch::default();
+    for i in 0..BATCH {
+        batch.set((i as u64).to_be_bytes(), vec![7; 32]);
+    }
+    t.apply_batch(batch)?;
+
+    assert_eq!(drainer.join().unwrap(), BATCH);
+
+    drop(t);
+
+    let t = sled::Db::start(config)?;
+    assert_eq!(t.iter().count(), BATCH);
+    for i in 0..BATCH {
+        assert_eq!(t.get((i as u64).to_be_bytes())?, Some(vec![7; 32].into()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_snapshot() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_node_split_size(0)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .build();
+
+    let db = sled::Db::start(config)?;
+    let other = db.open_tree(b"other")?;
+
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), kv(i))?;
+        other.set(kv(i), kv(i))?;
+    }
+
+    let snapshot = db.snapshot()?;
+
+    // rewrite everything while reading from the snapshot,
+    // which should not observe any of these changes.
+    let writer = {
+        let db = db.clone();
+        let other = other.clone();
+        thread::spawn(move || {
+            for i in 0..N_PER_THREAD {
+                db.del(kv(i)).unwrap();
+                other.set(kv(i), vec![0]).unwrap();
+                db.set(kv(N_PER_THREAD + i), vec![0]).unwrap();
+            }
+        })
+    };
+
+    for _ in 0..INTENSITY {
+        let items: Vec<_> = snapshot.iter().collect::<Result<Vec<_>>>()?;
+        let expected: Vec<_> = (0..N_PER_THREAD)
+            .map(|i| (kv(i), IVec::from(kv(i))))
+            .collect();
+        assert_eq!(items, expected);
+
+        let reversed: Vec<_> =
+            snapshot.iter().rev().collect::<Result<Vec<_>>>()?;
+        assert_eq!(reversed.len(), N_PER_THREAD);
+        assert!(reversed.into_iter().rev().eq(expected.into_iter()));
+    }
+
+    writer.join().unwrap();
+
+    let other_snapshot = snapshot.open_tree(b"other")?;
+    for i in 0..N_PER_THREAD {
+        assert_eq!(snapshot.get(kv(i))?, Some(IVec::from(kv(i))));
+        assert_eq!(other_snapshot.get(kv(i))?, Some(IVec::from(kv(i))));
+        assert_eq!(snapshot.get(kv(N_PER_THREAD + i))?, None);
+    }
+
+    // meeting in the middle should yield every item once
+    let mut iter = snapshot.range(kv(10)..kv(20));
+    let mut seen = vec![];
+    while let Some(front) = iter.next() {
+        seen.push(front?.0);
+        if let Some(back) = iter.next_back() {
+            seen.push(back?.0);
+        }
+    }
+    seen.sort();
+    assert_eq!(seen, (10..20).map(kv).collect::<Vec<_>>());
+
+    assert!(snapshot.open_tree(b"missing").is_err());
+    assert_eq!(db.len(), N_PER_THREAD);
+
+    drop(snapshot);
+    drop(other_snapshot);
+
+    let snapshot = db.snapshot()?;
+    assert_eq!(snapshot.len(), N_PER_THREAD);
+    assert_eq!(snapshot.get(kv(0))?, None);
+
+    Ok(())
+}
+
+#[test]
+fn tree_snapshot_held_blobs() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .async_io(false)
+        .snapshot_after_ops(10)
+        .build();
+
+    let db = sled::Db::start(config)?;
+
+    // large values are stored as blobs
+    let big = vec![7; 2000];
+
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), big.clone())?;
+    }
+
+    // the blobs of values that are overwritten while the
+    // snapshot is open are held until it is dropped.
+    let snapshot = db.snapshot()?;
+    for _ in 0..INTENSITY {
+        for i in 0..N_PER_THREAD {
+            db.set(kv(i), vec![0])?;
+        }
+    }
+
+    for i in 0..N_PER_THREAD {
+        assert_eq!(snapshot.get(kv(i))?, Some(IVec::from(big.clone())));
+    }
+
+    let checkpoint = |name: &str| -> Result<sled::Db> {
+        let path = std::env::temp_dir().join(format!(
+            "sled_tree_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        db.checkpoint(&path)?;
+
+        let config = ConfigBuilder::new()
+            .path(&path)
+            .temporary(true)
+            .io_buf_size(5000)
+            .build();
+        sled::Db::start(config)
+    };
+
+    // a checkpoint copies the held blobs, just like a crash
+    // while the snapshot is open would leave them behind,
+    // and recovery should not keep them around.
+    let held = checkpoint("held_blobs")?;
+    drop(snapshot);
+    let released = checkpoint("released_blobs")?;
+
+    assert_eq!(held.storage_stats()?.blobs, released.storage_stats()?.blobs);
+    assert_eq!(held.get(kv(0))?, Some(IVec::from(vec![0])));
+
+    Ok(())
+}
+
+#[test]
+fn tree_checkpoint() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(N_PER_THREAD as u64 / 2)
+        .build();
+
+    let db = sled::Db::start(config)?;
+    let other = db.open_tree(b"other")?;
+
+    // large values are stored as blobs
+    let big = vec![7; 2000];
+
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), kv(i))?;
+        other.set(kv(i), big.clone())?;
+    }
+
+    let path = std::env::temp_dir()
+        .join(format!("sled_tree_checkpoint_{}", std::process::id()));
+
+    // keep rewriting everything while the checkpoint is written
+    let writer = {
+        let db = db.clone();
+        let other = other.clone();
+        thread::spawn(move || {
+            for i in 0..N_PER_THREAD {
+                db.set(kv(N_PER_THREAD + i), vec![0]).unwrap();
+                other.del(kv(i)).unwrap();
+            }
+        })
+    };
+
+    let _ = std::fs::remove_dir_all(&path);
+    db.checkpoint(&path)?;
+    writer.join().unwrap();
+
+    // the destination must be empty
+    assert!(db.checkpoint(&path).is_err());
+
+    let config = ConfigBuilder::new()
+        .path(&path)
+        .temporary(true)
+        .io_buf_size(5000)
+        .build();
+    let copy = sled::Db::start(config)?;
+    let copy_other = copy.open_tree(b"other")?;
+
+    for i in 0..N_PER_THREAD {
+        assert_eq!(copy.get(kv(i))?, Some(IVec::from(kv(i))));
+    }
+
+    // whichever concurrent deletions made it into the
+    // checkpoint, every remaining value should be intact
+    for item in copy_other.iter() {
+        let (_k, v) = item?;
+        assert_eq!(v, big);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn tree_replication() -> Result<()> {
+    use std::os::unix::net::UnixStream;
+
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(N_PER_THREAD as u64 / 2)
+        .build();
+
+    let db = sled::Db::start(config)?;
+    let other = db.open_tree(b"other")?;
+    let doomed = db.open_tree(b"doomed")?;
+
+    let primary = db.replication_primary("standby")?;
+
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), kv(i))?;
+        doomed.set(kv(i), kv(i))?;
+    }
+
+    let path = std::env::temp_dir()
+        .join(format!("sled_tree_replication_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    db.checkpoint(&path)?;
+
+    let replica_config = ConfigBuilder::new()
+        .path(&path)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .read_only(true)
+        .build();
+    let replica = Replica::start(replica_config.clone())?;
+
+    // small chunks make each sync request several of them
+    let (mut client, server) = UnixStream::pair()?;
+    let server = {
+        let primary = primary.clone().chunk_limits(4096, 10);
+        thread::spawn(move || primary.serve(server))
+    };
+
+    // large values are stored as blobs
+    let big = vec![7; 2000];
+
+    let mut batch = Batch::default();
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), vec![1])?;
+        other.set(kv(i), big.clone())?;
+        batch.set(kv(N_PER_THREAD + i), kv(i));
+    }
+    db.apply_batch(batch)?;
+    assert!(db.drop_tree(b"doomed")?);
+    let late = db.open_tree(b"late")?;
+    late.set(b"k1", vec![2])?;
+    for i in 0..N_PER_THREAD / 2 {
+        db.del(kv(i))?;
+    }
+
+    assert!(replica.sync(&mut client)? > 0);
+
+    // the next request acknowledges the updates
+    let position = replica.position();
+    assert!(primary.position()? < position);
+    replica.sync(&mut client)?;
+    assert_eq!(primary.position()?, position);
+
+    // writes to a replica are rejected
+    assert!(replica.set(b"k1", vec![0]).is_err());
+
+    let assert_replicated = |replica: &Replica| -> Result<()> {
+        let mut names = db.tree_names();
+        let mut replica_names = replica.tree_names();
+        names.sort();
+        replica_names.sort();
+        assert_eq!(names, replica_names);
+
+        for name in names {
+            let tree = db.open_tree(&name)?;
+            let replica_tree = replica.open_tree(&name)?;
+            let items: Vec<_> = tree.iter().collect::<Result<_>>()?;
+            let replica_items: Vec<_> =
+                replica_tree.iter().collect::<Result<_>>()?;
+            assert_eq!(items, replica_items);
+        }
+
+        assert_eq!(
+            db.iter().collect::<Result<Vec<_>>>()?,
+            replica.iter().collect::<Result<Vec<_>>>()?
+        );
+
+        Ok(())
+    };
+
+    assert_replicated(&replica)?;
+
+    // a restarted replica resumes from its last position
+    let position = replica.position();
+    drop(replica);
+
+    for i in 0..N_PER_THREAD {
+        other.del(kv(i))?;
+        late.set(kv(i), kv(i))?;
+    }
+
+    let replica = Replica::start(replica_config)?;
+    assert_eq!(replica.position(), position);
+
+    assert!(replica.sync(&mut client)? > 0);
+    assert_eq!(replica.sync(&mut client)?, 0);
+    assert_replicated(&replica)?;
+
+    drop(client);
+    server.join().unwrap()?;
+
+    // a replica that falls behind what the primary
+    // retains is refused
+    let (mut client, server) = UnixStream::pair()?;
+    let server = {
+        let primary = primary.clone();
+        thread::spawn(move || primary.serve(server))
+    };
+    drop(replica);
+    let _ = std::fs::remove_dir_all(&path);
+    db.checkpoint(&path)?;
+    db.set(b"k2", vec![3])?;
+    primary.remove()?;
+    let _primary = db.replication_primary("standby")?;
+
+    let config = ConfigBuilder::new()
+        .path(&path)
+        .temporary(true)
+        .io_buf_size(5000)
+        .read_only(true)
+        .build();
+    let replica = Replica::start(config)?;
+    match replica.sync(&mut client) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+
+    drop(client);
+    server.join().unwrap()?;
+
+    // a message that is too long is rejected before it is read
+    let (mut client, mut server) = UnixStream::pair()?;
+    std::io::Write::write_all(&mut client, &std::u64::MAX.to_le_bytes())?;
+    assert!(Transport::recv(&mut server).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn tree_export_import() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .build();
+
+    let db = sled::Db::start(config)?;
+    let other = db.open_tree(b"other")?;
+    let _empty = db.open_tree(b"empty")?;
+
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), kv(i))?;
+        other.set(kv(i), vec![7; i])?;
+    }
+
+    // index trees are not exported
+    other
+        .register_index("len", |_k, v| vec![v.len().to_be_bytes().to_vec()])?;
+
+    let mut stream = vec![];
+    let written = db.export()?.write_to(&mut stream)?;
+    assert_eq!(written, N_PER_THREAD as u64 * 2);
+
+    let fresh = || {
+        let config = ConfigBuilder::new()
+            .temporary(true)
+            .io_buf_size(5000)
+            .flush_every_ms(None)
+            .build();
+        sled::Db::start(config)
+    };
+
+    let copy = fresh()?;
+    copy.import(|| ExportReader::new(&stream[..]))?;
+    let copy_other = copy.open_tree(b"other")?;
+
+    assert_eq!(copy.len(), N_PER_THREAD);
+    assert_eq!(copy_other.len(), N_PER_THREAD);
+    assert!(copy_other.index_lookup("len", &[0]).is_err());
+    for i in 0..N_PER_THREAD {
+        assert_eq!(copy.get(kv(i))?, Some(IVec::from(kv(i))));
+        assert_eq!(copy_other.get(kv(i))?, Some(IVec::from(vec![7; i])));
+    }
+
+    // importing directly from another db works too
+    let direct = fresh()?;
+    direct.import(|| db.export())?;
+    assert_eq!(direct.open_tree(b"other")?.len(), N_PER_THREAD);
+
+    // only empty databases may be imported into
+    assert!(copy.import(|| ExportReader::new(&stream[..])).is_err());
+
+    // a flipped byte fails the crc check
+    let mut corrupt = stream.clone();
+    let idx = corrupt.len() / 2;
+    corrupt[idx] ^= 0xFF;
+    let results: Vec<_> = ExportReader::new(&corrupt[..])?.collect();
+    assert!(results.last().unwrap().is_err());
+    let target = fresh()?;
+    assert!(target.import(|| ExportReader::new(&corrupt[..])).is_err());
+
+    // nothing is written unless the whole stream is valid
+    assert!(target.is_empty());
+    assert_eq!(target.tree_names(), fresh()?.tree_names());
+
+    // a stream missing its end frame is truncated
+    let truncated = &stream[..stream.len() - 1];
+    let target = fresh()?;
+    assert!(target.import(|| ExportReader::new(truncated)).is_err());
+    assert!(target.is_empty());
+
+    // records of index trees are skipped
+    let with_index = || {
+        Ok(vec![
+            Ok((
+                b"__sled__index".to_vec(),
+                b"a".to_vec(),
+                IVec::from(vec![1]),
+                None,
+            )),
+            Ok((b"t".to_vec(), b"a".to_vec(), IVec::from(vec![2]), None)),
+        ])
+    };
+    let target = fresh()?;
+    target.import(with_index)?;
+    assert_eq!(target.open_tree(b"t")?.len(), 1);
+    assert_eq!(target.snapshot()?.tree_names().len(), 2);
+
+    // records must be in the order that exports write them
+    let unordered = || {
+        Ok(vec![
+            Ok((b"t".to_vec(), b"b".to_vec(), IVec::from(vec![1]), None)),
+            Ok((b"t".to_vec(), b"a".to_vec(), IVec::from(vec![2]), None)),
+        ])
+    };
+    let target = fresh()?;
+    assert!(target.import(unordered).is_err());
+    assert!(target.open_tree(b"t")?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn tree_export_import_ttl() -> Result<()> {
+    use std::time::Duration;
+
+    tests::setup_logger();
+
+    let fresh = || {
+        let config = ConfigBuilder::new()
+            .temporary(true)
+            .io_buf_size(5000)
+            .flush_every_ms(None)
+            .build();
+        sled::Db::start(config)
+    };
+
+    let db = fresh()?;
+    let t = db.open_tree(b"ttl")?;
+    t.set(kv(0), vec![0])?;
+    t.set_with_ttl(kv(1), vec![1], Duration::from_secs(3600))?;
+    t.set_with_ttl(kv(2), vec![2], Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+
+    let expires_at = |db: &Db| -> Result<Vec<(Vec<u8>, Option<u64>)>> {
+        db.export()?
+            .filter(|res| match res {
+                Ok((tree_name, ..)) => tree_name == b"ttl",
+                Err(_) => true,
+            })
+            .map(|res| res.map(|(_, k, _v, expires_at)| (k, expires_at)))
+            .collect()
+    };
+
+    let exported = expires_at(&db)?;
+    assert_eq!(exported.len(), 2);
+    assert_eq!(exported[0], (kv(0), None));
+    assert!(exported[1].1.is_some());
+
+    // expiration times survive a round trip through a stream
+    let mut stream = vec![];
+    db.export()?.write_to(&mut stream)?;
+    let copy = fresh()?;
+    copy.import(|| ExportReader::new(&stream[..]))?;
+    assert_eq!(expires_at(&copy)?, exported);
+
+    // values that expired by the time they are imported are skipped
+    let expired = || {
+        Ok(vec![
+            Ok((b"t".to_vec(), b"a".to_vec(), IVec::from(vec![1]), Some(1))),
+            Ok((b"t".to_vec(), b"b".to_vec(), IVec::from(vec![2]), None)),
+        ])
+    };
+    let target = fresh()?;
+    target.import(expired)?;
+    let keys: Vec<Vec<u8>> = target
+        .open_tree(b"t")?
+        .iter()
+        .keys()
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"b".to_vec()]);
+
+    // streams written before expiration times were exported
+    // are still read
+    let mut old = b"sled-exp".to_vec();
+    old.extend_from_slice(&1_u32.to_le_bytes());
+    let mut write_frame = |payload: &[u8]| {
+        old.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        old.extend_from_slice(&pagecache::crc32(payload).to_le_bytes());
+        old.extend_from_slice(payload);
+    };
+    let mut record = vec![1];
+    record.extend_from_slice(&1_u64.to_le_bytes());
+    record.extend_from_slice(b"t");
+    record.extend_from_slice(&1_u64.to_le_bytes());
+    record.extend_from_slice(b"a");
+    record.extend_from_slice(&[7]);
+    write_frame(&record);
+    let mut end = vec![2];
+    end.extend_from_slice(&1_u64.to_le_bytes());
+    write_frame(&end);
+
+    let records: Vec<ExportRecord> =
+        ExportReader::new(&old[..])?.collect::<Result<_>>()?;
+    assert_eq!(
+        records,
+        vec![(b"t".to_vec(), b"a".to_vec(), IVec::from(vec![7]), None)]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn tree_flush_async() -> Result<()> {
+    use futures::{future::join_all, Future, Stream};
+
+    tests::setup_logger();
+
+    for async_io in &[true, false] {
+        let config = ConfigBuilder::new()
+            .temporary(true)
+            .io_buf_size(5000)
+            .flush_every_ms(None)
+            .async_io(*async_io)
+            .build();
+
+        let db = sled::Db::start(config)?;
+        let events = db.watch_prefix(vec![]);
+
+        let mut flushes = vec![];
+        for i in 0..N_PER_THREAD {
+            db.set(kv(i), kv(i))?;
+            if i % 10 == 0 {
+                flushes.push(db.flush_async());
+            }
+        }
+
+        flushes.push(db.flush_async());
+        join_all(flushes).wait()?;
+
+        // nothing is pending, so this resolves immediately
+        assert_eq!(db.flush_async().wait()?, 0);
+
+        let seen: Vec<Event> = Stream::take(events, N_PER_THREAD as u64)
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(seen.len(), N_PER_THREAD);
+        for (i, event) in seen.into_iter().enumerate() {
+            assert_eq!(event, Event::Set(kv(i), IVec::from(kv(i))));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_subscriber_backpressure() -> Result<()> {
+    use std::sync::mpsc::TryRecvError;
+    use std::time::Duration;
+
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config)?;
+
+    // neither of these is read while writing, and
+    // neither of them may stall the writers
+    let mut dropper =
+        db.watch_prefix_with_backpressure(vec![], 10, Backpressure::DropOldest);
+    let mut disconnector =
+        db.watch_prefix_with_backpressure(vec![], 10, Backpressure::Disconnect);
+
+    let mut threads = vec![];
+    for t in 0..N_THREADS {
+        let db = db.clone();
+        threads.push(thread::spawn(move || {
+            for i in 0..N_PER_THREAD {
+                db.set(kv(t * N_PER_THREAD + i), vec![]).unwrap();
+            }
+        }));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let mut delivered = 0;
+    loop {
+        match dropper.try_next() {
+            Ok(_) => delivered += 1,
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => panic!("should not end"),
+        }
+    }
+    // some buffered reservations may belong to writes that
+    // were retried, so fewer than 10 events may be delivered
+    assert!(delivered <= 10);
+    assert_eq!(delivered + dropper.lagged(), N);
+
+    let delivered = disconnector.by_ref().count();
+    assert!(delivered <= 10);
+    assert_eq!(disconnector.lagged(), N - delivered);
+
+    // the dropper keeps receiving new events
+    db.set(b"after", vec![])?;
+    assert_eq!(
+        dropper.next_timeout(Duration::from_secs(5)),
+        Ok(Event::Set(b"after".to_vec(), IVec::from(vec![])))
+    );
+    assert!(disconnector.next().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn tree_change_feed() -> Result<()> {
+    tests::setup_logger();
+
+    let path = std::env::temp_dir()
+        .join(format!("sled_tree_change_feed_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+
+    let open = || {
+        let config = ConfigBuilder::new()
+            .path(&path)
+            .io_buf_size(5000)
+            .flush_every_ms(None)
+            .snapshot_after_ops(N_PER_THREAD as u64 / 2)
+            .build();
+        sled::Db::start(config)
+    };
+
+    let db = open()?;
+    let other = db.open_tree(b"other")?;
+    let churn = db.open_tree(b"churn")?;
+
+    // writes from before a feed is registered are not replayed
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), kv(i))?;
+    }
+
+    let feed = db.change_feed("replicator")?;
+    let other_feed = other.change_feed("replicator")?;
+
+    // large values are stored as blobs
+    let big = vec![7; 2000];
+
+    let mut expected = vec![];
+    for i in 0..N_PER_THREAD {
+        db.set(kv(i), kv(i + 1))?;
+        expected.push(Change::Set(kv(i), kv(i + 1).into()));
+        other.set(kv(i), big.clone())?;
+        if i % 2 == 0 {
+            db.del(kv(i))?;
+            expected.push(Change::Del(kv(i)));
+        }
+    }
+
+    let changes = feed.iter()?.collect::<Result<Vec<_>>>()?;
+    let events: Vec<Change> = changes.iter().map(|c| c.event.clone()).collect();
+    assert_eq!(events, expected);
+    assert!(changes.windows(2).all(|w| w[0].lsn < w[1].lsn));
+
+    // acknowledge the first half
+    let half = changes.len() / 2;
+    feed.ack(changes[half - 1].lsn)?;
+    assert_eq!(feed.position()?, changes[half - 1].lsn + 1);
+    assert_eq!(feed.iter()?.count(), changes.len() - half);
+    assert_eq!(other_feed.iter()?.count(), N_PER_THREAD);
+
+    // rewrite other pages so that old segments would be reused
+    for _ in 0..5 {
+        for i in 0..N_PER_THREAD {
+            churn.set(kv(i), kv(i))?;
+        }
+    }
+
+    drop(feed);
+    drop(other_feed);
+    drop(churn);
+    drop(other);
+    drop(db);
+
+    let db = open()?;
+    let other = db.open_tree(b"other")?;
+
+    let feed = db.change_feed("replicator")?;
+    assert_eq!(feed.position()?, changes[half - 1].lsn + 1);
+    let resumed = feed.iter()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(&resumed[..], &changes[half..]);
+
+    let other_feed = other.change_feed("replicator")?;
+    let other_changes = other_feed.iter()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(other_changes.len(), N_PER_THREAD);
+    for (i, change) in other_changes.iter().enumerate() {
+        assert_eq!(change.event, Change::Set(kv(i), big.clone().into()));
+    }
+
+    // a removed feed starts over from the next write
+    other_feed.remove()?;
+    let other_feed = other.change_feed("replicator")?;
+    assert_eq!(other_feed.iter()?.count(), 0);
+    other.set(kv(0), kv(0))?;
+    let fresh = other_feed.iter()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(fresh.len(), 1);
+    assert_eq!(fresh[0].event, Change::Set(kv(0), kv(0).into()));
+
+    drop(other_feed);
+    drop(feed);
+    drop(other);
+    drop(db);
+    std::fs::remove_dir_all(&path)?;
+
+    Ok(())
+}
+
+#[test]
+fn tree_change_feed_page_reuse() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .build();
+
+    let t = sled::Db::start(config)?;
+    let feed = t.change_feed("replicator")?;
+
+    // the pages of a dropped tree are reused by `t`, after
+    // keys relative to their old low keys were logged to them
+    let u = t.open_tree(b"u")?;
+    for i in 0..200 {
+        u.set(kv(i), kv(i))?;
+    }
+    drop(u);
+    assert!(t.drop_tree(b"u")?);
+
+    let mut expected = vec![];
+    for i in 0..500 {
+        t.set(kv(i), kv(i))?;
+        expected.push(Change::Set(kv(i), kv(i).into()));
+    }
+
+    let changes = feed.iter()?.collect::<Result<Vec<_>>>()?;
+    let events: Vec<Change> = changes.iter().map(|c| c.event.clone()).collect();
+    assert_eq!(events, expected);
+
+    // the leaves that split while replaying are
+    // carried over to the acknowledged position
+    feed.ack(changes[249].lsn)?;
+    for i in 500..600 {
+        t.set(kv(i), kv(i))?;
+        expected.push(Change::Set(kv(i), kv(i).into()));
+    }
+    let events: Vec<Change> = feed.iter()?.map(|c| c.unwrap().event).collect();
+    assert_eq!(&events[..], &expected[250..]);
+
+    Ok(())
+}
+
+#[test]
+fn tree_ttl() -> Result<()> {
+    use std::time::{Duration, Instant};
+
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_node_split_size(0)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .snapshot_after_ops(N_PER_THREAD as u64 / 2)
+        .build();
+
+    let short = Duration::from_millis(500);
+    let long = Duration::from_secs(3600);
+
+    let db = sled::Db::start(config.clone())?;
+    let t = db.open_tree(b"ttl")?;
+    let deadline = Instant::now() + short;
+    for i in 0..N_PER_THREAD {
+        match i % 3 {
+            0 => t.set_with_ttl(kv(i), kv(i), short)?,
+            1 => t.set_with_ttl(kv(i), kv(i), long)?,
+            _ => t.set(kv(i), kv(i))?,
+        };
+    }
+
+    // setting a key again without a TTL keeps it around
+    t.set(kv(3), vec![3])?;
+    let len = t.len();
+    if Instant::now() < deadline {
+        assert_eq!(len, N_PER_THREAD);
+    }
+    drop(t);
+    drop(db);
+
+    // expiration times survive restarts
+    let db = sled::Db::start(config.clone())?;
+    let t = db.open_tree(b"ttl")?;
+    let value = t.get(kv(0))?;
+    if Instant::now() < deadline {
+        assert_eq!(value, Some(IVec::from(kv(0))));
+    }
+
+    thread::sleep(short * 2);
+
+    let expected: Vec<Vec<u8>> = (0..N_PER_THREAD)
+        .filter(|i| i % 3 != 0 || *i == 3)
+        .map(kv)
+        .collect();
+
+    for i in 0..N_PER_THREAD {
+        let present = i % 3 != 0 || i == 3;
+        assert_eq!(t.get(kv(i))?.is_some(), present, "key {}", i);
+    }
+
+    let keys: Vec<Vec<u8>> = t.iter().keys().collect::<Result<_>>()?;
+    assert_eq!(keys, expected);
+
+    let mut rev: Vec<Vec<u8>> = t
+        .range(kv(0)..kv(N_PER_THREAD))
+        .keys()
+        .rev()
+        .collect::<Result<_>>()?;
+    rev.reverse();
+    assert_eq!(rev, expected);
+
+    let snapshot = db.snapshot()?.open_tree(b"ttl")?;
+    assert_eq!(snapshot.get(kv(0))?, None);
+    assert_eq!(snapshot.iter().count(), expected.len());
+
+    assert_eq!(t.get_gt(kv(2))?.map(|(k, _v)| k), Some(kv(3)));
+    assert_eq!(t.get_lt(kv(4))?.map(|(k, _v)| k), Some(kv(3)));
+    assert_eq!(t.get_lt(kv(6))?.map(|(k, _v)| k), Some(kv(5)));
+
+    // an expired key is treated as absent by writes
+    assert_eq!(t.cas(kv(0), None as Option<&[u8]>, Some(vec![0]))?, Ok(()));
+    assert_eq!(t.set_with_ttl(kv(6), kv(6), long)?, None);
+    assert_eq!(t.get(kv(6))?, Some(IVec::from(kv(6))));
+    drop(t);
+    drop(db);
+
+    let db = sled::Db::start(config)?;
+    let t = db.open_tree(b"ttl")?;
+    assert_eq!(t.get(kv(0))?, Some(IVec::from(vec![0])));
+    assert_eq!(t.get(kv(6))?, Some(IVec::from(kv(6))));
+    assert_eq!(t.get(kv(9))?, None);
+    assert_eq!(t.len(), expected.len() + 2);
+
+    Ok(())
+}
+
+#[test]
+fn tree_typed() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .build();
+
+    let db = sled::Db::start(config.clone())?;
+
+    // integer keys are iterated over in numeric order
+    let numbers = db.open_typed_tree::<u64, String, Ordered>(b"numbers")?;
+    for i in (0..N_PER_THREAD as u64).rev() {
+        assert_eq!(numbers.set(&(i * 100), &i.to_string())?, None);
+    }
+    assert_eq!(numbers.get(&300)?, Some("3".to_owned()));
+    let keys: Vec<u64> = numbers
+        .range(250..=1000)
+        .map(|res| res.map(|(k, _v)| k))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![300, 400, 500, 600, 700, 800, 900, 1000]);
+    let last = numbers.iter().next_back().unwrap()?;
+    assert_eq!(last, (9900, "99".to_owned()));
+
+    assert_eq!(numbers.cas(&1, None, Some(&"one".to_owned()))?, Ok(()),);
+    assert_eq!(
+        numbers.cas(&1, Some(&"two".to_owned()), None)?,
+        Err(Some("one".to_owned())),
+    );
+    let appended = numbers.update_and_fetch(&1, |v| v.map(|v| v + "!"))?;
+    assert_eq!(appended, Some("one!".to_owned()));
+    assert_eq!(numbers.del(&1)?, Some("one!".to_owned()));
+
+    // signed integers, strings and tuples keep their order
+    let events =
+        db.open_typed_tree::<(i64, String), u32, Ordered>(b"events")?;
+    let mut subscriber = events.watch_prefix(&(-1_i64,))?;
+    let keys = vec![
+        (-300, "b".to_owned()),
+        (-1, "".to_owned()),
+        (-1, "a".to_owned()),
+        (-1, "a\0".to_owned()),
+        (-1, "ab".to_owned()),
+        (0, "a".to_owned()),
+        (7, "".to_owned()),
+    ];
+    for (i, key) in keys.iter().enumerate().rev() {
+        events.set(key, &(i as u32))?;
+    }
+    let iterated: Vec<(i64, String)> = events
+        .iter()
+        .map(|res| res.map(|(k, _v)| k))
+        .collect::<Result<_>>()?;
+    assert_eq!(iterated, keys);
+
+    for i in (1..=4).rev() {
+        let event = subscriber.next().unwrap()?;
+        assert_eq!(event, TypedEvent::Set(keys[i].clone(), i as u32));
+    }
+    events.del(&keys[0])?;
+    events.del(&keys[1])?;
+    let event = subscriber.next().unwrap()?;
+    assert_eq!(event, TypedEvent::Del(keys[1].clone()));
+
+    let floats = db.open_typed_tree::<(Option<i8>, f64), (), Ordered>(b"f")?;
+    let keys = vec![
+        (None, 1.0),
+        (Some(-5), -1.5),
+        (Some(-5), 0.0),
+        (Some(-5), 2.25),
+        (Some(3), f64::NEG_INFINITY),
+    ];
+    for key in keys.iter().rev() {
+        floats.set(key, &())?;
+    }
+    let iterated: Vec<(Option<i8>, f64)> = floats
+        .iter()
+        .map(|res| res.map(|(k, _v)| k))
+        .collect::<Result<_>>()?;
+    assert_eq!(iterated, keys);
+
+    // bincode stores keys compactly, without ordering them
+    let compact =
+        db.open_typed_tree::<String, Vec<u64>, Bincode>(b"compact")?;
+    compact.set(&"list".to_owned(), &vec![1, 2, 3])?;
+    assert_eq!(compact.get(&"list".to_owned())?, Some(vec![1, 2, 3]));
+
+    drop(numbers);
+    drop(events);
+    drop(compact);
+    drop(db);
+
+    // codecs are recorded, and mismatches are refused
+    let db = sled::Db::start(config)?;
+    match db.open_typed_tree::<u64, String, Bincode>(b"numbers") {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("opened a tree with a different codec: {:?}", other),
+    }
+    let numbers = db.open_typed_tree::<u64, String, Ordered>(b"numbers")?;
+    assert_eq!(numbers.iter().count(), N_PER_THREAD);
+
+    // dropping a tree forgets its codec
+    db.drop_tree(b"compact")?;
+    let compact = db.open_typed_tree::<u64, u64, Ordered>(b"compact")?;
+    assert_eq!(compact.get(&0)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn tree_index() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .build();
+
+    let db = sled::Db::start(config.clone())?;
+    let t = db.open_tree(b"indexed")?;
+
+    fn lookup(t: &Tree, index_key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        t.index_lookup("digit", index_key)?
+            .map(|res| res.map(|(k, _v)| k))
+            .collect()
+    }
+
+    // indexes are built from the existing contents
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), vec![(i % 10) as u8])?;
+    }
+    t.register_index("digit", |_k, v| vec![v.to_vec()])?;
+    let threes: Vec<Vec<u8>> = (0..10).map(|i| kv(i * 10 + 3)).collect();
+    assert_eq!(lookup(&t, &[3])?, threes);
+    assert_eq!(lookup(&t, &[3, 0])?, Vec::<Vec<u8>>::new());
+
+    // and kept up to date by every kind of write
+    t.set(kv(3), vec![4])?;
+    t.del(kv(13))?;
+    assert_eq!(t.cas(kv(23), Some(vec![3]), Some(vec![5]))?, Ok(()));
+    let mut batch = Batch::default();
+    batch.set(kv(33), vec![6]);
+    batch.del(kv(43));
+    t.apply_batch(batch)?;
+    t.transaction(|tx| tx.set(kv(53), vec![7]))
+        .map_err(|e| match e {
+            TransactionError::Storage(e) => e,
+            other => panic!("unexpected transaction error: {:?}", other),
+        })?;
+    assert_eq!(lookup(&t, &[3])?, threes[6..].to_vec());
+    assert_eq!(lookup(&t, &[4])?.len(), 11);
+    assert_eq!(lookup(&t, &[5])?.len(), 11);
+
+    // index trees are not trees of the Db
+    assert_eq!(db.tree_names().len(), 2);
+    assert_eq!(db.snapshot()?.tree_names().len(), 2);
+
+    drop(t);
+    drop(db);
+
+    // after a restart, lookups work, but writes need the
+    // index to be registered again
+    let db = sled::Db::start(config.clone())?;
+    let t = db.open_tree(b"indexed")?;
+    assert_eq!(lookup(&t, &[3])?, threes[6..].to_vec());
+    match t.set(kv(63), vec![8]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("wrote without registering the index: {:?}", other),
+    }
+    assert_eq!(t.get(kv(63))?, Some(IVec::from(vec![3])));
+    t.register_index("digit", |_k, v| vec![v.to_vec()])?;
+    t.set(kv(63), vec![8])?;
+    assert_eq!(lookup(&t, &[3])?, threes[7..].to_vec());
+
+    // a changed extractor takes effect once rebuilt
+    t.register_index("digit", |_k, v| vec![vec![v[0] % 2]])?;
+    t.rebuild_index("digit")?;
+    assert_eq!(lookup(&t, &[0])?.len() + lookup(&t, &[1])?.len(), 98);
+    assert_eq!(lookup(&t, &[3])?, Vec::<Vec<u8>>::new());
+
+    assert_eq!(t.drop_index("digit")?, true);
+    assert_eq!(t.drop_index("digit")?, false);
+    assert!(t.index_lookup("digit", &[1]).is_err());
+    t.set(kv(73), vec![9])?;
+
+    // dropping a tree drops its indexes
+    t.register_index("digit", |_k, v| vec![v.to_vec()])?;
+    drop(t);
+    db.drop_tree(b"indexed")?;
+    let t = db.open_tree(b"indexed")?;
+    assert!(t.index_lookup("digit", &[1]).is_err());
+    t.register_index("digit", |_k, v| vec![v.to_vec()])?;
+
+    // indexes can not be rebuilt in read-only mode
+    let path = std::env::temp_dir()
+        .join(format!("sled_tree_index_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    db.checkpoint(&path)?;
+    let read_only = ConfigBuilder::new()
+        .path(&path)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .read_only(true)
+        .build();
+    let copy = sled::Db::start(read_only)?;
+    match copy.open_tree(b"indexed")?.rebuild_index("digit") {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("rebuilt an index while read-only: {:?}", other),
+    }
+    drop(copy);
+    std::fs::remove_dir_all(&path)?;
+
+    Ok(())
+}
+
+#[test]
+fn tree_merge_operator() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .flush_every_ms(None)
+        .build();
+
+    let add: MergeOperator = Arc::new(|_k, old, new| {
+        Some(vec![old.map_or(0, |old| old[0]) + new[0]])
+    });
+    let concatenate: MergeOperator = Arc::new(|_k, old, new| {
+        let mut ret = old.map(<[u8]>::to_vec).unwrap_or_default();
+        ret.extend_from_slice(new);
+        Some(ret)
+    });
+
+    let db = sled::Db::start(config.clone())?;
+    let counters = db.open_tree(b"counters")?;
+    let logs = db.open_tree(b"logs")?;
+
+    match logs.merge(b"k", vec![1]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("merged without a merge operator: {:?}", other),
+    }
+
+    // each tree merges with its own operator
+    counters.set_merge_operator("add", add.clone())?;
+    logs.set_merge_operator("concatenate", concatenate.clone())?;
+    for i in 1..=3 {
+        counters.merge(b"k", vec![i])?;
+        logs.merge(b"k", vec![i])?;
+    }
+    assert_eq!(counters.get(b"k")?, Some(IVec::from(vec![6])));
+    assert_eq!(logs.get(b"k")?, Some(IVec::from(vec![1, 2, 3])));
+
+    // merging into a value that has a TTL keeps it
+    let ttl = std::time::Duration::from_secs(3600);
+    counters.set_with_ttl(b"ttl", vec![1], ttl)?;
+    counters.merge(b"ttl", vec![1])?;
+    assert_eq!(counters.get(b"ttl")?, Some(IVec::from(vec![2])));
+
+    drop(counters);
+    drop(logs);
+    drop(db);
+
+    // after a restart, trees with an unregistered operator
+    // can not be opened
+    let db = sled::Db::start(config.clone())?;
+    match db.open_tree(b"logs") {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("opened without a merge operator: {:?}", other),
+    }
+    db.register_merge_operator("concatenate", concatenate);
+    let logs = db.open_tree(b"logs")?;
+    logs.merge(b"k", vec![4])?;
+    assert_eq!(logs.get(b"k")?, Some(IVec::from(vec![1, 2, 3, 4])));
+
+    db.register_merge_operator("add", add);
+    let counters = db.open_tree(b"counters")?;
+    counters.merge(b"k", vec![4])?;
+    assert_eq!(counters.get(b"k")?, Some(IVec::from(vec![10])));
+
+    Ok(())
+}
+
+#[test]
+#[allow(deprecated)]
+fn tree_config_merge_operator() -> Result<()> {
+    tests::setup_logger();
+
+    fn add(_k: &[u8], old: Option<&[u8]>, new: &[u8]) -> Option<Vec<u8>> {
+        Some(vec![old.map_or(0, |old| old[0]) + new[0]])
+    }
+
+    let _ = std::fs::remove_dir_all("/tmp/test_tree_config_merge_operator");
+    let path = "/tmp/test_tree_config_merge_operator/db".to_owned();
+
+    // trees that never set a merge operator still use the
+    // one from the config
+    let config = ConfigBuilder::new()
+        .path(path.clone())
+        .merge_operator(add)
+        .build();
+    let db = sled::Db::start(config)?;
+    let counters = db.open_tree(b"counters")?;
+
+    for i in 1..=3 {
+        db.merge(b"k", vec![i])?;
+        counters.merge(b"k", vec![i])?;
+    }
+    assert_eq!(db.get(b"k")?, Some(IVec::from(vec![6])));
+    assert_eq!(counters.get(b"k")?, Some(IVec::from(vec![6])));
+
+    drop(counters);
+    drop(db);
+
+    // the system can not be recovered without it anymore
+    let config = ConfigBuilder::new().path(path.clone()).build();
+    match sled::Db::start(config) {
+        Err(Error::Unsupported(_)) => {}
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("recovered without a merge operator"),
+    }
+
+    let config = ConfigBuilder::new().path(path).merge_operator(add).build();
+    let db = sled::Db::start(config)?;
+    db.merge(b"k", vec![4])?;
+    assert_eq!(db.get(b"k")?, Some(IVec::from(vec![10])));
+
+    drop(db);
+    std::fs::remove_dir_all("/tmp/test_tree_config_merge_operator").unwrap();
+
+    Ok(())
+}
+
+#[test]
+fn tree_format_version() -> Result<()> {
+    tests::setup_logger();
+
+    let _ = std::fs::remove_dir_all("/tmp/test_tree_format_version");
+    let path = "/tmp/test_tree_format_version/db".to_owned();
+
+    let expect_unsupported = |res: Result<Db>| match res {
+        Err(Error::Unsupported(_)) => {}
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("opened a system written in another format"),
+    };
+
+    // a system written with an older format version
+    let mut builder = ConfigBuilder::new().path(path.clone());
+    builder.format_version = pagecache::FORMAT_VERSION - 1;
+    let db = sled::Db::start(builder.build())?;
+    db.set(b"k", vec![1])?;
+    drop(db);
+
+    let config = ConfigBuilder::new().path(path.clone()).build();
+    expect_unsupported(sled::Db::start(config));
+
+    // one written before the format version was recorded in
+    // the configuration, which lacks its trailing field
+    let conf_path = format!("{}/conf", path);
+    let conf = std::fs::read(&conf_path).unwrap();
+    let mut legacy_conf = conf[..conf.len() - 8].to_vec();
+    let crc = pagecache::crc32(&legacy_conf);
+    legacy_conf.extend_from_slice(&crc.to_le_bytes());
+    std::fs::write(&conf_path, &legacy_conf).unwrap();
+
+    let config = ConfigBuilder::new().path(path.clone()).build();
+    expect_unsupported(sled::Db::start(config));
+
+    // the configuration is left alone for the older version
+    assert_eq!(std::fs::read(&conf_path).unwrap(), legacy_conf);
+
+    std::fs::remove_dir_all("/tmp/test_tree_format_version").unwrap();
+
+    Ok(())
+}
+
+#[test]
+fn tree_delete_range() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .blink_node_split_size(128)
+        .flush_every_ms(None)
+        .build();
+
+    let keys = |t: &Tree| -> Result<Vec<Vec<u8>>> { t.iter().keys().collect() };
+
+    let t = sled::Db::start(config.clone())?;
+    for i in 0..N_PER_THREAD {
+        t.set(kv(i), kv(i))?;
+    }
+
+    let mut subscriber = t.watch_prefix(vec![]);
+    let feed = t.change_feed("replicator")?;
+
+    t.delete_range(kv(10)..kv(90))?;
+    let expected: Vec<Vec<u8>> = (0..10).chain(90..100).map(kv).collect();
+    assert_eq!(keys(&t)?, expected);
+
+    // subscribers see each key, in order
+    for i in 10..90 {
+        assert_eq!(subscriber.next(), Some(Event::Del(kv(i))));
+    }
+
+    // change feeds see one contiguous range per leaf
+    let events: Vec<Change> = feed.iter()?.map(|c| c.unwrap().event).collect();
+    assert!(events.len() > 1);
+    let mut next_start = kv(10);
+    for event in events {
+        match event {
+            Change::DelRange(start, Some(end)) => {
+                assert_eq!(start, next_start);
+                next_start = end;
+            }
+            other => panic!("unexpected change feed event: {:?}", other),
+        }
+    }
+    assert_eq!(next_start, kv(90));
+
+    t.delete_range(..=kv(5))?;
+    t.delete_range(kv(9)..kv(9))?;
+    let expected: Vec<Vec<u8>> = (6..10).chain(90..100).map(kv).collect();
+    assert_eq!(keys(&t)?, expected);
+
+    drop(subscriber);
+    drop(feed);
+    drop(t);
+
+    // the range tombstones are recovered from the log
+    let t = sled::Db::start(config.clone())?;
+    assert_eq!(keys(&t)?, expected);
+
+    // subscribers of keys outside of the range see nothing
+    let mut unrelated = t.watch_prefix(kv(7));
+    t.delete_range(kv(95)..)?;
+    t.set(kv(7), vec![])?;
+    assert_eq!(
+        unrelated.next(),
+        Some(Event::Set(kv(7), IVec::from(vec![])))
+    );
+
+    t.set(kv(42), kv(42))?;
+    assert_eq!(t.get(kv(42))?, Some(IVec::from(kv(42))));
+    assert_eq!(t.len(), 10);
+
+    t.clear()?;
+    assert!(t.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn tree_delete_range_concurrent_readers() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_node_split_size(128)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Db::start(config)?;
+
+    let done = Arc::new(AtomicBool::new(false));
+
+    // the leaves are removed from the first to the last, so
+    // a reader must never see the first key of a range removed
+    // and then the last one still present. every round uses
+    // its own range, so that a range is never written again
+    // after it was removed.
+    const ROUNDS: usize = 10;
+    let mut readers = vec![];
+    for _ in 0..N_THREADS {
+        let t = t.clone();
+        let done = done.clone();
+        let reader = thread::spawn(move || {
+            while !done.load(SeqCst) {
+                for round in 0..ROUNDS {
+                    let start = round * N_PER_THREAD;
+                    let first = t.get(kv(start)).unwrap();
+                    let last = t.get(kv(start + N_PER_THREAD - 1)).unwrap();
+                    assert!(
+                        first.is_some() || last.is_none(),
+                        "observed a partially removed range"
+                    );
+                }
+            }
+        });
+        readers.push(reader);
+    }
+
+    for round in 0..ROUNDS {
+        let start = round * N_PER_THREAD;
+        for i in start..start + N_PER_THREAD {
+            t.set(kv(i), kv(i))?;
+        }
+        t.delete_range(kv(start)..kv(start + N_PER_THREAD))?;
+    }
+
+    done.store(true, SeqCst);
+    for reader in readers.into_iter() {
+        reader.join().unwrap();
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_bulk_load() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .blink_node_split_size(128)
+        .flush_every_ms(None)
+        .build();
+
+    let n = N / 2;
+    let keys = |t: &Tree| -> Result<Vec<Vec<u8>>> { t.iter().keys().collect() };
+    let expected: Vec<Vec<u8>> = (0..n).map(kv).collect();
+
+    let db = sled::Db::start(config.clone())?;
+    let t = db.bulk_load_tree(b"loaded", (0..n).map(|i| (kv(i), kv(i))))?;
+
+    assert_eq!(keys(&t)?, expected);
+    assert_eq!(t.get(kv(42))?, Some(IVec::from(kv(42))));
+    let range: Vec<Vec<u8>> =
+        t.range(kv(10)..kv(20)).keys().collect::<Result<_>>()?;
+    assert_eq!(range, expected[10..20].to_vec());
+    let rev: Vec<Vec<u8>> = t.iter().keys().rev().collect::<Result<_>>()?;
+    assert_eq!(rev, expected.iter().rev().cloned().collect::<Vec<_>>());
+
+    // only empty trees can be bulk loaded
+    match t.bulk_load(vec![(kv(n), kv(n))]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("bulk loaded a non-empty tree: {:?}", other),
+    }
+
+    // keys must be strictly ascending, and nothing is
+    // loaded if they are not
+    let unsorted = db.open_tree(b"unsorted")?;
+    match unsorted.bulk_load(vec![(kv(2), kv(2)), (kv(1), kv(1))]) {
+        Err(Error::Unsupported(_)) => {}
+        other => panic!("bulk loaded unsorted keys: {:?}", other),
+    }
+    assert!(unsorted.is_empty());
+    unsorted.bulk_load(Vec::<(Vec<u8>, Vec<u8>)>::new())?;
+    assert!(unsorted.is_empty());
+
+    // the loaded nodes split and grow like any others
+    for i in (0..n).step_by(2) {
+        t.del(kv(i))?;
+    }
+    for i in n..n * 2 {
+        t.set(kv(i), kv(i))?;
+    }
+    let expected: Vec<Vec<u8>> =
+        (1..n).step_by(2).chain(n..n * 2).map(kv).collect();
+    assert_eq!(keys(&t)?, expected);
+
+    drop(t);
+    drop(unsorted);
+    drop(db);
+
+    // the loaded tree is recovered from the log
+    let db = sled::Db::start(config.clone())?;
+    let t = db.open_tree(b"loaded")?;
+    assert_eq!(keys(&t)?, expected);
+    assert!(db.open_tree(b"unsorted")?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn tree_node_merge() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .blink_node_split_size(1024)
+        .flush_every_ms(None)
+        .build();
+
+    let leaves = |t: &Tree| format!("{:?}", t).matches("Leaf(").count();
+    let keys = |t: &Tree| -> Result<Vec<Vec<u8>>> { t.iter().keys().collect() };
+
+    let t = sled::Db::start(config.clone())?;
+    for i in 0..N {
+        t.set(kv(i), kv(i))?;
+    }
+    let full = leaves(&t);
+
+    // iterators that run while leaves are merged see
+    // each remaining key exactly once, in order
+    let kept: Vec<Vec<u8>> = (0..N).step_by(10).map(kv).collect();
+    let scanners: Vec<thread::JoinHandle<Result<()>>> = (0..2)
+        .map(|n| {
+            let t = t.clone();
+            let kept = kept.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    let scanned: Vec<Vec<u8>> = if n == 0 {
+                        keys(&t)?
+                    } else {
+                        let mut rev: Vec<Vec<u8>> =
+                            t.iter().keys().rev().collect::<Result<_>>()?;
+                        rev.reverse();
+                        rev
+                    };
+                    assert!(scanned.windows(2).all(|w| w[0] < w[1]));
+                    assert!(kept.iter().all(|k| scanned.contains(k)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for i in (0..N).filter(|i| i % 10 != 0) {
+        t.del(kv(i))?;
+    }
+    for scanner in scanners {
+        scanner.join().expect("thread should not have crashed")?;
+    }
+
+    assert_eq!(keys(&t)?, kept);
+    let merged = leaves(&t);
+    assert!(merged * 2 < full, "{} leaves of {} remain", merged, full);
+
+    drop(t);
+
+    // merges are recovered from the log, and merged
+    // leaves split again as they fill up
+    let t = sled::Db::start(config.clone())?;
+    assert_eq!(keys(&t)?, kept);
+    assert_eq!(leaves(&t), merged);
+
+    for i in 0..N {
+        t.set(kv(i), kv(i))?;
+    }
+    assert_eq!(t.len(), N);
+    assert!(leaves(&t) > merged);
+
+    // range deletions merge the leaves that they empty
+    t.delete_range(kv(1)..kv(N - 1))?;
+    assert_eq!(keys(&t)?, vec![kv(0), kv(N - 1)]);
+    assert!(leaves(&t) * 2 < full, "{} leaves remain", leaves(&t));
+
+    Ok(())
+}
+
+#[test]
+fn tree_approximate_stats() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(5000)
+        .blink_node_split_size(128)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Db::start(config)?;
+
+    // estimates are exact for trees with few leaves
+    t.set(kv(1), vec![0; 10])?;
+    assert_eq!(t.approximate_len()?, 1);
+    assert_eq!(t.approximate_size_in_bytes()?, 3 + 10);
+    assert_eq!(t.approximate_count_in_range(kv(2)..)?, 0);
+    assert_eq!(t.split_points(2)?, Vec::<IVec>::new());
+    t.del(kv(1))?;
+    assert_eq!(t.approximate_len()?, 0);
+
+    for i in 0..N {
+        t.set(kv(i), kv(i))?;
+    }
+
+    let close = |estimate: usize, exact: usize| {
+        assert!(
+            estimate * 4 > exact * 3 && estimate * 4 < exact * 5,
+            "estimated {} instead of {}",
+            estimate,
+            exact
+        );
+    };
+
+    close(t.approximate_len()?, N);
+    close(t.approximate_size_in_bytes()? as usize, N * 6);
+    close(t.approximate_count_in_range(kv(100)..kv(900))?, 800);
+    assert_eq!(t.approximate_count_in_range(kv(100)..=kv(102))?, 3);
+    assert_eq!(t.approximate_count_in_range(kv(900)..kv(100))?, 0);
+
+    let points = t.split_points(4)?;
+    assert_eq!(points.len(), 3);
+    let mut bounds = vec![kv(0)];
+    bounds.extend(points.iter().map(|p| p.to_vec()));
+    bounds.push(kv(N - 1));
+    for window in bounds.windows(2) {
+        let count = t.range(window[0].clone()..window[1].clone()).count();
+        close(count, N / 4);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_par_range() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_node_split_size(128)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Db::start(config)?;
+
+    for i in 0..N {
+        t.set(kv(i), kv(i))?;
+    }
+
+    let par_keys = |iter: ParIter<'_>| -> Result<Vec<Vec<u8>>> {
+        let mut keys: Vec<Vec<u8>> = iter
+            .map(|res| res.map(|(k, _v)| k))
+            .collect::<Result<_>>()?;
+        keys.sort();
+        Ok(keys)
+    };
+
+    // the partitions are adjacent, and cover the range
+    let iter = t.par_range(kv(100)..kv(900), 4)?;
+    let partitions = iter.partitions().to_vec();
+    assert_eq!(partitions.len(), 4);
+    assert_eq!(partitions[0].0, Bound::Included(kv(100)));
+    assert_eq!(partitions[3].1, Bound::Excluded(kv(900)));
+    for pair in partitions.windows(2) {
+        match (&pair[0].1, &pair[1].0) {
+            (Bound::Excluded(end), Bound::Included(start)) => {
+                assert_eq!(end, start)
+            }
+            other => panic!("partitions are not adjacent: {:?}", other),
+        }
+    }
+    for partition in &partitions {
+        let count = t.range(partition.clone()).count();
+        assert!(count > 100, "unbalanced partitions {:?}", partitions);
+    }
+    let expected: Vec<Vec<u8>> = (100..900).map(kv).collect();
+    assert_eq!(par_keys(iter)?, expected);
+
+    let expected: Vec<Vec<u8>> = (0..N).map(kv).collect();
+    assert_eq!(par_keys(t.par_range::<Vec<u8>, _>(.., 16)?)?, expected);
+
+    // ranges within one leaf are not split
+    let iter = t.par_range(kv(10)..=kv(10), 4)?;
+    assert_eq!(iter.partitions().len(), 1);
+    assert_eq!(par_keys(iter)?, vec![kv(10)]);
+
+    assert_eq!(par_keys(t.par_range(kv(900)..kv(100), 4)?)?.len(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn tree_cursor() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_node_split_size(128)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Db::start(config)?;
+
+    let mut cursor = t.cursor()?;
+    assert!(!cursor.next()?);
+    assert!(!cursor.seek(kv(0))?);
+
+    // only even keys, so that odd keys can be sought
+    let n = N / 2;
+    for i in (0..n).step_by(2) {
+        t.set(kv(i), kv(i))?;
+    }
+    let expected: Vec<Vec<u8>> = (0..n).step_by(2).map(kv).collect();
+
+    let mut keys = vec![];
+    while cursor.next()? {
+        keys.push(cursor.key().unwrap().to_vec());
+        assert_eq!(cursor.value(), Some(&IVec::from(kv(keys.len() * 2 - 2))));
+    }
+    assert_eq!(keys, expected);
+
+    let mut keys = vec![];
+    while cursor.prev()? {
+        keys.push(cursor.key().unwrap().to_vec());
+    }
+    keys.reverse();
+    assert_eq!(keys, expected);
+
+    assert!(cursor.seek(kv(101))?);
+    assert_eq!(cursor.key(), Some(&*kv(102)));
+    assert!(cursor.prev()?);
+    assert_eq!(cursor.key(), Some(&*kv(100)));
+    assert!(cursor.seek_for_prev(kv(101))?);
+    assert_eq!(cursor.key(), Some(&*kv(100)));
+    assert!(cursor.next()?);
+    assert_eq!(cursor.key(), Some(&*kv(102)));
+    assert!(cursor.seek_for_prev(kv(100))?);
+    assert_eq!(cursor.key(), Some(&*kv(100)));
+    assert!(!cursor.seek_for_prev(vec![])?);
+    assert!(!cursor.seek(kv(n))?);
+
+    // changing direction at every step
+    assert!(cursor.seek(kv(0))?);
+    for i in (2..n - 2).step_by(2) {
+        assert!(cursor.next()?);
+        assert!(cursor.next()?);
+        assert!(cursor.prev()?);
+        assert_eq!(cursor.key(), Some(&*kv(i)));
+    }
+
+    // paginate while the keys are deleted, refreshing
+    // the cursor between pages
+    let mut keys = vec![];
+    assert!(cursor.seek(kv(0))?);
+    loop {
+        for _ in 0..10 {
+            let key = cursor.key().unwrap().to_vec();
+            t.del(&key)?;
+            keys.push(key);
+            if !cursor.next()? {
+                break;
+            }
+        }
+        if !cursor.refresh()? {
+            break;
+        }
+    }
+    assert_eq!(keys, expected);
+    assert!(t.is_empty());
+
+    // refreshing reads the current value
+    t.set(kv(1), vec![1])?;
+    assert!(cursor.seek(kv(0))?);
+    t.set(kv(1), vec![2])?;
+    assert_eq!(cursor.value(), Some(&IVec::from(vec![1])));
+    assert!(cursor.refresh()?);
+    assert_eq!(cursor.value(), Some(&IVec::from(vec![2])));
+
+    Ok(())
+}
+
+#[test]
+fn tree_prefix() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .blink_node_split_size(128)
+        .flush_every_ms(None)
+        .build();
+    let t = sled::Db::start(config)?;
+
+    // prefixes of 0xff bytes have no successor
+    let mut all: Vec<Vec<u8>> = vec![];
+    for a in &[0_u8, 1, 254, 255] {
+        for b in (0..=255_u8).step_by(5) {
+            all.push(vec![*a, b]);
+            all.push(vec![*a, b, 255]);
+        }
+    }
+    all.sort();
+    for key in &all {
+        t.set(key, key.clone())?;
+    }
+
+    let with_prefix = |keys: &[Vec<u8>], prefix: &[u8]| -> Vec<Vec<u8>> {
+        keys.iter()
+            .filter(|k| k.starts_with(prefix))
+            .cloned()
+            .collect()
+    };
+
+    let prefixes: Vec<&[u8]> = vec![
+        &[],
+        &[0],
+        &[1, 5],
+        &[1, 5, 255],
+        &[254],
+        &[255],
+        &[255, 255],
+        &[2],
+    ];
+    for prefix in &prefixes {
+        let expected = with_prefix(&all, prefix);
+
+        let keys: Vec<Vec<u8>> =
+            t.scan_prefix(prefix).keys().collect::<Result<_>>()?;
+        assert_eq!(keys, expected, "scanning prefix {:?}", prefix);
+
+        let mut keys: Vec<Vec<u8>> =
+            t.scan_prefix(prefix).keys().rev().collect::<Result<_>>()?;
+        keys.reverse();
+        assert_eq!(keys, expected, "scanning prefix {:?} in reverse", prefix);
+
+        assert_eq!(
+            t.count_prefix(prefix)?,
+            expected.len(),
+            "counting prefix {:?}",
+            prefix
+        );
+    }
+
+    t.delete_prefix(&[1])?;
+    t.delete_prefix(&[255])?;
+    t.delete_prefix(&[2])?;
+    let remaining: Vec<Vec<u8>> = all
+        .iter()
+        .filter(|k| k[0] != 1 && k[0] != 255)
+        .cloned()
+        .collect();
+    let keys: Vec<Vec<u8>> = t.iter().keys().collect::<Result<_>>()?;
+    assert_eq!(keys, remaining);
+    assert_eq!(t.count_prefix(&[1])?, 0);
+    assert_eq!(t.count_prefix(&[])?, remaining.len());
+
+    t.delete_prefix(&[])?;
+    assert!(t.is_empty());
+
+    Ok(())
+}
+#[test]
+fn tree_verify_integrity() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(10_000)
+        .blink_node_split_size(1024)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config.clone())?;
+    let other = db.open_tree(b"other")?;
+
+    for i in 0..2000_u32 {
+        db.set(i.to_be_bytes(), i.to_be_bytes().to_vec())?;
+        other.set(i.to_le_bytes(), vec![0; 10])?;
+    }
+    // values over a quarter of a segment are stored as blobs
+    for i in 0..10_u32 {
+        other.set(i.to_be_bytes(), vec![1; 5000])?;
+    }
+    // and deletions merge leaves
+    for i in (0..2000_u32).filter(|i| i % 8 != 0) {
+        db.del(i.to_be_bytes())?;
+    }
+
+    let report = db.verify_integrity()?;
+    assert!(report.is_ok(), "{}", report);
+    assert!(report.segments > 1);
+    assert!(report.messages > report.pages);
+    assert!(report.blobs > 0);
+    assert!(report.nodes > 2);
+
+    // corrupt the newest blob, which holds the last update to
+    // `other`, leaving alone the older ones that may no longer
+    // be referenced by any page
+    let blobs = config.get_path().join("blobs");
+    let newest = std::fs::read_dir(blobs)?
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            (name.parse::<Lsn>().unwrap(), path)
+        })
+        .max()
+        .unwrap();
+    let (newest_blob, newest_path) = newest;
+    let mut contents = std::fs::read(&newest_path)?;
+    let last = contents.len() - 1;
+    contents[last] ^= 0xFF;
+    std::fs::write(&newest_path, contents)?;
+
+    let report = db.verify_integrity()?;
+    assert_eq!(report.problems.len(), 1, "{}", report);
+    match &report.problems[0] {
+        IntegrityProblem::UnreadablePage {
+            ptr: pagecache::DiskPtr::Blob(_lid, blob_ptr),
+            ..
+        } => assert_eq!(*blob_ptr, newest_blob),
+        other => panic!("unexpected problem: {}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_salvage() -> Result<()> {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(10_000)
+        .blink_node_split_size(1024)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config.clone())?;
+    let other = db.open_tree(b"other")?;
+
+    let mut expected: BTreeMap<Vec<u8>, BTreeMap<Vec<u8>, IVec>> =
+        BTreeMap::new();
+    for i in 0..2000_u32 {
+        let k = i.to_be_bytes().to_vec();
+        db.set(&k, vec![1; 10])?;
+        other.set(&k, vec![2; 10])?;
+        expected
+            .entry(b"other".to_vec())
+            .or_default()
+            .insert(k.clone(), IVec::from(vec![2; 10]));
+        if i % 3 == 0 {
+            db.del(&k)?;
+        } else {
+            expected
+                .entry(b"__sled__default".to_vec())
+                .or_default()
+                .insert(k, IVec::from(vec![1; 10]));
+        }
+    }
+    other.set_with_ttl(b"ttl", vec![3], Duration::from_secs(3600))?;
+    expected
+        .get_mut(&b"other".to_vec())
+        .unwrap()
+        .insert(b"ttl".to_vec(), IVec::from(vec![3]));
+    db.flush()?;
+    drop(other);
+    drop(db);
+
+    let salvage_into = || -> Result<(sled::Db, SalvageReport)> {
+        let new_config = ConfigBuilder::new().temporary(true).build();
+        let new_db = sled::Db::start(new_config)?;
+        let report = sled::Db::salvage(config.clone(), &new_db)?;
+        Ok((new_db, report))
+    };
+
+    let read_all = |db: &sled::Db, name: &[u8]| -> Result<BTreeMap<_, _>> {
+        let tree = if name == b"__sled__default" {
+            db.open_tree(b"__sled__default").map(|_| ())?;
+            db.iter().collect::<Result<BTreeMap<_, _>>>()?
+        } else {
+            db.open_tree(name)?
+                .iter()
+                .collect::<Result<BTreeMap<_, _>>>()?
+        };
+        Ok(tree)
+    };
+
+    let (new_db, report) = salvage_into()?;
+    assert!(report.is_complete(), "{}", report);
+    assert!(report.problems.is_empty(), "{}", report);
+    for (name, items) in &expected {
+        assert_eq!(&read_all(&new_db, name)?, items);
+    }
+    let new_other = new_db.open_tree(b"other")?;
+    assert_eq!(new_other.get(b"ttl")?, Some(IVec::from(vec![3])));
+    drop(new_other);
+    drop(new_db);
+
+    // damage a segment in the middle of the log
+    let path = config.get_path().join("db");
+    let mut contents = std::fs::read(&path)?;
+    for byte in &mut contents[30_100..30_400] {
+        *byte ^= 0xFF;
+    }
+    std::fs::write(&path, contents)?;
+
+    let (new_db, report) = salvage_into()?;
+    assert!(!report.problems.is_empty(), "{}", report);
+    for tree in &report.trees {
+        let items = read_all(&new_db, &tree.name)?;
+        assert_eq!(items.len(), tree.items);
+
+        // every item that was not salvaged intact was
+        // in a range that was reported.
+        let in_reported = |key: &[u8]| {
+            tree.lost.iter().chain(tree.damaged.iter()).any(|range| {
+                key >= &*range.start
+                    && range.end.as_ref().map_or(true, |end| key < &**end)
+            })
+        };
+        for (k, v) in &expected[&tree.name] {
+            if items.get(k) != Some(v) {
+                assert!(in_reported(k), "{:?} was lost silently", k);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn tree_inspect() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(10_000)
+        .snapshot_after_ops(100)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config.clone())?;
+    let other = db.open_tree(b"other")?;
+
+    for i in 0..1000_u32 {
+        db.set(i.to_be_bytes(), vec![1; 10])?;
+        other.set(i.to_be_bytes(), vec![2; 10])?;
+    }
+    other.set(b"blob", vec![3; 5000])?;
+    db.flush()?;
+    drop(other);
+    drop(db);
+
+    let path = config.get_path().join("db");
+    let before = std::fs::read(&path)?;
+    let inspection = sled::Db::inspect(config.clone())?;
+    assert_eq!(std::fs::read(&path)?, before);
+
+    // only the segments that may still be written to are unsealed
+    let segments = &inspection.segments;
+    assert!(segments.len() > config.io_bufs);
+    let sealed = segments.len() - config.io_bufs;
+    assert!(segments[..sealed].iter().all(|segment| segment.sealed));
+
+    assert!(inspection
+        .messages()
+        .any(|message| message.kind == MessageKind::Blob
+            && message.blob.is_some()));
+
+    let (_lsn, meta) = inspection.meta().expect("the meta should be readable");
+    let tenants = meta.tenants();
+    assert!(tenants.contains_key(&b"other"[..]));
+
+    // the chain of every root starts from a whole node
+    for &root in tenants.values() {
+        let chain = inspection.page(root);
+        assert!(matches!(
+            chain[0].update,
+            Some((_pid, Update::Compact(ref frag))) if frag.kind == "Base"
+        ));
+        assert!(chain.iter().all(|message| {
+            matches!(message.update, Some((pid, _)) if pid == root)
+        }));
+    }
+
+    let snapshot = inspection.snapshot.expect("a snapshot should be written");
+    assert!(snapshot.max_lsn > 0);
+    assert!(tenants.values().all(|&root| root <= snapshot.max_pid));
+
+    Ok(())
+}
+
+#[test]
+fn tree_metrics() -> Result<()> {
+    tests::setup_logger();
+
+    let db = sled::Db::start(ConfigBuilder::new().temporary(true).build())?;
+    let idle = sled::Db::start(ConfigBuilder::new().temporary(true).build())?;
+
+    for i in 0..100_u32 {
+        db.set(i.to_be_bytes(), vec![1; 10])?;
+    }
+    for i in 0..50_u32 {
+        db.get(i.to_be_bytes())?;
+    }
+    db.flush()?;
+
+    // every Db has its own metrics
+    let metrics = db.metrics();
+    let get = metrics.histogram("tree_get").unwrap();
+    assert_eq!(get.count, 50);
+    assert_eq!(get.unit, MetricUnit::Seconds);
+    assert!(get.min <= get.p50 && get.p50 <= get.p99 && get.p99 <= get.max);
+    assert_eq!(metrics.histogram("tree_set").unwrap().count, 100);
+    assert!(metrics.histogram("written").unwrap().sum > 1000.);
+    assert!(metrics.counter("log_reservations").unwrap() >= 100);
+
+    let idle_metrics = idle.metrics();
+    assert_eq!(idle_metrics.histogram("tree_get").unwrap().count, 0);
+    assert!(idle_metrics.histogram("tree_get").unwrap().p50.is_nan());
+
+    let text = metrics.to_prometheus("sled");
+    assert!(text.contains("# TYPE sled_tree_get_seconds summary\n"));
+    assert!(text.contains("sled_tree_get_seconds_count 50\n"));
+    assert!(text.contains("sled_tree_get_seconds{quantile=\"0.99\"} "));
+    assert!(text.contains("# TYPE sled_log_reservations_total counter\n"));
+    assert!(text.contains("sled_written_bytes_sum "));
+
+    let json = idle_metrics.to_json();
+    assert!(json.starts_with("{\"counters\":{\"tree_loops\":0,"));
+    assert!(json.contains(
+        "\"tree_get\":{\"unit\":\"seconds\",\"count\":0,\"sum\":0,\
+         \"min\":null,"
+    ));
+    assert!(json.ends_with("}}"));
+
+    Ok(())
+}
+
+#[test]
+fn tree_storage_stats() -> Result<()> {
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new()
+        .temporary(true)
+        .io_buf_size(10_000)
+        .snapshot_after_ops(100)
+        .flush_every_ms(None)
+        .build();
+    let db = sled::Db::start(config.clone())?;
+
+    // overwriting every key leaves dead fragments behind
+    for _ in 0..5 {
+        for i in 0..500_u32 {
+            db.set(i.to_be_bytes(), vec![1; 10])?;
+        }
+    }
+    db.set(b"blob", vec![2; 5000])?;
+    db.flush()?;
+
+    let stats = db.storage_stats()?;
+    assert_eq!(stats.segment_bytes, config.io_buf_size as u64);
+    assert!(stats.file_bytes > 0);
+    assert!(stats.segments() > config.io_bufs);
+    assert!(stats.active_segments > 0);
+    assert_eq!(
+        stats.utilization.iter().sum::<usize>(),
+        stats.inactive_segments + stats.draining_segments
+    );
+    assert!(stats.live_bytes > 0);
+    assert!(stats.reclaimable_bytes > 0);
+    assert_eq!(stats.blobs, 1);
+    assert!(stats.blob_bytes > 5000);
+    assert!(stats.snapshot_bytes > 0);
+    assert!(stats.pages > 0);
+    assert_eq!(
+        stats.total_bytes(),
+        stats.file_bytes + stats.blob_bytes + stats.snapshot_bytes
+    );
+    assert!(stats.to_string().contains("1 blobs"));
+
+    // removing the blob value frees its file once its
+    // segment is no longer written to
+    db.del(b"blob")?;
+    for i in 0..500_u32 {
+        db.set(i.to_be_bytes(), vec![3; 10])?;
+    }
+    db.flush()?;
+    assert_eq!(db.storage_stats()?.blobs, 0);
+
+    Ok(())
+}
+
 #[test]
 fn recover_tree() {
     tests::setup_logger();
@@ -77,12 +77,12 @@ fn v(b: &[u8]) -> u16 {
     (u16::from(b[0]) << 8) + u16::from(b[1])
 }
 
-fn prop_tree_crashes_nicely(ops: Vec<Op>, flusher: bool) -> bool {
-    lazy_static! {
-        // forces quickcheck to run one thread at a time
-        static ref M: Mutex<()> = Mutex::new(());
-    }
+lazy_static! {
+    // forces quickcheck to run one thread at a time
+    static ref M: Mutex<()> = Mutex::new(());
+}
 
+fn prop_tree_crashes_nicely(ops: Vec<Op>, flusher: bool) -> bool {
     let _lock = M.lock().expect("our test lock should not be poisoned");
 
     // clear all failpoints that may be left over from the last run
@@ -1114,3 +1114,39 @@ fn failpoints_bug_24() {
         false,
     ))
 }
+
+#[test]
+fn failpoints_unsealed_batch() {
+    // a batch that could not be sealed fails everything after
+    // it, rather than staying visible until it is discarded
+    // by recovery along with whatever was written after it.
+    let _lock = M.lock().expect("our test lock should not be poisoned");
+    fail::teardown();
+    tests::setup_logger();
+
+    let config = ConfigBuilder::new().temporary(true).async_io(false).build();
+
+    let db = sled::Db::start(config.clone()).unwrap();
+    db.set(b"a", vec![1]).unwrap();
+    db.flush().unwrap();
+
+    fail::cfg("rewrite_batch_manifest", "return").unwrap();
+
+    let mut batch = Batch::default();
+    batch.set(b"a", vec![2]);
+    batch.set(b"b", vec![2]);
+    assert_eq!(db.apply_batch(batch), Err(Error::FailPoint));
+
+    fail::teardown();
+
+    assert_eq!(db.get(b"a"), Err(Error::FailPoint));
+    assert_eq!(db.set(b"c", vec![3]), Err(Error::FailPoint));
+    assert_eq!(db.flush(), Err(Error::FailPoint));
+
+    drop(db);
+
+    let db = sled::Db::start(config).unwrap();
+    assert_eq!(db.get(b"a"), Ok(Some(IVec::from(vec![1]))));
+    assert_eq!(db.get(b"b"), Ok(None));
+    assert_eq!(db.get(b"c"), Ok(None));
+}